@@ -0,0 +1,137 @@
+//! Benchmarks for each stage of running a program, to compare changes meant
+//! to speed things up against. Run with `cargo bench`.
+
+#[macro_use]
+extern crate criterion;
+extern crate fucker;
+
+use std::collections::VecDeque;
+use std::io::{self, Cursor};
+
+use criterion::{BatchSize, Criterion};
+
+use fucker::parser::{Ast, AstNode};
+use fucker::runnable::interpreter::Fucker;
+#[cfg(target_arch = "x86_64")]
+use fucker::runnable::jit::JITTarget;
+use fucker::runnable::{Instrumentation, Runnable};
+
+const HELLO_WORLD: &str = include_str!("../test/programs/hello_world.bf");
+const MANDELBROT: &str = include_str!("../test/programs/mandelbrot.bf");
+const ROT13: &str = include_str!("../test/programs/rot13-16char.bf");
+const ROT13_INPUT: &[u8] = include_bytes!("../test/programs/rot13-16char.in");
+
+/// Nested loops that can't be simplified away, so that running them is mostly
+/// dispatching instructions.
+const DISPATCH: &str = "++++++++[>++++++++[>++++++++[>+>.<<-]<-]<-]";
+
+fn nodes(source: &str) -> VecDeque<AstNode> {
+    Ast::parse(source).unwrap().data
+}
+
+fn interpreter(source: &str, input: &[u8]) -> Fucker {
+    Fucker::new(
+        nodes(source),
+        Box::new(Cursor::new(input.to_vec())),
+        Box::new(io::sink()),
+        Instrumentation::default(),
+    )
+}
+
+#[cfg(target_arch = "x86_64")]
+fn jit(source: &str, input: &[u8]) -> JITTarget {
+    JITTarget::new(
+        nodes(source),
+        Box::new(Cursor::new(input.to_vec())),
+        Box::new(io::sink()),
+        Instrumentation::default(),
+    )
+}
+
+fn parsing(c: &mut Criterion) {
+    c.bench_function("parse mandelbrot", |b| {
+        b.iter(|| Ast::parse_raw(MANDELBROT).unwrap())
+    });
+
+    let raw = Ast::parse_raw(MANDELBROT).unwrap();
+    c.bench_function("optimize mandelbrot", |b| {
+        b.iter_batched(|| raw.clone(), Ast::optimize, BatchSize::SmallInput)
+    });
+}
+
+fn interpreting(c: &mut Criterion) {
+    c.bench_function("interpreter dispatch", |b| {
+        b.iter_batched(
+            || interpreter(DISPATCH, &[]),
+            |mut fucker| fucker.run().unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    // Mandelbrot takes the interpreter too long to sample.
+    for &(name, source, input) in &[
+        ("interpret hello_world", HELLO_WORLD, &[][..]),
+        ("interpret rot13", ROT13, ROT13_INPUT),
+    ] {
+        c.bench_function(name, |b| {
+            b.iter_batched(
+                || interpreter(source, input),
+                |mut fucker| fucker.run().unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn compiling(c: &mut Criterion) {
+    let nodes = nodes(MANDELBROT);
+    c.bench_function("codegen mandelbrot", |b| {
+        b.iter_batched(
+            || nodes.clone(),
+            |nodes| {
+                JITTarget::new(
+                    nodes,
+                    Box::new(io::empty()),
+                    Box::new(io::sink()),
+                    Instrumentation::default(),
+                )
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    for &(name, source, input) in &[
+        ("jit dispatch", DISPATCH, &[][..]),
+        ("jit hello_world", HELLO_WORLD, &[][..]),
+        ("jit rot13", ROT13, ROT13_INPUT),
+    ] {
+        c.bench_function(name, |b| {
+            b.iter_batched(
+                || jit(source, input),
+                |mut jit_target| jit_target.run().unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn mandelbrot(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mandelbrot");
+    group.sample_size(10);
+    group.bench_function("jit", |b| {
+        b.iter_batched(
+            || jit(MANDELBROT, &[]),
+            |mut jit_target| jit_target.run().unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+#[cfg(target_arch = "x86_64")]
+criterion_group!(benches, parsing, interpreting, compiling, mandelbrot);
+#[cfg(not(target_arch = "x86_64"))]
+criterion_group!(benches, parsing, interpreting);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+use fucker::parser::symbolic;
+
+use crate::{config, read_program, tape_mode, Args};
+
+/// Explore each program with unknown input, printing the runtime errors found
+/// along with input that leads to them. Returns 1 if there were any.
+pub fn run(args: &Args) -> Result<i32, String> {
+    let mut failed = false;
+
+    for program in &args.arg_programs {
+        let source = read_program(program)
+            .map_err(|e| format!("Error occurred while loading {}: {}", program, e))?;
+        let mut args = args.clone();
+        config::apply_pragmas(&source, &mut args)?;
+
+        let report = symbolic::explore(
+            &source,
+            tape_mode(&args)?,
+            args.flag_max_tape,
+            args.flag_depth,
+        )?;
+
+        for finding in &report.findings {
+            println!(
+                "{}:{}: {}, with input \"{}\"",
+                program,
+                finding.position,
+                finding.message,
+                finding.input.escape_ascii()
+            );
+            failed = true;
+        }
+
+        println!(
+            "{}: Followed {} path{} for up to {} steps each{}",
+            program,
+            report.paths,
+            if report.paths == 1 { "" } else { "s" },
+            args.flag_depth,
+            if report.truncated {
+                ", leaving the rest unexplored"
+            } else {
+                ""
+            }
+        );
+    }
+
+    Ok(failed as i32)
+}
@@ -0,0 +1,331 @@
+//! Shell completion scripts. Subcommands and options are read from the usage
+//! text, so the scripts can't fall out of date with it.
+
+/// Shells a completion script can be written for.
+pub const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+/// Options that only accept a fixed set of values.
+const CHOICES: &[(&str, &[&str])] = &[
+    ("--exitcode", &["cell"]),
+    ("--bounds", &["wrap"]),
+    ("--tape", &["bidirectional"]),
+    ("--cell-overflow", &["error", "saturate", "wrap"]),
+];
+/// Value placeholders that name a file.
+const PATHS: &[&str] = &["file", "log"];
+
+/// An option from the usage text.
+#[derive(Debug, PartialEq)]
+struct Opt {
+    short: Option<String>,
+    long: String,
+    /// Placeholder for the option's value, if it takes one
+    value: Option<String>,
+    help: String,
+}
+
+impl Opt {
+    fn choices(&self) -> Option<&'static [&'static str]> {
+        CHOICES
+            .iter()
+            .find(|(long, _)| *long == self.long)
+            .map(|(_, choices)| *choices)
+    }
+
+    fn takes_path(&self) -> bool {
+        self.value
+            .as_ref()
+            .is_some_and(|value| PATHS.contains(&value.as_str()))
+    }
+}
+
+/// Subcommands, from usage lines like `fucker repl`.
+fn subcommands(usage: &str) -> Vec<&str> {
+    usage
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("fucker "))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .filter(|word| word.starts_with(|c: char| c.is_ascii_lowercase()))
+        .filter(|word| word.chars().all(|c| c.is_ascii_lowercase() || c == '-'))
+        .collect()
+}
+
+/// Options, from the lines following `Options:`. Lines that don't start with
+/// a dash continue the previous option's description.
+fn options(usage: &str) -> Vec<Opt> {
+    let mut options: Vec<Opt> = Vec::new();
+    let section = usage.split("Options:").nth(1).unwrap_or("");
+
+    for line in section
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        if !line.starts_with('-') {
+            if let Some(last) = options.last_mut() {
+                last.help.push(' ');
+                last.help.push_str(line);
+            }
+            continue;
+        }
+
+        let (names, help) = match line.find("  ") {
+            Some(end) => (&line[..end], line[end..].trim()),
+            None => (line, ""),
+        };
+        let mut opt = Opt {
+            short: None,
+            long: String::new(),
+            value: None,
+            help: help.to_string(),
+        };
+
+        for name in names.split_whitespace() {
+            if name.starts_with("--") {
+                let mut parts = name.splitn(2, '=');
+                opt.long = parts.next().unwrap_or_default().to_string();
+                opt.value = parts
+                    .next()
+                    .map(|value| value.trim_matches(&['<', '>'][..]).to_string());
+            } else {
+                opt.short = Some(name.to_string());
+            }
+        }
+
+        options.push(opt);
+    }
+
+    options
+}
+
+/// Write the completion script for `shell`.
+pub fn script(shell: &str, usage: &str) -> Result<String, String> {
+    let subcommands = subcommands(usage);
+    let options = options(usage);
+
+    match shell {
+        "bash" => Ok(bash(&subcommands, &options)),
+        "zsh" => Ok(zsh(&subcommands, &options)),
+        "fish" => Ok(fish(&subcommands, &options)),
+        _ => Err(format!(
+            "Unknown shell: {}. Expected one of {}",
+            shell,
+            SHELLS.join(", ")
+        )),
+    }
+}
+
+fn bash(subcommands: &[&str], options: &[Opt]) -> String {
+    let words: Vec<String> = options
+        .iter()
+        .flat_map(|opt| {
+            let long = match opt.value {
+                Some(_) => format!("{}=", opt.long),
+                None => opt.long.clone(),
+            };
+            opt.short.clone().into_iter().chain(Some(long))
+        })
+        .collect();
+    let paths: Vec<&str> = options
+        .iter()
+        .filter(|opt| opt.takes_path())
+        .map(|opt| opt.long.as_str())
+        .collect();
+    let choices: String = options
+        .iter()
+        .filter_map(|opt| {
+            opt.choices().map(|choices| {
+                format!(
+                    "            {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")) ;;\n",
+                    opt.long,
+                    choices.join(" ")
+                )
+            })
+        })
+        .collect();
+
+    format!(
+        r#"_fucker() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+
+    # Bash splits --option=value into three words.
+    if [ "$cur" = "=" ]; then
+        cur=""
+    elif [ "$prev" = "=" ]; then
+        prev="${{COMP_WORDS[COMP_CWORD-2]}}"
+    else
+        prev=""
+    fi
+
+    if [ -n "$prev" ]; then
+        case "$prev" in
+{}            {}) COMPREPLY=($(compgen -f -- "$cur")) ;;
+        esac
+        return
+    fi
+
+    if [ "${{COMP_WORDS[1]}}" = "completions" ]; then
+        COMPREPLY=($(compgen -W "{}" -- "$cur"))
+        return
+    fi
+
+    case "$cur" in
+        -*)
+            COMPREPLY=($(compgen -W "{}" -- "$cur"))
+            [[ "${{COMPREPLY[*]}}" == *= ]] && compopt -o nospace
+            ;;
+        *)
+            if [ "$COMP_CWORD" -eq 1 ]; then
+                COMPREPLY=($(compgen -W "{}" -- "$cur"))
+            fi
+            COMPREPLY+=($(compgen -f -- "$cur"))
+            ;;
+    esac
+}}
+
+complete -o filenames -F _fucker fucker
+"#,
+        choices,
+        paths.join("|"),
+        SHELLS.join(" "),
+        words.join(" "),
+        subcommands.join(" ")
+    )
+}
+
+fn zsh(subcommands: &[&str], options: &[Opt]) -> String {
+    let specs: String = options
+        .iter()
+        .map(|opt| {
+            let help = opt.help.replace('\'', "'\\''").replace(']', "\\]");
+            let names = match opt.short {
+                Some(ref short) => format!("'({} {})'{{{},{}}}'", short, opt.long, short, opt.long),
+                None => format!("'{}", opt.long),
+            };
+            let (equals, value) = match (&opt.value, opt.choices()) {
+                (Some(value), Some(choices)) => {
+                    ("=", format!(":{}:({})", value, choices.join(" ")))
+                }
+                (Some(value), None) if opt.takes_path() => ("=", format!(":{}:_files", value)),
+                (Some(value), None) => ("=", format!(":{}: ", value)),
+                (None, _) => ("", String::new()),
+            };
+
+            format!("    {}{}[{}]{}' \\\n", names, equals, help, value)
+        })
+        .collect();
+
+    format!(
+        r#"#compdef fucker
+
+_fucker() {{
+    local state
+
+    if [[ $words[2] == completions ]]; then
+        _values shell {}
+        return
+    fi
+
+    _arguments -s \
+{}    '1: :->first' \
+    '*:program:_files'
+
+    case $state in
+        first)
+            _alternative 'commands:command:({})' 'files:program:_files'
+            ;;
+    esac
+}}
+
+_fucker "$@"
+"#,
+        SHELLS.join(" "),
+        specs,
+        subcommands.join(" ")
+    )
+}
+
+fn fish(subcommands: &[&str], options: &[Opt]) -> String {
+    let mut script = format!(
+        "complete -c fucker -n __fish_use_subcommand -a '{}'\n\
+         complete -c fucker -n '__fish_seen_subcommand_from completions' -x -a '{}'\n",
+        subcommands.join(" "),
+        SHELLS.join(" ")
+    );
+
+    for opt in options {
+        script.push_str("complete -c fucker");
+        if let Some(ref short) = opt.short {
+            script.push_str(&format!(" -s {}", short.trim_start_matches('-')));
+        }
+        script.push_str(&format!(" -l {}", opt.long.trim_start_matches('-')));
+
+        match (&opt.value, opt.choices()) {
+            (Some(_), Some(choices)) => script.push_str(&format!(" -x -a '{}'", choices.join(" "))),
+            (Some(_), None) if opt.takes_path() => script.push_str(" -r -F"),
+            (Some(_), None) => script.push_str(" -x"),
+            (None, _) => {}
+        }
+
+        script.push_str(&format!(" -d '{}'\n", opt.help.replace('\'', "\\'")));
+    }
+
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::USAGE;
+
+    #[test]
+    fn reads_usage() {
+        assert_eq!(
+            subcommands(USAGE),
+            vec![
+                "repl",
+                "verify",
+                "gen",
+                "pipe",
+                "lint",
+                "tape-bound",
+                "analyze",
+                "serve",
+                "completions"
+            ]
+        );
+
+        let options = options(USAGE);
+        assert!(options.contains(&Opt {
+            short: Some("-d".to_string()),
+            long: "--debug".to_string(),
+            value: None,
+            help: "Display intermediate language, the same as the opt-ast stage.".to_string(),
+        }));
+        assert!(options.contains(&Opt {
+            short: None,
+            long: "--exitcode".to_string(),
+            value: Some("source".to_string()),
+            help: "Exit with the value of the cell under the data pointer when the program \
+                   finishes. <source> must be cell."
+                .to_string(),
+        }));
+    }
+
+    #[test]
+    fn scripts_cover_every_option() {
+        for shell in &SHELLS {
+            let script = script(shell, USAGE).unwrap();
+
+            for opt in options(USAGE) {
+                assert!(
+                    script.contains(opt.long.trim_start_matches('-')),
+                    "{} completions are missing {}",
+                    shell,
+                    opt.long
+                );
+            }
+        }
+    }
+}
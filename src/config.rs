@@ -0,0 +1,183 @@
+//! Defaults for command line options, read from a TOML file so that options
+//! used for every run don't need typing out each time. Options given on the
+//! command line always win.
+
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use crate::parser::pragma;
+use crate::Args;
+
+/// Options that can be given defaults, named after their command line flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Config {
+    /// Either `jit` or `interpreter`
+    backend: Option<String>,
+    sandbox: Option<bool>,
+    huge_pages: Option<bool>,
+    crlf: Option<bool>,
+    prompt: Option<bool>,
+    max_tape: Option<usize>,
+    max_output: Option<u64>,
+    max_jit_bytes: Option<usize>,
+    max_cpu: Option<u64>,
+    max_memory: Option<u64>,
+    exitcode: Option<String>,
+}
+
+/// `$XDG_CONFIG_HOME/fucker/config.toml`, falling back to `~/.config`.
+fn default_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(base.join("fucker").join("config.toml"))
+}
+
+/// Read the config file named by `--config`, or the default one if it exists.
+fn load(path: Option<&str>) -> Result<Config, String> {
+    let (path, required) = match path {
+        Some(path) => (PathBuf::from(path), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(Config::default()),
+        },
+    };
+
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == ErrorKind::NotFound && !required => return Ok(Config::default()),
+        Err(e) => return Err(format!("Could not read {}: {}", path.display(), e)),
+    };
+
+    toml::from_str(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+}
+
+/// Fill in options that weren't given on the command line from the config
+/// file.
+pub fn apply(args: &mut Args) -> Result<(), String> {
+    let config = load(args.flag_config.as_deref())?;
+    merge(config, args)
+}
+
+fn merge(config: Config, args: &mut Args) -> Result<(), String> {
+    if args.flag_int && args.flag_jit {
+        return Err("Only one of --int and --jit may be given".to_string());
+    }
+
+    match config.backend.as_deref() {
+        Some("interpreter") => args.flag_int |= !args.flag_jit,
+        Some("jit") | None => {}
+        Some(backend) => {
+            return Err(format!(
+                "Unknown backend in config file: {}. Expected jit or interpreter",
+                backend
+            ))
+        }
+    }
+
+    args.flag_sandbox |= config.sandbox.unwrap_or(false);
+    args.flag_huge_pages |= config.huge_pages.unwrap_or(false);
+    args.flag_crlf |= config.crlf.unwrap_or(false);
+    args.flag_prompt |= config.prompt.unwrap_or(false);
+    // A wrapped tape can't be limited, so a default limit doesn't apply to it
+    // rather than making it unusable.
+    args.flag_max_tape = args
+        .flag_max_tape
+        .or(config.max_tape.filter(|_| args.flag_bounds.is_none()));
+    args.flag_max_output = args.flag_max_output.or(config.max_output);
+    args.flag_max_jit_bytes = args.flag_max_jit_bytes.or(config.max_jit_bytes);
+    args.flag_max_cpu = args.flag_max_cpu.or(config.max_cpu);
+    args.flag_max_memory = args.flag_max_memory.or(config.max_memory);
+    args.flag_exitcode = args.flag_exitcode.take().or(config.exitcode);
+
+    Ok(())
+}
+
+/// Fill in options that weren't given on the command line or in the config
+/// file from the pragmas at the top of a program. Only options that change
+/// what a program does can be given this way.
+pub fn apply_pragmas(source: &str, args: &mut Args) -> Result<(), String> {
+    for (key, value) in pragma::pragmas(source)? {
+        // Bounds and tape kinds can't be combined, so either given already
+        // wins over both.
+        let tape_chosen = args.flag_bounds.is_some() || args.flag_tape.is_some();
+
+        match key {
+            "max-tape" => {
+                let cells = value
+                    .parse()
+                    .map_err(|_| format!("Invalid max-tape in pragma: {}", value))?;
+                args.flag_max_tape = args.flag_max_tape.or(Some(cells));
+            }
+            "bounds" if !tape_chosen => args.flag_bounds = Some(value.to_string()),
+            "tape" if !tape_chosen => args.flag_tape = Some(value.to_string()),
+            "bounds" | "tape" => {}
+            "cell-overflow" => {
+                args.flag_cell_overflow = args
+                    .flag_cell_overflow
+                    .take()
+                    .or(Some(value.to_string()));
+            }
+            "exitcode" => {
+                args.flag_exitcode = args.flag_exitcode.take().or(Some(value.to_string()));
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown option in pragma: {}. Expected max-tape, bounds, tape, cell-overflow or exitcode",
+                    key
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use docopt::Docopt;
+
+    use super::*;
+    use crate::USAGE;
+
+    fn args(argv: &[&str]) -> Args {
+        Docopt::new(USAGE)
+            .and_then(|d| d.argv(argv).deserialize())
+            .unwrap()
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let config: Config =
+            toml::from_str("backend = \"interpreter\"\nmax-tape = 100\nmax-output = 10\n").unwrap();
+        let mut args = args(&["fucker", "--jit", "--max-tape=5", "a.bf"]);
+
+        merge(config, &mut args).unwrap();
+
+        assert!(!args.flag_int);
+        assert_eq!(args.flag_max_tape, Some(5));
+        assert_eq!(args.flag_max_output, Some(10));
+    }
+
+    #[test]
+    fn pragmas_fill_in_options() {
+        let source = "#!fucker: max-tape=100 bounds=wrap exitcode=cell\n+.";
+        let mut args = args(&["fucker", "--max-tape=5", "--tape=bidirectional", "a.bf"]);
+
+        apply_pragmas(source, &mut args).unwrap();
+
+        assert_eq!(args.flag_max_tape, Some(5));
+        assert_eq!(args.flag_bounds, None);
+        assert_eq!(args.flag_exitcode.as_deref(), Some("cell"));
+        assert!(apply_pragmas("#!fucker: cells=16\n", &mut args).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert!(toml::from_str::<Config>("optimize = 3\n").is_err());
+    }
+}
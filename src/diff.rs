@@ -0,0 +1,92 @@
+use std::io::{self, Write};
+
+/// A line that appears in only one of two texts.
+#[derive(Debug, PartialEq)]
+enum Change<'a> {
+    /// Line number and text of a line only in the expected text
+    Missing(usize, &'a str),
+    /// Line number and text of a line only in the actual text
+    Extra(usize, &'a str),
+}
+
+/// Lines that need to be removed from `expected` and added to it to get
+/// `actual`, based on their longest common subsequence.
+fn changes<'a>(expected: &[&'a str], actual: &[&'a str]) -> Vec<Change<'a>> {
+    // common[i][j] is the length of the longest common subsequence of
+    // expected[i..] and actual[j..].
+    let mut common = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && common[i + 1][j] >= common[i][j + 1])
+        {
+            changes.push(Change::Missing(i + 1, expected[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Extra(j + 1, actual[j]));
+            j += 1;
+        }
+    }
+
+    changes
+}
+
+/// Write the lines that differ between what a program was expected to print
+/// and what it printed. Returns whether there were any differences.
+pub fn write_diff(expected: &[u8], actual: &[u8], out: &mut dyn Write) -> io::Result<bool> {
+    let expected = String::from_utf8_lossy(expected);
+    let actual = String::from_utf8_lossy(actual);
+    let expected_lines: Vec<&str> = expected.split('\n').collect();
+    let actual_lines: Vec<&str> = actual.split('\n').collect();
+
+    let changes = changes(&expected_lines, &actual_lines);
+
+    for change in &changes {
+        match change {
+            Change::Missing(line, text) => writeln!(out, "-{:>5}: {}", line, text)?,
+            Change::Extra(line, text) => writeln!(out, "+{:>5}: {}", line, text)?,
+        }
+    }
+
+    Ok(!changes.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_changed_lines() {
+        assert_eq!(
+            changes(&["a", "b", "c"], &["a", "x", "c", "d"]),
+            vec![
+                Change::Missing(2, "b"),
+                Change::Extra(2, "x"),
+                Change::Extra(4, "d")
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_output_has_no_diff() {
+        let mut out = Vec::new();
+
+        assert!(!write_diff(b"same\n", b"same\n", &mut out).unwrap());
+        assert!(out.is_empty());
+    }
+}
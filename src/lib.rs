@@ -0,0 +1,129 @@
+//! BrainFuck parser, interpreter and JIT compiler.
+//!
+//! The `fucker` binary is a command line frontend for this library.
+
+// Code generated by pyo3's macros refers to `::core`, which needs declaring
+// before the 2018 edition.
+#[cfg(feature = "python")]
+extern crate core;
+#[cfg(feature = "wasm")]
+extern crate js_sys;
+extern crate libc;
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "python")]
+extern crate pyo3;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+extern crate serde_json;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+
+#[cfg(target_arch = "x86_64")]
+extern crate iced_x86;
+
+/// Enter a span of the `tracing` crate for the rest of the enclosing block,
+/// when built with the `tracing` feature. Takes the same arguments as
+/// `tracing::info_span!`.
+macro_rules! span {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = ::tracing::info_span!($($args)*).entered();
+    };
+}
+
+pub mod parser;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod runnable;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use parser::Ast;
+    use runnable::interpreter::Fucker;
+    use runnable::{Instrumentation, Runnable};
+
+    /// Records the name of every span created.
+    struct SpanNames(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Subscriber for SpanNames {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut names = self.0.lock().unwrap();
+            names.push(span.metadata().name());
+
+            Id::from_u64(names.len() as u64)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn emits_spans() {
+        let names = Arc::new(Mutex::new(Vec::new()));
+
+        tracing::subscriber::with_default(SpanNames(names.clone()), || {
+            let ast = Ast::parse("+[-]").unwrap();
+            Fucker::new(
+                ast.data,
+                Box::new(io::empty()),
+                Box::new(io::sink()),
+                Instrumentation::default(),
+            )
+            .run()
+            .unwrap();
+        });
+
+        assert_eq!(*names.lock().unwrap(), ["parse", "run"]);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn emits_jit_spans() {
+        use runnable::jit::JITTarget;
+
+        let names = Arc::new(Mutex::new(Vec::new()));
+        // Long enough for the loop to be compiled separately.
+        let source = format!("+[{}-]", ">+<".repeat(8));
+
+        tracing::subscriber::with_default(SpanNames(names.clone()), || {
+            let ast = Ast::parse(&source).unwrap();
+            JITTarget::new(
+                ast.data,
+                Box::new(io::empty()),
+                Box::new(io::sink()),
+                Instrumentation::default(),
+            )
+            .run()
+            .unwrap();
+        });
+
+        assert_eq!(
+            *names.lock().unwrap(),
+            ["parse", "codegen", "run", "compile_promise", "codegen"]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Programs made of several files joined in the order given, since BrainFuck
+//! libraries are distributed as snippets meant to be pasted together.
+
+use std::fs::File;
+use std::io::{stdin, BufRead, BufReader, Cursor, Read};
+
+use crate::parser::stream::StreamParser;
+use crate::parser::{pragma, Ast};
+use crate::Args;
+
+/// One of the files of a program, read as far as the end of its pragmas.
+struct Source {
+    name: String,
+    reader: Box<dyn BufRead>,
+    /// Line of the file that the program starts on, counting from 1
+    line: usize,
+}
+
+/// The files of a program, with the pragmas of all of them gathered so that
+/// they still apply. The rest of each file is only read when the program is
+/// parsed, so that huge programs never have to fit in memory as text.
+pub struct Linked {
+    pub pragmas: String,
+    files: Vec<Source>,
+}
+
+impl Linked {
+    /// Read the pragmas of files, given along with their names.
+    fn join(files: Vec<(String, Box<dyn BufRead>)>) -> Result<Self, String> {
+        let mut pragmas = String::new();
+        let mut sources = Vec::new();
+
+        for (name, mut reader) in files {
+            let read =
+                pragma::read(&mut *reader).map_err(|e| format!("Could not read file: {:?}", e))?;
+            pragmas.push_str(&read);
+
+            // A file may end on a pragma.
+            if !read.is_empty() && !read.ends_with('\n') {
+                pragmas.push('\n');
+            }
+
+            sources.push(Source {
+                name,
+                reader,
+                line: read.lines().count() + 1,
+            });
+        }
+
+        Ok(Linked {
+            pragmas,
+            files: sources,
+        })
+    }
+
+    /// Like `parse_with`, without optimizing the program.
+    pub fn parse_raw(self) -> Result<Ast, String> {
+        self.parse_with(StreamParser::raw())
+    }
+
+    /// The text of the program, for programs that aren't BrainFuck source.
+    /// Pragma lines are left blank, so that lines keep their numbers.
+    pub fn text(self) -> Result<String, String> {
+        let mut text = String::new();
+
+        for mut file in self.files {
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+
+            text.push_str(&"\n".repeat(file.line - 1));
+            file.reader
+                .read_to_string(&mut text)
+                .map_err(|e| format!("Could not read file: {:?}", e))?;
+        }
+
+        Ok(text)
+    }
+
+    /// Parse the program and optimize it the way `parser` does, saying which
+    /// file any unmatched bracket is in.
+    pub fn parse_with(self, mut parser: StreamParser) -> Result<Ast, String> {
+        for mut file in self.files {
+            parser.start_file(&file.name, file.line);
+            parser.read(&mut file.reader)?;
+        }
+
+        parser.finish()
+    }
+}
+
+/// Open the files that make up a program, with `-` for stdin, and read their
+/// pragmas.
+pub fn read(paths: &[String]) -> Result<Linked, String> {
+    let files = paths
+        .iter()
+        .map(|path| {
+            let reader: Box<dyn BufRead> = if path == "-" {
+                Box::new(BufReader::new(stdin()))
+            } else {
+                let file = File::open(path).map_err(|e| format!("Could not open file: {:?}", e))?;
+                Box::new(BufReader::new(file))
+            };
+
+            Ok((path.clone(), reader))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Linked::join(files)
+}
+
+/// Split a program from the input after the first `!` in it, the usual way
+/// of passing both in one stream.
+pub fn split_input(source: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match source.iter().position(|&byte| byte == b'!') {
+        Some(bang) => (&source[..bang], Some(&source[bang + 1..])),
+        None => (source, None),
+    }
+}
+
+/// Load the program given on the command line, either as files or inline
+/// with `-e`, along with any input that followed a `!` in an inline program.
+pub fn load(args: &Args) -> Result<(Linked, Option<Vec<u8>>), String> {
+    match args.flag_eval {
+        Some(ref source) => {
+            let (program, input) = split_input(source.as_bytes());
+            let linked = Linked::join(vec![(
+                "-e".to_string(),
+                Box::new(Cursor::new(program.to_vec())),
+            )])?;
+
+            Ok((linked, input.map(<[u8]>::to_vec)))
+        }
+        None => Ok((read(&args.arg_program)?, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use docopt::Docopt;
+
+    use super::*;
+    use crate::parser::AstNode;
+    use crate::USAGE;
+
+    fn join(files: &[(&str, &'static str)]) -> Linked {
+        Linked::join(
+            files
+                .iter()
+                .map(|&(name, text)| {
+                    let reader: Box<dyn BufRead> = Box::new(text.as_bytes());
+                    (name.to_string(), reader)
+                })
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn joins_files_in_order() {
+        let files = [
+            ("a.bf", "#!fucker: max-tape=9\n+\n"),
+            ("b.bf", "#!fucker: bounds=wrap"),
+            ("c.bf", ">\n\n+."),
+        ];
+
+        let linked = join(&files);
+        assert_eq!(
+            linked.pragmas,
+            "#!fucker: max-tape=9\n#!fucker: bounds=wrap\n"
+        );
+        assert_eq!(
+            linked.parse_with(StreamParser::new()).unwrap().data,
+            [
+                AstNode::Incr(1),
+                AstNode::Next(1),
+                AstNode::Incr(1),
+                AstNode::Print
+            ]
+        );
+
+        let files = [("a.bf", "#!fucker: max-tape=9\n+\n"), ("c.bf", ">\n\n+]")];
+        assert_eq!(
+            join(&files)
+                .parse_with(StreamParser::new())
+                .err()
+                .as_deref(),
+            Some("More ] than [ at c.bf:3:2")
+        );
+    }
+
+    #[test]
+    fn splits_program_from_input() {
+        assert_eq!(split_input(b",.!a!b"), (&b",."[..], Some(&b"a!b"[..])));
+        assert_eq!(split_input(b"+.!"), (&b"+."[..], Some(&b""[..])));
+        assert_eq!(split_input(b"+."), (&b"+."[..], None));
+    }
+
+    #[test]
+    fn loads_inline_programs() {
+        let args: Args = Docopt::new(USAGE)
+            .and_then(|d| d.argv(["fucker", "-e", "+[,.]!in!put"]).deserialize())
+            .unwrap();
+
+        let (linked, input) = load(&args).unwrap();
+
+        assert_eq!(linked.pragmas, "");
+        assert_eq!(linked.parse_raw().unwrap().data.len(), 2);
+        assert_eq!(input.as_deref(), Some(&b"in!put"[..]));
+    }
+}
@@ -0,0 +1,23 @@
+use fucker::parser::lint;
+
+use crate::read_program;
+
+/// Print the lint warnings for each program, one per line as
+/// `program:line:column: message`. Returns 1 if there were any, so that
+/// scripts can fail on them.
+pub fn run(programs: &[String]) -> Result<i32, String> {
+    let mut warned = false;
+
+    for program in programs {
+        let warnings = read_program(program)
+            .and_then(|source| lint::lint(&source))
+            .map_err(|e| format!("Error occurred while loading {}: {}", program, e))?;
+
+        for warning in warnings {
+            println!("{}:{}: {}", program, warning.position, warning.message);
+            warned = true;
+        }
+    }
+
+    Ok(warned as i32)
+}
@@ -3,74 +3,1077 @@ extern crate libc;
 #[macro_use]
 extern crate serde_derive;
 extern crate docopt;
+extern crate notify;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+extern crate toml;
 
-mod parser;
-mod runnable;
+extern crate fucker;
 
-use std::fs::File;
-use std::io::{stdin, Read};
+mod analyze;
+mod completions;
+mod config;
+mod diff;
+mod link;
+mod lint;
+mod pipe;
+mod repl;
+mod sandbox;
+mod serve;
+mod socket;
+mod tape_bound;
+mod tape_dump;
+mod verify;
+mod version;
+mod watch;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, stderr, stdin, stdout, BufWriter, Cursor, IsTerminal, Read, Write};
+use std::ops::Range;
 use std::process::exit;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use docopt::Docopt;
 
-use parser::Ast;
-use runnable::interpreter::Fucker;
+use fucker::{parser, runnable};
+use link::Linked;
+use parser::opt_stats::OptStats;
+use parser::stream::StreamParser;
+use parser::{tree, Ast, AstNode};
+use runnable::crlf::{CrlfReader, CrlfWriter};
+use runnable::environment;
+use runnable::events::EventLog;
+use runnable::heatmap;
+use runnable::hot_loops::HotLoops;
+use runnable::interpreter::{bytecode, Fucker};
+use runnable::interrupt;
+use runnable::io_log::{self, RecordingReader, RecordingWriter};
 #[cfg(target_arch = "x86_64")]
-use runnable::jit::JITTarget;
-use runnable::Runnable;
+use runnable::jit::{CodeLimit, CompileOptions, JITTarget};
+use runnable::jit_stats::JitStats;
+use runnable::limits::LimitedWriter;
+use runnable::loop_profile::LoopProfile;
+use runnable::profile::Profile;
+use runnable::profiler::Profiler;
+use runnable::prompt::PromptReader;
+use runnable::shared_buffer::{SharedBuffer, TeeWriter};
+use runnable::stats::Stats;
+use runnable::tape_stats::TapeStats;
+use runnable::trace::Tracer;
+use runnable::{CellOverflow, Instrumentation, Runnable, TapeMode, LEFT_TAPE_SIZE};
 
 const USAGE: &str = "
 Fucker
 
 Usage:
-  fucker [--int] <program>
-  fucker (-d | --debug) <program>
+  fucker repl
+  fucker verify [--input=<file>] <program>
+  fucker gen [--seed=<n>] [--size=<n>] [--nesting=<n>]
+  fucker pipe [--int | --jit] [--config=<file>] <programs>...
+  fucker lint <programs>...
+  fucker tape-bound [--max-tape=<cells>] [--bounds=<mode> | --tape=<kind>] <programs>...
+  fucker analyze --symbolic [--depth=<n>] [--max-tape=<cells>] [--bounds=<mode> | --tape=<kind>] <programs>...
+  fucker serve [options] <addr>
+  fucker completions <shell>
+  fucker [options] [--env=<var>]... (-e <source> | <program>...)
+  fucker (-d | --debug) [--stats] (-e <source> | <program>...)
+  fucker --emit=<stage> [--bytecode] [--stats] (-e <source> | <program>...)
+  fucker --dump-bytecode [--bytecode] [--stats] (-e <source> | <program>...)
   fucker (-h | --help)
+  fucker --version
 
 Options:
-  -h --help     Show this screen.
-  -d --debug    Display intermediate language.
-  --int         Use an interpreter instead of the JIT compiler.
+  -h --help             Show this screen.
+  --version             Show the version along with build and platform details.
+  -d --debug            Display intermediate language, the same as the opt-ast
+                        stage.
+  --emit=<stage>        Stop after a stage of compiling the program and print
+                        what it produced. <stage> must be ast, opt-ast,
+                        bytecode or asm.
+  --dump-bytecode       The same as --emit=bytecode.
+  -e --eval=<source>    Run <source> as the program. Anything after a ! in it
+                        is the program's input.
+  --bytecode            Read the program as interpreter instructions, in the
+                        format --emit=bytecode prints, rather than BrainFuck.
+  --int                 Use an interpreter instead of the JIT compiler.
+  --jit                 Use the JIT compiler, even if the config file picks the
+                        interpreter.
+  --trace-jit           Experimental. Recompile hot loops in the JIT along the
+                        paths they were seen to take.
+  --config=<file>       Read default options from a file instead of
+                        ~/.config/fucker/config.toml.
+  --record=<log>        Record all input read by the program to a log file.
+  --record-output       Also record output written by the program to the log.
+  --replay=<log>        Feed the input recorded in a log file to the program.
+  --connect=<addr>      Read and write through a TCP connection to HOST:PORT
+                        instead of stdin and stdout.
+  --listen=<addr>       Accept TCP connections on HOST:PORT, running the program
+                        afresh for each one with the connection as its input
+                        and output.
+  --crlf                Print each newline as CRLF and read CRLF as a newline,
+                        as Windows consoles expect.
+  --prompt              Print a prompt to stderr whenever the program waits on
+                        a new line of input from a terminal, echoing what is
+                        typed.
+  --trace               Print each executed instruction to stderr.
+  --trace-file=<file>   Print each executed instruction to a file.
+  --trace-range=<r>     Only trace steps in the range START:END (either optional).
+  --trace-every=<n>     Only trace every n-th step [default: 1].
+  --profile             Print instruction execution counts to stderr at exit.
+  --profile-loops       Print the hottest loops to stderr at exit.
+  --profile-out=<file>  Save how often each loop ran to a file to be read back
+                        with --profile-in.
+  --profile-in=<file>   Compile the program around the loop counts saved in a
+                        file, inlining the loops that were hot.
+  --stats               Print time spent parsing, compiling and executing, or
+                        what the optimizer did when stopping after a stage.
+  --jit-stats           Print the memory, compile time and calls of each piece
+                        of code the JIT compiled.
+  --tape-stats          Print how far the data pointer moved and how many cells
+                        were written.
+  --heatmap=<file>      Write a heatmap of writes to each cell. Files ending in
+                        .ppm get an image, anything else gets text.
+  --log-json=<file>     Write compilations and errors to a file as JSON lines.
+  --watch               Run the program again every time its file changes.
+  --expect=<file>       Fail with a diff if the program's output differs from
+                        the contents of a file.
+  --env=<var>           Place a NAME=VALUE variable in memory before running.
+                        May be repeated.
+  --tape-init=<file>    Copy the bytes of a file into memory before running,
+                        starting at the first cell.
+  --tape-dump=<file>    Write memory to a file once the program finishes, from
+                        the first cell up to the last non-zero one. Ending
+                        <file> in :N writes exactly N cells instead.
+  --assert-tape=<file>  Fail with the first cell that differs if memory doesn't
+                        match the contents of a file once the program finishes.
+  --sandbox             Forbid all system calls other than those needed for
+                        input, output and memory while the program runs.
+                        Linux only.
+  --max-tape=<cells>    Stop the program if it moves past this many cells.
+  --bounds=<mode>       Wrap the data pointer around the ends of a 32768 cell
+                        tape. <mode> must be wrap.
+  --tape=<kind>         Let the data pointer move up to 30000 cells left of
+                        where it starts. <kind> must be bidirectional.
+  --cell-overflow=<p>   Stop the program, or keep the cell at 255 or 0, when
+                        arithmetic goes past them rather than wrapping around.
+                        <p> must be error, saturate or wrap.
+  --huge-pages          Back large tapes with huge pages where the system
+                        allows it.
+  --max-output=<bytes>  Stop the program once it has printed this many bytes.
+  --max-jit-bytes=<n>   Stop the program rather than let the JIT allocate more
+                        than this many bytes of executable memory. The code
+                        that enters the program is allocated regardless.
+  --jit-fallback        Run code that doesn't fit under --max-jit-bytes without
+                        compiling it rather than stopping. Not seen by
+                        tracing, profiles or tape stats, so can't be used
+                        with them.
+  --max-cpu=<seconds>   Kill the process after this much CPU time. Unix only.
+  --max-memory=<bytes>  Limit the memory the process may map. Unix only.
+  --exitcode=<source>   Exit with the value of the cell under the data pointer
+                        when the program finishes. <source> must be cell.
+  --input=<file>        Feed the contents of a file to the program as its
+                        input, or to both backends when verifying.
+  --seed=<n>            Seed for generating a program. Random if not given.
+  --size=<n>            Commands in a generated program [default: 64].
+  --nesting=<n>         Deepest loop nesting in a generated program
+                        [default: 3].
+  --symbolic            Explore every path through a program with unknown
+                        input, looking for input that makes it fail.
+  --depth=<n>           Steps to follow each path for when exploring
+                        [default: 10000].
 ";
 
-#[derive(Debug, Deserialize)]
+/// Streams used by a program's `,` and `.` commands.
+type ProgramIO = (Box<dyn Read>, Box<dyn Write>);
+
+/// How a program's tape is set up.
+#[derive(Clone, Copy, Default)]
+struct TapeOptions {
+    /// Cells memory may grow to
+    limit: Option<usize>,
+    mode: TapeMode,
+    cell_overflow: CellOverflow,
+    huge_pages: bool,
+}
+
+/// How the JIT compiles a program.
+#[derive(Clone, Default)]
+struct JitOptions {
+    /// Whether hot loops are recompiled as traces
+    trace: bool,
+    /// Loop counts from an earlier run to compile around
+    hot_loops: Option<HotLoops>,
+    /// Most executable memory to allocate
+    max_bytes: Option<usize>,
+    /// Whether code that doesn't fit under `max_bytes` is interpreted
+    fallback: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
 struct Args {
-    arg_program: String,
+    cmd_repl: bool,
+    cmd_verify: bool,
+    cmd_gen: bool,
+    cmd_pipe: bool,
+    cmd_lint: bool,
+    cmd_tape_bound: bool,
+    cmd_analyze: bool,
+    cmd_serve: bool,
+    cmd_completions: bool,
+    arg_programs: Vec<String>,
+    arg_shell: String,
+    arg_addr: String,
+    arg_program: Vec<String>,
     flag_debug: bool,
+    flag_emit: Option<String>,
+    flag_dump_bytecode: bool,
+    flag_bytecode: bool,
+    flag_eval: Option<String>,
     flag_int: bool,
+    flag_jit: bool,
+    flag_trace_jit: bool,
+    flag_config: Option<String>,
+    flag_record: Option<String>,
+    flag_record_output: bool,
+    flag_replay: Option<String>,
+    flag_connect: Option<String>,
+    flag_listen: Option<String>,
+    flag_crlf: bool,
+    flag_prompt: bool,
+    flag_trace: bool,
+    flag_trace_file: Option<String>,
+    flag_trace_range: Option<String>,
+    flag_trace_every: u64,
+    flag_profile: bool,
+    flag_profile_loops: bool,
+    flag_profile_out: Option<String>,
+    flag_profile_in: Option<String>,
+    flag_stats: bool,
+    flag_jit_stats: bool,
+    flag_tape_stats: bool,
+    flag_heatmap: Option<String>,
+    flag_log_json: Option<String>,
+    flag_watch: bool,
+    flag_expect: Option<String>,
+    flag_exitcode: Option<String>,
+    flag_sandbox: bool,
+    flag_max_tape: Option<usize>,
+    flag_bounds: Option<String>,
+    flag_tape: Option<String>,
+    flag_cell_overflow: Option<String>,
+    flag_huge_pages: bool,
+    flag_max_output: Option<u64>,
+    flag_max_jit_bytes: Option<usize>,
+    flag_jit_fallback: bool,
+    flag_max_cpu: Option<u64>,
+    flag_max_memory: Option<u64>,
+    flag_env: Vec<String>,
+    flag_tape_init: Option<String>,
+    flag_tape_dump: Option<String>,
+    flag_assert_tape: Option<String>,
+    flag_input: Option<String>,
+    flag_seed: Option<u64>,
+    flag_size: usize,
+    flag_nesting: usize,
+    flag_symbolic: bool,
+    flag_depth: usize,
 }
 
 fn main() {
-    let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.deserialize())
+    let mut args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.version(Some(version::describe())).deserialize())
         .unwrap_or_else(|e| e.exit());
 
-    let program = read_program(&args.arg_program)
-        .and_then(|source| Ast::parse(&source))
-        .unwrap_or_else(|e| {
-            eprintln!("Error occurred while loading program: {}", e);
+    if args.cmd_repl {
+        repl::run().unwrap_or_else(|e| {
+            eprintln!("Error occurred in the REPL: {}", e);
             exit(1)
         });
 
-    if args.flag_debug {
-        println!("{:?}", program);
+        return;
+    }
+
+    if args.cmd_gen {
+        let seed = args.flag_seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64)
+        });
+
+        println!(
+            "{}",
+            parser::generate(seed, args.flag_size, args.flag_nesting)
+        );
+
+        return;
+    }
+
+    if args.cmd_completions {
+        match completions::script(&args.arg_shell, USAGE) {
+            Ok(script) => print!("{}", script),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
 
         return;
     }
 
-    let mut runnable: Box<dyn Runnable> = if args.flag_int {
-        Box::new(Fucker::new(program.data))
+    if let Err(e) = config::apply(&mut args) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+
+    let result = if args.cmd_verify {
+        verify::run(&args.arg_program[0], args.flag_input.as_deref()).map(|_| 0)
+    } else if args.cmd_pipe {
+        pipe::run(&args.arg_programs, args.flag_int).map(|_| 0)
+    } else if args.cmd_lint {
+        lint::run(&args.arg_programs)
+    } else if args.cmd_tape_bound {
+        tape_bound::run(&args)
+    } else if args.cmd_analyze && args.flag_symbolic {
+        analyze::run(&args)
+    } else if args.cmd_serve {
+        serve::run(&args).map(|_| 0)
+    } else if args.flag_listen.is_some() {
+        socket::listen(&args).map(|_| 0)
+    } else if args.flag_watch {
+        watch::run(&args).map(|_| 0)
     } else {
-        #[cfg(not(target_arch = "x86_64"))]
-        {
-            eprintln!("JIT is not supported for this architecture");
+        run_program(&args)
+    };
+
+    match result {
+        Ok(code) => exit(code),
+        Err(e) => {
+            eprintln!("{}", e);
             exit(1);
         }
+    }
+}
+
+/// Load, run and report on the program named on the command line, returning
+/// the status the process should exit with. A program given as several files
+/// is run as if they had been joined into one.
+fn run_program(args: &Args) -> Result<i32, String> {
+    let parse_start = Instant::now();
+    let (linked, input) =
+        link::load(args).map_err(|e| format!("Error occurred while loading program: {}", e))?;
+    let mut args = args.clone();
+    config::apply_pragmas(&linked.pragmas, &mut args)?;
+    let args = &args;
+    let stage = emit_stage(args)?;
+
+    let program = match stage {
+        // Bytecode has no tree as written other than the one it describes.
+        Some(stage) if (stage == Stage::Ast || args.flag_stats) && !args.flag_bytecode => {
+            let raw = linked
+                .parse_raw()
+                .map_err(|e| format!("Error occurred while loading program: {}", e))?;
+            let optimized = args
+                .flag_stats
+                .then(|| optimize(raw.clone(), args))
+                .transpose()?;
+
+            if let Some(ref optimized) = optimized {
+                let stats = OptStats::new(&raw.data, optimized);
+                check_report("optimizer stats", stats.write_report(&mut stderr()))?;
+            }
+
+            match optimized {
+                Some(optimized) if stage != Stage::Ast => optimized,
+                _ => {
+                    print!("{}", tree::render(&raw.data, use_color()));
+                    return Ok(0);
+                }
+            }
+        }
+        _ => parse(linked, args)
+            .map_err(|e| format!("Error occurred while loading program: {}", e))?,
+    };
+    let parse_time = parse_start.elapsed();
+
+    let use_cell_as_exit_code = exits_with_cell(args.flag_exitcode.as_deref())?;
+    let tape_mode = tape_mode(args)?;
+    let cell_overflow = cell_overflow(args)?;
+
+    if tape_mode == TapeMode::Wrapped
+        && !args.flag_int
+        && (args.flag_tape_stats || args.flag_heatmap.is_some())
+    {
+        return Err("Tape stats aren't gathered by the JIT with --bounds=wrap".to_string());
+    }
+
+    if args.flag_jit_stats && args.flag_int {
+        return Err("--jit-stats only applies to the JIT compiler".to_string());
+    }
+
+    if args.flag_sandbox && args.flag_heatmap.is_some() {
+        return Err("Can't write a heatmap from inside the sandbox".to_string());
+    }
+
+    if let Some(stage) = stage {
+        emit(stage, program.data, tape_mode, cell_overflow)?;
+
+        return Ok(0);
+    }
+
+    let (io_read, mut io_write) = program_io(args, input)
+        .map_err(|e| format!("Error occurred while setting up I/O: {}", e))?;
+    let expected_output = match args.flag_expect {
+        Some(ref path) => {
+            Some(fs::read(path).map_err(|e| format!("Could not read expected output: {:?}", e))?)
+        }
+        None => None,
+    };
+    let output = expected_output.as_ref().map(|_| SharedBuffer::new());
+    let expected_tape = match args.flag_assert_tape {
+        Some(ref path) => {
+            Some(fs::read(path).map_err(|e| format!("Could not read expected tape: {:?}", e))?)
+        }
+        None => None,
+    };
+
+    if let Some(ref output) = output {
+        io_write = Box::new(TeeWriter::new(io_write, output.clone()));
+    }
+    let tracer = program_tracer(args)
+        .map_err(|e| format!("Error occurred while setting up tracing: {}", e))?;
+    let profile = shared_if::<Profile>(args.flag_profile);
+    let loops =
+        shared_if::<LoopProfile>(args.flag_profile_loops || args.flag_profile_out.is_some());
+    let stats = shared_if::<Stats>(args.flag_stats);
+    let jit_stats = shared_if::<JitStats>(args.flag_jit_stats);
+    let tape = shared_if::<TapeStats>(args.flag_tape_stats || args.flag_heatmap.is_some());
+    let mut profilers: Vec<Box<dyn Profiler>> = Vec::new();
+
+    if let Some(ref profile) = profile {
+        profilers.push(Box::new(profile.clone()));
+    }
+
+    if let Some(ref loops) = loops {
+        profilers.push(Box::new(loops.clone()));
+    }
+
+    if let Some(ref stats) = stats {
+        profilers.push(Box::new(stats.clone()));
+    }
+
+    if let Some(ref jit_stats) = jit_stats {
+        profilers.push(Box::new(jit_stats.clone()));
+    }
+
+    let instrumentation = Instrumentation {
+        tracer,
+        profiler: match profilers.len() {
+            0 => None,
+            _ => Some(Box::new(profilers)),
+        },
+        tape: tape.clone(),
+        cancel: None,
+        progress: None,
+        events: program_events(args)?,
+    };
+
+    let mut runnable = build_runnable(
+        args.flag_int,
+        jit_options(args)?,
+        TapeOptions {
+            limit: args.flag_max_tape,
+            mode: tape_mode,
+            cell_overflow,
+            huge_pages: args.flag_huge_pages,
+        },
+        program.data,
+        io_read,
+        io_write,
+        instrumentation,
+    )?;
+
+    let cells = preload(args)?;
+    if !cells.is_empty() {
+        runnable.preload(&cells);
+    }
+
+    let tape_dump = open_tape_dump(args)?;
+    // Created before the sandbox would forbid it.
+    let profile_out = match args.flag_profile_out {
+        Some(ref path) => {
+            Some(File::create(path).map_err(|e| format!("Could not create profile: {:?}", e))?)
+        }
+        None => None,
+    };
+
+    sandbox::limit_resources(args.flag_max_cpu, args.flag_max_memory)?;
+    interrupt::catch_sigint()?;
+
+    if args.flag_sandbox {
+        sandbox::enter()?;
+    }
+
+    let run_start = Instant::now();
+    let result = runnable.run();
+    let run_time = run_start.elapsed();
+
+    stdout().flush().ok();
+
+    if let Some(profile) = profile {
+        check_report("profile", profile.borrow().write_report(&mut stderr()))?;
+    }
+
+    if let Some(loops) = loops {
+        let loops = loops.borrow();
+
+        if args.flag_profile_loops {
+            check_report("loop profile", loops.write_report(&mut stderr()))?;
+        }
+
+        if let Some(file) = profile_out {
+            check_report("profile", write_hot_loops(file, &loops))?;
+        }
+    }
+
+    if let Some(stats) = stats {
+        let mut stats = stats.borrow_mut();
+        stats.parse = parse_time;
+        stats.removed_loops = program.removed_loops;
+        stats.run = run_time;
+
+        check_report("stats", stats.write_report(&mut stderr()))?;
+    }
+
+    if let Some(jit_stats) = jit_stats {
+        check_report("JIT stats", jit_stats.borrow().write_report(&mut stderr()))?;
+    }
+
+    if let Some(tape) = tape {
+        let tape = tape.borrow();
+
+        if args.flag_tape_stats {
+            check_report("tape stats", tape.write_report(&mut stderr()))?;
+        }
+
+        if let Some(ref path) = args.flag_heatmap {
+            check_report("heatmap", write_heatmap(path, tape.write_counts()))?;
+        }
+    }
+
+    // Reports are still useful when the program failed part way through.
+    let state = result?;
+
+    // Where the data pointer started, which dumped and asserted cells count
+    // from
+    let origin = match tape_mode {
+        TapeMode::Bidirectional => LEFT_TAPE_SIZE,
+        _ => 0,
+    };
+
+    if let Some((mut file, count)) = tape_dump {
+        file.write_all(&tape_dump::dumped_cells(
+            &state.memory,
+            origin,
+            count,
+            cells.len(),
+        ))
+        .map_err(|e| format!("Could not write tape dump: {:?}", e))?;
+    }
+
+    if state.interrupted {
+        eprintln!("Interrupted with the data pointer at cell {}", state.dp);
+
+        if let Some(ref location) = state.location {
+            eprintln!("Next instruction: {}", location);
+        }
+
+        eprintln!("{}", repl::format_tape(&state.memory, state.dp));
+
+        // The status of a process killed by SIGINT.
+        return Ok(130);
+    }
+
+    if let Some(expected) = expected_tape {
+        let cells = tape_dump::dumped_cells(&state.memory, origin, None, expected.len());
+
+        if let Some((cell, want, got)) = tape_dump::first_difference(&cells, &expected) {
+            return Err(format!(
+                "Tape differs from {} at cell {}: expected {}, got {}",
+                args.flag_assert_tape.as_deref().unwrap_or_default(),
+                cell,
+                want,
+                got
+            ));
+        }
+    }
+
+    if let (Some(expected), Some(output)) = (expected_output, output) {
+        let differs = diff::write_diff(&expected, &output.contents(), &mut stderr())
+            .map_err(|e| format!("Error occurred while writing diff: {}", e))?;
+
+        if differs {
+            return Err("Output did not match what was expected".to_string());
+        }
+    }
+
+    if state.output_closed {
+        // The status of a process killed by SIGPIPE, which is how most programs
+        // end when whatever was reading their output goes away.
+        return Ok(141);
+    }
+
+    if use_cell_as_exit_code {
+        return Ok(state.current_cell().into());
+    }
+
+    Ok(0)
+}
+
+/// Whether `--exitcode` asks for the cell under the data pointer to be the
+/// exit code.
+fn exits_with_cell(source: Option<&str>) -> Result<bool, String> {
+    match source {
+        Some("cell") => Ok(true),
+        Some(source) => Err(format!("Unknown exit code source: {}", source)),
+        None => Ok(false),
+    }
+}
+
+/// Pick the tape mode from `--bounds` and `--tape`.
+fn tape_mode(args: &Args) -> Result<TapeMode, String> {
+    match (args.flag_bounds.as_deref(), args.flag_tape.as_deref()) {
+        (Some(_), Some(_)) => Err("Only one of --bounds and --tape may be given".to_string()),
+        (Some("wrap"), None) => Ok(TapeMode::Wrapped),
+        (Some(mode), None) => Err(format!("Unknown bounds mode: {}", mode)),
+        (None, Some("bidirectional")) => Ok(TapeMode::Bidirectional),
+        (None, Some(kind)) => Err(format!("Unknown tape kind: {}", kind)),
+        (None, None) => Ok(TapeMode::Standard),
+    }
+}
+
+/// A parser that optimizes the program without counting on memory starting
+/// out zeroed if `--tape-init` fills it, or on cells wrapping unless
+/// `--cell-overflow` lets them.
+fn parser(args: &Args) -> Result<StreamParser, String> {
+    let mut parser = match args.flag_tape_init {
+        Some(_) => StreamParser::preloaded(),
+        None => StreamParser::new(),
+    };
+    parser.set_cell_overflow(cell_overflow(args)?);
+
+    Ok(parser)
+}
+
+/// Parse and optimize a program with `parser`. Bytecode is taken as it is.
+fn parse(linked: Linked, args: &Args) -> Result<Ast, String> {
+    if args.flag_bytecode {
+        return Ok(Ast {
+            data: bytecode::parse(&linked.text()?)?,
+            removed_loops: 0,
+        });
+    }
+
+    linked.parse_with(parser(args)?)
+}
+
+/// Optimize a program parsed with `Ast::parse_raw` the same way `parse`
+/// would have.
+fn optimize(raw: Ast, args: &Args) -> Result<Ast, String> {
+    Ok(raw.optimize_with(parser(args)?))
+}
+
+/// Values to place in memory before running, from `--tape-init` or `--env`.
+fn preload(args: &Args) -> Result<Vec<u8>, String> {
+    match args.flag_tape_init {
+        Some(_) if !args.flag_env.is_empty() => {
+            Err("Only one of --tape-init and --env may be given".to_string())
+        }
+        Some(ref path) => {
+            fs::read(path).map_err(|e| format!("Could not read initial tape: {:?}", e))
+        }
+        None if args.flag_env.is_empty() => Ok(Vec::new()),
+        None => environment::encode(&args.flag_env),
+    }
+}
+
+/// Create the file given with `--tape-dump`, before the sandbox would forbid
+/// it, along with the number of cells to write to it if one was given.
+fn open_tape_dump(args: &Args) -> Result<Option<(File, Option<usize>)>, String> {
+    let spec = match args.flag_tape_dump {
+        Some(ref spec) => spec.as_str(),
+        None => return Ok(None),
+    };
+    let (path, count) = tape_dump::parse_spec(spec)?;
+    let file = File::create(path).map_err(|e| format!("Could not create tape dump: {:?}", e))?;
+
+    Ok(Some((file, count)))
+}
+
+/// Pick what cell arithmetic does when it overflows from `--cell-overflow`.
+fn cell_overflow(args: &Args) -> Result<CellOverflow, String> {
+    match args.flag_cell_overflow.as_deref() {
+        Some("wrap") | None => Ok(CellOverflow::Wrap),
+        Some("error") => Ok(CellOverflow::Error),
+        Some("saturate") => Ok(CellOverflow::Saturate),
+        Some(policy) => Err(format!(
+            "Unknown cell overflow policy: {}. Expected wrap, error or saturate",
+            policy
+        )),
+    }
+}
+
+/// A stage of compiling a program that `--emit` can stop after.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    /// The tree of commands as written
+    Ast,
+    /// The tree after optimization
+    OptAst,
+    /// The interpreter's instructions
+    Bytecode,
+    /// The JIT compiler's machine code
+    Asm,
+}
+
+/// The stage to stop after, if the program isn't to be run.
+fn emit_stage(args: &Args) -> Result<Option<Stage>, String> {
+    match args.flag_emit.as_deref() {
+        Some("ast") => Ok(Some(Stage::Ast)),
+        Some("opt-ast") => Ok(Some(Stage::OptAst)),
+        Some("bytecode") => Ok(Some(Stage::Bytecode)),
+        Some("asm") => Ok(Some(Stage::Asm)),
+        Some(stage) => Err(format!(
+            "Unknown stage: {}. Expected ast, opt-ast, bytecode or asm",
+            stage
+        )),
+        None if args.flag_dump_bytecode => Ok(Some(Stage::Bytecode)),
+        None if args.flag_debug => Ok(Some(Stage::OptAst)),
+        None => Ok(None),
+    }
+}
+
+/// Print what compiling `nodes` as far as `stage` produces.
+fn emit(
+    stage: Stage,
+    nodes: VecDeque<AstNode>,
+    tape_mode: TapeMode,
+    cell_overflow: CellOverflow,
+) -> Result<(), String> {
+    match stage {
+        Stage::Ast | Stage::OptAst => {
+            print!("{}", tree::render(&nodes, use_color()));
+
+            Ok(())
+        }
+        Stage::Bytecode => Fucker::new(
+            nodes,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        )
+        .write_bytecode(&mut stdout())
+        .map_err(|e| format!("Could not write bytecode: {}", e)),
         #[cfg(target_arch = "x86_64")]
-        Box::new(JITTarget::new(program.data))
+        Stage::Asm => JITTarget::for_asm(
+            nodes,
+            CompileOptions {
+                tape_mode,
+                cell_overflow,
+                ..CompileOptions::default()
+            },
+        )
+        .write_asm(&mut stdout())
+        .map_err(|e| format!("Could not write assembly: {}", e)),
+        #[cfg(not(target_arch = "x86_64"))]
+        Stage::Asm => {
+            let _ = (tape_mode, cell_overflow);
+            Err("The JIT compiler is only supported on x86_64".to_string())
+        }
+    }
+}
+
+/// Create the interpreter, or the JIT compiler unless `interpret` is set.
+/// A wrapped tape has a fixed size, so it can't be given a limit.
+fn build_runnable(
+    interpret: bool,
+    jit: JitOptions,
+    tape: TapeOptions,
+    nodes: VecDeque<AstNode>,
+    io_read: Box<dyn Read>,
+    io_write: Box<dyn Write>,
+    instrumentation: Instrumentation,
+) -> Result<Box<dyn Runnable>, String> {
+    if tape.mode == TapeMode::Wrapped && tape.limit.is_some() {
+        return Err("--max-tape can't be used with --bounds=wrap".to_string());
+    }
+
+    if jit.trace && interpret {
+        return Err("--trace-jit only applies to the JIT compiler".to_string());
+    }
+
+    if jit.trace && (instrumentation.observes_instructions() || instrumentation.observes_loops()) {
+        return Err(
+            "--trace-jit can't be used with --trace, --profile, --profile-loops or --profile-out"
+                .to_string(),
+        );
+    }
+
+    if jit.fallback
+        && (instrumentation.observes_instructions()
+            || instrumentation.observes_loops()
+            || instrumentation.tape.is_some())
+    {
+        return Err(
+            "--jit-fallback can't be used with --trace, --profile, --profile-loops, \
+             --profile-out, --tape-stats or --heatmap"
+                .to_string(),
+        );
+    }
+
+    if jit.hot_loops.is_some() && interpret {
+        return Err("--profile-in only applies to the JIT compiler".to_string());
+    }
+
+    if jit.hot_loops.is_some() && jit.trace {
+        return Err("Only one of --profile-in and --trace-jit may be given".to_string());
+    }
+
+    if jit.max_bytes.is_some() && interpret {
+        return Err("--max-jit-bytes only applies to the JIT compiler".to_string());
+    }
+
+    if interpret {
+        let mut fucker = Fucker::new(nodes, io_read, io_write, instrumentation);
+
+        if let Some(cells) = tape.limit {
+            fucker.limit_tape(cells);
+        }
+
+        match tape.mode {
+            TapeMode::Standard => {}
+            TapeMode::Wrapped => fucker.wrap_tape(),
+            TapeMode::Bidirectional => fucker.bidirectional_tape(),
+        }
+
+        fucker.set_cell_overflow(tape.cell_overflow);
+
+        if tape.huge_pages {
+            fucker.use_huge_pages();
+        }
+
+        return Ok(Box::new(fucker));
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    return Err("JIT is not supported for this architecture".to_string());
+    #[cfg(target_arch = "x86_64")]
+    {
+        let code_limit = jit.max_bytes.map(|bytes| CodeLimit {
+            bytes,
+            interpret: jit.fallback,
+        });
+        let options = CompileOptions {
+            tape_mode: tape.mode,
+            cell_overflow: tape.cell_overflow,
+            hot_loops: jit.hot_loops,
+            code_limit,
+        };
+        let mut jit_target =
+            JITTarget::with_options(nodes, io_read, io_write, instrumentation, options);
+
+        if let Some(cells) = tape.limit {
+            jit_target.limit_tape(cells);
+        }
+
+        if tape.huge_pages {
+            jit_target.use_huge_pages();
+        }
+
+        if jit.trace {
+            jit_target.trace_hot_loops();
+        }
+
+        Ok(Box::new(jit_target))
+    }
+}
+
+/// Read the options for the JIT, loading the profile given with
+/// `--profile-in`.
+fn jit_options(args: &Args) -> Result<JitOptions, String> {
+    let hot_loops = match args.flag_profile_in {
+        Some(ref path) => {
+            let text =
+                fs::read_to_string(path).map_err(|e| format!("Could not read profile: {:?}", e))?;
+            Some(HotLoops::parse_json(&text)?)
+        }
+        None => None,
     };
 
-    runnable.run();
+    if args.flag_jit_fallback && args.flag_max_jit_bytes.is_none() {
+        return Err("--jit-fallback needs --max-jit-bytes".to_string());
+    }
+
+    Ok(JitOptions {
+        trace: args.flag_trace_jit,
+        hot_loops,
+        max_bytes: args.flag_max_jit_bytes,
+        fallback: args.flag_jit_fallback,
+    })
+}
+
+/// Save the loop counts of a run to `file` for `--profile-in`.
+fn write_hot_loops(file: File, loops: &LoopProfile) -> io::Result<()> {
+    let mut out = BufWriter::new(file);
+    HotLoops::from_profile(loops).write_json(&mut out)?;
+
+    out.flush()
+}
+
+/// Write a heatmap of cell write counts, picking the format from the file
+/// extension.
+fn write_heatmap(path: &str, counts: &[u64]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+
+    if path.ends_with(".ppm") {
+        heatmap::write_ppm(counts, &mut out)?;
+    } else {
+        heatmap::write_text(counts, &mut out)?;
+    }
+
+    out.flush()
+}
+
+/// Create a shared, default initialized value when `enabled` is set.
+fn shared_if<T: Default>(enabled: bool) -> Option<Rc<RefCell<T>>> {
+    if enabled {
+        Some(Rc::new(RefCell::new(T::default())))
+    } else {
+        None
+    }
+}
+
+/// Describe a failure to write a report.
+fn check_report(name: &str, result: io::Result<()>) -> Result<(), String> {
+    result.map_err(|e| format!("Error occurred while writing {}: {}", name, e))
+}
+
+/// Build the streams used by the program's `,` and `.` commands.
+///
+/// Input comes from stdin unless it was given after a `!` in an inline
+/// program, in a file with `--input` or by replaying a log, and both streams
+/// go through a connection instead with `--connect`. When recording, both
+/// streams are wrapped so that their traffic ends up in the log. With
+/// `--prompt`, reads from a terminal on stdin are prompted for. Newlines are
+/// translated first, so that limits and logs see the program's own.
+fn program_io(args: &Args, input: Option<Vec<u8>>) -> Result<ProgramIO, String> {
+    let input: Option<Box<dyn Read>> = match (input, &args.flag_input) {
+        (Some(_), Some(_)) => {
+            return Err("Only one of --input and input after a ! may be given".to_string())
+        }
+        (Some(input), None) => Some(Box::new(Cursor::new(input))),
+        (None, Some(path)) => Some(Box::new(
+            File::open(path).map_err(|e| format!("Could not open input: {:?}", e))?,
+        )),
+        (None, None) => None,
+    };
+    let (mut io_read, mut io_write): ProgramIO =
+        match (&args.flag_connect, &args.flag_replay, input) {
+            (Some(_), Some(_), _) => {
+                return Err("Only one of --connect and --replay may be given".to_string())
+            }
+            (Some(_), _, Some(_)) | (_, Some(_), Some(_)) => {
+                return Err("Input can't be given along with --connect or --replay".to_string())
+            }
+            (Some(addr), None, None) => socket::connect(addr)?,
+            (None, Some(path), None) => (Box::new(io_log::open_replay(path)?), Box::new(stdout())),
+            (None, None, Some(input)) => (input, Box::new(stdout())),
+            (None, None, None) if args.flag_prompt && stdin().is_terminal() => (
+                Box::new(PromptReader::new(Box::new(stdin()), Box::new(stderr()))),
+                Box::new(stdout()),
+            ),
+            (None, None, None) => (Box::new(stdin()), Box::new(stdout())),
+        };
+
+    if args.flag_crlf {
+        io_read = Box::new(CrlfReader::new(io_read));
+        io_write = Box::new(CrlfWriter::new(io_write));
+    }
+
+    if let Some(limit) = args.flag_max_output {
+        io_write = Box::new(LimitedWriter::new(io_write, limit));
+    }
+
+    if let Some(ref path) = args.flag_record {
+        let log = io_log::create_log(path)?;
+
+        if args.flag_record_output {
+            io_write = Box::new(RecordingWriter::new(io_write, log.clone()));
+        }
+        io_read = Box::new(RecordingReader::new(io_read, log));
+    }
+
+    Ok((io_read, io_write))
+}
+
+/// Build the tracer requested on the command line, if any.
+fn program_tracer(args: &Args) -> Result<Option<Tracer>, String> {
+    let out: Box<dyn Write> = match args.flag_trace_file {
+        Some(ref path) => {
+            let file =
+                File::create(path).map_err(|e| format!("Could not create trace: {:?}", e))?;
+            Box::new(BufWriter::new(file))
+        }
+        None if args.flag_trace => Box::new(BufWriter::new(stderr())),
+        None => return Ok(None),
+    };
+
+    let range = match args.flag_trace_range {
+        Some(ref range) => parse_range(range)?,
+        None => 0..u64::MAX,
+    };
+
+    Ok(Some(Tracer::new(out, range, args.flag_trace_every)))
+}
+
+/// The event log asked for with `--log-json`, if any.
+fn program_events(args: &Args) -> Result<Option<EventLog>, String> {
+    match args.flag_log_json {
+        Some(ref path) => {
+            let file =
+                File::create(path).map_err(|e| format!("Could not create event log: {:?}", e))?;
+
+            Ok(Some(EventLog::new(Box::new(BufWriter::new(file)))))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Parse a range in the form START:END where either bound may be omitted.
+fn parse_range(range: &str) -> Result<Range<u64>, String> {
+    let (start, end) = range
+        .split_once(':')
+        .ok_or_else(|| format!("Range is missing a colon: {}", range))?;
+    let parse_bound = |bound: &str, default: u64| {
+        if bound.is_empty() {
+            Ok(default)
+        } else {
+            bound
+                .parse()
+                .map_err(|e| format!("Invalid range bound {:?}: {}", bound, e))
+        }
+    };
+
+    Ok(parse_bound(start, 0)?..parse_bound(end, u64::MAX)?)
+}
+
+/// Whether output to stdout should be colored: it must be a terminal, and
+/// NO_COLOR must not be set.
+fn use_color() -> bool {
+    env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && stdout().is_terminal()
 }
 
 /// Read a BrainFuck program's source code.
@@ -92,3 +1095,39 @@ fn read_program(path: &str) -> Result<String, String> {
 
     Ok(buffer)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_exit_code_source() {
+        assert_eq!(exits_with_cell(None), Ok(false));
+        assert_eq!(exits_with_cell(Some("cell")), Ok(true));
+        assert_eq!(
+            exits_with_cell(Some("status")),
+            Err("Unknown exit code source: status".to_string())
+        );
+    }
+
+    #[test]
+    fn dumps_bytecode_as_emit_stage() {
+        let stage = |argv: &[&str]| {
+            let args: Args = Docopt::new(USAGE)
+                .and_then(|d| d.argv(argv).deserialize())
+                .unwrap();
+            emit_stage(&args)
+        };
+
+        assert_eq!(
+            stage(&["fucker", "--dump-bytecode", "a.bf"]),
+            Ok(Some(Stage::Bytecode))
+        );
+        assert_eq!(
+            stage(&["fucker", "--emit=bytecode", "a.bf"]),
+            Ok(Some(Stage::Bytecode))
+        );
+        assert_eq!(stage(&["fucker", "-d", "a.bf"]), Ok(Some(Stage::OptAst)));
+        assert_eq!(stage(&["fucker", "a.bf"]), Ok(None));
+    }
+}
@@ -1,7 +1,16 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::mem;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use super::pragma;
+use super::stream::StreamParser;
+use crate::runnable::CellOverflow;
 
 /// BrainFuck AST node
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum AstNode {
     /// Add to the current memory cell.
     Incr(u8),
@@ -24,85 +33,243 @@ pub enum AstNode {
     /// Loop over the contained instructions while the current memory cell is
     /// not zero.
     Loop(VecDeque<AstNode>),
+    /// Run the extension added for a command on the current memory cell.
+    Extension(char),
+}
+
+impl AstNode {
+    /// Name of this node's variant.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AstNode::Incr(_) => "Incr",
+            AstNode::Decr(_) => "Decr",
+            AstNode::Next(_) => "Next",
+            AstNode::Prev(_) => "Prev",
+            AstNode::Print => "Print",
+            AstNode::Read => "Read",
+            AstNode::Set(_) => "Set",
+            AstNode::AddTo(_) => "AddTo",
+            AstNode::SubFrom(_) => "SubFrom",
+            AstNode::Loop(_) => "Loop",
+            AstNode::Extension(_) => "Extension",
+        }
+    }
+}
+
+/// Write `count` copies of a BrainFuck command.
+fn write_repeated(f: &mut fmt::Formatter<'_>, command: char, count: usize) -> fmt::Result {
+    for _ in 0..count {
+        write!(f, "{}", command)?;
+    }
+
+    Ok(())
+}
+
+/// Renders a node back into equivalent BrainFuck source.
+impl fmt::Display for AstNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AstNode::Incr(n) => write_repeated(f, '+', *n as usize),
+            AstNode::Decr(n) => write_repeated(f, '-', *n as usize),
+            AstNode::Next(n) => write_repeated(f, '>', *n),
+            AstNode::Prev(n) => write_repeated(f, '<', *n),
+            AstNode::Print => write!(f, "."),
+            AstNode::Read => write!(f, ","),
+            AstNode::Extension(command) => write!(f, "{}", command),
+            AstNode::Set(n) => {
+                write!(f, "[-]")?;
+                write_repeated(f, '+', *n as usize)
+            }
+            AstNode::AddTo(offset) | AstNode::SubFrom(offset) => {
+                let (there, back) = if *offset < 0 { ('<', '>') } else { ('>', '<') };
+                let command = if let AstNode::AddTo(_) = self {
+                    '+'
+                } else {
+                    '-'
+                };
+
+                write!(f, "[-")?;
+                write_repeated(f, there, offset.unsigned_abs())?;
+                write!(f, "{}", command)?;
+                write_repeated(f, back, offset.unsigned_abs())?;
+                write!(f, "]")
+            }
+            AstNode::Loop(nodes) => {
+                write!(f, "[")?;
+                for node in nodes {
+                    write!(f, "{}", node)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
 }
 
 /// Container for a vector of AstNodes.
 #[derive(Debug, Clone)]
 pub struct Ast {
     pub data: VecDeque<AstNode>,
+    /// Loops removed because they could never run
+    pub removed_loops: usize,
 }
 
 impl Ast {
-    /// Convert raw input into an AST.
+    /// Convert raw input into an AST, optimizing it along the way. Pragma
+    /// lines at the start of the input are skipped.
     pub fn parse(input: &str) -> Result<Self, String> {
+        span!("parse", bytes = input.len());
+
+        Self::parse_with(input, StreamParser::new())
+    }
+
+    /// Like `parse`, optimizing the program the way `parser` does.
+    pub fn parse_with(input: &str, mut parser: StreamParser) -> Result<Self, String> {
+        parser.feed(pragma::body(input).as_bytes())?;
+        parser.finish()
+    }
+
+    /// Like `parse`, without counting on memory starting out zeroed, for
+    /// programs run on a tape filled beforehand or left by an earlier run.
+    pub fn parse_preloaded(input: &str) -> Result<Self, String> {
+        Self::parse_with(input, StreamParser::preloaded())
+    }
+
+    /// Convert raw input into an AST with a node for every command, as
+    /// written. Pragma lines at the start of the input are skipped.
+    pub fn parse_raw(input: &str) -> Result<Self, String> {
+        Self::parse_with(input, StreamParser::raw())
+    }
+
+    /// Combine runs of commands, replace common loops with shorthands and
+    /// remove loops that can never run, with the built-in passes.
+    pub fn optimize(self) -> Self {
+        self.optimize_with(StreamParser::new())
+    }
+
+    /// Like `optimize`, without counting on memory starting out zeroed, for
+    /// programs run on a tape filled beforehand.
+    pub fn optimize_preloaded(self) -> Self {
+        self.optimize_with(StreamParser::preloaded())
+    }
+
+    /// Optimize a program parsed with `parse_raw` the same way `parser` would
+    /// have as it read it, counting on no more than it does about how the
+    /// program is run.
+    pub fn optimize_with(self, parser: StreamParser) -> Self {
+        parser.optimize(self)
+    }
+
+    /// Simplify every loop in `nodes`, innermost first, with shorthands that
+    /// hold when cells overflow as `cell_overflow` says.
+    pub(super) fn simplify_loops(
+        nodes: VecDeque<AstNode>,
+        cell_overflow: CellOverflow,
+    ) -> VecDeque<AstNode> {
+        map_nodes(nodes, |node| Self::optimize_node(node, cell_overflow))
+    }
+
+    /// Optimize the body of a loop, and replace the loop with a shorthand if
+    /// there is one.
+    fn optimize_node(node: AstNode, cell_overflow: CellOverflow) -> AstNode {
+        match node {
+            AstNode::Loop(body) => {
+                let mut body = Self::simplify_loops(body, cell_overflow);
+                let body = Self::combine_consecutive_nodes(&mut body);
+
+                Self::simplify_loop(&body, cell_overflow).unwrap_or(AstNode::Loop(body))
+            }
+            node => node,
+        }
+    }
+
+    /// Remove loops that start on a cell known to be zero, since they are
+    /// skipped. Cells are known to be zero after a loop ends on them or they
+    /// are set to zero, until something else changes them. `zeros` holds the
+    /// offsets from the data pointer of the cells known to be zero when
+    /// `input` starts, and is left holding those known when it ends.
+    pub(super) fn remove_dead_loops(
+        input: VecDeque<AstNode>,
+        zeros: &mut BTreeSet<isize>,
+        removed: &mut usize,
+    ) -> VecDeque<AstNode> {
         let mut output = VecDeque::new();
-        let mut loops = VecDeque::new();
-
-        for character in input.chars() {
-            let next_node = match character {
-                '+' => AstNode::Incr(1),
-                '-' => AstNode::Decr(1),
-                '>' => AstNode::Next(1),
-                '<' => AstNode::Prev(1),
-                '.' => AstNode::Print,
-                ',' => AstNode::Read,
-                '[' => {
-                    loops.push_back(VecDeque::new());
+
+        for node in input {
+            let shift = |zeros: &mut BTreeSet<isize>, by: isize| {
+                *zeros = zeros.iter().map(|offset| offset - by).collect();
+            };
+
+            match node {
+                // Simplified loops are skipped on a zero cell as well, and
+                // setting a zero cell to zero does nothing.
+                AstNode::Loop(_) | AstNode::AddTo(_) | AstNode::SubFrom(_) | AstNode::Set(0)
+                    if zeros.contains(&0) =>
+                {
+                    *removed += 1;
                     continue;
                 }
-                ']' => {
-                    // Example program that will cause this error:
-                    //
-                    // []]
-                    let mut current_loop = loops.pop_back().ok_or("More ] than [")?;
-
-                    // Do not add loop if it will be the first element in the
-                    // output vector. This is because:
-                    //
-                    // 1. The BrainFuck machine starts all cells at 0
-                    // 2. Loops are skipped when the current cell is 0
-                    //
-                    // So if no non-loops have executed there is no use in
-                    // emitting a Loop AstNode.
-                    if output.is_empty() {
-                        continue;
-                    }
-
-                    current_loop = Self::combine_consecutive_nodes(&mut current_loop);
-
-                    if let Some(node) = Self::simplify_loop(&current_loop) {
-                        node
-                    } else {
-                        AstNode::Loop(current_loop)
-                    }
+                AstNode::Incr(_)
+                | AstNode::Decr(_)
+                | AstNode::Read
+                | AstNode::Set(_)
+                | AstNode::Extension(_) => {
+                    zeros.remove(&0);
                 }
-                // All other characters are comments and will be ignored
-                _ => continue,
-            };
+                AstNode::AddTo(offset) | AstNode::SubFrom(offset) => {
+                    zeros.remove(&offset);
+                }
+                AstNode::Next(n) => shift(zeros, n as isize),
+                AstNode::Prev(n) => shift(zeros, -(n as isize)),
+                AstNode::Print => {}
+                AstNode::Loop(body) => {
+                    // Bodies are handled once the loops around them are.
+                    output.push_back(AstNode::Loop(body));
+                    zeros.clear();
+                    zeros.insert(0);
+                    continue;
+                }
+            }
 
-            loops.back_mut().unwrap_or(&mut output).push_back(next_node);
-        }
+            if let AstNode::Set(0) | AstNode::AddTo(_) | AstNode::SubFrom(_) = node {
+                zeros.insert(0);
+            }
 
-        if !loops.is_empty() {
-            // Example program that will cause this error:
-            //
-            // [[]
-            return Err("More [ than ]".to_string());
+            output.push_back(node);
         }
 
-        Ok(Ast {
-            data: Self::combine_consecutive_nodes(&mut output),
-        })
+        // Nothing is known at the start of a pass through a body, since it may
+        // follow an earlier pass, so bodies don't depend on each other.
+        *removed += sum_bodies(&mut output, |body| {
+            let mut removed = 0;
+            *body = Self::remove_dead_loops(mem::take(body), &mut BTreeSet::new(), &mut removed);
+            removed
+        });
+
+        // Removing a loop can leave runs of the same node next to each other.
+        Self::combine_consecutive_nodes(&mut output)
     }
 
-    /// If a shorthand for the provided loop exists, return that.
-    fn simplify_loop(input: &VecDeque<AstNode>) -> Option<AstNode> {
+    /// If a shorthand for the provided loop exists, return that. Loops that
+    /// don't match a pattern here are left to the superoptimizer, which only
+    /// knows about cells that wrap.
+    pub(super) fn simplify_loop(
+        input: &VecDeque<AstNode>,
+        cell_overflow: CellOverflow,
+    ) -> Option<AstNode> {
+        let wraps = cell_overflow == CellOverflow::Wrap;
+
         // Zero loop
         if input.len() == 1 {
             match input[0] {
-                AstNode::Incr(1) => return Some(AstNode::Set(0)),
+                // Counting up only reaches zero by wrapping past 255.
+                AstNode::Incr(1) if wraps => return Some(AstNode::Set(0)),
                 AstNode::Decr(1) => return Some(AstNode::Set(0)),
-                _ => return None,
+                // The inner loop always leaves the current cell zero, so the
+                // outer one never runs more than once.
+                AstNode::Loop(_) | AstNode::Set(0) | AstNode::AddTo(_) | AstNode::SubFrom(_) => {
+                    return Some(input[0].clone())
+                }
+                _ => {}
             }
         }
 
@@ -135,60 +302,98 @@ impl Ast {
                     let offset = *a as isize;
                     return Some(AstNode::SubFrom(offset));
                 }
-                _ => return None,
+                _ => {}
             };
         }
 
-        None
+        if !wraps {
+            return None;
+        }
+
+        super::superopt::simplify(input)
     }
 
     /// Convert runs of instructions into bulk operations.
-    fn combine_consecutive_nodes(input: &mut VecDeque<AstNode>) -> VecDeque<AstNode> {
+    pub(super) fn combine_consecutive_nodes(input: &mut VecDeque<AstNode>) -> VecDeque<AstNode> {
         let mut output = VecDeque::new();
 
         while let Some(next_node) = input.pop_front() {
-            let prev_node = output.back();
-
-            // For each operator +, -, < and >, if the last instruction in the
-            // output Vec is the same, then increment that instruction instead
-            // of adding another identical instruction.
-            let combined = match (prev_node, &next_node) {
-                // Combine sequential Incr, Decr, Next and Prev
-                (Some(AstNode::Incr(b)), AstNode::Incr(a)) => {
-                    Some(AstNode::Incr(a.wrapping_add(*b)))
-                }
-                (Some(AstNode::Decr(b)), AstNode::Decr(a)) => {
-                    Some(AstNode::Decr(a.wrapping_add(*b)))
-                }
-                (Some(AstNode::Next(b)), AstNode::Next(a)) => {
-                    Some(AstNode::Next(a.wrapping_add(*b)))
-                }
-                (Some(AstNode::Prev(b)), AstNode::Prev(a)) => {
-                    Some(AstNode::Prev(a.wrapping_add(*b)))
-                }
-                // Combine Incr or Decr with Set
-                (Some(AstNode::Set(a)), AstNode::Incr(b)) => Some(AstNode::Set(a.wrapping_add(*b))),
-                (Some(AstNode::Set(a)), AstNode::Decr(b)) => Some(AstNode::Set(a.wrapping_sub(*b))),
-                // Node is not combinable
-                _ => None,
-            };
-
-            if let Some(new_node) = combined {
-                // Replace last node with the combined one
-                output.pop_back();
-                output.push_back(new_node);
-            } else {
-                output.push_back(next_node);
-            }
+            Self::push_combined(&mut output, next_node);
         }
 
         output
     }
+
+    /// Add a node to the end of `output`, combining it with the last node
+    /// there if they make a single bulk operation.
+    pub(super) fn push_combined(output: &mut VecDeque<AstNode>, next_node: AstNode) {
+        let prev_node = output.back();
+
+        // For each operator +, -, < and >, if the last instruction in the
+        // output Vec is the same, then increment that instruction instead
+        // of adding another identical instruction.
+        let combined = match (prev_node, &next_node) {
+            // Combine sequential Incr, Decr, Next and Prev. Counts of Incr
+            // and Decr stop short of wrapping around, since a run of 256 or
+            // more only comes to the same as a shorter one if cells wrap.
+            (Some(AstNode::Incr(b)), AstNode::Incr(a)) => a.checked_add(*b).map(AstNode::Incr),
+            (Some(AstNode::Decr(b)), AstNode::Decr(a)) => a.checked_add(*b).map(AstNode::Decr),
+            (Some(AstNode::Next(b)), AstNode::Next(a)) => Some(AstNode::Next(a.wrapping_add(*b))),
+            (Some(AstNode::Prev(b)), AstNode::Prev(a)) => Some(AstNode::Prev(a.wrapping_add(*b))),
+            // A Set overwrites whatever was set before it
+            (Some(AstNode::Set(_)), AstNode::Set(b)) => Some(AstNode::Set(*b)),
+            // Combine Incr or Decr with Set, unless the cell would overflow
+            (Some(AstNode::Set(a)), AstNode::Incr(b)) => a.checked_add(*b).map(AstNode::Set),
+            (Some(AstNode::Set(a)), AstNode::Decr(b)) => a.checked_sub(*b).map(AstNode::Set),
+            // Node is not combinable
+            _ => None,
+        };
+
+        if let Some(new_node) = combined {
+            // Replace last node with the combined one
+            output.pop_back();
+            output.push_back(new_node);
+        } else {
+            output.push_back(next_node);
+        }
+    }
+}
+
+/// Apply `f` to each of `nodes`, on several threads when built with the
+/// `rayon` feature.
+fn map_nodes<F>(nodes: VecDeque<AstNode>, f: F) -> VecDeque<AstNode>
+where
+    F: Fn(AstNode) -> AstNode + Send + Sync,
+{
+    #[cfg(feature = "rayon")]
+    return nodes.into_par_iter().map(f).collect();
+
+    #[cfg(not(feature = "rayon"))]
+    return nodes.into_iter().map(f).collect();
+}
+
+/// Apply `f` to the body of each loop in `nodes`, on several threads when
+/// built with the `rayon` feature, returning the sum of its results.
+fn sum_bodies<F>(nodes: &mut VecDeque<AstNode>, f: F) -> usize
+where
+    F: Fn(&mut VecDeque<AstNode>) -> usize + Send + Sync,
+{
+    let body = |node: &mut AstNode| match node {
+        AstNode::Loop(body) => f(body),
+        _ => 0,
+    };
+
+    #[cfg(feature = "rayon")]
+    return nodes.par_iter_mut().map(body).sum();
+
+    #[cfg(not(feature = "rayon"))]
+    return nodes.iter_mut().map(body).sum();
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::parser::generate;
 
     #[test]
     fn too_many_loop_begins() {
@@ -202,6 +407,112 @@ mod tests {
         assert!(ast.is_err());
     }
 
+    #[test]
+    fn parses_in_stages() {
+        let raw = Ast::parse_raw("[-]++[->+<]").unwrap();
+
+        assert_eq!(
+            raw.data,
+            [
+                AstNode::Loop(VecDeque::from([AstNode::Decr(1)])),
+                AstNode::Incr(1),
+                AstNode::Incr(1),
+                AstNode::Loop(VecDeque::from([
+                    AstNode::Decr(1),
+                    AstNode::Next(1),
+                    AstNode::Incr(1),
+                    AstNode::Prev(1),
+                ])),
+            ]
+        );
+        assert_eq!(raw.removed_loops, 0);
+
+        let optimized = raw.optimize();
+
+        assert_eq!(optimized.data, [AstNode::Incr(2), AstNode::AddTo(1)]);
+        assert_eq!(optimized.removed_loops, 1);
+    }
+
+    #[test]
+    fn keeps_combined_counts_from_wrapping() {
+        let ast = Ast::parse(&format!(">{}<[-]-", "+".repeat(300))).unwrap();
+
+        assert_eq!(
+            ast.data,
+            [
+                AstNode::Next(1),
+                AstNode::Incr(255),
+                AstNode::Incr(45),
+                AstNode::Prev(1),
+                AstNode::Set(0),
+                AstNode::Decr(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_dead_loops() {
+        let ast = Ast::parse("+[->+<][-.]>[-]<.[+][<]>>[[>]<[-]]").unwrap();
+
+        assert_eq!(
+            ast.data,
+            [
+                AstNode::Incr(1),
+                AstNode::AddTo(1),
+                AstNode::Next(1),
+                AstNode::Set(0),
+                AstNode::Prev(1),
+                AstNode::Print,
+                AstNode::Next(2),
+                AstNode::Loop(VecDeque::from([
+                    AstNode::Loop(VecDeque::from([AstNode::Next(1)])),
+                    AstNode::Prev(1),
+                    AstNode::Set(0),
+                ])),
+            ]
+        );
+        assert_eq!(ast.removed_loops, 3);
+    }
+
+    #[test]
+    fn removes_redundant_nesting() {
+        let ast = Ast::parse("+[[-]]>+[[[->+<]]]>+[[>]]>+[-]+++[-]+").unwrap();
+
+        assert_eq!(
+            ast.data,
+            [
+                AstNode::Incr(1),
+                AstNode::Set(0),
+                AstNode::Next(1),
+                AstNode::Incr(1),
+                AstNode::AddTo(1),
+                AstNode::Next(1),
+                AstNode::Incr(1),
+                AstNode::Loop(VecDeque::from([AstNode::Next(1)])),
+                AstNode::Next(1),
+                AstNode::Incr(1),
+                AstNode::Set(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn nesting_generated_loops_changes_nothing() {
+        // Every generated loop leaves the cell it starts on zero, so wrapping
+        // each one in another loop doesn't change what the program does.
+        for seed in 0..50 {
+            let source = generate(seed, 64, 3);
+            let nested = source.replace('[', "[[").replace(']', "]]");
+
+            assert_eq!(
+                Ast::parse(&nested).unwrap().data,
+                Ast::parse(&source).unwrap().data,
+                "{}",
+                source
+            );
+        }
+    }
+
     #[test]
     fn run_length_encode() {
         let ast = Ast::parse("+++++").unwrap();
@@ -217,6 +528,45 @@ mod tests {
         assert_eq!(ast.data[1], AstNode::Set(3));
     }
 
+    #[test]
+    fn only_counts_up_to_zero_when_cells_wrap() {
+        let parse = |cell_overflow| {
+            let mut parser = StreamParser::new();
+            parser.set_cell_overflow(cell_overflow);
+            parser.feed(b"+[+]>+[---]").unwrap();
+            parser.finish().unwrap().data
+        };
+
+        assert_eq!(
+            parse(CellOverflow::Wrap),
+            [
+                AstNode::Incr(1),
+                AstNode::Set(0),
+                AstNode::Next(1),
+                AstNode::Incr(1),
+                AstNode::Set(0)
+            ]
+        );
+
+        for cell_overflow in [CellOverflow::Error, CellOverflow::Saturate] {
+            let raw = Ast::parse_raw("+[+]>+[---]").unwrap();
+            let mut parser = StreamParser::new();
+            parser.set_cell_overflow(cell_overflow);
+
+            assert_eq!(
+                parse(cell_overflow),
+                [
+                    AstNode::Incr(1),
+                    AstNode::Loop(VecDeque::from([AstNode::Incr(1)])),
+                    AstNode::Next(1),
+                    AstNode::Incr(1),
+                    AstNode::Loop(VecDeque::from([AstNode::Decr(3)])),
+                ]
+            );
+            assert_eq!(raw.optimize_with(parser).data, parse(cell_overflow));
+        }
+    }
+
     #[test]
     fn simplify_to_add() {
         let ast = Ast::parse("+[->+<]").unwrap();
@@ -239,6 +589,14 @@ mod tests {
         assert_eq!(ast.data.len(), 0);
     }
 
+    #[test]
+    fn displays_as_source() {
+        let source = "+[->>+<<]>[-<->]<[-]++[>.<,-]";
+        let ast = Ast::parse(source).unwrap();
+        let rendered: String = ast.data.iter().map(|node| node.to_string()).collect();
+        assert_eq!(rendered, source);
+    }
+
     #[test]
     fn parses_rot13() {
         let ast = Ast::parse(include_str!("../../test/programs/rot13-16char.bf"));
@@ -0,0 +1,99 @@
+//! How far from where it starts a program can move the data pointer. When
+//! every loop that could run is balanced, the data pointer only ever lands on
+//! cells at fixed offsets from its start, so the furthest of those bound how
+//! much of the tape the program needs.
+
+use super::lint::{self, Item, Position};
+
+/// How far a program can move the data pointer.
+#[derive(Debug, PartialEq)]
+pub enum Bound {
+    /// The data pointer stays between these cells, counting from the one it
+    /// starts on
+    Within(isize, isize),
+    /// The loop starting here moves the data pointer this many cells each
+    /// pass, so how far it goes depends on memory or input
+    Unbounded(Position, isize),
+}
+
+/// The bound on the data pointer in `source`, assuming memory starts zeroed.
+/// Loops that can never run, like comments at the start of a program, don't
+/// count.
+pub fn bound(source: &str) -> Result<Bound, String> {
+    let items = lint::items(source)?;
+    let mut reach = (0, 0);
+
+    Ok(match walk(&items, 0, true, &mut reach) {
+        Ok(_) => Bound::Within(reach.0, reach.1),
+        Err((start, stride)) => Bound::Unbounded(start, stride),
+    })
+}
+
+/// Widen `reach` to the cells `items` can move the data pointer to when
+/// started on `cell`, returning the cell they end on, or find the first loop
+/// that ends somewhere other than where it started. `all_zero` is whether
+/// every cell is still zero at the start of `items`.
+fn walk(
+    items: &[Item],
+    mut cell: isize,
+    mut all_zero: bool,
+    reach: &mut (isize, isize),
+) -> Result<isize, (Position, isize)> {
+    let mut current_zero = all_zero;
+
+    for item in items {
+        match item {
+            Item::Command('>', _) => {
+                cell += 1;
+                current_zero = all_zero;
+            }
+            Item::Command('<', _) => {
+                cell -= 1;
+                current_zero = all_zero;
+            }
+            Item::Command('.', _) => {}
+            Item::Command(_, _) => {
+                all_zero = false;
+                current_zero = false;
+            }
+            Item::Loop(_, _) if current_zero => {}
+            Item::Loop(start, body) => {
+                // Loops inside are checked first, so that the innermost loop
+                // at fault is the one reported.
+                let end = walk(body, cell, false, reach)?;
+                if end != cell {
+                    return Err((*start, end - cell));
+                }
+
+                current_zero = true;
+            }
+        }
+
+        *reach = (reach.0.min(cell), reach.1.max(cell));
+    }
+
+    Ok(cell)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_balanced_programs() {
+        assert_eq!(bound("+[>>+<<-]>>."), Ok(Bound::Within(0, 2)));
+        assert_eq!(bound("+[<+>-]"), Ok(Bound::Within(-1, 0)));
+        assert_eq!(bound(">>>>>>>>>>"), Ok(Bound::Within(0, 10)));
+        // The leading loop is a comment, which never runs.
+        assert_eq!(bound("[<<<]+>."), Ok(Bound::Within(0, 1)));
+        assert_eq!(bound("+[[-][<]>-<]"), Ok(Bound::Within(0, 1)));
+    }
+
+    #[test]
+    fn finds_unbounded_loops() {
+        let at = |line, column| Position { line, column };
+
+        assert_eq!(bound("+[>+]"), Ok(Bound::Unbounded(at(1, 2), 1)));
+        assert_eq!(bound("+[-\n>+[<<]>]"), Ok(Bound::Unbounded(at(2, 3), -2)));
+    }
+}
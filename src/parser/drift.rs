@@ -0,0 +1,58 @@
+//! How far loops move the data pointer each time around. Loops that end
+//! where they started (balanced loops) only ever touch cells at fixed offsets
+//! from where they were entered, while loops that drift by a constant amount
+//! step through memory with a fixed stride, like `[>>]` looking for a zero.
+
+use std::collections::VecDeque;
+
+use super::AstNode;
+
+/// How far running `nodes` once moves the data pointer, or `None` if that
+/// depends on how often a loop inside them runs.
+pub fn movement(nodes: &VecDeque<AstNode>) -> Option<isize> {
+    nodes.iter().try_fold(0isize, |total, node| {
+        let moved = match node {
+            AstNode::Next(n) => *n as isize,
+            AstNode::Prev(n) => -(*n as isize),
+            // However often a balanced loop runs, it ends where it started.
+            AstNode::Loop(body) => match movement(body) {
+                Some(0) => 0,
+                _ => return None,
+            },
+            _ => 0,
+        };
+
+        Some(total.wrapping_add(moved))
+    })
+}
+
+/// How far each pass through a loop's body moves the data pointer, or `None`
+/// if it varies from pass to pass.
+pub fn drift(node: &AstNode) -> Option<isize> {
+    match node {
+        AstNode::Loop(body) => movement(body),
+        // Simplified loops only look at cells around the current one.
+        AstNode::Set(_) | AstNode::AddTo(_) | AstNode::SubFrom(_) => Some(0),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Ast;
+
+    fn first_drift(source: &str) -> Option<isize> {
+        drift(&Ast::parse(source).unwrap().data[1])
+    }
+
+    #[test]
+    fn measures_loop_bodies() {
+        assert_eq!(first_drift("+[>>+<<-]"), Some(0));
+        assert_eq!(first_drift("+[>>]"), Some(2));
+        assert_eq!(first_drift("+[<-<]"), Some(-2));
+        // The inner loop is balanced, so the outer one still has a stride.
+        assert_eq!(first_drift("+[>[-.]>]"), Some(2));
+        assert_eq!(first_drift("+[>[>]<]"), None);
+    }
+}
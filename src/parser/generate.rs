@@ -0,0 +1,143 @@
+/// Highest cell a generated program may move to.
+const MAX_CELL: usize = 16;
+
+/// xorshift64* pseudo random number generator.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Zero is a fixed point of xorshift.
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Random number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Generate a random BrainFuck program with `size` commands in its top level
+/// and loops nested at most `nesting` deep. The same seed always gives the
+/// same program.
+///
+/// Every loop counts down the cell it starts on and nothing inside it touches
+/// that cell, so a program runs at most 256^nesting iterations of any loop
+/// body and always terminates. The data pointer stays between cells 0 and 16.
+pub fn generate(seed: u64, size: usize, nesting: usize) -> String {
+    let mut generator = Generator {
+        rng: Rng::new(seed),
+        source: String::new(),
+        cell: 0,
+        counters: Vec::new(),
+        nesting,
+    };
+
+    generator.block(size);
+
+    generator.source
+}
+
+struct Generator {
+    rng: Rng,
+    source: String,
+    /// Position of the data pointer
+    cell: usize,
+    /// Cells counted down by the loops around the current position
+    counters: Vec<usize>,
+    /// Deepest loop nesting allowed
+    nesting: usize,
+}
+
+impl Generator {
+    /// Append `len` random commands.
+    fn block(&mut self, len: usize) {
+        for _ in 0..len {
+            let writable = !self.counters.contains(&self.cell);
+
+            match self.rng.below(10) {
+                0 | 1 if writable => self.source.push('+'),
+                2 | 3 if writable => self.source.push('-'),
+                4 if self.cell < MAX_CELL => self.move_to(self.cell + 1),
+                5 if self.cell > 0 => self.move_to(self.cell - 1),
+                6 if writable && self.rng.below(2) == 0 => self.source.push(','),
+                6 => self.source.push('.'),
+                7 if writable => self.transfer(),
+                8 | 9 if self.counters.len() < self.nesting => {
+                    // Loop bodies return the data pointer to where it started
+                    // so that the counter is decremented on every iteration.
+                    let start = self.cell;
+                    let body_len = self.rng.below(len / 2 + 1) + 1;
+
+                    self.counters.push(start);
+                    self.source.push_str("[-");
+                    self.block(body_len);
+                    self.move_to(start);
+                    self.source.push(']');
+                    self.counters.pop();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Append a loop the parser simplifies into a Set, AddTo or SubFrom.
+    fn transfer(&mut self) {
+        let start = self.cell;
+        let target = self.rng.below(MAX_CELL + 1);
+
+        if target == start {
+            self.source.push_str("[-]");
+            return;
+        }
+
+        if self.counters.contains(&target) {
+            return;
+        }
+
+        self.source.push_str("[-");
+        self.move_to(target);
+        let op = if self.rng.below(2) == 0 { '+' } else { '-' };
+        self.source.push(op);
+        self.move_to(start);
+        self.source.push(']');
+    }
+
+    /// Append the moves that take the data pointer to `target`.
+    fn move_to(&mut self, target: usize) {
+        while self.cell < target {
+            self.source.push('>');
+            self.cell += 1;
+        }
+
+        while self.cell > target {
+            self.source.push('<');
+            self.cell -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Ast;
+
+    #[test]
+    fn same_seed_same_program() {
+        assert_eq!(generate(7, 64, 3), generate(7, 64, 3));
+        assert_ne!(generate(7, 64, 3), generate(8, 64, 3));
+    }
+
+    #[test]
+    fn generates_valid_programs() {
+        for seed in 0..100 {
+            assert!(Ast::parse(&generate(seed, 64, 4)).is_ok());
+        }
+    }
+}
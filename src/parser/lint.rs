@@ -0,0 +1,408 @@
+//! Warnings about source that is valid BrainFuck but probably doesn't do what
+//! its author meant. The AST doesn't keep where each command came from, so
+//! the source is read again here with positions.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::mem;
+
+use super::{drift, pragma, AstNode};
+
+/// A place in the source, with lines and columns counted from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    pub position: Position,
+    pub message: String,
+}
+
+/// A command, or a loop and its body, along with where it starts.
+pub(super) enum Item {
+    Command(char, Position),
+    Loop(Position, Vec<Item>),
+}
+
+impl Item {
+    /// The item as an unoptimized AST node.
+    fn node(&self) -> AstNode {
+        match self {
+            Item::Command('+', _) => AstNode::Incr(1),
+            Item::Command('-', _) => AstNode::Decr(1),
+            Item::Command('>', _) => AstNode::Next(1),
+            Item::Command('<', _) => AstNode::Prev(1),
+            Item::Command('.', _) => AstNode::Print,
+            Item::Command(_, _) => AstNode::Read,
+            Item::Loop(_, body) => AstNode::Loop(body.iter().map(Item::node).collect()),
+        }
+    }
+}
+
+/// Warnings about `source`, in the order they appear in it. Fails if the
+/// brackets in `source` don't match, since nothing else can be said then.
+pub fn lint(source: &str) -> Result<Vec<Warning>, String> {
+    let mut warnings = Vec::new();
+    let items = read(source, &mut warnings)?;
+
+    find_dead_loops(&items, true, &mut warnings);
+    find_unread_cells(&items, &mut warnings);
+    find_runaway_pointers(&items, &mut warnings);
+
+    warnings.sort_by_key(|warning| warning.position);
+    Ok(warnings)
+}
+
+/// Read the commands in `source` into items, for other checks of the source.
+pub(super) fn items(source: &str) -> Result<Vec<Item>, String> {
+    read(source, &mut Vec::new())
+}
+
+/// Read the commands in `source` into items, warning about commands that
+/// look like they're part of a comment.
+fn read(source: &str, warnings: &mut Vec<Warning>) -> Result<Vec<Item>, String> {
+    let (pragmas, body) = pragma::split(source);
+    let chars: Vec<char> = body.chars().collect();
+    let mut position = Position {
+        line: pragmas.matches('\n').count() + 1,
+        column: 1,
+    };
+    let mut items = Vec::new();
+    // Loops still open, with the items from around them.
+    let mut open: Vec<(Position, Vec<Item>)> = Vec::new();
+
+    for (index, &c) in chars.iter().enumerate() {
+        if next_to_text(&chars, index) {
+            let message = match c {
+                '.' => "`.` next to text looks like punctuation in a comment, but prints a cell",
+                ',' => "`,` next to text looks like punctuation in a comment, but reads input",
+                '[' | ']' => "Bracket next to text looks like part of a comment, but is a loop",
+                _ => "",
+            };
+
+            if !message.is_empty() {
+                warnings.push(Warning {
+                    position,
+                    message: message.to_string(),
+                });
+            }
+        }
+
+        match c {
+            '+' | '-' | '>' | '<' | '.' | ',' => items.push(Item::Command(c, position)),
+            '[' => open.push((position, mem::take(&mut items))),
+            ']' => {
+                let (start, outer) = open
+                    .pop()
+                    .ok_or_else(|| format!("More ] than [ at {}", position))?;
+                let body = mem::replace(&mut items, outer);
+                items.push(Item::Loop(start, body));
+            }
+            _ => {}
+        }
+
+        if c == '\n' {
+            position.line += 1;
+            position.column = 1;
+        } else {
+            position.column += 1;
+        }
+    }
+
+    match open.first() {
+        Some((start, _)) => Err(format!("More [ than ] at {}", start)),
+        None => Ok(items),
+    }
+}
+
+/// Whether the character at `index` has a letter or digit right before or
+/// after it.
+fn next_to_text(chars: &[char], index: usize) -> bool {
+    let before = index.checked_sub(1).and_then(|i| chars.get(i));
+    let after = chars.get(index + 1);
+
+    [before, after]
+        .iter()
+        .any(|c| c.is_some_and(|c| c.is_alphanumeric()))
+}
+
+/// Warn about loops that start on a cell that is always zero there, so their
+/// bodies never run. `all_zero` is whether every cell is still zero at the
+/// start of `items`.
+fn find_dead_loops(items: &[Item], mut all_zero: bool, warnings: &mut Vec<Warning>) {
+    let mut current_zero = all_zero;
+
+    for item in items {
+        match item {
+            Item::Command('+', _) | Item::Command('-', _) | Item::Command(',', _) => {
+                all_zero = false;
+                current_zero = false;
+            }
+            Item::Command('>', _) | Item::Command('<', _) => current_zero = all_zero,
+            Item::Command(_, _) => {}
+            Item::Loop(start, _) if current_zero => warnings.push(Warning {
+                position: *start,
+                message: "Loop never runs, since the cell it starts on is always zero".to_string(),
+            }),
+            Item::Loop(_, body) => {
+                // Loop bodies start on a cell that isn't zero.
+                find_dead_loops(body, false, warnings);
+                // Loops only end on a zero cell.
+                current_zero = true;
+            }
+        }
+    }
+}
+
+/// Warn about changes to cells outside of loops that nothing reads before the
+/// program ends.
+fn find_unread_cells(items: &[Item], warnings: &mut Vec<Warning>) {
+    // Cell the data pointer is on, counting from where the last loop ended.
+    let mut cell = 0isize;
+    // Where each changed cell was first changed since it was last read.
+    let mut unread: BTreeMap<isize, Position> = BTreeMap::new();
+
+    for item in items {
+        match item {
+            Item::Command('+', at) | Item::Command('-', at) => {
+                unread.entry(cell).or_insert(*at);
+            }
+            // Some interpreters leave the cell alone at the end of input,
+            // so a change before `,` can be how a program spots it, as in
+            // `-,+`.
+            Item::Command('.', _) | Item::Command(',', _) => {
+                unread.remove(&cell);
+            }
+            Item::Command('>', _) => cell += 1,
+            Item::Command(_, _) => cell -= 1,
+            // Loops could read any cell, and could end anywhere.
+            Item::Loop(_, _) => {
+                unread.clear();
+                cell = 0;
+            }
+        }
+    }
+
+    for at in unread.into_values() {
+        warnings.push(Warning {
+            position: at,
+            message: "Cell changed here is never read".to_string(),
+        });
+    }
+}
+
+/// Warn about the data pointer leaving the tape outside of loops, and about
+/// loops that move in one direction onto fresh cells without ever finding a
+/// zero one, so that they run off the tape once entered.
+fn find_runaway_pointers(items: &[Item], warnings: &mut Vec<Warning>) {
+    // Cell the data pointer is on, counting from the first one, while it is
+    // known.
+    let mut cell = Some(0isize);
+    // Cells that have been changed, while they are known.
+    let mut changed = Some(BTreeSet::new());
+
+    for item in items {
+        match (item, cell) {
+            (_, None) => return,
+            (Item::Command('>', _), Some(at)) => cell = Some(at + 1),
+            (Item::Command('<', position), Some(at)) => {
+                if at == 0 {
+                    warnings.push(Warning {
+                        position: *position,
+                        message: "Moves left of the first cell, which fails unless the tape is \
+                                  bidirectional or wraps"
+                            .to_string(),
+                    });
+                    return;
+                }
+
+                cell = Some(at - 1);
+            }
+            (Item::Command('.', _), _) => {}
+            (Item::Command(_, _), Some(at)) => {
+                if let Some(ref mut changed) = changed {
+                    changed.insert(at);
+                }
+            }
+            (Item::Loop(start, body), Some(at)) => {
+                let stride = drift::drift(&item.node());
+                let pass = Pass::of(body);
+
+                if let (Some(stride), Some(pass), Some(changed)) = (stride, &pass, &changed) {
+                    if let Some(message) = pass.runaway(stride, at, changed) {
+                        warnings.push(Warning {
+                            position: *start,
+                            message: message.to_string(),
+                        });
+                    }
+                }
+
+                match (stride, pass) {
+                    (Some(0), Some(pass)) => {
+                        if let Some(ref mut changed) = changed {
+                            changed.extend(pass.changed.keys().map(|offset| at + offset));
+                        }
+                    }
+                    (Some(0), None) => changed = None,
+                    _ => cell = None,
+                }
+            }
+        }
+    }
+}
+
+/// What one pass through a loop body without loops inside it does.
+struct Pass {
+    /// How much each cell the body changes goes up by, by its offset from
+    /// where the pass started
+    changed: BTreeMap<isize, u8>,
+    /// Whether the body reads input, so that its changes aren't fixed
+    reads: bool,
+}
+
+impl Pass {
+    /// The pass through `body`, or None if it has loops inside it.
+    fn of(body: &[Item]) -> Option<Pass> {
+        let mut pass = Pass {
+            changed: BTreeMap::new(),
+            reads: false,
+        };
+        let mut offset = 0isize;
+
+        for item in body {
+            match item {
+                Item::Command('+', _) => {
+                    let cell = pass.changed.entry(offset).or_insert(0);
+                    *cell = cell.wrapping_add(1);
+                }
+                Item::Command('-', _) => {
+                    let cell = pass.changed.entry(offset).or_insert(0);
+                    *cell = cell.wrapping_sub(1);
+                }
+                Item::Command(',', _) => {
+                    pass.changed.entry(offset).or_insert(0);
+                    pass.reads = true;
+                }
+                Item::Command('>', _) => offset += 1,
+                Item::Command('<', _) => offset -= 1,
+                Item::Command(_, _) => {}
+                Item::Loop(_, _) => return None,
+            }
+        }
+
+        Some(pass)
+    }
+
+    /// If a loop that makes this pass, moving `stride` cells each time,
+    /// never stops once entered at `cell`, why. Cells other than those in
+    /// `changed` are still zero.
+    ///
+    /// Each pass has to land on a cell no pass has changed yet, and leave it
+    /// non-zero. A pass that changes nothing beyond where it lands can't have
+    /// changed where the next pass lands.
+    fn runaway(
+        &self,
+        stride: isize,
+        cell: isize,
+        changed: &BTreeSet<isize>,
+    ) -> Option<&'static str> {
+        let landed = self.changed.get(&stride).copied().unwrap_or(0);
+        if self.reads || stride == 0 || landed == 0 {
+            return None;
+        }
+
+        let (&lowest, &highest) = (self.changed.keys().next()?, self.changed.keys().last()?);
+        let lands = cell + stride;
+
+        if stride > 0 && highest <= stride && changed.iter().last().is_none_or(|&c| c < lands) {
+            Some("Loop moves right onto cells it leaves non-zero, so once entered it runs off the end of the tape")
+        } else if stride < 0 && lowest >= stride && changed.iter().next().is_none_or(|&c| c > lands)
+        {
+            Some("Loop moves left onto cells it leaves non-zero, so once entered it runs off the start of the tape")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(source: &str) -> Vec<(usize, usize, String)> {
+        lint(source)
+            .unwrap()
+            .into_iter()
+            .map(|w| (w.position.line, w.position.column, w.message))
+            .collect()
+    }
+
+    fn positions(source: &str) -> Vec<(usize, usize)> {
+        messages(source)
+            .into_iter()
+            .map(|(line, column, _)| (line, column))
+            .collect()
+    }
+
+    #[test]
+    fn accepts_clean_programs() {
+        assert!(messages("++++++++[>++++++++<-]>+.[-]").is_empty());
+        assert!(messages(",[.,]").is_empty());
+    }
+
+    #[test]
+    fn finds_dead_loops() {
+        assert_eq!(positions("[-]"), vec![(1, 1)]);
+        assert_eq!(positions(">>[-]"), vec![(1, 3)]);
+        assert_eq!(positions("+[-][.]"), vec![(1, 5)]);
+        // Moving off a zero cell after a loop could land anywhere.
+        assert!(positions("+[-]>[.]").is_empty());
+    }
+
+    #[test]
+    fn finds_commands_in_comments() {
+        assert_eq!(positions("+.\nHello, world.\n"), vec![(2, 6), (2, 13)]);
+        assert_eq!(
+            positions("+[-]\nsee foo[bar]"),
+            vec![(2, 8), (2, 8), (2, 12)]
+        );
+    }
+
+    #[test]
+    fn finds_unread_cells() {
+        assert_eq!(positions("+>++<."), vec![(1, 3)]);
+        assert!(positions("-,+.").is_empty());
+        assert!(positions("+>+[<.>-]").is_empty());
+    }
+
+    #[test]
+    fn finds_runaway_pointers() {
+        assert_eq!(positions("+.<"), vec![(1, 3)]);
+        assert_eq!(positions("+[>+]"), vec![(1, 2)]);
+        assert_eq!(positions("+[<+<+]"), vec![(1, 2)]);
+        // Cells to the right were set first, so the loop can stop on them.
+        assert!(positions("+>>>+<<<[>]").is_empty());
+        assert!(positions("+[>]").is_empty());
+        assert!(positions("+[>,]").is_empty());
+    }
+
+    #[test]
+    fn skips_pragmas() {
+        assert_eq!(positions("#!fucker: max-tape=9\n[-]"), vec![(2, 1)]);
+    }
+
+    #[test]
+    fn rejects_unmatched_brackets() {
+        assert_eq!(lint("+\n+]"), Err("More ] than [ at 2:2".to_string()));
+        assert_eq!(lint("[+"), Err("More [ than ] at 1:1".to_string()));
+    }
+}
@@ -1,3 +1,15 @@
 mod ast;
+pub mod bounds;
+pub mod drift;
+pub mod generate;
+pub mod lint;
+pub mod opt_stats;
+pub mod pass;
+pub mod pragma;
+pub mod stream;
+mod superopt;
+pub mod symbolic;
+pub mod tree;
 
 pub use self::ast::{Ast, AstNode};
+pub use self::generate::generate;
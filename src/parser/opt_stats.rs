@@ -0,0 +1,133 @@
+//! What the optimizer did to a program, so that how much it helps a given
+//! program can be seen at a glance.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use super::{Ast, AstNode};
+
+/// Nodes and loops in a program before and after it was optimized.
+#[derive(Debug, Default, PartialEq)]
+pub struct OptStats {
+    /// Nodes as written, one for each command and loop
+    pub nodes_before: usize,
+    /// Nodes left after optimizing, including those inside loops
+    pub nodes_after: usize,
+    pub loops_before: usize,
+    /// Loops left as loops
+    pub loops_after: usize,
+    /// Loops replaced with a node that sets the cell
+    pub set: usize,
+    /// Loops replaced with a node that adds the cell to another
+    pub add_to: usize,
+    /// Loops replaced with a node that subtracts the cell from another
+    pub sub_from: usize,
+    /// Loops removed because they could never run
+    pub removed_loops: usize,
+}
+
+impl OptStats {
+    /// Compare a program as parsed by `Ast::parse_raw` with the same program
+    /// optimized.
+    pub fn new(raw: &VecDeque<AstNode>, optimized: &Ast) -> Self {
+        let mut stats = OptStats {
+            removed_loops: optimized.removed_loops,
+            ..OptStats::default()
+        };
+
+        walk(raw, &mut |node| {
+            stats.nodes_before += 1;
+
+            if let AstNode::Loop(_) = node {
+                stats.loops_before += 1;
+            }
+        });
+
+        walk(&optimized.data, &mut |node| {
+            stats.nodes_after += 1;
+
+            match node {
+                AstNode::Loop(_) => stats.loops_after += 1,
+                // Only loops turn into these.
+                AstNode::Set(_) => stats.set += 1,
+                AstNode::AddTo(_) => stats.add_to += 1,
+                AstNode::SubFrom(_) => stats.sub_from += 1,
+                _ => {}
+            }
+        });
+
+        stats
+    }
+
+    /// Write a summary of what the optimizer did.
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let eliminated = self.nodes_before.saturating_sub(self.nodes_after);
+        let percent = match self.nodes_before {
+            0 => 0.0,
+            before => eliminated as f64 * 100.0 / before as f64,
+        };
+
+        writeln!(
+            out,
+            "{:<14}{:>10} -> {}  ({} eliminated, {:.1}%)",
+            "nodes", self.nodes_before, self.nodes_after, eliminated, percent
+        )?;
+        writeln!(
+            out,
+            "{:<14}{:>10} -> {}",
+            "loops", self.loops_before, self.loops_after
+        )?;
+        writeln!(out, "{:<14}{:>10}", "  to Set", self.set)?;
+        writeln!(out, "{:<14}{:>10}", "  to AddTo", self.add_to)?;
+        writeln!(out, "{:<14}{:>10}", "  to SubFrom", self.sub_from)?;
+        writeln!(
+            out,
+            "{:<14}{:>10}  (could never run)",
+            "  removed", self.removed_loops
+        )
+    }
+}
+
+/// Call `f` on each of `nodes`, and on the nodes inside loops after the loop
+/// itself.
+fn walk<F: FnMut(&AstNode)>(nodes: &VecDeque<AstNode>, f: &mut F) {
+    for node in nodes {
+        f(node);
+
+        if let AstNode::Loop(body) = node {
+            walk(body, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_what_the_optimizer_did() {
+        let source = "[.]+++[-]++>[-<+>]<[->>-<<]>>[-]<<[.-]";
+        let raw = Ast::parse_raw(source).unwrap();
+        let stats = OptStats::new(&raw.data, &raw.clone().optimize());
+
+        assert_eq!(
+            stats,
+            OptStats {
+                nodes_before: 32,
+                nodes_after: 9,
+                loops_before: 6,
+                loops_after: 0,
+                set: 2,
+                add_to: 1,
+                sub_from: 1,
+                removed_loops: 2,
+            }
+        );
+
+        let mut report = Vec::new();
+        stats.write_report(&mut report).unwrap();
+        assert!(String::from_utf8(report)
+            .unwrap()
+            .starts_with("nodes                 32 -> 9  (23 eliminated, 71.9%)\n"));
+    }
+}
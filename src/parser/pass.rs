@@ -0,0 +1,160 @@
+//! Optimization passes over a finished tree. Programs embedding the library
+//! can add their own rewrites to the ones a `StreamParser` makes with
+//! `StreamParser::add_pass`.
+//!
+//! The built-in passes are available one by one, so that a custom pipeline
+//! can run them around its own. `StreamParser` simplifies loops and combines
+//! runs of commands as it reads rather than as passes over the whole tree,
+//! since huge programs never exist as an unoptimized tree, but it uses the
+//! same rewrites as those passes.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::mem;
+use std::ops::AddAssign;
+
+use super::{Ast, AstNode};
+use crate::runnable::CellOverflow;
+
+/// What a pass did.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PassStats {
+    /// Loops removed because they could never run
+    pub removed_loops: usize,
+}
+
+impl AddAssign for PassStats {
+    fn add_assign(&mut self, other: PassStats) {
+        self.removed_loops += other.removed_loops;
+    }
+}
+
+/// A rewrite of a program's tree that leaves what it does unchanged.
+pub trait Pass {
+    /// Name of the pass, for reports.
+    fn name(&self) -> &str;
+
+    /// Rewrite `nodes`.
+    fn run(&mut self, nodes: &mut VecDeque<AstNode>) -> PassStats;
+}
+
+/// Run `passes` on `nodes` in order.
+pub fn run<P: Pass + ?Sized>(passes: &mut [Box<P>], nodes: &mut VecDeque<AstNode>) -> PassStats {
+    let mut stats = PassStats::default();
+
+    for pass in passes {
+        stats += pass.run(nodes);
+    }
+
+    stats
+}
+
+/// The passes `Ast::optimize` makes over a whole tree, in order, for a
+/// program whose cells overflow as `cell_overflow` says.
+pub fn builtin(cell_overflow: CellOverflow) -> Vec<Box<dyn Pass + Send>> {
+    vec![
+        Box::new(SimplifyLoops { cell_overflow }),
+        Box::new(CombineRuns),
+        Box::new(RemoveDeadLoops),
+    ]
+}
+
+/// Replaces loops with shorthands, like `[-]` with `Set(0)`, innermost first,
+/// combining runs of commands in each loop body along the way.
+#[derive(Default)]
+pub struct SimplifyLoops {
+    /// What arithmetic past the range of a cell does. Some shorthands, like
+    /// `Set(0)` for `[+]`, only stand in for their loops when cells wrap.
+    pub cell_overflow: CellOverflow,
+}
+
+impl Pass for SimplifyLoops {
+    fn name(&self) -> &str {
+        "simplify-loops"
+    }
+
+    fn run(&mut self, nodes: &mut VecDeque<AstNode>) -> PassStats {
+        *nodes = Ast::simplify_loops(mem::take(nodes), self.cell_overflow);
+        PassStats::default()
+    }
+}
+
+/// Combines runs of the same command into one node, like `+++` into
+/// `Incr(3)`.
+pub struct CombineRuns;
+
+impl Pass for CombineRuns {
+    fn name(&self) -> &str {
+        "combine-runs"
+    }
+
+    fn run(&mut self, nodes: &mut VecDeque<AstNode>) -> PassStats {
+        *nodes = Ast::combine_consecutive_nodes(nodes);
+        PassStats::default()
+    }
+}
+
+/// Removes loops that start on a cell known to be zero.
+pub struct RemoveDeadLoops;
+
+impl Pass for RemoveDeadLoops {
+    fn name(&self) -> &str {
+        "remove-dead-loops"
+    }
+
+    fn run(&mut self, nodes: &mut VecDeque<AstNode>) -> PassStats {
+        let mut removed_loops = 0;
+        *nodes = Ast::remove_dead_loops(mem::take(nodes), &mut BTreeSet::new(), &mut removed_loops);
+
+        PassStats { removed_loops }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::stream::StreamParser;
+
+    #[test]
+    fn runs_builtin_passes() {
+        let mut nodes = Ast::parse_raw("+[-]>++[<+>-][.]").unwrap().data;
+        let stats = run(&mut builtin(CellOverflow::Wrap), &mut nodes);
+
+        assert_eq!(nodes, Ast::parse("+[-]>++[<+>-][.]").unwrap().data);
+        assert_eq!(stats.removed_loops, 1);
+    }
+
+    /// Appends the program parsed from its source, which it parses as it
+    /// runs.
+    struct Append(&'static str);
+
+    impl Pass for Append {
+        fn name(&self) -> &str {
+            "append"
+        }
+
+        fn run(&mut self, nodes: &mut VecDeque<AstNode>) -> PassStats {
+            nodes.extend(Ast::parse(self.0).unwrap().data);
+            PassStats::default()
+        }
+    }
+
+    #[test]
+    fn runs_added_passes() {
+        let with_pass = || {
+            let mut parser = StreamParser::new();
+            parser.add_pass(Box::new(Append(".")));
+            parser
+        };
+
+        assert_eq!(
+            Ast::parse_with("+", with_pass()).unwrap().data,
+            [AstNode::Incr(1), AstNode::Print]
+        );
+        assert_eq!(
+            Ast::parse_raw("+").unwrap().optimize_with(with_pass()).data,
+            [AstNode::Incr(1), AstNode::Print]
+        );
+        // Other parsers are left as they were.
+        assert_eq!(Ast::parse("+").unwrap().data, [AstNode::Incr(1)]);
+    }
+}
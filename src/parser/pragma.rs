@@ -0,0 +1,78 @@
+//! Settings a program declares for itself in lines like
+//! `#!fucker: max-tape=65536 bounds=wrap` at its very top. The parser skips
+//! these lines, since they may contain BrainFuck commands.
+
+use std::io::{self, BufRead};
+
+const PREFIX: &str = "#!fucker:";
+
+/// Length of the pragma lines at the start of `source`, including their
+/// newlines.
+fn pragmas_len(source: &str) -> usize {
+    source
+        .split_inclusive('\n')
+        .take_while(|line| line.starts_with(PREFIX))
+        .map(str::len)
+        .sum()
+}
+
+/// Split `source` into the pragma lines at its start and the program after
+/// them.
+pub fn split(source: &str) -> (&str, &str) {
+    source.split_at(pragmas_len(source))
+}
+
+/// The program without the pragma lines at its start.
+pub fn body(source: &str) -> &str {
+    split(source).1
+}
+
+/// Read the pragma lines at the start of `reader`, leaving it at the start of
+/// the program after them.
+pub fn read(reader: &mut dyn BufRead) -> io::Result<String> {
+    let mut pragmas = String::new();
+
+    while reader.fill_buf()?.starts_with(PREFIX.as_bytes()) {
+        reader.read_line(&mut pragmas)?;
+    }
+
+    Ok(pragmas)
+}
+
+/// The `key=value` settings from the pragma lines at the start of `source`,
+/// in the order they were given.
+pub fn pragmas(source: &str) -> Result<Vec<(&str, &str)>, String> {
+    source[..pragmas_len(source)]
+        .lines()
+        .flat_map(|line| line[PREFIX.len()..].split_whitespace())
+        .map(|setting| {
+            setting
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value in pragma, found {}", setting))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_leading_pragmas() {
+        let source = "#!fucker: max-tape=10\n#!fucker: bounds=wrap\n+.\n#!fucker: tape=x\n";
+
+        assert_eq!(
+            pragmas(source),
+            Ok(vec![("max-tape", "10"), ("bounds", "wrap")])
+        );
+        assert_eq!(body(source), "+.\n#!fucker: tape=x\n");
+        assert!(pragmas("#!fucker: wrap\n").is_err());
+
+        let mut reader = source.as_bytes();
+        assert_eq!(
+            read(&mut reader).unwrap(),
+            "#!fucker: max-tape=10\n#!fucker: bounds=wrap\n"
+        );
+        assert_eq!(reader, body(source).as_bytes());
+    }
+}
@@ -0,0 +1,390 @@
+//! Parsing programs a piece at a time as they are read, for programs too large
+//! to hold in memory as text. Runs of commands are combined and loops
+//! simplified as soon as they end, so memory use follows the size of the
+//! optimized AST rather than the size of the source.
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read};
+
+use super::pass::{self, Pass, RemoveDeadLoops};
+use super::{Ast, AstNode};
+use crate::runnable::extension::Extensions;
+use crate::runnable::CellOverflow;
+
+/// Bytes read from a source at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A place in the source, for pointing at the cause of a parse error.
+#[derive(Clone, Copy)]
+struct Position {
+    /// Index into the names of the files fed so far, if they were named
+    file: Option<usize>,
+    /// Line, counting from 1
+    line: usize,
+    /// Column in characters, counting from 1
+    column: usize,
+}
+
+/// Builds an AST from source fed to it in pieces.
+pub struct StreamParser {
+    /// Keep a node for every command, as `Ast::parse_raw` does
+    raw: bool,
+    /// Whether memory starts out zeroed, so that loops before any other
+    /// command never run
+    zeroed: bool,
+    /// What arithmetic past the range of a cell does, which decides the
+    /// shorthands loops can be replaced with
+    cell_overflow: CellOverflow,
+    output: VecDeque<AstNode>,
+    /// Bodies of the loops that haven't been closed yet, innermost last
+    loops: Vec<VecDeque<AstNode>>,
+    /// Where each of the open loops started
+    starts: Vec<Position>,
+    position: Position,
+    files: Vec<String>,
+    removed_loops: usize,
+    /// Characters read as extension commands
+    extensions: Vec<u8>,
+    /// Passes run once the program has been parsed, after the built-in ones
+    passes: Vec<Box<dyn Pass + Send>>,
+}
+
+impl StreamParser {
+    /// A parser that optimizes the program the same way `Ast::parse` does.
+    pub fn new() -> Self {
+        StreamParser {
+            raw: false,
+            zeroed: true,
+            cell_overflow: CellOverflow::Wrap,
+            output: VecDeque::new(),
+            loops: Vec::new(),
+            starts: Vec::new(),
+            position: Position {
+                file: None,
+                line: 1,
+                column: 1,
+            },
+            files: Vec::new(),
+            removed_loops: 0,
+            extensions: Vec::new(),
+            passes: Vec::new(),
+        }
+    }
+
+    /// A parser that keeps a node for every command, as `Ast::parse_raw`
+    /// does.
+    pub fn raw() -> Self {
+        StreamParser {
+            raw: true,
+            ..Self::new()
+        }
+    }
+
+    /// A parser that optimizes the program without counting on memory
+    /// starting out zeroed, for programs run on a tape filled beforehand.
+    pub fn preloaded() -> Self {
+        StreamParser {
+            zeroed: false,
+            ..Self::new()
+        }
+    }
+
+    /// Only replace loops with shorthands that do the same as the loop when
+    /// arithmetic carries a cell past 255 or below 0 as `cell_overflow` says.
+    /// Without it cells are taken to wrap.
+    pub fn set_cell_overflow(&mut self, cell_overflow: CellOverflow) {
+        self.cell_overflow = cell_overflow;
+    }
+
+    /// Read the commands in `extensions` as `Extension` nodes rather than as
+    /// comments.
+    pub fn set_extensions(&mut self, extensions: &Extensions) {
+        self.extensions = extensions
+            .commands()
+            .into_iter()
+            .map(|command| command as u8)
+            .collect();
+    }
+
+    /// Run `pass` on the program once it has been parsed, after the built-in
+    /// passes and those added before it. Raw parsers don't run any passes.
+    pub fn add_pass(&mut self, pass: Box<dyn Pass + Send>) {
+        self.passes.push(pass);
+    }
+
+    /// Say that the source fed from now on comes from the file `name`,
+    /// starting on `line`. Errors name the file, line and column of the
+    /// bracket that caused them.
+    pub fn start_file(&mut self, name: &str, line: usize) {
+        self.files.push(name.to_string());
+        self.position = Position {
+            file: Some(self.files.len() - 1),
+            line,
+            column: 1,
+        };
+    }
+
+    /// Parse the next piece of the source.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<(), String> {
+        for &byte in bytes {
+            match byte {
+                b'+' => self.push(AstNode::Incr(1)),
+                b'-' => self.push(AstNode::Decr(1)),
+                b'>' => self.push(AstNode::Next(1)),
+                b'<' => self.push(AstNode::Prev(1)),
+                b'.' => self.push(AstNode::Print),
+                b',' => self.push(AstNode::Read),
+                b'[' => {
+                    self.loops.push(VecDeque::new());
+                    self.starts.push(self.position);
+                }
+                b']' => {
+                    // Example program that will cause this error:
+                    //
+                    // []]
+                    let body = self
+                        .loops
+                        .pop()
+                        .ok_or_else(|| format!("More ] than [{}", self.locate(self.position)))?;
+                    self.starts.pop();
+                    self.close_loop(body);
+                }
+                _ if self.extensions.contains(&byte) => self.push(AstNode::Extension(byte as char)),
+                // All other characters are comments and will be ignored
+                _ => {}
+            }
+
+            self.advance(byte);
+        }
+
+        Ok(())
+    }
+
+    /// Parse everything left in `reader`.
+    pub fn read(&mut self, reader: &mut dyn Read) -> Result<(), String> {
+        let mut chunk = vec![0; CHUNK_SIZE];
+
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(len) => self.feed(&chunk[..len])?,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(format!("Could not read file: {:?}", e)),
+            }
+        }
+    }
+
+    /// The AST of everything fed, once the source has ended.
+    pub fn finish(mut self) -> Result<Ast, String> {
+        if let Some(&start) = self.starts.first() {
+            // Example program that will cause this error:
+            //
+            // [[]
+            return Err(format!("More [ than ]{}", self.locate(start)));
+        }
+
+        if self.raw {
+            return Ok(Ast {
+                data: self.output,
+                removed_loops: 0,
+            });
+        }
+
+        // Loops were simplified and runs combined as they were read, which
+        // leaves the passes that need the whole program.
+        let mut stats = RemoveDeadLoops.run(&mut self.output);
+        stats += pass::run(&mut self.passes, &mut self.output);
+
+        Ok(Ast {
+            data: self.output,
+            removed_loops: self.removed_loops + stats.removed_loops,
+        })
+    }
+
+    /// Optimize a program parsed with `Ast::parse_raw` the way the parser
+    /// would have as it read it.
+    pub(super) fn optimize(mut self, ast: Ast) -> Ast {
+        let mut removed_loops = ast.removed_loops;
+        let mut output = VecDeque::new();
+
+        for node in ast.data {
+            // Do not add loop if it will be the first element in the
+            // output vector. This is because:
+            //
+            // 1. The BrainFuck machine starts all cells at 0
+            // 2. Loops are skipped when the current cell is 0
+            //
+            // So if no non-loops have executed there is no use in
+            // emitting a Loop AstNode.
+            if let (AstNode::Loop(_), true) = (&node, self.zeroed && output.is_empty()) {
+                removed_loops += 1;
+                continue;
+            }
+
+            output.push_back(node);
+        }
+
+        let mut stats = pass::run(&mut pass::builtin(self.cell_overflow), &mut output);
+        stats += pass::run(&mut self.passes, &mut output);
+
+        Ast {
+            data: output,
+            removed_loops: removed_loops + stats.removed_loops,
+        }
+    }
+
+    /// Add a node to the innermost open loop, or to the program if there
+    /// isn't one.
+    fn push(&mut self, node: AstNode) {
+        let nodes = self.loops.last_mut().unwrap_or(&mut self.output);
+
+        if self.raw {
+            nodes.push_back(node);
+        } else {
+            Ast::push_combined(nodes, node);
+        }
+    }
+
+    /// Add a loop that has just ended, simplified if possible.
+    fn close_loop(&mut self, body: VecDeque<AstNode>) {
+        if self.raw {
+            return self.push(AstNode::Loop(body));
+        }
+
+        // Do not add loop if it will be the first element in the output
+        // vector. This is because:
+        //
+        // 1. The BrainFuck machine starts all cells at 0
+        // 2. Loops are skipped when the current cell is 0
+        //
+        // So if no non-loops have executed there is no use in emitting a
+        // Loop AstNode.
+        if self.zeroed && self.loops.is_empty() && self.output.is_empty() {
+            self.removed_loops += 1;
+            return;
+        }
+
+        // The body's own loops were simplified when they ended, and its
+        // commands combined as they arrived.
+        let node = Ast::simplify_loop(&body, self.cell_overflow).unwrap_or(AstNode::Loop(body));
+        self.push(node);
+    }
+
+    /// Move past a byte of the source.
+    fn advance(&mut self, byte: u8) {
+        if byte == b'\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else if byte & 0xc0 != 0x80 {
+            // Continuation bytes are part of the character before them.
+            self.position.column += 1;
+        }
+    }
+
+    /// Describe a position for the end of an error message.
+    fn locate(&self, position: Position) -> String {
+        match position.file {
+            Some(file) => format!(
+                " at {}:{}:{}",
+                self.files[file], position.line, position.column
+            ),
+            None => String::new(),
+        }
+    }
+}
+
+impl Default for StreamParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::generate;
+
+    #[test]
+    fn matches_parsing_all_at_once() {
+        for seed in 0..64 {
+            let source = generate(seed, 256, 4);
+            let mut parser = StreamParser::new();
+
+            // Split the source at awkward places.
+            for piece in source.as_bytes().chunks(7) {
+                parser.feed(piece).unwrap();
+            }
+            let streamed = parser.finish().unwrap();
+            let parsed = Ast::parse_raw(&source).unwrap().optimize();
+
+            assert_eq!(streamed.data, parsed.data);
+            assert_eq!(streamed.removed_loops, parsed.removed_loops);
+        }
+    }
+
+    #[test]
+    fn locates_unmatched_brackets() {
+        let mut parser = StreamParser::new();
+        parser.start_file("a.bf", 2);
+        assert_eq!(
+            parser.feed("+\n é[]]".as_bytes()),
+            Err("More ] than [ at a.bf:3:5".to_string())
+        );
+
+        let mut parser = StreamParser::new();
+        parser.start_file("a.bf", 1);
+        parser.feed(b"+[[").unwrap();
+        parser.start_file("b.bf", 1);
+        parser.feed(b"]").unwrap();
+        assert_eq!(
+            parser.finish().err().as_deref(),
+            Some("More [ than ] at a.bf:1:2")
+        );
+        assert_eq!(Ast::parse("+[]]").err().as_deref(), Some("More ] than ["));
+    }
+
+    #[test]
+    fn keeps_leading_loops_for_preloaded_tapes() {
+        let mut parser = StreamParser::preloaded();
+        parser.feed(b"[.>][-]").unwrap();
+        let ast = parser.finish().unwrap();
+
+        // The loop after it still starts on a zero cell.
+        assert_eq!(
+            ast.data,
+            [AstNode::Loop(VecDeque::from([
+                AstNode::Print,
+                AstNode::Next(1)
+            ]))]
+        );
+        assert_eq!(ast.removed_loops, 1);
+        assert!(Ast::parse("[.>][-]").unwrap().data.is_empty());
+    }
+
+    #[test]
+    fn parses_extension_commands() {
+        assert_eq!(
+            Ast::parse("+[-]@[-]").unwrap().data,
+            [AstNode::Incr(1), AstNode::Set(0)]
+        );
+
+        let mut extensions = Extensions::new();
+        extensions.add('@', |_: &mut u8| Ok(())).unwrap();
+        let mut parser = StreamParser::new();
+        parser.set_extensions(&extensions);
+
+        // The extension may leave the cell non-zero, so the loop after it
+        // stays.
+        let ast = Ast::parse_with("+[-]@[-]", parser).unwrap();
+        assert_eq!(
+            ast.data,
+            [
+                AstNode::Incr(1),
+                AstNode::Set(0),
+                AstNode::Extension('@'),
+                AstNode::Set(0)
+            ]
+        );
+        assert_eq!(ast.data[2].to_string(), "@");
+    }
+}
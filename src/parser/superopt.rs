@@ -0,0 +1,213 @@
+//! A superoptimizer for small loops. Rather than recognizing loops by their
+//! shape, like `Ast::simplify_loop` does, it tries each shorthand that could
+//! stand in for a loop and keeps the first one the interpreter shows to do
+//! the same thing. That finds shorthands for loops written in an order the
+//! patterns don't expect, like `[>+<-]`, and for some they don't cover at
+//! all, like `[---]`.
+//!
+//! Only loops whose bodies move and change cells and end where they started
+//! are searched. Every pass through one of those adds the same amounts to the
+//! same cells, so memory after the loop is memory before it plus a multiple
+//! of the number of passes, which depends only on the current cell. The same
+//! goes for every shorthand, so trying a few values of the current cell
+//! against a couple of backgrounds for the others is enough to show that two
+//! of them always agree.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+
+use super::AstNode;
+use crate::runnable::interpreter::Fucker;
+use crate::runnable::Instrumentation;
+
+/// Longest loop body, in nodes, that is searched for a shorthand.
+const MAX_BODY: usize = 8;
+/// Values of the current cell loops are tried with.
+const VALUES: [u8; 7] = [0, 1, 2, 3, 127, 128, 255];
+
+thread_local! {
+    /// Shorthands found for loop bodies, or None for bodies without one.
+    static FOUND: RefCell<HashMap<VecDeque<AstNode>, Option<AstNode>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// A shorthand that does the same as a loop with `body`, if the search finds
+/// one.
+pub fn simplify(body: &VecDeque<AstNode>) -> Option<AstNode> {
+    let (lowest, highest) = reach(body)?;
+
+    if let Some(found) = FOUND.with(|found| found.borrow().get(body).cloned()) {
+        return found;
+    }
+
+    let shorthand = search(body, lowest, highest);
+    FOUND.with(|found| found.borrow_mut().insert(body.clone(), shorthand.clone()));
+    shorthand
+}
+
+/// The lowest and highest offsets `body` moves to, if it is short enough to
+/// search, only moves and changes cells, ends where it started, and could
+/// match a shorthand at all. Every shorthand finishes whatever the current
+/// cell holds, which takes an odd change to it each pass, and changes at most
+/// one other cell.
+fn reach(body: &VecDeque<AstNode>) -> Option<(isize, isize)> {
+    if body.len() > MAX_BODY {
+        return None;
+    }
+
+    let mut offset = 0isize;
+    let (mut lowest, mut highest) = (0, 0);
+    let mut changes: HashMap<isize, u8> = HashMap::new();
+
+    for node in body {
+        match node {
+            AstNode::Incr(n) => {
+                let change = changes.entry(offset).or_insert(0);
+                *change = change.wrapping_add(*n);
+            }
+            AstNode::Decr(n) => {
+                let change = changes.entry(offset).or_insert(0);
+                *change = change.wrapping_sub(*n);
+            }
+            AstNode::Next(n) => offset += *n as isize,
+            AstNode::Prev(n) => offset -= *n as isize,
+            _ => return None,
+        }
+
+        lowest = lowest.min(offset);
+        highest = highest.max(offset);
+    }
+
+    let odd = changes.get(&0).is_some_and(|change| change % 2 == 1);
+    let others = changes
+        .iter()
+        .filter(|&(&offset, &change)| offset != 0 && change != 0)
+        .count();
+
+    if offset == 0 && odd && others <= 1 {
+        Some((lowest, highest))
+    } else {
+        None
+    }
+}
+
+/// The first shorthand that leaves memory the same as a loop with `body` for
+/// every test tape.
+fn search(body: &VecDeque<AstNode>, lowest: isize, highest: isize) -> Option<AstNode> {
+    let mut oracle = Oracle::new(lowest, highest, body.len());
+    let tapes = oracle.tapes();
+
+    let expected = tapes
+        .iter()
+        .map(|tape| oracle.run(AstNode::Loop(body.clone()), tape))
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut candidates = vec![AstNode::Set(0)];
+    for offset in (lowest..=highest).filter(|&offset| offset != 0) {
+        candidates.push(AstNode::AddTo(offset));
+        candidates.push(AstNode::SubFrom(offset));
+    }
+
+    candidates.into_iter().find(|candidate| {
+        tapes
+            .iter()
+            .zip(&expected)
+            .all(|(tape, expected)| oracle.run(candidate.clone(), tape).as_ref() == Some(expected))
+    })
+}
+
+/// Runs single nodes in the interpreter, on tapes just wide enough for the
+/// cells a loop body reaches.
+struct Oracle {
+    interpreter: Fucker,
+    /// Index in the tape of the cell loops start on
+    origin: usize,
+    width: usize,
+    /// Instructions a loop may take before it's taken to never finish
+    budget: usize,
+}
+
+impl Oracle {
+    fn new(lowest: isize, highest: isize, body_len: usize) -> Self {
+        Oracle {
+            interpreter: Fucker::new(
+                VecDeque::new(),
+                Box::new(io::empty()),
+                Box::new(io::sink()),
+                Instrumentation::default(),
+            ),
+            origin: -lowest as usize,
+            width: (highest - lowest + 1) as usize,
+            // Odd changes to the current cell bring it to zero within 256
+            // passes.
+            budget: 256 * (body_len + 1) + 1,
+        }
+    }
+
+    /// Each of `VALUES` in the starting cell, with the cells around it all
+    /// zero and then all different.
+    fn tapes(&self) -> Vec<Vec<u8>> {
+        let backgrounds = [
+            vec![0; self.width],
+            (0..self.width).map(|i| (i * 37 + 11) as u8).collect(),
+        ];
+
+        backgrounds
+            .iter()
+            .flat_map(|background| {
+                VALUES.iter().map(move |&value| {
+                    let mut tape = background.clone();
+                    tape[self.origin] = value;
+                    tape
+                })
+            })
+            .collect()
+    }
+
+    /// Memory after running `node` on `tape`, or None if it didn't finish
+    /// within the budget.
+    fn run(&mut self, node: AstNode, tape: &[u8]) -> Option<Vec<u8>> {
+        self.interpreter.load(VecDeque::from(vec![node]));
+        self.interpreter.set_tape(tape.to_vec(), self.origin);
+
+        for _ in 0..self.budget {
+            if !self.interpreter.step() {
+                return match self.interpreter.take_error() {
+                    Some(_) => None,
+                    None => Some(self.interpreter.memory().to_vec()),
+                };
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Ast;
+
+    fn shorthand(source: &str) -> Option<AstNode> {
+        match Ast::parse_raw(source).unwrap().data.pop_front() {
+            Some(AstNode::Loop(body)) => simplify(&body),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn finds_shorthands_patterns_miss() {
+        assert_eq!(shorthand("[>+<-]"), Some(AstNode::AddTo(1)));
+        assert_eq!(shorthand("[+<<->>]"), Some(AstNode::AddTo(-2)));
+        assert_eq!(shorthand("[---]"), Some(AstNode::Set(0)));
+    }
+
+    #[test]
+    fn rejects_loops_without_shorthands() {
+        assert_eq!(shorthand("[--]"), None);
+        assert_eq!(shorthand("[->+>+<<]"), None);
+        assert_eq!(shorthand("[->+]"), None);
+        assert_eq!(shorthand("[-.]"), None);
+    }
+}
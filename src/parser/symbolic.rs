@@ -0,0 +1,438 @@
+//! Symbolic execution, for finding input that makes a program fail. Each
+//! byte of input starts out unknown, and whenever a loop tests a cell that
+//! depends on one, both ways the test could go are followed, narrowing down
+//! what the byte must be along each. BrainFuck only ever changes a cell by a
+//! constant, so a cell that depends on input is always a single input byte
+//! plus some amount.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::hash::{Hash, Hasher};
+
+use super::lint::{self, Item, Position};
+use crate::runnable::{TapeMode, LEFT_TAPE_SIZE, WRAPPED_TAPE_SIZE};
+
+/// Paths followed before the rest are given up on.
+pub const MAX_PATHS: usize = 4096;
+
+/// A runtime error some input leads to.
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub position: Position,
+    pub message: String,
+    /// Input that makes the program fail this way
+    pub input: Vec<u8>,
+}
+
+/// What exploring a program found.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Errors in the order they appear in the source, with the first input
+    /// found for each
+    pub findings: Vec<Finding>,
+    /// Paths followed
+    pub paths: usize,
+    /// Whether paths were left unexplored after following `MAX_PATHS`
+    pub truncated: bool,
+}
+
+/// Follow every path through `source` for up to `depth` steps each, reading
+/// unknown input, on a tape of the given kind. Memory starts zeroed and input
+/// never runs out.
+pub fn explore(
+    source: &str,
+    mode: TapeMode,
+    limit: Option<usize>,
+    depth: usize,
+) -> Result<Report, String> {
+    let explorer = Explorer {
+        program: Program::new(&lint::items(source)?),
+        mode,
+        limit,
+        depth,
+    };
+    let mut report = Report::default();
+    let mut pending = vec![State::default()];
+
+    while let Some(mut state) = pending.pop() {
+        if report.paths == MAX_PATHS {
+            report.truncated = true;
+            break;
+        }
+        report.paths += 1;
+
+        if let Some((position, message)) = explorer.follow(&mut state, &mut pending) {
+            let seen = report
+                .findings
+                .iter()
+                .any(|finding| finding.position == position && finding.message == message);
+
+            if !seen {
+                report.findings.push(Finding {
+                    position,
+                    message,
+                    input: state.example_input(),
+                });
+            }
+        }
+    }
+
+    report.findings.sort_by_key(|finding| finding.position);
+    Ok(report)
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Incr,
+    Decr,
+    Next,
+    Prev,
+    Print,
+    Read,
+    /// Start of a loop, with the index of its end
+    Open(usize),
+    /// End of a loop, with the index of its start
+    Close(usize),
+}
+
+/// A program as a flat list of commands, for stepping through.
+struct Program {
+    ops: Vec<Op>,
+    /// Where each command is in the source. Ends of loops share their
+    /// start's position.
+    positions: Vec<Position>,
+    /// Start of the innermost loop each command is in
+    loops: Vec<Option<usize>>,
+}
+
+impl Program {
+    fn new(items: &[Item]) -> Self {
+        let mut program = Program {
+            ops: Vec::new(),
+            positions: Vec::new(),
+            loops: Vec::new(),
+        };
+        program.flatten(items, None);
+        program
+    }
+
+    fn flatten(&mut self, items: &[Item], enclosing: Option<usize>) {
+        for item in items {
+            match item {
+                Item::Command(c, at) => {
+                    let op = match c {
+                        '+' => Op::Incr,
+                        '-' => Op::Decr,
+                        '>' => Op::Next,
+                        '<' => Op::Prev,
+                        '.' => Op::Print,
+                        _ => Op::Read,
+                    };
+                    self.push(op, *at, enclosing);
+                }
+                Item::Loop(at, body) => {
+                    let open = self.ops.len();
+                    self.push(Op::Open(0), *at, enclosing);
+                    self.flatten(body, Some(open));
+                    self.ops[open] = Op::Open(self.ops.len());
+                    self.push(Op::Close(open), *at, enclosing);
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, op: Op, position: Position, enclosing: Option<usize>) {
+        self.ops.push(op);
+        self.positions.push(position);
+        self.loops.push(enclosing);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum Value {
+    Known(u8),
+    /// The byte read by the nth `,`, plus an amount
+    Input(usize, u8),
+}
+
+impl Value {
+    fn plus(self, n: u8) -> Value {
+        match self {
+            Value::Known(value) => Value::Known(value.wrapping_add(n)),
+            Value::Input(index, plus) => Value::Input(index, plus.wrapping_add(n)),
+        }
+    }
+}
+
+/// What a path has learned about a byte of input.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+enum Byte {
+    Fixed(u8),
+    /// Anything but these
+    Except(BTreeSet<u8>),
+}
+
+/// Where a path has got to.
+#[derive(Clone, Default)]
+struct State {
+    ip: usize,
+    dp: isize,
+    /// Cells that aren't known to be zero
+    cells: BTreeMap<isize, Value>,
+    input: Vec<Byte>,
+    steps: usize,
+    /// Steps taken when input was last read
+    last_read: usize,
+    /// Fingerprints of the state each time a loop went around again
+    seen: HashSet<u64>,
+}
+
+impl State {
+    /// The current cell, in terms of known values where possible.
+    fn cell(&self) -> Value {
+        match self.cells.get(&self.dp) {
+            Some(&Value::Input(index, plus)) => match self.input[index] {
+                Byte::Fixed(byte) => Value::Known(byte.wrapping_add(plus)),
+                Byte::Except(_) => Value::Input(index, plus),
+            },
+            Some(&value) => value,
+            None => Value::Known(0),
+        }
+    }
+
+    fn set_cell(&mut self, value: Value) {
+        if value == Value::Known(0) {
+            self.cells.remove(&self.dp);
+        } else {
+            self.cells.insert(self.dp, value);
+        }
+    }
+
+    /// Rule out `byte` as the value of the input at `index`.
+    fn exclude(&mut self, index: usize, byte: u8) {
+        if let Byte::Except(ref mut except) = self.input[index] {
+            except.insert(byte);
+
+            if except.len() == 255 {
+                let last = (0..=255).find(|b| !except.contains(b)).unwrap_or(0);
+                self.input[index] = Byte::Fixed(last);
+            }
+        }
+    }
+
+    /// Whether the current cell is zero along this path. If that depends on
+    /// input, this path takes it to be zero and the path where it isn't is
+    /// added to `pending`.
+    fn is_zero(&mut self, pending: &mut Vec<State>) -> bool {
+        match self.cell() {
+            Value::Known(value) => value == 0,
+            Value::Input(index, plus) => {
+                let byte = plus.wrapping_neg();
+                if let Byte::Except(ref except) = self.input[index] {
+                    if except.contains(&byte) {
+                        return false;
+                    }
+                }
+
+                let mut other = self.clone();
+                other.exclude(index, byte);
+                pending.push(other);
+
+                self.input[index] = Byte::Fixed(byte);
+                true
+            }
+        }
+    }
+
+    /// Everything that decides where this path goes from here.
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (self.ip, self.dp, &self.cells, &self.input).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Input that takes a program down this path, using the lowest byte each
+    /// unknown may be.
+    fn example_input(&self) -> Vec<u8> {
+        self.input
+            .iter()
+            .map(|byte| match byte {
+                Byte::Fixed(byte) => *byte,
+                Byte::Except(except) => (0..=255).find(|b| !except.contains(b)).unwrap_or(0),
+            })
+            .collect()
+    }
+}
+
+struct Explorer {
+    program: Program,
+    mode: TapeMode,
+    limit: Option<usize>,
+    depth: usize,
+}
+
+impl Explorer {
+    /// Step through a path until it ends, returning the error it ends on if
+    /// any. Paths that split off are added to `pending`.
+    fn follow(&self, state: &mut State, pending: &mut Vec<State>) -> Option<(Position, String)> {
+        let ops = &self.program.ops;
+
+        while state.ip < ops.len() {
+            if state.steps == self.depth {
+                return self.stuck(state);
+            }
+            state.steps += 1;
+
+            let position = self.program.positions[state.ip];
+            match ops[state.ip] {
+                Op::Incr => state.set_cell(state.cell().plus(1)),
+                Op::Decr => state.set_cell(state.cell().plus(255)),
+                Op::Next => {
+                    state.dp += 1;
+                    if let Some(error) = self.check_pointer(state) {
+                        return Some((position, error));
+                    }
+                }
+                Op::Prev => {
+                    state.dp -= 1;
+                    if let Some(error) = self.check_pointer(state) {
+                        return Some((position, error));
+                    }
+                }
+                Op::Print => {}
+                Op::Read => {
+                    state.input.push(Byte::Except(BTreeSet::new()));
+                    state.set_cell(Value::Input(state.input.len() - 1, 0));
+                    state.last_read = state.steps;
+                }
+                Op::Open(end) => {
+                    if state.is_zero(pending) {
+                        state.ip = end;
+                    }
+                }
+                Op::Close(start) => {
+                    if !state.is_zero(pending) {
+                        state.ip = start;
+
+                        if !state.seen.insert(state.fingerprint()) {
+                            return Some((
+                                position,
+                                "Loop comes back around to the same state, so it never finishes"
+                                    .to_string(),
+                            ));
+                        }
+                    }
+                }
+            }
+
+            state.ip += 1;
+        }
+
+        None
+    }
+
+    /// The error for the data pointer leaving the tape, wrapping it around
+    /// instead if the tape wraps.
+    fn check_pointer(&self, state: &mut State) -> Option<String> {
+        match self.mode {
+            TapeMode::Wrapped => {
+                state.dp = state.dp.rem_euclid(WRAPPED_TAPE_SIZE as isize);
+                None
+            }
+            TapeMode::Standard if state.dp < 0 => Some("Moves left of the first cell".to_string()),
+            TapeMode::Bidirectional if state.dp < -(LEFT_TAPE_SIZE as isize) => Some(format!(
+                "Moves more than {} cells left of where it starts",
+                LEFT_TAPE_SIZE
+            )),
+            _ => match self.limit {
+                Some(limit) if state.dp >= limit as isize => {
+                    Some(format!("Exceeds the tape limit of {} cells", limit))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// The suspicion that a path which ran out of steps never finishes. Paths
+    /// that read input recently are waiting on more of it rather than stuck.
+    fn stuck(&self, state: &State) -> Option<(Position, String)> {
+        if state.steps - state.last_read < self.depth / 2 {
+            return None;
+        }
+
+        let at = self.program.loops[state.ip].unwrap_or(state.ip);
+        Some((
+            self.program.positions[at],
+            format!(
+                "Still running after {} steps, so it may never finish",
+                self.depth
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn findings(source: &str) -> Vec<(usize, usize, String, Vec<u8>)> {
+        explore(source, TapeMode::Standard, None, 1000)
+            .unwrap()
+            .findings
+            .into_iter()
+            .map(|f| (f.position.line, f.position.column, f.message, f.input))
+            .collect()
+    }
+
+    #[test]
+    fn finds_input_that_leaves_the_tape() {
+        // The flag in the second cell is only left set by an "a".
+        let source = format!(",{}>+<[[-]>-<]>[<<]", "-".repeat(97));
+        assert_eq!(
+            findings(&source),
+            vec![(
+                1,
+                113,
+                "Moves left of the first cell".to_string(),
+                b"a".to_vec()
+            )]
+        );
+    }
+
+    #[test]
+    fn finds_loops_that_never_finish() {
+        assert_eq!(
+            findings(",--[+-]"),
+            vec![(
+                1,
+                4,
+                "Loop comes back around to the same state, so it never finishes".to_string(),
+                vec![0],
+            )]
+        );
+        assert_eq!(
+            findings("+[>+]")[0].2,
+            "Still running after 1000 steps, so it may never finish"
+        );
+    }
+
+    #[test]
+    fn passes_programs_without_errors() {
+        let report = explore(",[.,]", TapeMode::Standard, None, 1000).unwrap();
+        assert!(report.findings.is_empty());
+        assert!(report.paths > 1);
+
+        assert!(findings(",[-]<").len() == 1);
+        assert!(explore("+<", TapeMode::Bidirectional, None, 100)
+            .unwrap()
+            .findings
+            .is_empty());
+        assert_eq!(
+            explore("+>>", TapeMode::Standard, Some(2), 100)
+                .unwrap()
+                .findings[0]
+                .message,
+            "Exceeds the tape limit of 2 cells"
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Renders an AST as an indented tree with one node per line, which is far
+//! easier to follow than its `Debug` output once a program has a few loops.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+use super::{drift, AstNode};
+
+/// Spaces per level of loop nesting.
+const INDENT: usize = 2;
+
+/// ANSI color for each kind of node, grouped by what the node touches.
+fn color(node: &AstNode) -> &'static str {
+    match node {
+        AstNode::Incr(_) | AstNode::Decr(_) | AstNode::Set(_) => "32",
+        AstNode::Next(_) | AstNode::Prev(_) => "34",
+        AstNode::Print | AstNode::Read | AstNode::Extension(_) => "33",
+        AstNode::AddTo(_) | AstNode::SubFrom(_) => "35",
+        AstNode::Loop(_) => "36;1",
+    }
+}
+
+/// Number of nodes in `nodes`, including those inside loops.
+fn count(nodes: &VecDeque<AstNode>) -> usize {
+    nodes
+        .iter()
+        .map(|node| match node {
+            AstNode::Loop(body) => 1 + count(body),
+            _ => 1,
+        })
+        .sum()
+}
+
+/// How far each pass through a loop moves the data pointer, in words.
+fn describe_drift(node: &AstNode) -> String {
+    match drift::drift(node) {
+        Some(0) => "balanced".to_string(),
+        Some(stride) => format!("drift {:+}", stride),
+        None => "drift varies".to_string(),
+    }
+}
+
+/// Render `nodes` as a tree, coloring node names with ANSI escapes if `color`
+/// is set.
+pub fn render(nodes: &VecDeque<AstNode>, color: bool) -> String {
+    let mut out = format!("Program ({} nodes)\n", count(nodes));
+    write_nodes(&mut out, nodes, 1, color);
+    out
+}
+
+fn write_nodes(out: &mut String, nodes: &VecDeque<AstNode>, depth: usize, use_color: bool) {
+    for node in nodes {
+        let name = node.name();
+        let details = match node {
+            AstNode::Loop(body) => format!(" ({} nodes, {})", count(body), describe_drift(node)),
+            // Debug output is the name followed by any operand.
+            _ => format!("{:?}", node)[name.len()..].to_string(),
+        };
+
+        let _ = write!(out, "{:width$}", "", width = depth * INDENT);
+        let _ = if use_color {
+            writeln!(out, "\x1b[{}m{}\x1b[0m{}", color(node), name, details)
+        } else {
+            writeln!(out, "{}{}", name, details)
+        };
+
+        if let AstNode::Loop(body) = node {
+            write_nodes(out, body, depth + 1, use_color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Ast;
+
+    #[test]
+    fn renders_nested_loops() {
+        let ast = Ast::parse("++[>+[-.>]<-]").unwrap();
+
+        assert_eq!(
+            render(&ast.data, false),
+            "Program (10 nodes)\n\
+             \x20 Incr(2)\n\
+             \x20 Loop (8 nodes, drift varies)\n\
+             \x20   Next(1)\n\
+             \x20   Incr(1)\n\
+             \x20   Loop (3 nodes, drift +1)\n\
+             \x20     Decr(1)\n\
+             \x20     Print\n\
+             \x20     Next(1)\n\
+             \x20   Prev(1)\n\
+             \x20   Decr(1)\n"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::VecDeque;
+use std::io::{self, stdin, stdout, Read, Write};
+use std::mem;
+use std::thread;
+
+use crate::parser::{Ast, AstNode};
+use crate::runnable::channel::channel;
+#[cfg(feature = "tokio")]
+use crate::runnable::interpreter::{async_io, Fucker};
+use crate::runnable::Instrumentation;
+use crate::{build_runnable, read_program, JitOptions, TapeOptions};
+
+/// Run programs side by side, with each program's output feeding the next
+/// program's input. The first program reads stdin and the last writes stdout.
+pub fn run(programs: &[String], interpret: bool) -> Result<(), String> {
+    // Load everything up front so that a bad program is reported before any
+    // of them start.
+    let mut asts = Vec::new();
+    for program in programs {
+        let ast = read_program(program)
+            .and_then(|source| Ast::parse(&source))
+            .map_err(|e| format!("Error occurred while loading {}: {}", program, e))?;
+        asts.push(ast);
+    }
+
+    #[cfg(feature = "tokio")]
+    {
+        if interpret {
+            return run_tasks(programs, asts);
+        }
+    }
+
+    let last = asts.len() - 1;
+    let mut io_read: Box<dyn Read + Send> = Box::new(stdin());
+    let mut threads = Vec::new();
+
+    for (index, ast) in asts.into_iter().enumerate() {
+        let (io_write, next_read): (Box<dyn Write + Send>, Box<dyn Read + Send>) = if index == last
+        {
+            (Box::new(stdout()), Box::new(io::empty()))
+        } else {
+            let (writer, reader) = channel();
+            (Box::new(writer), Box::new(reader))
+        };
+        let program_read = mem::replace(&mut io_read, next_read);
+
+        threads.push(spawn(ast.data, program_read, io_write, interpret)?);
+    }
+
+    for (program, thread) in programs.iter().zip(threads) {
+        thread
+            .join()
+            .map_err(|_| format!("{} crashed", program))?
+            .map_err(|e| format!("Error occurred while running {}: {}", program, e))?;
+    }
+
+    stdout().flush().ok();
+
+    Ok(())
+}
+
+/// Start running a program on its own thread. Each backend holds data that
+/// can't be shared between threads, so it is created on the thread that runs
+/// it.
+fn spawn(
+    nodes: VecDeque<AstNode>,
+    io_read: Box<dyn Read + Send>,
+    io_write: Box<dyn Write + Send>,
+    interpret: bool,
+) -> Result<thread::JoinHandle<Result<(), String>>, String> {
+    thread::Builder::new()
+        .spawn(move || {
+            let mut runnable = build_runnable(
+                interpret,
+                JitOptions::default(),
+                TapeOptions::default(),
+                nodes,
+                io_read,
+                io_write,
+                Instrumentation::default(),
+            )?;
+            runnable.run()?;
+
+            Ok(())
+        })
+        .map_err(|e| format!("Could not start a thread: {}", e))
+}
+
+/// Run interpreted programs as tasks on a single thread, connected by
+/// in-memory pipes, rather than giving each one a thread of its own.
+#[cfg(feature = "tokio")]
+fn run_tasks(programs: &[String], asts: Vec<Ast>) -> Result<(), String> {
+    use crate::runnable::channel::PIPE_CAPACITY;
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::runtime::Builder;
+    use tokio::task::LocalSet;
+
+    let runtime = Builder::new_current_thread()
+        .build()
+        .map_err(|e| format!("Could not start runtime: {}", e))?;
+    let local = LocalSet::new();
+
+    let last = asts.len() - 1;
+    let mut io_read: Box<dyn AsyncRead + Unpin> = Box::new(tokio::io::stdin());
+    let mut tasks = Vec::new();
+
+    for (index, ast) in asts.into_iter().enumerate() {
+        let (io_write, next_read): (Box<dyn AsyncWrite + Unpin>, Box<dyn AsyncRead + Unpin>) =
+            if index == last {
+                (Box::new(tokio::io::stdout()), Box::new(tokio::io::empty()))
+            } else {
+                let (writer, reader) = tokio::io::duplex(PIPE_CAPACITY);
+                (Box::new(writer), Box::new(reader))
+            };
+        let program_read = mem::replace(&mut io_read, next_read);
+        let fucker = Fucker::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+
+        tasks.push(local.spawn_local(async_io::run(fucker, program_read, io_write)));
+    }
+
+    for (program, task) in programs.iter().zip(tasks) {
+        local
+            .block_on(&runtime, task)
+            .map_err(|_| format!("{} crashed", program))?
+            .map_err(|e| format!("Error occurred while running {}: {}", program, e))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Bindings for running programs from Python, for tools such as graders that
+//! would otherwise shell out to the binary. Programs always run under the
+//! interpreter.
+
+use std::io::Cursor;
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::parser::Ast;
+use crate::runnable::interpreter::Fucker;
+use crate::runnable::shared_buffer::SharedBuffer;
+use crate::runnable::{Instrumentation, Runnable};
+
+fn load(source: &str, input: Option<&[u8]>, output: SharedBuffer) -> PyResult<Fucker> {
+    let ast = Ast::parse(source).map_err(PyValueError::new_err)?;
+
+    Ok(Fucker::new(
+        ast.data,
+        Box::new(Cursor::new(input.unwrap_or_default().to_vec())),
+        Box::new(output),
+        Instrumentation::default(),
+    ))
+}
+
+/// Parse a program, returning its intermediate language.
+#[pyfunction]
+fn parse(source: &str) -> PyResult<String> {
+    let ast = Ast::parse(source).map_err(PyValueError::new_err)?;
+
+    Ok(format!("{:?}", ast))
+}
+
+/// Run a program to completion on `input`, returning everything it printed.
+#[pyfunction]
+#[pyo3(signature = (source, input = None))]
+fn run<'py>(py: Python<'py>, source: &str, input: Option<&[u8]>) -> PyResult<Bound<'py, PyBytes>> {
+    let output = SharedBuffer::new();
+    let mut fucker = load(source, input, output.clone())?;
+
+    fucker.run().map_err(PyRuntimeError::new_err)?;
+
+    Ok(PyBytes::new(py, &output.contents()))
+}
+
+/// A program that runs a step at a time. Iterating over it runs the program,
+/// yielding its output as it is printed.
+#[pyclass(unsendable)]
+struct Execution {
+    fucker: Fucker,
+    output: SharedBuffer,
+}
+
+#[pymethods]
+impl Execution {
+    #[new]
+    #[pyo3(signature = (source, input = None))]
+    fn new(source: &str, input: Option<&[u8]>) -> PyResult<Self> {
+        let output = SharedBuffer::new();
+        let fucker = load(source, input, output.clone())?;
+
+        Ok(Execution { fucker, output })
+    }
+
+    /// Run one instruction, returning whether the program is still running.
+    fn step(&mut self) -> PyResult<bool> {
+        if self.fucker.step() {
+            return Ok(true);
+        }
+
+        match self.fucker.take_error() {
+            Some(e) => Err(PyRuntimeError::new_err(e)),
+            None => Ok(false),
+        }
+    }
+
+    /// Output printed since it was last read.
+    fn read_output<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.output.take())
+    }
+
+    #[getter]
+    fn memory<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.fucker.memory())
+    }
+
+    #[getter]
+    fn dp(&self) -> usize {
+        self.fucker.dp()
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        while self.step()? {
+            let output = self.output.take();
+
+            if !output.is_empty() {
+                return Ok(Some(PyBytes::new(py, &output)));
+            }
+        }
+
+        let output = self.output.take();
+        Ok(if output.is_empty() {
+            None
+        } else {
+            Some(PyBytes::new(py, &output))
+        })
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "fucker")]
+fn python_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(self::parse, m)?)?;
+    m.add_function(wrap_pyfunction!(self::run, m)?)?;
+    m.add_class::<Execution>()?;
+
+    Ok(())
+}
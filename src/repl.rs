@@ -0,0 +1,186 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{self, stdin, stdout, BufRead, Read, Write};
+use std::rc::Rc;
+
+use crate::parser::{tree, Ast};
+use crate::runnable::hooks::{on_input, on_output};
+use crate::runnable::interpreter::Fucker;
+use crate::runnable::{Instrumentation, Runnable};
+use crate::{read_program, use_color};
+
+/// Number of cells shown by `:dump`.
+const DUMP_CELLS: usize = 16;
+
+const HELP: &str = "\
+Enter BrainFuck to run it on the current tape, or one of:
+  :dump          Show the cells around the data pointer.
+  :reset         Clear the tape and move the data pointer back to 0.
+  :load <file>   Run a program from a file on the current tape.
+  :ast           Show the intermediate language of the last program run.
+  :help          Show this message.
+  :quit          Exit.";
+
+/// Read BrainFuck from stdin a line at a time, running each line on a tape
+/// that lasts for the whole session.
+pub fn run() -> io::Result<()> {
+    // Last byte the program printed, so the prompt can start on a new line.
+    let last_output = Rc::new(Cell::new(b'\n'));
+    let output = on_output({
+        let last_output = last_output.clone();
+        move |byte| {
+            last_output.set(byte);
+            stdout().write_all(&[byte]).ok();
+        }
+    });
+    // Show anything the program printed as a prompt before waiting for input.
+    let input = on_input(|| {
+        stdout().flush().ok();
+
+        let mut byte = [0];
+        match stdin().read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    });
+
+    let mut fucker = Fucker::new(VecDeque::new(), input, output, Instrumentation::default());
+    let mut last: Option<Ast> = None;
+
+    println!("Type :help for a list of commands.");
+
+    loop {
+        if last_output.replace(b'\n') != b'\n' {
+            println!();
+        }
+
+        print!("bf> ");
+        stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin().lock().read_line(&mut line)? == 0 {
+            // End of input
+            println!();
+            return Ok(());
+        }
+
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+
+        let source = match command {
+            "" => continue,
+            ":quit" | ":q" => return Ok(()),
+            ":help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            ":dump" => {
+                println!("{}", format_tape(fucker.memory(), fucker.dp()));
+                continue;
+            }
+            ":reset" => {
+                fucker.reset();
+                continue;
+            }
+            ":ast" => {
+                match last {
+                    Some(ref ast) => print!("{}", tree::render(&ast.data, use_color())),
+                    None => println!("Nothing has been run yet."),
+                }
+                continue;
+            }
+            ":load" => match read_program(argument) {
+                Ok(source) => source,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+            },
+            _ if command.starts_with(':') => {
+                eprintln!("Unknown command {}. Type :help for a list.", command);
+                continue;
+            }
+            _ => line.to_string(),
+        };
+
+        let ast = match run_source(&mut fucker, &source) {
+            Ok(ast) => ast,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        stdout().flush()?;
+
+        if let Some(e) = fucker.take_error() {
+            eprintln!("{}", e);
+        }
+
+        last = Some(ast);
+    }
+}
+
+/// Parse a line or file and run it on the current tape, returning its AST.
+/// Errors while running are left for `take_error`.
+fn run_source(fucker: &mut Fucker, source: &str) -> Result<Ast, String> {
+    // The tape holds whatever earlier lines left on it, so loops at the start
+    // may run.
+    let ast = Ast::parse_preloaded(source)?;
+
+    fucker.load(ast.data.clone());
+    while fucker.step() {}
+
+    Ok(ast)
+}
+
+/// Show a row of cells around the data pointer, with the pointed to cell in
+/// brackets.
+pub fn format_tape(memory: &[u8], dp: usize) -> String {
+    let start = dp.saturating_sub(DUMP_CELLS / 2);
+    let cells: Vec<String> = (start..start + DUMP_CELLS)
+        .map(|cell| {
+            // Memory past the end hasn't been allocated yet, but is all zeros.
+            let value = memory.get(cell).copied().unwrap_or(0);
+
+            if cell == dp {
+                format!("[{}]", value)
+            } else {
+                value.to_string()
+            }
+        })
+        .collect();
+
+    format!("{:>8}: {}", start, cells.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_lines_on_the_tape_left_before() {
+        let mut fucker = Fucker::new(
+            VecDeque::new(),
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+
+        run_source(&mut fucker, "+++").unwrap();
+        run_source(&mut fucker, "[-]").unwrap();
+
+        assert_eq!(fucker.memory()[0], 0);
+    }
+
+    #[test]
+    fn formats_cells_around_pointer() {
+        let mut memory = vec![0u8; 32];
+        memory[10] = 65;
+
+        let expected = format!("       2: 0 0 0 0 0 0 0 0 [65]{}", " 0".repeat(7));
+        assert_eq!(format_tape(&memory, 10), expected);
+    }
+}
@@ -0,0 +1,129 @@
+//! I/O between programs running on different threads.
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+/// Bytes buffered between a writer and its reader. Writing more than this
+/// before the reader catches up blocks the writer.
+pub const PIPE_CAPACITY: usize = 4096;
+
+/// Most bytes sent to the reader at once.
+const CHUNK_SIZE: usize = 256;
+
+/// Create a connected writer and reader. Once the writer is dropped the reader
+/// reaches the end of its input.
+pub fn channel() -> (ChannelWriter, ChannelReader) {
+    let (sender, receiver) = mpsc::sync_channel(PIPE_CAPACITY / CHUNK_SIZE);
+
+    (
+        ChannelWriter { sender },
+        ChannelReader {
+            receiver,
+            pending: Vec::new(),
+            position: 0,
+        },
+    )
+}
+
+/// Sends everything written to it to a ChannelReader.
+pub struct ChannelWriter {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Send a chunk at a time, so that what's waiting for the reader stays
+        // under PIPE_CAPACITY however much is written at once.
+        let len = buf.len().min(CHUNK_SIZE);
+        self.sender
+            .send(buf[..len].to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Reader has finished"))?;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads what was written to a ChannelWriter, blocking until it is available.
+pub struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    /// Most recently received chunk
+    pending: Vec<u8>,
+    /// Bytes of `pending` that have already been read
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.pending.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.pending = chunk;
+                    self.position = 0;
+                }
+                // The writer is gone, so this is the end of the input.
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let available = &self.pending[self.position..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn passes_bytes_between_threads() {
+        let (mut writer, mut reader) = channel();
+
+        let producer = thread::spawn(move || {
+            writer.write_all(b"ab").unwrap();
+            writer.write_all(b"").unwrap();
+            writer.write_all(b"c").unwrap();
+        });
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        producer.join().unwrap();
+
+        assert_eq!(received, b"abc");
+    }
+
+    #[test]
+    fn blocks_writer_until_read() {
+        let (mut writer, mut reader) = channel();
+        let written = Arc::new(AtomicBool::new(false));
+
+        let producer = {
+            let written = Arc::clone(&written);
+            thread::spawn(move || {
+                writer.write_all(&[1; PIPE_CAPACITY + 1]).unwrap();
+                written.store(true, Ordering::SeqCst);
+            })
+        };
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(!written.load(Ordering::SeqCst));
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        producer.join().unwrap();
+
+        assert!(written.load(Ordering::SeqCst));
+        assert_eq!(received.len(), PIPE_CAPACITY + 1);
+    }
+}
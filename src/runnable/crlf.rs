@@ -0,0 +1,130 @@
+//! Newline translation for consoles that expect `\r\n`, like cmd and
+//! PowerShell, so that programs written for `\n` display correctly there.
+
+use std::io::{self, Read, Write};
+
+/// Writer that turns each `\n` into `\r\n`. A `\n` that already follows a
+/// `\r` is left alone, so output that is already `\r\n` isn't doubled up.
+pub struct CrlfWriter {
+    inner: Box<dyn Write>,
+    /// Whether the last byte written was `\r`
+    after_cr: bool,
+}
+
+impl CrlfWriter {
+    pub fn new(inner: Box<dyn Write>) -> Self {
+        CrlfWriter {
+            inner,
+            after_cr: false,
+        }
+    }
+}
+
+impl Write for CrlfWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let mut start = 0;
+
+        for (i, &byte) in buf.iter().enumerate() {
+            let after_cr = match i {
+                0 => self.after_cr,
+                _ => buf[i - 1] == b'\r',
+            };
+
+            if byte == b'\n' && !after_cr {
+                self.inner.write_all(&buf[start..i])?;
+                self.inner.write_all(b"\r")?;
+                start = i;
+            }
+        }
+
+        self.inner.write_all(&buf[start..])?;
+        if let Some(&last) = buf.last() {
+            self.after_cr = last == b'\r';
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that turns each `\r\n` into `\n`. A `\r` on its own is passed
+/// through, once the byte after it shows that it is on its own.
+pub struct CrlfReader {
+    inner: Box<dyn Read>,
+    /// Byte read past a `\r` to see whether it was `\n`
+    held: Option<u8>,
+}
+
+impl CrlfReader {
+    pub fn new(inner: Box<dyn Read>) -> Self {
+        CrlfReader { inner, held: None }
+    }
+
+    /// The next byte of input, or None at the end of it.
+    fn next_byte(&mut self) -> Result<Option<u8>, io::Error> {
+        if let Some(byte) = self.held.take() {
+            return Ok(Some(byte));
+        }
+
+        let mut buffer = [0];
+        match self.inner.read_exact(&mut buffer) {
+            Ok(()) => Ok(Some(buffer[0])),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Read for CrlfReader {
+    /// Read a single byte at a time, since programs read one with each `,`
+    /// and a `\r` has to wait on the byte after it.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        buf[0] = match self.next_byte()? {
+            Some(b'\r') => match self.next_byte()? {
+                Some(b'\n') => b'\n',
+                next => {
+                    self.held = next;
+                    b'\r'
+                }
+            },
+            Some(byte) => byte,
+            None => return Ok(0),
+        };
+
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runnable::shared_buffer::SharedBuffer;
+    use std::io::Cursor;
+
+    #[test]
+    fn writes_crlf() {
+        let output = SharedBuffer::new();
+        let mut writer = CrlfWriter::new(Box::new(output.clone()));
+
+        writer.write_all(b"a\nb\r\n\n").unwrap();
+        writer.write_all(b"c\r").unwrap();
+        writer.write_all(b"\n").unwrap();
+        assert_eq!(output.contents(), b"a\r\nb\r\n\r\nc\r\n");
+    }
+
+    #[test]
+    fn reads_crlf() {
+        let mut reader = CrlfReader::new(Box::new(Cursor::new(b"a\r\nb\r\r\n\rc\r".to_vec())));
+        let mut input = Vec::new();
+
+        reader.read_to_end(&mut input).unwrap();
+        assert_eq!(input, b"a\nb\r\n\rc\r");
+    }
+}
@@ -0,0 +1,49 @@
+//! Passing environment variables to a program through its memory.
+//!
+//! When variables are given, memory starts out as:
+//!
+//! ```text
+//! cell 0   1                        n
+//!      0   NAME=VALUE 0 NAME=VALUE 0 0
+//! ```
+//!
+//! Each variable is a zero terminated string and an empty string ends the
+//! block. The data pointer starts on cell 0 as usual, so a program can step
+//! right to read the first variable and use `[>]` to skip to the end of one.
+
+/// Build the initial memory for a set of `NAME=VALUE` assignments.
+pub fn encode(vars: &[String]) -> Result<Vec<u8>, String> {
+    let mut cells = vec![0];
+
+    for var in vars {
+        match var.split_once('=') {
+            Some((name, _)) if !name.is_empty() => {}
+            _ => return Err(format!("Expected NAME=VALUE but got {:?}", var)),
+        }
+
+        if var.contains('\0') {
+            return Err(format!("Variable contains a zero byte: {:?}", var));
+        }
+
+        cells.extend_from_slice(var.as_bytes());
+        cells.push(0);
+    }
+
+    cells.push(0);
+
+    Ok(cells)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lays_out_variables() {
+        let vars = vec!["A=1".to_string(), "BC=".to_string()];
+
+        assert_eq!(encode(&vars).unwrap(), b"\0A=1\0BC=\0\0".to_vec());
+        assert!(encode(&["=1".to_string()]).is_err());
+        assert!(encode(&["A".to_string()]).is_err());
+    }
+}
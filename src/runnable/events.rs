@@ -0,0 +1,161 @@
+//! Notable things that happen while a program is compiled and run, written as
+//! JSON lines so that external tools can analyze runs. When built with the
+//! `tracing` feature the same events are also emitted through `tracing`.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+
+pub enum Event<'a> {
+    /// A deferred loop or segment was compiled when it was reached, or
+    /// recompiled. `kind` is `fragment`, `recording`, `trace` or `segment`.
+    Compiled {
+        fragment: usize,
+        name: &'a str,
+        kind: &'static str,
+        nodes: usize,
+    },
+    /// A loop was reached often enough to be recompiled as a trace
+    Promoted {
+        fragment: usize,
+        name: &'a str,
+        runs: usize,
+    },
+    /// The program stopped with an error, at `location` if the backend knows
+    /// where
+    Error {
+        message: &'a str,
+        location: Option<&'a str>,
+    },
+}
+
+impl Event<'_> {
+    /// The event as a single line of JSON, without the newline.
+    pub fn to_json(&self) -> String {
+        match *self {
+            Event::Compiled {
+                fragment,
+                name,
+                kind,
+                nodes,
+            } => format!(
+                "{{\"event\":\"compiled\",\"fragment\":{},\"name\":{},\"kind\":{},\"nodes\":{}}}",
+                fragment,
+                json_string(name),
+                json_string(kind),
+                nodes
+            ),
+            Event::Promoted {
+                fragment,
+                name,
+                runs,
+            } => format!(
+                "{{\"event\":\"promoted\",\"fragment\":{},\"name\":{},\"runs\":{}}}",
+                fragment,
+                json_string(name),
+                runs
+            ),
+            Event::Error { message, location } => format!(
+                "{{\"event\":\"error\",\"message\":{},\"location\":{}}}",
+                json_string(message),
+                location.map_or_else(|| "null".to_string(), json_string)
+            ),
+        }
+    }
+}
+
+/// Quote and escape a string for JSON.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                write!(quoted, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// Writes a line of JSON to a stream for each event.
+pub struct EventLog {
+    out: Box<dyn Write>,
+}
+
+impl EventLog {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        EventLog { out }
+    }
+
+    fn write(&mut self, event: &Event) -> io::Result<()> {
+        writeln!(self.out, "{}", event.to_json())?;
+        // Events are rare, and the log is most useful when a run ends badly.
+        self.out.flush()
+    }
+}
+
+/// Send an event to `log` if there is one, and to `tracing` when built with
+/// it.
+pub fn emit(log: &mut Option<EventLog>, event: Event) -> io::Result<()> {
+    #[cfg(feature = "tracing")]
+    trace(&event);
+
+    match *log {
+        Some(ref mut log) => log.write(&event),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn trace(event: &Event) {
+    match *event {
+        Event::Compiled {
+            fragment,
+            name,
+            kind,
+            nodes,
+        } => ::tracing::info!(fragment, name, kind, nodes, "compiled"),
+        Event::Promoted {
+            fragment,
+            name,
+            runs,
+        } => ::tracing::info!(fragment, name, runs, "promoted"),
+        Event::Error { message, location } => {
+            ::tracing::error!(message, location, "error")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_json() {
+        assert_eq!(
+            Event::Compiled {
+                fragment: 2,
+                name: "loop[->+<]",
+                kind: "trace",
+                nodes: 7,
+            }
+            .to_json(),
+            r#"{"event":"compiled","fragment":2,"name":"loop[->+<]","kind":"trace","nodes":7}"#
+        );
+        assert_eq!(
+            Event::Error {
+                message: "Bad \"thing\"\n\u{1}",
+                location: None,
+            }
+            .to_json(),
+            r#"{"event":"error","message":"Bad \"thing\"\n\u0001","location":null}"#
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Commands added to the language by programs embedding the library, for
+//! using BrainFuck as a scripting language. Each is a character that would
+//! otherwise be a comment, tied to a callback that runs on the current cell,
+//! so that `~` could read a sensor into it for example.
+//!
+//! Commands are collected in an `Extensions` table, which is given to the
+//! parser, with `StreamParser::set_extensions`, so that it reads the commands,
+//! and to a backend, so that it can run them.
+
+use std::sync::Arc;
+
+/// Characters that are already BrainFuck commands.
+const COMMANDS: &str = "+-<>[].,";
+
+/// The work done by a command added with `Extensions::add`.
+pub trait Extension: Send + Sync {
+    /// Run the command on the current cell. An error stops the program.
+    fn run(&self, cell: &mut u8) -> Result<(), String>;
+}
+
+impl<F> Extension for F
+where
+    F: Fn(&mut u8) -> Result<(), String> + Send + Sync,
+{
+    fn run(&self, cell: &mut u8) -> Result<(), String> {
+        self(cell)
+    }
+}
+
+/// Commands added to the language, each with the extension it runs. Cloning
+/// the table shares the extensions.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    commands: Vec<(char, Arc<dyn Extension>)>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `command` run `extension`. The command has to be an ASCII
+    /// character that isn't already a command.
+    pub fn add(
+        &mut self,
+        command: char,
+        extension: impl Extension + 'static,
+    ) -> Result<(), String> {
+        if !command.is_ascii() || command.is_ascii_control() || command.is_ascii_whitespace() {
+            return Err(format!("{:?} can't be used as a command", command));
+        }
+
+        if COMMANDS.contains(command) {
+            return Err(format!("{:?} is already a BrainFuck command", command));
+        }
+
+        if self.commands.iter().any(|&(c, _)| c == command) {
+            return Err(format!("{:?} is already an extension", command));
+        }
+
+        self.commands.push((command, Arc::new(extension)));
+        Ok(())
+    }
+
+    /// The characters added as commands.
+    pub fn commands(&self) -> Vec<char> {
+        self.commands.iter().map(|&(command, _)| command).collect()
+    }
+
+    /// Run the extension added for `command` on `cell`.
+    pub fn run(&self, command: char, cell: &mut u8) -> Result<(), String> {
+        let extension = self
+            .commands
+            .iter()
+            .find(|&&(c, _)| c == command)
+            .map(|(_, extension)| extension)
+            .ok_or_else(|| format!("No extension is added for {:?}", command))?;
+
+        extension.run(cell)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_commands() {
+        let mut extensions = Extensions::new();
+        extensions
+            .add('~', |cell: &mut u8| {
+                *cell = 42;
+                Ok(())
+            })
+            .unwrap();
+
+        let mut cell = 0;
+        extensions.run('~', &mut cell).unwrap();
+        assert_eq!(cell, 42);
+        assert_eq!(extensions.commands(), ['~']);
+
+        assert_eq!(
+            extensions.add('~', |_: &mut u8| Ok(())),
+            Err("'~' is already an extension".to_string())
+        );
+        assert_eq!(
+            extensions.add('+', |_: &mut u8| Ok(())),
+            Err("'+' is already a BrainFuck command".to_string())
+        );
+        assert_eq!(
+            extensions.add('é', |_: &mut u8| Ok(())),
+            Err("'é' can't be used as a command".to_string())
+        );
+        assert_eq!(
+            extensions.run('`', &mut cell),
+            Err("No extension is added for '`'".to_string())
+        );
+        // Other tables don't have the command.
+        assert_eq!(
+            Extensions::new().run('~', &mut cell),
+            Err("No extension is added for '~'".to_string())
+        );
+    }
+}
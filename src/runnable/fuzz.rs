@@ -0,0 +1,119 @@
+//! Differential fuzzing of the JIT compiler against the interpreter.
+//!
+//! Random programs are run under the interpreter with a limited number of
+//! steps. Those that finish are then run under the JIT compiler, with and
+//! without tracing hot loops, and with a code limit too low to compile
+//! anything, and all must produce the same output and leave memory in the
+//! same state.
+//!
+//! Set `FUCKER_FUZZ_ITERATIONS` to run more programs and `FUCKER_FUZZ_SEED` to
+//! explore a different set of them. Compiled code is never freed, so very long
+//! runs (above roughly 15,000 programs) eventually fail to allocate executable
+//! memory; use several seeds instead.
+
+use std::env;
+use std::io::Cursor;
+
+use super::interpreter::Fucker;
+use super::jit::{CodeLimit, CompileOptions, JITTarget};
+use super::shared_buffer::SharedBuffer;
+use super::{Instrumentation, Runnable};
+use crate::parser::generate::Rng;
+use crate::parser::{generate, Ast};
+
+/// Programs run by default.
+const ITERATIONS: u64 = 300;
+/// Interpreter steps a program may take before it is skipped.
+const FUEL: usize = 1_000_000;
+/// Commands in the top level of a program.
+const PROGRAM_LEN: usize = 64;
+/// Deepest loop nesting generated.
+const NESTING: usize = 4;
+/// Bytes of input given to each program.
+const INPUT_LEN: usize = 16;
+
+/// Output and final memory of a program run.
+type Outcome = (Vec<u8>, Vec<u8>);
+
+/// Run a program under the interpreter, giving up if it runs out of fuel.
+fn interpret(ast: &Ast, input: &[u8]) -> Option<Outcome> {
+    let output = SharedBuffer::new();
+    let mut fucker = Fucker::new(
+        ast.data.clone(),
+        Box::new(Cursor::new(input.to_vec())),
+        Box::new(output.clone()),
+        Instrumentation::default(),
+    );
+
+    for _ in 0..FUEL {
+        if !fucker.step() {
+            return Some((output.contents(), fucker.memory().to_vec()));
+        }
+    }
+
+    None
+}
+
+/// Run a program under the JIT compiler, tracing hot loops if `trace` is set
+/// and running it without compiling it if `code_limit` is too low.
+fn compile_and_run(ast: &Ast, input: &[u8], trace: bool, code_limit: Option<CodeLimit>) -> Outcome {
+    let output = SharedBuffer::new();
+    let mut jit_target = JITTarget::with_options(
+        ast.data.clone(),
+        Box::new(Cursor::new(input.to_vec())),
+        Box::new(output.clone()),
+        Instrumentation::default(),
+        CompileOptions {
+            code_limit,
+            ..CompileOptions::default()
+        },
+    );
+    if trace {
+        jit_target.trace_hot_loops();
+    }
+    let state = jit_target.run().unwrap();
+
+    (output.contents(), state.memory)
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[test]
+fn interpreter_and_jit_agree() {
+    let iterations = env_or("FUCKER_FUZZ_ITERATIONS", ITERATIONS);
+    let seed = env_or("FUCKER_FUZZ_SEED", 0);
+
+    for case in seed..seed + iterations {
+        let source = generate(case, PROGRAM_LEN, NESTING);
+        let mut rng = Rng::new(case);
+        let input: Vec<u8> = (0..INPUT_LEN).map(|_| rng.next_u64() as u8).collect();
+        let ast = Ast::parse(&source).unwrap();
+
+        let interpreted = match interpret(&ast, &input) {
+            Some(outcome) => outcome,
+            None => continue,
+        };
+        let uncompiled = CodeLimit {
+            bytes: 0,
+            interpret: true,
+        };
+        for (trace, code_limit) in [(false, None), (true, None), (false, Some(uncompiled))] {
+            let compiled = compile_and_run(&ast, &input, trace, code_limit);
+
+            assert!(
+                interpreted == compiled,
+                "Backends disagree on seed {} with input {:?} (tracing: {}, code limit: {:?}):\n{}",
+                case,
+                input,
+                trace,
+                code_limit,
+                source
+            );
+        }
+    }
+}
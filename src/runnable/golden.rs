@@ -0,0 +1,111 @@
+//! Runs every program in `test/programs` that has a sibling `.out` file and
+//! checks that it prints exactly that. If there is also a sibling `.in` file it
+//! is used as the program's input.
+//!
+//! Adding a regression test is a matter of dropping the three files into the
+//! directory.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+
+use super::interpreter::Fucker;
+#[cfg(target_arch = "x86_64")]
+use super::jit::JITTarget;
+use super::shared_buffer::SharedBuffer;
+use super::{Instrumentation, Runnable};
+use crate::parser::Ast;
+
+/// A program along with its input and expected output.
+struct Golden {
+    path: PathBuf,
+    ast: Ast,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+/// Find every program with an expected output.
+fn discover() -> Vec<Golden> {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/programs");
+    let mut paths: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(OsStr::new("bf")))
+        .filter(|path| path.with_extension("out").exists())
+        .collect();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let source = fs::read_to_string(&path).unwrap();
+            let ast = Ast::parse(&source)
+                .unwrap_or_else(|e| panic!("Could not parse {}: {}", path.display(), e));
+
+            Golden {
+                input: fs::read(path.with_extension("in")).unwrap_or_default(),
+                output: fs::read(path.with_extension("out")).unwrap(),
+                ast,
+                path,
+            }
+        })
+        .collect()
+}
+
+/// Run every golden program with the runnable built by `build`, failing with
+/// the names of the programs whose output was wrong.
+fn check<F>(backend: &str, build: F)
+where
+    F: Fn(&Golden) -> Box<dyn Runnable>,
+{
+    let programs = discover();
+    assert!(!programs.is_empty(), "No golden programs were found");
+
+    let failures: Vec<String> = programs
+        .iter()
+        .filter(|golden| {
+            let output = SharedBuffer::new();
+            let result = build(golden).run_with_io(
+                Box::new(Cursor::new(golden.input.clone())),
+                Box::new(output.clone()),
+            );
+
+            result.is_err() || output.contents() != golden.output
+        })
+        .map(|golden| golden.path.display().to_string())
+        .collect();
+
+    assert!(
+        failures.is_empty(),
+        "Wrong output under the {}: {}",
+        backend,
+        failures.join(", ")
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn jit_matches_golden_output() {
+    check("JIT", |golden| {
+        Box::new(JITTarget::new(
+            golden.ast.data.clone(),
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        ))
+    });
+}
+
+#[test]
+#[ignore = "slow without optimizations, run with --release -- --ignored"]
+fn interpreter_matches_golden_output() {
+    check("interpreter", |golden| {
+        Box::new(Fucker::new(
+            golden.ast.data.clone(),
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        ))
+    });
+}
@@ -0,0 +1,108 @@
+use std::io::{self, Write};
+
+/// Number of cells drawn on each row.
+const ROW_CELLS: usize = 64;
+/// Width and height in pixels of each cell in an image.
+const CELL_PIXELS: usize = 8;
+/// Characters used for increasing heat in a text heatmap.
+const SHADES: &[u8] = b" .:-=+*#%@";
+
+/// Heat of each cell between 0 and 1.
+///
+/// A logarithmic scale keeps a handful of very hot cells from washing out
+/// everything else.
+fn heat(counts: &[u64]) -> Vec<f64> {
+    let max = counts.iter().copied().max().unwrap_or(0);
+    let scale = (max as f64).ln_1p();
+
+    counts
+        .iter()
+        .map(|&count| {
+            if count == 0 {
+                0.0
+            } else {
+                (count as f64).ln_1p() / scale
+            }
+        })
+        .collect()
+}
+
+/// Write a heatmap as rows of characters, each prefixed with the index of its
+/// first cell.
+pub fn write_text(counts: &[u64], out: &mut dyn Write) -> io::Result<()> {
+    let heat = heat(counts);
+
+    for (row, cells) in heat.chunks(ROW_CELLS).enumerate() {
+        let shades: Vec<u8> = cells
+            .iter()
+            .map(|&heat| {
+                let level = (heat * (SHADES.len() - 1) as f64).round() as usize;
+                SHADES[level]
+            })
+            .collect();
+
+        write!(out, "{:>8} |", row * ROW_CELLS)?;
+        out.write_all(&shades)?;
+        writeln!(out, "|")?;
+    }
+
+    Ok(())
+}
+
+/// Map heat to a black-red-yellow-white color.
+fn color(heat: f64) -> [u8; 3] {
+    let channel = |start: f64| ((heat * 3.0 - start).clamp(0.0, 1.0) * 255.0).round() as u8;
+
+    [channel(0.0), channel(1.0), channel(2.0)]
+}
+
+/// Write a heatmap as a binary PPM image.
+pub fn write_ppm(counts: &[u64], out: &mut dyn Write) -> io::Result<()> {
+    let heat = heat(counts);
+    let rows = heat.len().div_ceil(ROW_CELLS).max(1);
+
+    writeln!(out, "P6")?;
+    writeln!(out, "{} {}", ROW_CELLS * CELL_PIXELS, rows * CELL_PIXELS)?;
+    writeln!(out, "255")?;
+
+    for row in 0..rows {
+        let mut line = Vec::with_capacity(ROW_CELLS * CELL_PIXELS * 3);
+
+        for column in 0..ROW_CELLS {
+            let heat = heat.get(row * ROW_CELLS + column).copied().unwrap_or(0.0);
+
+            for _ in 0..CELL_PIXELS {
+                line.extend_from_slice(&color(heat));
+            }
+        }
+
+        for _ in 0..CELL_PIXELS {
+            out.write_all(&line)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shades_text_by_heat() {
+        let mut out = Vec::new();
+        write_text(&[0, 1, 1000], &mut out).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "       0 | .@|\n");
+    }
+
+    #[test]
+    fn sizes_image_by_rows() {
+        let mut out = Vec::new();
+        write_ppm(&[1; ROW_CELLS + 1], &mut out).unwrap();
+
+        let header = format!("P6\n{} {}\n255\n", ROW_CELLS * 8, 2 * 8);
+        assert!(out.starts_with(header.as_bytes()));
+        assert_eq!(out.len(), header.len() + ROW_CELLS * 8 * 2 * 8 * 3);
+    }
+}
@@ -0,0 +1,83 @@
+//! Closures that can stand in for a program's input and output streams, for
+//! frontends that want each byte as it happens rather than a `Read` or
+//! `Write` to hand over.
+
+use std::io::{self, Read, Write};
+
+/// Writer that passes every byte a program prints to a closure.
+pub struct OutputHook<F>(F);
+
+impl<F: FnMut(u8)> Write for OutputHook<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        for &byte in buf {
+            (self.0)(byte);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// Reader that asks a closure for every byte a program reads. The closure
+/// returns None once there is no more input.
+pub struct InputHook<F>(F);
+
+impl<F: FnMut() -> Option<u8>> Read for InputHook<F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        match (self.0)() {
+            Some(byte) => {
+                buf[0] = byte;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+/// Output stream that calls `hook` with each byte printed with `.`.
+pub fn on_output<F: FnMut(u8) + 'static>(hook: F) -> Box<dyn Write> {
+    Box::new(OutputHook(hook))
+}
+
+/// Input stream that calls `hook` for each byte read with `,`.
+pub fn on_input<F: FnMut() -> Option<u8> + 'static>(hook: F) -> Box<dyn Read> {
+    Box::new(InputHook(hook))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::parser::Ast;
+    use crate::runnable::interpreter::Fucker;
+    use crate::runnable::{Instrumentation, Runnable};
+
+    #[test]
+    fn runs_with_closures() {
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let mut input = b"ab".to_vec().into_iter();
+        let mut fucker = Fucker::new(
+            Ast::parse(",+.,+.,.").unwrap().data,
+            on_input(move || input.next()),
+            on_output({
+                let printed = printed.clone();
+                move |byte| printed.borrow_mut().push(byte)
+            }),
+            Instrumentation::default(),
+        );
+
+        fucker.run().unwrap();
+
+        // Input past the end reads as a newline.
+        assert_eq!(*printed.borrow(), b"bc\n");
+    }
+}
@@ -0,0 +1,190 @@
+//! Loop counts saved from one run of a program, for the JIT to compile later
+//! runs around. Loops are told apart by their source, so a profile carries
+//! over to any run of the same program, whichever backend recorded it.
+//!
+//! Profiles are saved as a JSON array with an object for each loop, hottest
+//! first:
+//!
+//! ```text
+//! [
+//! {"source":"[->+<]","entries":12,"iterations":3072}
+//! ]
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+
+use super::loop_profile::{LoopCounters, LoopKind, LoopProfile};
+use crate::parser::AstNode;
+
+/// Iterations of its body that make a loop hot
+pub const HOT_ITERATIONS: u64 = 16;
+
+/// How often each loop of a program ran, keyed by its source.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HotLoops {
+    loops: HashMap<String, LoopCounters>,
+}
+
+impl HotLoops {
+    /// Collect the counts from a loop profile. Loops with the same source,
+    /// such as one inlined into several fragments, are added together.
+    /// Simplified loops are left out, since they never run as loops.
+    pub fn from_profile(profile: &LoopProfile) -> Self {
+        let mut hot_loops = HotLoops::default();
+
+        for (source, kind, counters) in profile.loops() {
+            if kind == LoopKind::Simplified {
+                continue;
+            }
+
+            let total = hot_loops.loops.entry(source.to_string()).or_default();
+            total.entries += counters.entries;
+            total.iterations += counters.iterations;
+        }
+
+        hot_loops
+    }
+
+    /// Counts for the loop with the body `nodes`, if it ran.
+    pub fn get(&self, nodes: &VecDeque<AstNode>) -> Option<&LoopCounters> {
+        let body: String = nodes.iter().map(AstNode::to_string).collect();
+        self.loops.get(&format!("[{}]", body))
+    }
+
+    /// Whether the loop with the body `nodes` ran often enough to be worth
+    /// compiling inline.
+    pub fn is_hot(&self, nodes: &VecDeque<AstNode>) -> bool {
+        self.get(nodes)
+            .is_some_and(|counters| counters.iterations >= HOT_ITERATIONS)
+    }
+
+    /// Write the counts as JSON, hottest loop first.
+    pub fn write_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut hottest: Vec<(&String, &LoopCounters)> = self.loops.iter().collect();
+        hottest.sort_by(|a, b| {
+            (b.1.iterations, b.1.entries, a.0).cmp(&(a.1.iterations, a.1.entries, b.0))
+        });
+
+        writeln!(out, "[")?;
+
+        for (index, (source, counters)) in hottest.iter().enumerate() {
+            let separator = if index + 1 < hottest.len() { "," } else { "" };
+            let record = Record {
+                source: source.to_string(),
+                entries: counters.entries,
+                iterations: counters.iterations,
+            };
+
+            writeln!(out, "{}{}", serde_json::to_string(&record)?, separator)?;
+        }
+
+        writeln!(out, "]")
+    }
+
+    /// Read counts written by `write_json`.
+    pub fn parse_json(text: &str) -> Result<Self, String> {
+        let records: Vec<Record> =
+            serde_json::from_str(text).map_err(|e| format!("Invalid profile: {}", e))?;
+        let loops = records
+            .into_iter()
+            .map(|record| {
+                let counters = LoopCounters {
+                    entries: record.entries,
+                    iterations: record.iterations,
+                };
+
+                (record.source, counters)
+            })
+            .collect();
+
+        Ok(HotLoops { loops })
+    }
+}
+
+/// How often one loop ran, as saved in a profile.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Record {
+    source: String,
+    #[serde(default)]
+    entries: u64,
+    #[serde(default)]
+    iterations: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Ast;
+
+    #[test]
+    fn saves_and_reads_profiles() {
+        let mut hot_loops = HotLoops::default();
+        hot_loops.loops.insert(
+            "[->+<]".to_string(),
+            LoopCounters {
+                entries: 2,
+                iterations: 40,
+            },
+        );
+        hot_loops.loops.insert(
+            "[.\"\\]".to_string(),
+            LoopCounters {
+                entries: 1,
+                iterations: 3,
+            },
+        );
+
+        let mut json = Vec::new();
+        hot_loops.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+
+        assert_eq!(
+            json,
+            "[\n\
+             {\"source\":\"[->+<]\",\"entries\":2,\"iterations\":40},\n\
+             {\"source\":\"[.\\\"\\\\]\",\"entries\":1,\"iterations\":3}\n\
+             ]\n"
+        );
+        assert_eq!(HotLoops::parse_json(&json), Ok(hot_loops.clone()));
+
+        let body = |source: &str| match Ast::parse_raw(source).unwrap().data.pop_front() {
+            Some(AstNode::Loop(body)) => body,
+            _ => unreachable!(),
+        };
+        assert!(hot_loops.is_hot(&body("[->+<]")));
+        assert!(!hot_loops.is_hot(&body("[.]")));
+        assert_eq!(HotLoops::parse_json(" [ ] "), Ok(HotLoops::default()));
+    }
+
+    #[test]
+    fn rejects_bad_profiles() {
+        assert_eq!(
+            HotLoops::parse_json("{}"),
+            Err(
+                "Invalid profile: invalid type: map, expected a sequence at line 1 column 0"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            HotLoops::parse_json("[{\"entries\":1}]"),
+            Err("Invalid profile: missing field `source` at line 1 column 14".to_string())
+        );
+        assert_eq!(
+            HotLoops::parse_json("[{\"source\":\"[-]\",\"iterations\":-1}]"),
+            Err(
+                "Invalid profile: invalid value: integer `-1`, expected u64 at line 1 column 32"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            HotLoops::parse_json("[{\"source\":\"[-]\",\"runs\":1}]"),
+            Err("Invalid profile: unknown field `runs`, expected one of `source`, `entries`, `iterations` at line 1 column 23".to_string())
+        );
+        assert_eq!(
+            HotLoops::parse_json("[] x"),
+            Err("Invalid profile: trailing characters at line 1 column 4".to_string())
+        );
+    }
+}
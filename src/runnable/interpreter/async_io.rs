@@ -0,0 +1,174 @@
+//! Running the interpreter on asynchronous streams, so that a program waiting
+//! for input doesn't hold up a thread other tasks could be using.
+
+use std::future::Future;
+use std::io::{self, Cursor, Read};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::instr::Instr;
+use super::Fucker;
+use crate::runnable::FinalState;
+
+/// Future that runs a program, waiting on `reader` for every `,` and on
+/// `writer` for every `.`. Created by `run`.
+pub struct Run<R, W> {
+    fucker: Fucker,
+    reader: R,
+    writer: W,
+    /// Whether the program has stopped and only output is left to flush
+    stopped: bool,
+    /// Whether the program stopped because nothing was reading its output
+    output_closed: bool,
+}
+
+/// Run a program on asynchronous streams. The task only yields when the
+/// program reads or writes, so a long stretch of computation still keeps its
+/// thread busy.
+///
+/// The interpreter isn't `Send`, so the future has to be run with something
+/// like `tokio::task::spawn_local`.
+pub fn run<R, W>(mut fucker: Fucker, reader: R, writer: W) -> Run<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // The interpreter still does its own I/O, but only ever from a byte that
+    // has already been read, or into a sink after the byte has been written.
+    fucker.set_io(Box::new(io::empty()), Box::new(io::sink()));
+
+    Run {
+        fucker,
+        reader,
+        writer,
+        stopped: false,
+        output_closed: false,
+    }
+}
+
+impl<R, W> Run<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Step through the program until it finishes or has to wait for I/O.
+    fn poll_program(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), String>> {
+        while let Some(instr) = self.fucker.next_instr() {
+            match instr {
+                Instr::Read => {
+                    let mut byte = [0];
+                    let mut buf = ReadBuf::new(&mut byte);
+
+                    match Pin::new(&mut self.reader).poll_read(cx, &mut buf) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(e)) => {
+                            return Poll::Ready(Err(format!("Failed to read input: {}", e)))
+                        }
+                        Poll::Ready(Ok(())) => {
+                            let input: Box<dyn Read> = if buf.filled().is_empty() {
+                                Box::new(io::empty())
+                            } else {
+                                Box::new(Cursor::new(byte))
+                            };
+
+                            self.fucker.set_io(input, Box::new(io::sink()));
+                        }
+                    }
+                }
+                Instr::Print => {
+                    let memory = self.fucker.memory();
+                    let cell = memory.get(self.fucker.dp()).copied().unwrap_or(0);
+
+                    match Pin::new(&mut self.writer).poll_write(cx, &[cell]) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Ok(0)) => {
+                            return Poll::Ready(Err("Failed to write output".to_string()))
+                        }
+                        Poll::Ready(Ok(_)) => {}
+                        Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::BrokenPipe => {
+                            self.output_closed = true;
+                            return Poll::Ready(Ok(()));
+                        }
+                        Poll::Ready(Err(e)) => {
+                            return Poll::Ready(Err(format!("Failed to write output: {}", e)))
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            if !self.fucker.step() {
+                break;
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R, W> Future for Run<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    type Output = Result<FinalState, String>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if !this.stopped {
+            match this.poll_program(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Ready(Ok(())) => this.stopped = true,
+            }
+        }
+
+        if !this.output_closed {
+            match Pin::new(&mut this.writer).poll_flush(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => {
+                    return Poll::Ready(Err(format!("Failed to write output: {}", e)))
+                }
+                Poll::Ready(Ok(())) => {}
+            }
+        }
+
+        let state = this.fucker.finish(false).map(|mut state| {
+            state.output_closed = this.output_closed;
+            state
+        });
+
+        Poll::Ready(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Builder;
+
+    use super::*;
+    use crate::parser::Ast;
+    use crate::runnable::Instrumentation;
+
+    #[test]
+    fn runs_on_async_streams() {
+        let fucker = Fucker::new(
+            Ast::parse(",+.,+.,.").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        let mut output = Vec::new();
+        let runtime = Builder::new_current_thread().build().unwrap();
+
+        runtime
+            .block_on(run(fucker, &b"ab"[..], &mut output))
+            .unwrap();
+
+        // Input past the end reads as a newline.
+        assert_eq!(output, b"bc\n");
+    }
+}
@@ -0,0 +1,237 @@
+//! The text format `--emit=bytecode` lists the interpreter's instructions in,
+//! and reading it back, so that programs can be tuned by hand and optimizer
+//! bugs reproduced without the source they came from.
+//!
+//! Each line holds an instruction's address, its name and its operand, if it
+//! has one, separated by spaces:
+//!
+//! ```text
+//! 0000  Incr       2
+//! 0001  BeginLoop  -> 0004
+//! 0002  AddTo      +1
+//! 0003  Decr       1
+//! 0004  EndLoop    -> 0001
+//! ```
+//!
+//! Loop instructions point at their partner. When reading, addresses and the
+//! partners of loop instructions may be left out, since loops are matched up
+//! like brackets, but they have to be right if they are given. Blank lines
+//! and lines starting with `#` are skipped.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::mem;
+use std::str::FromStr;
+
+use super::instr::Instr;
+use crate::parser::AstNode;
+
+/// Write `program` with one instruction per line, preceded by its address.
+pub fn write(program: &[Instr], out: &mut dyn Write) -> io::Result<()> {
+    for (pc, instr) in program.iter().enumerate() {
+        let operand = match *instr {
+            Instr::Incr(n) | Instr::Decr(n) | Instr::Set(n) => n.to_string(),
+            Instr::Next(n) | Instr::Prev(n) => n.to_string(),
+            Instr::AddTo(offset) | Instr::SubFrom(offset) => format!("{:+}", offset),
+            Instr::BeginLoop(offset) => format!("-> {:04}", pc + offset),
+            Instr::EndLoop(offset) => format!("-> {:04}", pc - offset),
+            Instr::Print | Instr::Read => String::new(),
+            Instr::Extension(command) => command.to_string(),
+        };
+
+        let line = format!("{:04}  {:<10} {}", pc, instr.name(), operand);
+        writeln!(out, "{}", line.trim_end())?;
+    }
+
+    Ok(())
+}
+
+/// A loop whose `BeginLoop` has been read, but not its `EndLoop`.
+struct OpenLoop {
+    line: usize,
+    address: usize,
+    /// Where its `BeginLoop` says the `EndLoop` is
+    end: Option<usize>,
+    /// Nodes from before the loop
+    outer: VecDeque<AstNode>,
+}
+
+/// Read a program listed by `write` back into the nodes it was compiled
+/// from.
+pub fn parse(text: &str) -> Result<VecDeque<AstNode>, String> {
+    let mut nodes = VecDeque::new();
+    let mut open: Vec<OpenLoop> = Vec::new();
+    let mut address = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let on_line = |message: String| format!("{} on line {}", message, line_number);
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut words = line.split_whitespace().peekable();
+        if let Some(given) = words.next_if(|word| word.bytes().all(|b| b.is_ascii_digit())) {
+            let given: usize = operand(given).map_err(on_line)?;
+            if given != address {
+                return Err(on_line(format!(
+                    "Address {:04} should be {:04}",
+                    given, address
+                )));
+            }
+        }
+
+        let name = words
+            .next()
+            .ok_or_else(|| on_line("Missing instruction".to_string()))?;
+        let rest = words.collect::<Vec<_>>().join(" ");
+
+        let node = match name {
+            "Incr" => AstNode::Incr(operand(&rest).map_err(on_line)?),
+            "Decr" => AstNode::Decr(operand(&rest).map_err(on_line)?),
+            "Next" => AstNode::Next(operand(&rest).map_err(on_line)?),
+            "Prev" => AstNode::Prev(operand(&rest).map_err(on_line)?),
+            "Set" => AstNode::Set(operand(&rest).map_err(on_line)?),
+            "AddTo" => AstNode::AddTo(operand(&rest).map_err(on_line)?),
+            "SubFrom" => AstNode::SubFrom(operand(&rest).map_err(on_line)?),
+            "Print" | "Read" if !rest.is_empty() => {
+                return Err(on_line(format!("{} takes no operand", name)))
+            }
+            "Print" => AstNode::Print,
+            "Read" => AstNode::Read,
+            "Extension" => AstNode::Extension(operand(&rest).map_err(on_line)?),
+            "BeginLoop" => {
+                open.push(OpenLoop {
+                    line: line_number,
+                    address,
+                    end: partner(&rest).map_err(on_line)?,
+                    outer: mem::take(&mut nodes),
+                });
+                address += 1;
+                continue;
+            }
+            "EndLoop" => {
+                let begin = open
+                    .pop()
+                    .ok_or_else(|| on_line("EndLoop without a BeginLoop".to_string()))?;
+
+                if let Some(end) = begin.end.filter(|&end| end != address) {
+                    return Err(format!(
+                        "BeginLoop on line {} points at {:04}, but its EndLoop is at {:04}",
+                        begin.line, end, address
+                    ));
+                }
+                if let Some(start) = partner(&rest)
+                    .map_err(on_line)?
+                    .filter(|&start| start != begin.address)
+                {
+                    return Err(on_line(format!(
+                        "EndLoop points at {:04}, but its BeginLoop is at {:04}",
+                        start, begin.address
+                    )));
+                }
+
+                let body = mem::replace(&mut nodes, begin.outer);
+                AstNode::Loop(body)
+            }
+            _ => return Err(on_line(format!("Unknown instruction {}", name))),
+        };
+
+        nodes.push_back(node);
+        address += 1;
+    }
+
+    match open.last() {
+        Some(begin) => Err(format!("BeginLoop on line {} has no EndLoop", begin.line)),
+        None => Ok(nodes),
+    }
+}
+
+/// The number an instruction takes.
+fn operand<T: FromStr>(text: &str) -> Result<T, String> {
+    text.parse()
+        .map_err(|_| format!("Invalid operand {:?}", text))
+}
+
+/// The address a loop instruction points at, if it's given.
+fn partner(text: &str) -> Result<Option<usize>, String> {
+    match text.strip_prefix("->") {
+        Some(address) => operand(address.trim()).map(Some),
+        None if text.is_empty() => Ok(None),
+        None => Err(format!("Invalid operand {:?}", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{generate, Ast};
+    use crate::runnable::interpreter::Fucker;
+    use crate::runnable::Instrumentation;
+
+    fn listing(nodes: VecDeque<AstNode>) -> String {
+        let fucker = Fucker::new(
+            nodes,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        let mut listing = Vec::new();
+
+        fucker.write_bytecode(&mut listing).unwrap();
+        String::from_utf8(listing).unwrap()
+    }
+
+    #[test]
+    fn reads_back_listings() {
+        for seed in 0..20 {
+            let nodes = Ast::parse(&generate(seed, 64, 3)).unwrap().data;
+            assert_eq!(parse(&listing(nodes.clone())), Ok(nodes));
+        }
+
+        let nodes = Ast::parse("++[>[->-<].<-],[>>+<<-]").unwrap().data;
+        assert_eq!(parse(&listing(nodes.clone())), Ok(nodes));
+    }
+
+    #[test]
+    fn reads_hand_written_listings() {
+        assert_eq!(
+            parse("# Clear the second cell\nNext 1\nBeginLoop\n  Decr 1\nEndLoop\n\nPrint\n"),
+            Ok(VecDeque::from(vec![
+                AstNode::Next(1),
+                AstNode::Loop(VecDeque::from(vec![AstNode::Decr(1)])),
+                AstNode::Print,
+            ]))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_listings() {
+        assert_eq!(
+            parse("0000  Incr  1\n0002  Print\n"),
+            Err("Address 0002 should be 0001 on line 2".to_string())
+        );
+        assert_eq!(
+            parse("Incr 256"),
+            Err("Invalid operand \"256\" on line 1".to_string())
+        );
+        assert_eq!(
+            parse("BeginLoop -> 0003\nPrint\nEndLoop\n"),
+            Err("BeginLoop on line 1 points at 0003, but its EndLoop is at 0002".to_string())
+        );
+        assert_eq!(
+            parse("Print\nEndLoop"),
+            Err("EndLoop without a BeginLoop on line 2".to_string())
+        );
+        assert_eq!(
+            parse("BeginLoop"),
+            Err("BeginLoop on line 1 has no EndLoop".to_string())
+        );
+        assert_eq!(
+            parse("Jump 3"),
+            Err("Unknown instruction Jump on line 1".to_string())
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Watch expressions for debugging programs under the interpreter, such as
+//! `watch 5 == 0` or `watch dp > 1000`. Rather than stopping every time a cell
+//! changes, which may be millions of times, a watch stops the program only
+//! when its condition flips between holding and not holding.
+
+use std::cell::RefCell;
+use std::ops::ControlFlow;
+use std::rc::Rc;
+
+use super::step_hook::StepHook;
+use super::Instr;
+
+/// What a watch looks at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Subject {
+    /// The cell at an index, counting from the start of memory as
+    /// `FinalState::dp` does
+    Cell(usize),
+    /// The data pointer
+    Dp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A condition on a cell or the data pointer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watch {
+    pub subject: Subject,
+    pub comparison: Comparison,
+    pub value: usize,
+}
+
+impl Watch {
+    /// Parse an expression like `5 == 0` or `dp > 1000`, optionally starting
+    /// with `watch`.
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let mut words = expression.split_whitespace().peekable();
+
+        if words.peek() == Some(&"watch") {
+            words.next();
+        }
+
+        let (subject, comparison, value) = match (words.next(), words.next(), words.next()) {
+            (Some(subject), Some(comparison), Some(value)) => (subject, comparison, value),
+            _ => {
+                return Err(format!(
+                    "Expected <cell or dp> <comparison> <value>, found {}",
+                    expression
+                ))
+            }
+        };
+
+        if let Some(extra) = words.next() {
+            return Err(format!("Unexpected {} in watch expression", extra));
+        }
+
+        let subject = match subject {
+            "dp" => Subject::Dp,
+            cell => Subject::Cell(parse_number(cell)?),
+        };
+        let comparison = match comparison {
+            "==" => Comparison::Eq,
+            "!=" => Comparison::Ne,
+            "<" => Comparison::Lt,
+            "<=" => Comparison::Le,
+            ">" => Comparison::Gt,
+            ">=" => Comparison::Ge,
+            other => return Err(format!("Unknown comparison {}", other)),
+        };
+
+        Ok(Watch {
+            subject,
+            comparison,
+            value: parse_number(value)?,
+        })
+    }
+
+    /// Whether the condition holds. Cells past the end of memory, which
+    /// hasn't grown to them yet, are 0.
+    pub fn holds(&self, dp: usize, memory: &[u8]) -> bool {
+        let actual = match self.subject {
+            Subject::Cell(index) => memory.get(index).copied().unwrap_or(0) as usize,
+            Subject::Dp => dp,
+        };
+
+        match self.comparison {
+            Comparison::Eq => actual == self.value,
+            Comparison::Ne => actual != self.value,
+            Comparison::Lt => actual < self.value,
+            Comparison::Le => actual <= self.value,
+            Comparison::Gt => actual > self.value,
+            Comparison::Ge => actual >= self.value,
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    text.parse()
+        .map_err(|_| format!("Expected a number in watch expression, found {}", text))
+}
+
+struct State {
+    watches: Vec<Watch>,
+    /// Whether each watch held before the last instruction, once known
+    held: Vec<Option<bool>>,
+    /// Index of the watch that stopped the program last
+    triggered: Option<usize>,
+}
+
+/// A step hook that stops the program before an instruction once any watch
+/// has flipped. Clones share their watches, so one can be installed while
+/// another is kept to see which watch stopped the program.
+#[derive(Clone)]
+pub struct Watchpoints {
+    state: Rc<RefCell<State>>,
+}
+
+impl Watchpoints {
+    pub fn new(watches: Vec<Watch>) -> Self {
+        let held = vec![None; watches.len()];
+
+        Watchpoints {
+            state: Rc::new(RefCell::new(State {
+                watches,
+                held,
+                triggered: None,
+            })),
+        }
+    }
+
+    /// Index of the watch that last stopped the program, if any has.
+    pub fn triggered(&self) -> Option<usize> {
+        self.state.borrow().triggered
+    }
+}
+
+impl StepHook for Watchpoints {
+    fn on_step(&mut self, _: &Instr, _: usize, dp: usize, memory: &mut [u8]) -> ControlFlow<()> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        let mut flipped = None;
+
+        for (index, (watch, held)) in state.watches.iter().zip(&mut state.held).enumerate() {
+            let holds = watch.holds(dp, memory);
+
+            if held.is_some_and(|held| held != holds) && flipped.is_none() {
+                flipped = Some(index);
+            }
+
+            *held = Some(holds);
+        }
+
+        match flipped {
+            Some(index) => {
+                state.triggered = Some(index);
+                ControlFlow::Break(())
+            }
+            None => ControlFlow::Continue(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::parser::Ast;
+    use crate::runnable::interpreter::Fucker;
+    use crate::runnable::{Instrumentation, Runnable};
+
+    #[test]
+    fn parses_expressions() {
+        assert_eq!(
+            Watch::parse("watch 5 == 0"),
+            Ok(Watch {
+                subject: Subject::Cell(5),
+                comparison: Comparison::Eq,
+                value: 0,
+            })
+        );
+        assert_eq!(
+            Watch::parse("dp >= 1000"),
+            Ok(Watch {
+                subject: Subject::Dp,
+                comparison: Comparison::Ge,
+                value: 1000,
+            })
+        );
+        assert!(Watch::parse("watch cell == 0").is_err());
+        assert!(Watch::parse("dp =< 3").is_err());
+        assert!(Watch::parse("dp > 3 4").is_err());
+    }
+
+    #[test]
+    fn stops_when_a_watch_flips() {
+        // Counts cell 1 up while counting cell 0 down, printing so that the
+        // loop isn't simplified away.
+        let ast = Ast::parse(&format!("{}[>+.<-]", "+".repeat(200))).unwrap();
+        let mut fucker = Fucker::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        let watchpoints = Watchpoints::new(vec![
+            Watch::parse("dp > 5").unwrap(),
+            Watch::parse("1 >= 100").unwrap(),
+        ]);
+        fucker.set_step_hook(watchpoints.clone());
+
+        let state = fucker.run().unwrap();
+        assert!(state.interrupted);
+        assert_eq!(watchpoints.triggered(), Some(1));
+        // Stopped as soon as cell 1 reached 100, before cell 0 counted down.
+        assert_eq!(&state.memory[..2], &[101, 100]);
+    }
+}
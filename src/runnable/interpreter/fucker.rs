@@ -1,16 +1,31 @@
+use std::cell::RefCell;
 use std::cmp;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::io::{self, Read, Write};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
-use super::super::Runnable;
+use super::super::{interrupt, Runnable};
+use super::bytecode;
 use super::instr::Instr;
+#[cfg(feature = "step-hook")]
+use super::step_hook::StepHook;
 use crate::parser::AstNode;
-use crate::runnable::BF_MEMORY_SIZE;
+use crate::runnable::events::{self, Event};
+use crate::runnable::extension::Extensions;
+use crate::runnable::loop_profile::LoopKind;
+use crate::runnable::profiler::Profiler;
+use crate::runnable::tape::Tape;
+use crate::runnable::tape_stats::TapeStats;
+use crate::runnable::{
+    CellOverflow, FinalState, Instrumentation, RunReport, BF_MEMORY_SIZE, CANCELLED,
+    LEFT_TAPE_SIZE, WRAPPED_TAPE_SIZE,
+};
 
 /// BrainFuck virtual machine
 pub struct Fucker {
     program: Vec<Instr>,
-    memory: Vec<u8>,
+    memory: Tape,
     /// Program counter
     pc: usize,
     /// Data pointer
@@ -19,17 +34,241 @@ pub struct Fucker {
     io_read: Box<dyn Read>,
     /// Writer used by brainfuck's . command
     io_write: Box<dyn Write>,
+    /// Observers of each executed instruction
+    instrumentation: Instrumentation,
+    /// Profiler loop IDs keyed by the pc of the instruction that begins the
+    /// loop
+    loop_ids: HashMap<usize, usize>,
+    /// Loops registered with the profiler, over every program loaded
+    loops_added: usize,
+    /// Profiler location of the first instruction in the program, if the
+    /// profiler is told about instructions
+    first_location: Option<usize>,
+    /// Locations registered with the profiler, over every program loaded
+    locations_added: usize,
+    /// Number of cells memory may grow to
+    tape_limit: usize,
+    /// Whether the data pointer wraps around the ends of memory
+    wrap: bool,
+    /// What arithmetic past the range of a cell does
+    cell_overflow: CellOverflow,
+    /// Commands added to the language that the program may run
+    extensions: Extensions,
+    /// Index of the cell the data pointer starts at
+    origin: usize,
+    /// Whether memory and the data pointer are left as they are after a run
+    keep_state: bool,
+    /// Whether the program stopped because nothing was reading its output
+    output_closed: bool,
+    /// Why the program stopped, if it was because of an error
+    error: Option<String>,
+    /// Instructions executed so far in this run
+    executed: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+    /// Highest cell the data pointer has moved to in this run
+    peak_dp: usize,
+    /// When the current run started
+    started: Instant,
+    /// Called before every instruction
+    #[cfg(feature = "step-hook")]
+    step_hook: Option<Box<dyn StepHook>>,
+    /// Whether the step hook stopped the program
+    #[cfg(feature = "step-hook")]
+    hook_stopped: bool,
 }
 
 impl Fucker {
-    pub fn new(nodes: VecDeque<AstNode>) -> Self {
-        Fucker {
-            program: Self::compile(nodes),
-            memory: vec![0u8; BF_MEMORY_SIZE],
+    /// Initialize a VM that reads with `,` from `io_read` and writes with `.`
+    /// to `io_write`.
+    pub fn new(
+        nodes: VecDeque<AstNode>,
+        io_read: Box<dyn Read>,
+        io_write: Box<dyn Write>,
+        instrumentation: Instrumentation,
+    ) -> Self {
+        let mut fucker = Fucker {
+            program: Vec::new(),
+            memory: Tape::new(BF_MEMORY_SIZE),
             pc: 0,
             dp: 0,
-            io_read: Box::new(io::stdin()),
-            io_write: Box::new(io::stdout()),
+            io_read,
+            io_write,
+            instrumentation,
+            loop_ids: HashMap::new(),
+            loops_added: 0,
+            first_location: None,
+            locations_added: 0,
+            tape_limit: usize::MAX,
+            wrap: false,
+            cell_overflow: CellOverflow::Wrap,
+            extensions: Extensions::new(),
+            origin: 0,
+            keep_state: false,
+            output_closed: false,
+            error: None,
+            executed: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            peak_dp: 0,
+            started: Instant::now(),
+            #[cfg(feature = "step-hook")]
+            step_hook: None,
+            #[cfg(feature = "step-hook")]
+            hook_stopped: false,
+        };
+
+        fucker.load(nodes);
+        fucker
+    }
+
+    /// Replace the program being run, keeping the contents of memory and the
+    /// data pointer.
+    pub fn load(&mut self, nodes: VecDeque<AstNode>) {
+        let profiler = match self.instrumentation.profiler {
+            Some(ref mut profiler) => profiler,
+            None => {
+                self.program = Self::compile(nodes);
+                self.pc = 0;
+                return;
+            }
+        };
+
+        if profiler.observes_loops() {
+            self.loop_ids = Self::register_loops(&nodes, &mut **profiler, self.loops_added);
+            self.loops_added += self.loop_ids.len();
+        }
+
+        // The clock is only read when profiling, since there isn't one on
+        // every target this runs on.
+        let compile_start = Instant::now();
+        self.program = Self::compile(nodes);
+        self.pc = 0;
+        profiler.program_compiled(compile_start.elapsed());
+
+        if profiler.observes_instructions() {
+            for instr in &self.program {
+                profiler.add_location(instr.name(), format!("{:?}", instr));
+            }
+
+            self.first_location = Some(self.locations_added);
+            self.locations_added += self.program.len();
+        }
+    }
+
+    /// Call `hook` before every instruction. A run stopped by the hook ends
+    /// as though it was interrupted, and `step` returns false without
+    /// executing the instruction, so that calling it again carries on from
+    /// there.
+    #[cfg(feature = "step-hook")]
+    pub fn set_step_hook(&mut self, hook: impl StepHook + 'static) {
+        self.step_hook = Some(Box::new(hook));
+    }
+
+    /// Stop the program with an error if the data pointer moves past `cells`
+    /// cells rather than growing memory beyond that.
+    pub fn limit_tape(&mut self, cells: usize) {
+        self.tape_limit = cells;
+        // Memory starts out larger than a small limit.
+        self.memory.truncate(cells);
+    }
+
+    /// Move memory into huge pages once it grows large enough for them to
+    /// help.
+    pub fn use_huge_pages(&mut self) {
+        let mut memory = Tape::with_huge_pages(self.memory.len());
+        memory.copy_from_slice(&self.memory);
+        self.memory = memory;
+    }
+
+    /// Make the data pointer wrap around a tape of `WRAPPED_TAPE_SIZE` cells,
+    /// rather than memory growing to the right and moving left of cell 0
+    /// being an error.
+    pub fn wrap_tape(&mut self) {
+        self.wrap = true;
+        self.memory.resize(WRAPPED_TAPE_SIZE);
+    }
+
+    /// Make arithmetic that carries a cell past 255 or below 0 stop the
+    /// program or saturate, rather than wrap around.
+    pub fn set_cell_overflow(&mut self, cell_overflow: CellOverflow) {
+        self.cell_overflow = cell_overflow;
+    }
+
+    /// Run `Extension` nodes with the commands in `extensions`. Without it
+    /// they stop the program with an error.
+    pub fn set_extensions(&mut self, extensions: Extensions) {
+        self.extensions = extensions;
+    }
+
+    /// Leave memory and the data pointer as they are after each run, so the
+    /// next run carries on from them, until `reset` is called.
+    ///
+    /// Runs after the first start on whatever the last one left, so the
+    /// program has to be parsed with `Ast::parse_preloaded` or optimized with
+    /// `Ast::optimize_preloaded`. `Ast::parse` drops loops at the start of a
+    /// program, since they never run on zeroed memory.
+    pub fn keep_state(&mut self) {
+        self.keep_state = true;
+    }
+
+    /// Add `LEFT_TAPE_SIZE` cells to the left of the starting cell, for
+    /// programs that move left of where they start. A tape limit still counts
+    /// from the starting cell.
+    pub fn bidirectional_tape(&mut self) {
+        let len = self.memory.len();
+        self.memory.resize(LEFT_TAPE_SIZE + len);
+        self.memory.copy_within(..len, LEFT_TAPE_SIZE);
+        self.memory[..LEFT_TAPE_SIZE].fill(0);
+
+        self.origin += LEFT_TAPE_SIZE;
+        self.dp += LEFT_TAPE_SIZE;
+        self.tape_limit = self.tape_limit.saturating_add(LEFT_TAPE_SIZE);
+    }
+
+    /// Register every loop in a program with a profiler, numbering them from
+    /// `first_id`, and return the mapping from instruction address to loop ID.
+    fn register_loops(
+        nodes: &VecDeque<AstNode>,
+        profiler: &mut dyn Profiler,
+        first_id: usize,
+    ) -> HashMap<usize, usize> {
+        let mut loop_ids = HashMap::new();
+        let mut pc = 0;
+
+        Self::register_loops_from(nodes, profiler, first_id, &mut loop_ids, &mut pc);
+
+        loop_ids
+    }
+
+    /// Walk nodes in the same order that `compile` emits them, keeping `pc` in
+    /// step with the address the current node will be compiled to.
+    fn register_loops_from(
+        nodes: &VecDeque<AstNode>,
+        profiler: &mut dyn Profiler,
+        first_id: usize,
+        loop_ids: &mut HashMap<usize, usize>,
+        pc: &mut usize,
+    ) {
+        for node in nodes {
+            match node {
+                AstNode::Set(_) | AstNode::AddTo(_) | AstNode::SubFrom(_) => {
+                    profiler.add_loop(LoopKind::Simplified, node.to_string());
+                    loop_ids.insert(*pc, first_id + loop_ids.len());
+                }
+                AstNode::Loop(inner) => {
+                    profiler.add_loop(LoopKind::Interpreted, node.to_string());
+                    loop_ids.insert(*pc, first_id + loop_ids.len());
+
+                    // Step over BeginLoop
+                    *pc += 1;
+                    Self::register_loops_from(inner, profiler, first_id, loop_ids, pc);
+                }
+                _ => {}
+            }
+
+            // Step over this node, or EndLoop for loops
+            *pc += 1;
         }
     }
 
@@ -47,6 +286,7 @@ impl Fucker {
                 AstNode::Set(n) => instrs.push(Instr::Set(n)),
                 AstNode::AddTo(n) => instrs.push(Instr::AddTo(n)),
                 AstNode::SubFrom(n) => instrs.push(Instr::SubFrom(n)),
+                AstNode::Extension(command) => instrs.push(Instr::Extension(command)),
                 AstNode::Loop(vec) => {
                     let inner_loop = Self::compile(vec);
                     // Add 1 to the offset to account for the BeginLoop/EndLoop instr
@@ -72,50 +312,105 @@ impl Fucker {
         }
 
         // If the data pointer ends up outside of memory, expand either to a
-        // double of the current memory size, or the new data pointer location
-        // (whichever is bigger).
+        // double of the current memory size, or just past the new data pointer
+        // location (whichever is bigger), but no further than the tape limit.
         if self.dp >= self.memory.len() {
-            let new_len = cmp::max(self.memory.len() * 2, self.dp);
-            self.memory.resize(new_len, 0);
+            if self.dp >= self.tape_limit {
+                return self.fail(format!(
+                    "Exceeded the tape limit of {} cells.",
+                    self.tape_limit
+                ));
+            }
+
+            let new_len = cmp::max(self.memory.len() * 2, self.dp + 1);
+            self.memory.resize(cmp::min(new_len, self.tape_limit));
         }
 
         let instr = self.program[self.pc];
+
+        #[cfg(feature = "step-hook")]
+        if let Some(ref mut hook) = self.step_hook {
+            if hook
+                .on_step(&instr, self.pc, self.dp, &mut self.memory)
+                .is_break()
+            {
+                self.hook_stopped = true;
+                return false;
+            }
+        }
+
         let current = self.memory[self.dp];
 
-        match instr {
-            Instr::Incr(n) => {
-                self.memory[self.dp] = current.wrapping_add(n);
+        if let Some(ref mut tracer) = self.instrumentation.tracer {
+            if let Err(error) = tracer.trace(self.pc, self.dp, current, &instr) {
+                return self.fail(format!("Failed to write trace: {}", error));
             }
-            Instr::Decr(n) => {
-                self.memory[self.dp] = current.wrapping_sub(n);
+        }
+
+        if let Some(ref mut profiler) = self.instrumentation.profiler {
+            if let Some(first_location) = self.first_location {
+                profiler.instruction_retired(first_location + self.pc);
             }
+
+            Self::count_loop(&mut **profiler, &self.loop_ids, self.pc, instr, current);
+        }
+
+        if let Some(ref tape) = self.instrumentation.tape {
+            self.count_tape_usage(tape, instr, current);
+        }
+
+        match instr {
+            Instr::Incr(n) => match self.cell_overflow.add(current, n) {
+                Some(value) => self.memory[self.dp] = value,
+                None => return self.overflowed(self.dp),
+            },
+            Instr::Decr(n) => match self.cell_overflow.sub(current, n) {
+                Some(value) => self.memory[self.dp] = value,
+                None => return self.overflowed(self.dp),
+            },
             Instr::Next(n) => {
                 self.dp += n;
+
+                if self.wrap {
+                    self.dp %= self.memory.len();
+                }
+
+                self.peak_dp = self.peak_dp.max(self.dp);
+            }
+            Instr::Prev(n) if self.wrap => {
+                let len = self.memory.len();
+                self.dp = (self.dp + len - n % len) % len;
             }
             Instr::Prev(n) => {
                 if self.dp < n {
-                    eprintln!("Attempted to point below memory location 0.");
-                    return false;
+                    return self.fail("Attempted to point below memory location 0.".to_string());
                 }
 
                 self.dp -= n;
             }
             Instr::Print => {
-                if let Err(msg) = self.io_write.write_all(&[current]) {
-                    eprintln!("{}", msg);
-                    return false;
+                if let Err(error) = self.io_write.write_all(&[current]) {
+                    if error.kind() == io::ErrorKind::BrokenPipe {
+                        self.output_closed = true;
+                        return false;
+                    }
+
+                    return self.fail(format!("Failed to write output: {}", error));
                 }
+
+                self.bytes_written += 1;
             }
             Instr::Read => {
                 let mut buf = [0u8; 1];
                 if let Err(error) = self.io_read.read_exact(&mut buf) {
                     if error.kind() != io::ErrorKind::UnexpectedEof {
-                        eprintln!("{}", error);
-                        return false;
+                        return self.fail(format!("Failed to read input: {}", error));
                     }
 
                     // Default to newlines if the input stream is empty.
                     buf[0] = b'\n';
+                } else {
+                    self.bytes_read += 1;
                 }
                 self.memory[self.dp] = buf[0];
             }
@@ -124,29 +419,45 @@ impl Fucker {
             }
             Instr::AddTo(n) => {
                 if self.memory[self.dp] != 0 {
-                    let target_pos = self.dp as isize + n;
+                    let target_pos = match self.offset_cell(n) {
+                        Some(target_pos) => target_pos,
+                        None => {
+                            return self.fail(
+                                "Attempted to move data outside of the bounds of memory"
+                                    .to_string(),
+                            )
+                        }
+                    };
 
-                    if (target_pos < 0) || (target_pos as usize >= self.memory.len()) {
-                        eprintln!("Attempted to move data outside of the bounds of memory");
-                        return false;
+                    let target = self.memory[target_pos];
+
+                    match self.cell_overflow.add(target, self.memory[self.dp]) {
+                        Some(value) => self.memory[target_pos] = value,
+                        None => return self.overflowed(target_pos),
                     }
 
-                    self.memory[target_pos as usize] =
-                        self.memory[target_pos as usize].wrapping_add(self.memory[self.dp]);
                     self.memory[self.dp] = 0;
                 }
             }
             Instr::SubFrom(n) => {
                 if self.memory[self.dp] != 0 {
-                    let target_pos = self.dp as isize + n;
+                    let target_pos = match self.offset_cell(n) {
+                        Some(target_pos) => target_pos,
+                        None => {
+                            return self.fail(
+                                "Attempted to move data outside of the bounds of memory"
+                                    .to_string(),
+                            )
+                        }
+                    };
 
-                    if (target_pos < 0) || (target_pos as usize >= self.memory.len()) {
-                        eprintln!("Attempted to move data outside of the bounds of memory");
-                        return false;
+                    let target = self.memory[target_pos];
+
+                    match self.cell_overflow.sub(target, self.memory[self.dp]) {
+                        Some(value) => self.memory[target_pos] = value,
+                        None => return self.overflowed(target_pos),
                     }
 
-                    self.memory[target_pos as usize] =
-                        self.memory[target_pos as usize].wrapping_sub(self.memory[self.dp]);
                     self.memory[self.dp] = 0;
                 }
             }
@@ -160,45 +471,310 @@ impl Fucker {
                     self.pc -= offset;
                 }
             }
+            Instr::Extension(command) => {
+                if let Err(error) = self.extensions.run(command, &mut self.memory[self.dp]) {
+                    return self.fail(error);
+                }
+            }
         }
 
         self.pc += 1;
+        self.executed += 1;
 
         true
     }
 
-    pub fn reset(&mut self) {
-        for i in 0..(self.memory.len() - 1) {
-            self.memory[i] = 0;
+    /// Index of the cell `offset` cells away from the data pointer, if it is
+    /// inside memory.
+    fn offset_cell(&self, offset: isize) -> Option<usize> {
+        let target_pos = self.dp as isize + offset;
+
+        if self.wrap {
+            return Some(target_pos.rem_euclid(self.memory.len() as isize) as usize);
+        }
+
+        if target_pos < 0 || target_pos as usize >= self.memory.len() {
+            return None;
+        }
+
+        Some(target_pos as usize)
+    }
+
+    /// Stop the program because of an error. Always returns false, for
+    /// `step` to return.
+    fn fail(&mut self, error: String) -> bool {
+        self.error = Some(error);
+
+        false
+    }
+
+    /// Stop the program because the cell at `index` overflowed. Always
+    /// returns false, like `fail`.
+    fn overflowed(&mut self, index: usize) -> bool {
+        self.fail(format!("Cell {} overflowed.", index))
+    }
+
+    /// Take the error that stopped the program, if there was one.
+    pub fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+
+    /// Tell the profiler about any loop the instruction at `pc`, which is
+    /// about to execute, enters or iterates.
+    fn count_loop(
+        profiler: &mut dyn Profiler,
+        loop_ids: &HashMap<usize, usize>,
+        pc: usize,
+        instr: Instr,
+        current: u8,
+    ) {
+        let (begin, entered, iterated) = match instr {
+            Instr::BeginLoop(_) => (pc, true, current != 0),
+            Instr::EndLoop(offset) => (pc - offset, false, current != 0),
+            Instr::Set(_) | Instr::AddTo(_) | Instr::SubFrom(_) => (pc, true, false),
+            _ => return,
+        };
+
+        // Loops are only registered if the profiler observes them.
+        let id = match loop_ids.get(&begin) {
+            Some(&id) => id,
+            None => return,
+        };
+
+        if entered {
+            profiler.loop_entered(id);
+        }
+
+        if iterated {
+            profiler.loop_iterated(id);
+        }
+    }
+
+    /// Update tape statistics for an instruction that is about to execute.
+    fn count_tape_usage(&self, tape: &RefCell<TapeStats>, instr: Instr, current: u8) {
+        let mut tape = tape.borrow_mut();
+
+        match instr {
+            Instr::Incr(_) | Instr::Decr(_) | Instr::Read | Instr::Set(_) | Instr::Extension(_) => {
+                tape.record_write(self.dp);
+            }
+            Instr::AddTo(offset) | Instr::SubFrom(offset) if current != 0 => {
+                // Out of bounds targets are reported as errors when executed.
+                if let Some(target_pos) = self.offset_cell(offset) {
+                    tape.record_write(self.dp);
+                    tape.record_write(target_pos);
+                }
+            }
+            Instr::Next(n) if self.wrap => tape.record_dp((self.dp + n) % self.memory.len()),
+            Instr::Next(n) => tape.record_dp(self.dp + n),
+            _ => {}
+        }
+    }
+
+    /// Counters for the current run.
+    fn report(&self) -> RunReport {
+        RunReport {
+            instructions: Some(self.executed),
+            bytes_read: self.bytes_read,
+            bytes_written: self.bytes_written,
+            peak_dp: Some(self.peak_dp.max(self.origin)),
+            elapsed: self.started.elapsed(),
+        }
+    }
+
+    /// Pass the counters so far to the progress callback, if one is due.
+    fn report_progress(&mut self) {
+        let due = self
+            .instrumentation
+            .progress
+            .as_ref()
+            .is_some_and(|progress| self.executed.is_multiple_of(progress.every.max(1)));
+
+        if due {
+            let report = self.report();
+
+            if let Some(ref mut progress) = self.instrumentation.progress {
+                (progress.callback)(&report);
+            }
+        }
+    }
+
+    /// Instruction `step` will execute next, if the program hasn't finished.
+    #[cfg(feature = "tokio")]
+    pub(super) fn next_instr(&self) -> Option<Instr> {
+        self.program.get(self.pc).copied()
+    }
+
+    /// Replace the streams used by `,` and `.`.
+    pub(super) fn set_io(&mut self, io_read: Box<dyn Read>, io_write: Box<dyn Write>) {
+        self.io_read = io_read;
+        self.io_write = io_write;
+    }
+
+    /// Describe where the program stopped and prepare the VM for another run.
+    /// A failed run always resets, since it may have stopped part way through
+    /// changing memory.
+    pub(super) fn finish(&mut self, interrupted: bool) -> Result<FinalState, String> {
+        if let Some(error) = self.take_error() {
+            let location = self.location();
+            let event = Event::Error {
+                message: &error,
+                location: location.as_deref(),
+            };
+            // The error is more important than the log.
+            events::emit(&mut self.instrumentation.events, event).ok();
+
+            self.reset();
+            return Err(error);
+        }
+
+        let state = FinalState {
+            memory: self.memory.to_vec(),
+            dp: self.dp,
+            output_closed: self.output_closed,
+            interrupted,
+            location: self.location(),
+        };
+
+        if self.keep_state {
+            self.restart();
+        } else {
+            self.reset();
         }
 
+        Ok(state)
+    }
+
+    /// The instruction about to be executed, if the program hasn't finished.
+    fn location(&self) -> Option<String> {
+        self.program
+            .get(self.pc)
+            .map(|instr| format!("pc={} {:?}", self.pc, instr))
+    }
+
+    /// Write the compiled program with one instruction per line, preceded by
+    /// its address. Loop instructions show the address of their partner.
+    pub fn write_bytecode(&self, out: &mut dyn Write) -> io::Result<()> {
+        bytecode::write(&self.program, out)
+    }
+
+    /// Contents of memory.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Current data pointer.
+    pub fn dp(&self) -> usize {
+        self.dp
+    }
+
+    /// Start from a prepared tape rather than an empty one.
+    pub fn set_tape(&mut self, memory: Vec<u8>, dp: usize) {
+        self.memory = Tape::new(memory.len());
+        self.memory.copy_from_slice(&memory);
+        self.dp = dp;
+    }
+
+    /// Go back to the start of the program, leaving memory alone.
+    fn restart(&mut self) {
         self.pc = 0;
-        self.dp = 0;
+        self.output_closed = false;
+        self.error = None;
+        self.executed = 0;
+        self.bytes_read = 0;
+        self.bytes_written = 0;
+        self.peak_dp = 0;
     }
 }
 
 impl Runnable for Fucker {
-    fn run(&mut self) {
-        while self.step() {}
-        self.reset();
+    fn run_report(&mut self) -> Result<(FinalState, RunReport), String> {
+        span!("run", backend = "interpreter");
+        self.started = Instant::now();
+        let mut interrupted = false;
+
+        while self.step() {
+            if interrupt::requested() {
+                interrupted = true;
+                break;
+            }
+
+            if let Some(ref cancel) = self.instrumentation.cancel {
+                if cancel.load(Ordering::Relaxed) {
+                    self.fail(CANCELLED.to_string());
+                    break;
+                }
+            }
+
+            self.report_progress();
+        }
+
+        #[cfg(feature = "step-hook")]
+        {
+            interrupted |= std::mem::take(&mut self.hook_stopped);
+        }
+
+        let report = self.report();
+
+        Ok((self.finish(interrupted)?, report))
+    }
+
+    fn run_with_io(
+        &mut self,
+        io_read: Box<dyn Read>,
+        io_write: Box<dyn Write>,
+    ) -> Result<FinalState, String> {
+        self.set_io(io_read, io_write);
+
+        self.run()
+    }
+
+    fn reset(&mut self) {
+        for cell in self.memory.iter_mut() {
+            *cell = 0;
+        }
+
+        self.dp = self.origin;
+        self.restart();
+    }
+
+    fn preload(&mut self, cells: &[u8]) {
+        let end = self.origin + cells.len();
+
+        if end > self.memory.len() && !self.wrap {
+            self.memory.resize(end);
+        }
+
+        let len = cells.len().min(self.memory.len() - self.origin);
+        self.memory[self.origin..self.origin + len].copy_from_slice(&cells[..len]);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::super::test_buffer::SharedBuffer;
+    use super::super::super::shared_buffer::{ClosedPipe, SharedBuffer};
     use super::*;
     use crate::parser::Ast;
+    use crate::runnable::Progress;
     use std::io::Cursor;
+    use std::rc::Rc;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
 
     #[test]
     fn run_hello_world() {
         let ast = Ast::parse(include_str!("../../../test/programs/hello_world.bf")).unwrap();
-        let mut fucker = Fucker::new(ast.data);
         let shared_buffer = SharedBuffer::new();
-        fucker.io_write = Box::new(shared_buffer.clone());
+        let mut fucker = Fucker::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
 
-        fucker.run();
+        fucker.run().unwrap();
 
         let output_string = shared_buffer.get_string_content();
         assert_eq!(output_string, "Hello World!\n");
@@ -209,15 +785,308 @@ mod tests {
         // This rot13 program terminates after 16 characters so we can test it. Otherwise it would
         // wait on input forever.
         let ast = Ast::parse(include_str!("../../../test/programs/rot13-16char.bf")).unwrap();
-        let mut fucker = Fucker::new(ast.data);
         let shared_buffer = SharedBuffer::new();
-        fucker.io_write = Box::new(shared_buffer.clone());
         let in_cursor = Box::new(Cursor::new("Hello World! 123".as_bytes().to_vec()));
-        fucker.io_read = in_cursor;
+        let mut fucker = Fucker::new(
+            ast.data,
+            in_cursor,
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
 
-        fucker.run();
+        fucker.run().unwrap();
 
         let output_string = shared_buffer.get_string_content();
         assert_eq!(output_string, "Uryyb Jbeyq! 123");
     }
+
+    #[test]
+    fn reports_runs() {
+        let ast = Ast::parse(",>>,[.>]<<<").unwrap();
+        let mut fucker = Fucker::new(
+            ast.data,
+            Box::new(Cursor::new(b"ab".to_vec())),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+
+        let (state, report) = fucker.run_report().unwrap();
+
+        assert_eq!(state.dp, 0);
+        assert_eq!(report.instructions, Some(8));
+        assert_eq!(report.bytes_read, 2);
+        assert_eq!(report.bytes_written, 1);
+        assert_eq!(report.peak_dp, Some(3));
+    }
+
+    #[test]
+    fn lists_bytecode() {
+        let fucker = Fucker::new(
+            Ast::parse("++[>[->+<].<-]").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        let mut listing = Vec::new();
+
+        fucker.write_bytecode(&mut listing).unwrap();
+
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            "0000  Incr       2\n\
+             0001  BeginLoop  -> 0007\n\
+             0002  Next       1\n\
+             0003  AddTo      +1\n\
+             0004  Print\n\
+             0005  Prev       1\n\
+             0006  Decr       1\n\
+             0007  EndLoop    -> 0001\n"
+        );
+    }
+
+    #[test]
+    fn load_keeps_tape() {
+        let shared_buffer = SharedBuffer::new();
+        let mut fucker = Fucker::new(
+            Ast::parse("++++++++[>++++++++<-]>+").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
+
+        while fucker.step() {}
+        fucker.load(Ast::parse(".+.").unwrap().data);
+        while fucker.step() {}
+
+        assert_eq!(shared_buffer.get_string_content(), "AB");
+        assert_eq!(fucker.dp(), 1);
+    }
+
+    #[test]
+    fn tape_stops_growing_at_limit() {
+        let mut fucker = Fucker::new(
+            Ast::parse("+[>+]").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        fucker.limit_tape(40_000);
+
+        while fucker.step() {}
+
+        assert_eq!(fucker.dp(), 40_000);
+        assert_eq!(fucker.memory().len(), 40_000);
+        assert!(fucker.take_error().is_some());
+    }
+
+    #[test]
+    fn tape_limit_below_initial_memory() {
+        let mut fucker = Fucker::new(
+            Ast::parse(">>>>+").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        fucker.limit_tape(4);
+
+        while fucker.step() {}
+
+        assert_eq!(fucker.dp(), 4);
+        assert_eq!(fucker.memory().len(), 4);
+    }
+
+    #[test]
+    fn wraps_around_the_tape() {
+        let mut fucker = Fucker::new(
+            Ast::parse("<+>>[-]+[-<<+>>]").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        fucker.wrap_tape();
+
+        let state = fucker.run().unwrap();
+
+        assert_eq!(state.dp, 1);
+        assert_eq!(state.memory.len(), WRAPPED_TAPE_SIZE);
+        assert_eq!(state.memory[WRAPPED_TAPE_SIZE - 1], 2);
+    }
+
+    #[test]
+    fn applies_cell_overflow_policy() {
+        let run = |source: &str, cell_overflow| {
+            let mut fucker = Fucker::new(
+                Ast::parse(source).unwrap().data,
+                Box::new(io::empty()),
+                Box::new(io::sink()),
+                Instrumentation::default(),
+            );
+            fucker.set_cell_overflow(cell_overflow);
+
+            fucker.run().map(|state| state.memory[0])
+        };
+        // Goes below 0, then adds 3.
+        let below = "-->+++[-<+>]<";
+        // Adds 2 to 255.
+        let above = format!("{}>++[-<+>]<", "+".repeat(255));
+
+        assert_eq!(run(below, CellOverflow::Wrap), Ok(1));
+        assert_eq!(run(below, CellOverflow::Saturate), Ok(3));
+        assert_eq!(
+            run(below, CellOverflow::Error),
+            Err("Cell 0 overflowed.".to_string())
+        );
+        assert_eq!(run(&above, CellOverflow::Wrap), Ok(1));
+        assert_eq!(run(&above, CellOverflow::Saturate), Ok(255));
+        assert_eq!(
+            run(&above, CellOverflow::Error),
+            Err("Cell 0 overflowed.".to_string())
+        );
+    }
+
+    #[test]
+    fn moves_left_of_the_start() {
+        let mut fucker = Fucker::new(
+            Ast::parse("+++<<[-]+[->>-<<]").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        fucker.limit_tape(10);
+        fucker.bidirectional_tape();
+        fucker.preload(&[7]);
+
+        let state = fucker.run().unwrap();
+
+        assert_eq!(state.dp, LEFT_TAPE_SIZE - 2);
+        assert_eq!(state.memory.len(), LEFT_TAPE_SIZE + 10);
+        assert_eq!(state.memory[LEFT_TAPE_SIZE], 9);
+    }
+
+    #[test]
+    fn stops_when_output_closes() {
+        let mut fucker = Fucker::new(
+            Ast::parse("+[.]").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(ClosedPipe),
+            Instrumentation::default(),
+        );
+
+        assert!(fucker.run().unwrap().output_closed);
+    }
+
+    #[test]
+    fn keeps_state_between_runs() {
+        let mut fucker = Fucker::new(
+            Ast::parse("+>").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        fucker.keep_state();
+
+        fucker.run().unwrap();
+        let state = fucker.run().unwrap();
+        assert_eq!(state.dp, 2);
+        assert_eq!(state.memory[..2], [1, 1]);
+
+        fucker.reset();
+        let state = fucker.run().unwrap();
+        assert_eq!(state.dp, 1);
+        assert_eq!(state.memory[..2], [1, 0]);
+
+        // The clear at the start runs on what the last run left.
+        let mut fucker = Fucker::new(
+            Ast::parse_preloaded("[-]+").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        fucker.keep_state();
+
+        fucker.run().unwrap();
+        assert_eq!(fucker.run().unwrap().memory[0], 1);
+    }
+
+    #[test]
+    fn reports_progress() {
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let seen = reports.clone();
+        let mut fucker = Fucker::new(
+            Ast::parse("++.++.").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation {
+                progress: Some(Progress {
+                    every: 2,
+                    callback: Box::new(move |report| seen.borrow_mut().push(*report)),
+                }),
+                ..Instrumentation::default()
+            },
+        );
+
+        fucker.run().unwrap();
+
+        let reports = reports.borrow();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].instructions, Some(2));
+        assert_eq!(reports[0].bytes_written, 1);
+        assert_eq!(reports[1].instructions, Some(4));
+        assert_eq!(reports[1].bytes_written, 2);
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut fucker = Fucker::new(
+            Ast::parse("+[]").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation {
+                cancel: Some(cancel.clone()),
+                ..Instrumentation::default()
+            },
+        );
+
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            cancel.store(true, Ordering::Relaxed);
+        });
+
+        assert_eq!(fucker.run().err().as_deref(), Some(CANCELLED));
+        stopper.join().unwrap();
+    }
+
+    #[cfg(feature = "step-hook")]
+    #[test]
+    fn stops_at_step_hook() {
+        use std::ops::ControlFlow;
+
+        let ast = Ast::parse("+++.>+.").unwrap();
+        let shared_buffer = SharedBuffer::new();
+        let mut fucker = Fucker::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
+        // Change the first cell before it's printed, and stop before the
+        // second one is.
+        fucker.set_step_hook(|_: &Instr, pc: usize, _: usize, memory: &mut [u8]| {
+            if pc == 1 {
+                memory[0] = 7;
+            }
+
+            match pc {
+                4 => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+
+        let state = fucker.run().unwrap();
+
+        assert!(state.interrupted);
+        assert_eq!(state.location.as_deref(), Some("pc=4 Print"));
+        assert_eq!(shared_buffer.contents(), [7]);
+    }
 }
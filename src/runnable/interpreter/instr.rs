@@ -23,4 +23,26 @@ pub enum Instr {
     BeginLoop(usize),
     /// If the current memory cell is not 0, jump backward by the contained offset.
     EndLoop(usize),
+    /// Run the extension added for a command on the current memory cell.
+    Extension(char),
+}
+
+impl Instr {
+    /// Name of this instruction's variant.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instr::Incr(_) => "Incr",
+            Instr::Decr(_) => "Decr",
+            Instr::Next(_) => "Next",
+            Instr::Prev(_) => "Prev",
+            Instr::Print => "Print",
+            Instr::Read => "Read",
+            Instr::Set(_) => "Set",
+            Instr::AddTo(_) => "AddTo",
+            Instr::SubFrom(_) => "SubFrom",
+            Instr::BeginLoop(_) => "BeginLoop",
+            Instr::EndLoop(_) => "EndLoop",
+            Instr::Extension(_) => "Extension",
+        }
+    }
 }
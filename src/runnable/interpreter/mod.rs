@@ -1,4 +1,12 @@
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod bytecode;
+#[cfg(feature = "step-hook")]
+pub mod debugger;
 mod fucker;
 mod instr;
+#[cfg(feature = "step-hook")]
+pub mod step_hook;
 
 pub use self::fucker::Fucker;
+pub use self::instr::Instr;
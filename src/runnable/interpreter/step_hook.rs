@@ -0,0 +1,36 @@
+//! A hook the interpreter calls before every instruction, for tools that watch
+//! or stop a program one instruction at a time, such as debuggers and
+//! watchpoints. It is only built with the `step-hook` feature, so that the
+//! interpreter's loop doesn't even check for one otherwise.
+
+use std::ops::ControlFlow;
+
+use super::Instr;
+
+pub trait StepHook {
+    /// Called before `instr`, at `pc`, executes with the data pointer at `dp`.
+    /// Memory may be changed before the instruction sees it. Returning
+    /// `Break` stops the program without executing the instruction.
+    fn on_step(
+        &mut self,
+        instr: &Instr,
+        pc: usize,
+        dp: usize,
+        memory: &mut [u8],
+    ) -> ControlFlow<()>;
+}
+
+impl<F> StepHook for F
+where
+    F: FnMut(&Instr, usize, usize, &mut [u8]) -> ControlFlow<()>,
+{
+    fn on_step(
+        &mut self,
+        instr: &Instr,
+        pc: usize,
+        dp: usize,
+        memory: &mut [u8],
+    ) -> ControlFlow<()> {
+        self(instr, pc, dp, memory)
+    }
+}
@@ -0,0 +1,53 @@
+//! Lets a running program be stopped with Ctrl-C while still reporting where
+//! it got to.
+//!
+//! The signal handler only sets a flag. The interpreter checks it between
+//! instructions and the JIT checks it whenever compiled code calls back into
+//! Rust or enters a deferred loop, so a JIT compiled loop that never prints,
+//! reads or enters a deferred loop can't be interrupted this way. A second
+//! Ctrl-C always ends the process.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn handle_sigint(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/// Start catching SIGINT. The handler is removed after the first one, so that
+/// a program stuck waiting for input can still be killed.
+#[cfg(unix)]
+pub fn catch_sigint() -> Result<(), String> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sigint as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        if libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut()) != 0 {
+            return Err(format!(
+                "Could not catch SIGINT: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn catch_sigint() -> Result<(), String> {
+    Ok(())
+}
+
+/// Whether Ctrl-C has been pressed since SIGINT started being caught.
+pub fn requested() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Address of the flag set by Ctrl-C, for compiled code to check.
+pub fn flag() -> *const bool {
+    INTERRUPTED.as_ptr()
+}
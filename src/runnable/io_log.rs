@@ -0,0 +1,151 @@
+//! Recording and replaying of a program's I/O.
+//!
+//! A log is a flat sequence of two byte entries. The first byte of an entry is
+//! a tag describing the direction (`INPUT_TAG` for bytes read by `,`,
+//! `OUTPUT_TAG` for bytes written by `.`) and the second is the byte itself.
+//! Entries appear in the order they happened, so a log doubles as a
+//! transcript of an interactive session.
+
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::rc::Rc;
+
+/// Tag for a byte that was read by the program.
+pub const INPUT_TAG: u8 = b'<';
+/// Tag for a byte that was written by the program.
+pub const OUTPUT_TAG: u8 = b'>';
+
+/// Log destination shared between a RecordingReader and a RecordingWriter.
+pub type SharedLog = Rc<RefCell<Box<dyn Write>>>;
+
+/// Create a log file at `path`.
+pub fn create_log(path: &str) -> Result<SharedLog, String> {
+    let file = File::create(path).map_err(|e| format!("Could not create log: {:?}", e))?;
+    let writer: Box<dyn Write> = Box::new(BufWriter::new(file));
+
+    Ok(Rc::new(RefCell::new(writer)))
+}
+
+/// Open a previously recorded log and return a reader over its input bytes.
+pub fn open_replay(path: &str) -> Result<Cursor<Vec<u8>>, String> {
+    let log = fs::read(path).map_err(|e| format!("Could not read log: {:?}", e))?;
+
+    Ok(Cursor::new(input_bytes(&log)?))
+}
+
+/// Extract the bytes that were read by the program from a log.
+fn input_bytes(log: &[u8]) -> Result<Vec<u8>, String> {
+    let entries = log.chunks_exact(2);
+
+    if !entries.remainder().is_empty() {
+        return Err("Log is truncated".to_string());
+    }
+
+    let mut input = Vec::new();
+
+    for entry in entries {
+        match entry[0] {
+            INPUT_TAG => input.push(entry[1]),
+            OUTPUT_TAG => {}
+            tag => return Err(format!("Unknown log entry tag: {:#04x}", tag)),
+        }
+    }
+
+    Ok(input)
+}
+
+fn write_entries(log: &SharedLog, tag: u8, bytes: &[u8]) -> io::Result<()> {
+    let mut log = log.borrow_mut();
+
+    for byte in bytes {
+        log.write_all(&[tag, *byte])?;
+    }
+
+    Ok(())
+}
+
+/// Reader that logs every byte it hands out.
+pub struct RecordingReader {
+    inner: Box<dyn Read>,
+    log: SharedLog,
+}
+
+impl RecordingReader {
+    pub fn new(inner: Box<dyn Read>, log: SharedLog) -> Self {
+        RecordingReader { inner, log }
+    }
+}
+
+impl Read for RecordingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        write_entries(&self.log, INPUT_TAG, &buf[..count])?;
+
+        // Input is rare compared to output, and it's where interactive
+        // sessions tend to get killed. Flush so the log survives a Ctrl-C.
+        self.log.borrow_mut().flush()?;
+
+        Ok(count)
+    }
+}
+
+/// Writer that logs every byte written through it.
+pub struct RecordingWriter {
+    inner: Box<dyn Write>,
+    log: SharedLog,
+}
+
+impl RecordingWriter {
+    pub fn new(inner: Box<dyn Write>, log: SharedLog) -> Self {
+        RecordingWriter { inner, log }
+    }
+}
+
+impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        write_entries(&self.log, OUTPUT_TAG, &buf[..count])?;
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.log.borrow_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::shared_buffer::SharedBuffer;
+    use super::*;
+
+    #[test]
+    fn records_interleaved_io() {
+        let log_buffer = SharedBuffer::new();
+        let log_writer: Box<dyn Write> = Box::new(log_buffer.clone());
+        let log: SharedLog = Rc::new(RefCell::new(log_writer));
+
+        let mut reader = RecordingReader::new(Box::new(Cursor::new(b"ab".to_vec())), log.clone());
+        let mut writer = RecordingWriter::new(Box::new(io::sink()), log);
+
+        let mut buf = [0u8; 1];
+        reader.read_exact(&mut buf).unwrap();
+        writer.write_all(b"X").unwrap();
+        reader.read_exact(&mut buf).unwrap();
+
+        assert_eq!(log_buffer.get_string_content(), "<a>X<b");
+    }
+
+    #[test]
+    fn replays_only_input() {
+        assert_eq!(input_bytes(b"<a>X<b").unwrap(), b"ab");
+    }
+
+    #[test]
+    fn rejects_malformed_logs() {
+        assert!(input_bytes(b"<a>").is_err());
+        assert!(input_bytes(b"?a").is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! A buffer of machine code with jumps to labels, for code whose layout isn't
+//! known until all of it has been emitted, such as loops whose bodies are
+//! padded for alignment.
+
+use std::convert::TryInto;
+use std::ops::{Deref, DerefMut};
+
+use crate::runnable::CellOverflow;
+
+/// A position in the code, which may be jumped to before it is reached.
+#[derive(Clone, Copy, Debug)]
+pub struct Label(usize);
+
+/// What compiled code knows about the current cell at some point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CellState {
+    Unknown,
+    /// The zero flag is set exactly when the current cell is zero.
+    Flagged,
+    Known(u8),
+}
+
+/// Machine code along with the jumps that still need their targets filled in.
+///
+/// Instructions that don't jump to labels are pushed onto the underlying
+/// bytes directly, so every code generation function works on an assembler.
+#[derive(Default)]
+pub struct Assembler {
+    bytes: Vec<u8>,
+    /// Offset each label was bound to, if it has been
+    labels: Vec<Option<usize>>,
+    /// Offsets of 32-bit displacements along with the labels they point to
+    fixups: Vec<(usize, Label)>,
+    /// What is known about the current cell, and the offset it is known at
+    cell: Option<(usize, CellState)>,
+    /// Where the code for each part of the program starts, along with a
+    /// description of it, when it is being recorded
+    annotations: Option<Vec<(usize, String)>>,
+    /// What arithmetic that carries a cell out of its range does
+    cell_overflow: CellOverflow,
+    /// Jumps taken when a cell overflows, each with the offset of the cell
+    /// from the data pointer, for the code that stops the program to be
+    /// emitted out of the way at the end
+    traps: Vec<(Label, isize)>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like `new`, but recording what is passed to `annotate`.
+    pub fn annotated() -> Self {
+        Assembler {
+            annotations: Some(Vec::new()),
+            ..Self::default()
+        }
+    }
+
+    /// Whether annotations are being recorded, for callers that have to do
+    /// work to describe the code.
+    pub fn annotating(&self) -> bool {
+        self.annotations.is_some()
+    }
+
+    /// Note that the code emitted next comes from what `describe` returns.
+    pub fn annotate(&mut self, describe: impl FnOnce() -> String) {
+        let offset = self.bytes.len();
+
+        if let Some(ref mut annotations) = self.annotations {
+            annotations.push((offset, describe()));
+        }
+    }
+
+    /// Take the annotations recorded so far, each with the offset of the code
+    /// it describes.
+    pub fn take_annotations(&mut self) -> Vec<(usize, String)> {
+        self.annotations
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Make cell arithmetic emitted from now on behave as `cell_overflow`
+    /// says when it carries.
+    pub fn set_cell_overflow(&mut self, cell_overflow: CellOverflow) {
+        self.cell_overflow = cell_overflow;
+    }
+
+    pub fn cell_overflow(&self) -> CellOverflow {
+        self.cell_overflow
+    }
+
+    /// A label to jump to when the cell `offset` cells from the data pointer
+    /// overflows.
+    pub fn trap(&mut self, offset: isize) -> Label {
+        let label = self.label();
+        self.traps.push((label, offset));
+
+        label
+    }
+
+    /// Take the traps created so far, for their code to be emitted.
+    pub fn take_traps(&mut self) -> Vec<(Label, isize)> {
+        std::mem::take(&mut self.traps)
+    }
+
+    /// Create a label to be bound later.
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+
+        Label(self.labels.len() - 1)
+    }
+
+    /// Make `label` point at the next byte emitted.
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "Label bound twice");
+
+        self.labels[label.0] = Some(self.bytes.len());
+        // Jumps here may come from anywhere.
+        self.cell = None;
+    }
+
+    /// Record what the code emitted so far leaves known about the current
+    /// cell. Emitting anything else forgets it.
+    pub fn note(&mut self, state: CellState) {
+        self.cell = Some((self.bytes.len(), state));
+    }
+
+    /// What is known about the current cell at this point.
+    pub fn cell(&self) -> CellState {
+        match self.cell {
+            Some((offset, state)) if offset == self.bytes.len() => state,
+            _ => CellState::Unknown,
+        }
+    }
+
+    /// Emit a 32-bit displacement to `label`, relative to the end of the
+    /// displacement.
+    pub fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.bytes.len(), label));
+        self.bytes.extend([0; 4]);
+    }
+
+    /// Fill in the displacements and return the finished code.
+    pub fn finish(mut self) -> Vec<u8> {
+        for (offset, label) in self.fixups {
+            let target = self.labels[label.0].expect("Jump to a label that was never bound");
+            let displacement: i32 = (target as isize - (offset + 4) as isize)
+                .try_into()
+                .expect("jump was more than 32 bits");
+
+            self.bytes[offset..offset + 4].copy_from_slice(&displacement.to_ne_bytes());
+        }
+
+        self.bytes
+    }
+}
+
+impl Deref for Assembler {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.bytes
+    }
+}
+
+impl DerefMut for Assembler {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_jumps_both_ways() {
+        let mut asm = Assembler::new();
+        let back = asm.label();
+        let forward = asm.label();
+
+        asm.bind(back);
+        asm.push(0x90);
+        asm.rel32(forward);
+        asm.rel32(back);
+        asm.bind(forward);
+
+        assert_eq!(asm.finish(), [0x90, 4, 0, 0, 0, 0xf7, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn forgets_cell_state() {
+        let mut asm = Assembler::new();
+        asm.note(CellState::Known(0));
+        assert_eq!(asm.cell(), CellState::Known(0));
+
+        asm.push(0x90);
+        assert_eq!(asm.cell(), CellState::Unknown);
+
+        asm.note(CellState::Flagged);
+        let label = asm.label();
+        asm.bind(label);
+        assert_eq!(asm.cell(), CellState::Unknown);
+    }
+
+    #[test]
+    fn records_annotations_when_asked() {
+        let mut asm = Assembler::new();
+        asm.annotate(|| unreachable!());
+        assert!(asm.take_annotations().is_empty());
+
+        let mut asm = Assembler::annotated();
+        asm.annotate(|| "+".to_string());
+        asm.push(0x90);
+        asm.annotate(|| ".".to_string());
+        assert_eq!(
+            asm.take_annotations(),
+            [(0, "+".to_string()), (1, ".".to_string())]
+        );
+    }
+}
@@ -1,4 +1,20 @@
+mod assembler;
 mod x86_64;
 
+pub use self::assembler::{Assembler, Label};
+
 #[cfg(target_arch = "x86_64")]
 pub use self::x86_64::*;
+
+/// An update to a single cell, made relative to the data pointer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CellOp {
+    Incr(u8),
+    Decr(u8),
+    Set(u8),
+    /// Add the cell to the one this many cells further along, then zero it.
+    AddTo(isize),
+    /// Subtract the cell from the one this many cells further along, then
+    /// zero it.
+    SubFrom(isize),
+}
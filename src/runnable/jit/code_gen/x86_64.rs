@@ -1,10 +1,16 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
 use super::super::jit_promise::JITPromiseID;
 use super::super::jit_target::VTableEntry;
+use super::assembler::{Assembler, CellState, Label};
+use super::CellOp;
+use crate::runnable::CellOverflow;
 
 pub const RET: u8 = 0xc3;
 const PTR_BYTES: u8 = 8;
+/// Boundary the start of loop bodies, where back edges land, is aligned to,
+/// which keeps small loops inside as few instruction fetch blocks as possible.
+const LOOP_ALIGNMENT: usize = 16;
 
 fn callee_save_to_stack(bytes: &mut Vec<u8>) {
     // push   rbx
@@ -39,7 +45,9 @@ fn callee_save_to_stack(bytes: &mut Vec<u8>) {
     bytes.push(0x57);
 }
 
-pub fn wrapper(bytes: &mut Vec<u8>, content: Vec<u8>) {
+/// Start compiled code that takes the data pointer, JITTarget and vtable as
+/// its arguments. Code between this and `epilogue` makes up the wrapper.
+pub fn prologue(bytes: &mut Vec<u8>) {
     callee_save_to_stack(bytes);
 
     // Store pointer to brainfuck memory (first argument) in r10
@@ -60,9 +68,16 @@ pub fn wrapper(bytes: &mut Vec<u8>, content: Vec<u8>) {
     bytes.push(0x89);
     bytes.push(0xd4);
 
-    bytes.extend(content);
+    // Keep the stack pointer for leaving from anywhere in the wrapper after a
+    // fault, which RBP is saved across calls for.
+    // mov    rbp,rsp
+    bytes.push(0x48);
+    bytes.push(0x89);
+    bytes.push(0xe5);
+}
 
-    // Return the data pointer
+/// Return the data pointer to the caller of the wrapper.
+pub fn epilogue(bytes: &mut Vec<u8>) {
     // mov    rax,r10
     bytes.push(0x4c);
     bytes.push(0x89);
@@ -74,6 +89,59 @@ pub fn wrapper(bytes: &mut Vec<u8>, content: Vec<u8>) {
     bytes.push(RET);
 }
 
+/// Report a cell left of the tape being touched and return from the wrapper.
+/// Run by the SIGSEGV handler in `guard`, which first puts the stack back to
+/// holding nothing but the registers saved by the wrapper.
+pub fn underflow(bytes: &mut Vec<u8>) {
+    fn_call_pre(bytes);
+
+    // Move the JITTarget pointer into the first argument register
+    // mov    rdi,r11
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xdf);
+
+    call_vtable_entry(bytes, VTableEntry::Underflow);
+
+    fn_call_post(bytes);
+    epilogue(bytes);
+}
+
+/// Return from the wrapper early if the byte at `flag` is non-zero.
+///
+/// Only valid between AstNodes, where the stack holds nothing but the
+/// registers saved by the wrapper.
+pub fn return_if_set(bytes: &mut Vec<u8>, flag: *const bool) {
+    let flag_bytes = (flag as usize).to_ne_bytes();
+
+    // movabs rax,flag
+    bytes.push(0x48);
+    bytes.push(0xb8);
+    bytes.push(flag_bytes[0]);
+    bytes.push(flag_bytes[1]);
+    bytes.push(flag_bytes[2]);
+    bytes.push(flag_bytes[3]);
+    bytes.push(flag_bytes[4]);
+    bytes.push(flag_bytes[5]);
+    bytes.push(flag_bytes[6]);
+    bytes.push(flag_bytes[7]);
+
+    // cmp    BYTE PTR [rax],0x0
+    bytes.push(0x80);
+    bytes.push(0x38);
+    bytes.push(0x00);
+
+    let mut early_return = Vec::new();
+    epilogue(&mut early_return);
+
+    // Skip over the early return if the flag is clear.
+    // je    early_return.len()
+    bytes.push(0x74);
+    bytes.push(early_return.len() as u8);
+
+    bytes.extend(early_return);
+}
+
 fn callee_restore_from_stack(bytes: &mut Vec<u8>) {
     // pop    r15
     bytes.push(0x41);
@@ -107,48 +175,198 @@ fn callee_restore_from_stack(bytes: &mut Vec<u8>) {
     bytes.push(0x5b);
 }
 
-pub fn decr(bytes: &mut Vec<u8>, n: u8) {
-    // sub    BYTE PTR [r10],n
-    bytes.push(0x41);
-    bytes.push(0x80);
-    bytes.push(0x2a);
-    bytes.push(n);
+pub fn decr(asm: &mut Assembler, n: u8) {
+    decr_at(asm, 0, n);
 }
 
-pub fn incr(bytes: &mut Vec<u8>, n: u8) {
-    // add    BYTE PTR [r10],n
-    bytes.push(0x41);
-    bytes.push(0x80);
-    bytes.push(0x02);
-    bytes.push(n);
+pub fn incr(asm: &mut Assembler, n: u8) {
+    incr_at(asm, 0, n);
+}
+
+/// Push an instruction on the cell `offset` cells from the data pointer: a
+/// REX.B prefix, `opcode`, then a ModRM byte with `reg` in the reg field.
+/// Offsets too large for a displacement are put in RCX first.
+fn cell_instruction(asm: &mut Assembler, opcode: &[u8], reg: u8, offset: isize) {
+    let displacement = i32::try_from(offset);
+
+    if displacement.is_err() {
+        // movabs rcx,offset
+        asm.push(0x48);
+        asm.push(0xb9);
+        push_imm64(asm, offset as u64);
+    }
+
+    asm.push(0x41);
+    asm.extend(opcode);
+
+    match displacement {
+        // [r10]
+        Ok(0) => asm.push(reg << 3 | 0x02),
+        // [r10+offset]
+        Ok(displacement) => {
+            asm.push(0x80 | reg << 3 | 0x02);
+            push_imm32(asm, displacement as u32);
+        }
+        // [r10+rcx]
+        Err(_) => {
+            asm.push(reg << 3 | 0x04);
+            asm.push(0x0a);
+        }
+    }
+}
+
+/// Follow arithmetic on the cell `offset` cells from the data pointer with
+/// what happens if it carried, as the assembler's `CellOverflow` says: jump to
+/// a trap, or have `saturate` set the cell to the limit it went past.
+fn on_carry(asm: &mut Assembler, offset: isize, saturate: impl FnOnce(&mut Assembler)) {
+    match asm.cell_overflow() {
+        CellOverflow::Wrap => {}
+        CellOverflow::Error => {
+            let trap = asm.trap(offset);
+
+            // jc    trap
+            asm.push(0x0f);
+            asm.push(0x82);
+            asm.rel32(trap);
+        }
+        CellOverflow::Saturate => {
+            let done = asm.label();
+
+            // jnc   done
+            asm.push(0x0f);
+            asm.push(0x83);
+            asm.rel32(done);
+
+            saturate(asm);
+            asm.bind(done);
+        }
+    }
+}
+
+/// Note that the flags hold the current cell after arithmetic on it, unless
+/// saturating may have changed the cell since.
+fn note_flagged(asm: &mut Assembler, offset: isize) {
+    if offset == 0 && asm.cell_overflow() != CellOverflow::Saturate {
+        asm.note(CellState::Flagged);
+    }
+}
+
+fn decr_at(asm: &mut Assembler, offset: isize, n: u8) {
+    // sub    BYTE PTR [r10+offset],n
+    cell_instruction(asm, &[0x80], 5, offset);
+    asm.push(n);
+
+    on_carry(asm, offset, |asm| set_at(asm, offset, 0));
+    note_flagged(asm, offset);
+}
+
+fn incr_at(asm: &mut Assembler, offset: isize, n: u8) {
+    // add    BYTE PTR [r10+offset],n
+    cell_instruction(asm, &[0x80], 0, offset);
+    asm.push(n);
+
+    on_carry(asm, offset, |asm| set_at(asm, offset, u8::MAX));
+    note_flagged(asm, offset);
 }
 
 pub fn next(bytes: &mut Vec<u8>, n: usize) {
-    let n_u32: u32 = n.try_into().expect("n was more than 32 bits");
-    let n_bytes = n_u32.to_ne_bytes();
+    match i32::try_from(n) {
+        Ok(n) => {
+            // add    r10,n
+            bytes.push(0x49);
+            bytes.push(0x81);
+            bytes.push(0xc2);
+            push_imm32(bytes, n as u32);
+        }
+        Err(_) => {
+            // The immediate would be sign extended, so use a register.
+            // movabs rax,n
+            bytes.push(0x48);
+            bytes.push(0xb8);
+            push_imm64(bytes, n as u64);
+
+            // add    r10,rax
+            bytes.push(0x49);
+            bytes.push(0x01);
+            bytes.push(0xc2);
+        }
+    }
+}
+
+pub fn prev(bytes: &mut Vec<u8>, n: usize) {
+    match i32::try_from(n) {
+        Ok(n) => {
+            // sub    r10,n
+            bytes.push(0x49);
+            bytes.push(0x81);
+            bytes.push(0xea);
+            push_imm32(bytes, n as u32);
+        }
+        Err(_) => {
+            // The immediate would be sign extended, so use a register.
+            // movabs rax,n
+            bytes.push(0x48);
+            bytes.push(0xb8);
+            push_imm64(bytes, n as u64);
+
+            // sub    r10,rax
+            bytes.push(0x49);
+            bytes.push(0x29);
+            bytes.push(0xc2);
+        }
+    }
+}
+
+/// Push the low 32 bits of an immediate.
+fn push_imm32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend(value.to_ne_bytes());
+}
+
+/// Push a full 64-bit immediate.
+fn push_imm64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend(value.to_ne_bytes());
+}
+
+/// Move the data pointer `n` cells right around a tape of `size` cells. `size`
+/// must be a power of two and the tape must be aligned to it, so that the
+/// index of the current cell is the low bits of the data pointer.
+pub fn next_wrapping(bytes: &mut Vec<u8>, n: usize, size: usize) {
+    let mask = (size - 1) as u32;
+
+    // Keep the start of the tape.
+    // mov    rax,r10
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xd0);
+
+    // and    rax,-size
+    bytes.push(0x48);
+    bytes.push(0x25);
+    push_imm32(bytes, !mask);
 
     // add    r10,n
     bytes.push(0x49);
     bytes.push(0x81);
     bytes.push(0xc2);
-    bytes.push(n_bytes[0]);
-    bytes.push(n_bytes[1]);
-    bytes.push(n_bytes[2]);
-    bytes.push(n_bytes[3]);
-}
+    push_imm32(bytes, (n % size) as u32);
 
-pub fn prev(bytes: &mut Vec<u8>, n: usize) {
-    let n_u32: u32 = n.try_into().expect("n was more than 32 bits");
-    let n_bytes = n_u32.to_ne_bytes();
-
-    // sub    r10,n
+    // Wrap the index and put the start of the tape back.
+    // and    r10,size-1
     bytes.push(0x49);
     bytes.push(0x81);
-    bytes.push(0xea);
-    bytes.push(n_bytes[0]);
-    bytes.push(n_bytes[1]);
-    bytes.push(n_bytes[2]);
-    bytes.push(n_bytes[3]);
+    bytes.push(0xe2);
+    push_imm32(bytes, mask);
+
+    // or     r10,rax
+    bytes.push(0x49);
+    bytes.push(0x09);
+    bytes.push(0xc2);
+}
+
+/// Move the data pointer `n` cells left around a tape of `size` cells, with
+/// the same requirements as `next_wrapping`.
+pub fn prev_wrapping(bytes: &mut Vec<u8>, n: usize, size: usize) {
+    next_wrapping(bytes, size - n % size, size);
 }
 
 fn fn_call_pre(bytes: &mut Vec<u8>) {
@@ -166,9 +384,24 @@ fn fn_call_pre(bytes: &mut Vec<u8>) {
     // push   r12
     bytes.push(0x41);
     bytes.push(0x54);
+
+    // Three pushes leave the stack 8 bytes short of the 16 byte alignment the
+    // System V ABI requires at a call instruction.
+    // sub    rsp,0x8
+    bytes.push(0x48);
+    bytes.push(0x83);
+    bytes.push(0xec);
+    bytes.push(0x08);
 }
 
 fn fn_call_post(bytes: &mut Vec<u8>) {
+    // Undo the alignment padding from fn_call_pre
+    // add    rsp,0x8
+    bytes.push(0x48);
+    bytes.push(0x83);
+    bytes.push(0xc4);
+    bytes.push(0x08);
+
     // Pop vtable pointer from the stack
     // pop    r12
     bytes.push(0x41);
@@ -237,134 +470,803 @@ pub fn read(bytes: &mut Vec<u8>) {
     bytes.push(0x02);
 }
 
-pub fn set(bytes: &mut Vec<u8>, value: u8) {
-    // Set current memory cell to the value
-    // mov    BYTE PTR [r10],value
-    bytes.push(0x41);
-    bytes.push(0xc6);
-    bytes.push(0x02);
-    bytes.push(value);
+/// Call the extension registered for `command` on the current cell.
+pub fn extension(bytes: &mut Vec<u8>, command: u32) {
+    fn_call_pre(bytes);
+
+    // Move the JITTarget pointer into the first argument register
+    // mov    rdi,r11
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xdf);
+
+    // Move the command into the second argument register
+    // mov    esi,command
+    bytes.push(0xbe);
+    push_imm32(bytes, command);
+
+    // Move the data pointer into the third argument register
+    // mov    rdx,r10
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xd2);
+
+    call_vtable_entry(bytes, VTableEntry::Extension);
+
+    fn_call_post(bytes);
 }
 
-pub fn add(bytes: &mut Vec<u8>, offset: isize) {
-    // Copy the current cell into EAX.
-    // movzx  eax,BYTE PTR [r10]
-    bytes.push(0x41);
-    bytes.push(0x0f);
-    bytes.push(0xb6);
-    bytes.push(0x02);
+pub fn instrument(bytes: &mut Vec<u8>, location: usize) {
+    fn_call_pre(bytes);
 
-    let offset_bytes = offset.to_ne_bytes();
+    // Move the JITTarget pointer into the first argument register
+    // mov    rdi,r11
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xdf);
 
-    // Set r13 to the offset.
-    // movabs r13,offset
-    bytes.push(0x49);
-    bytes.push(0xbd);
-    bytes.push(offset_bytes[0]);
-    bytes.push(offset_bytes[1]);
-    bytes.push(offset_bytes[2]);
-    bytes.push(offset_bytes[3]);
-    bytes.push(offset_bytes[4]);
-    bytes.push(offset_bytes[5]);
-    bytes.push(offset_bytes[6]);
-    bytes.push(offset_bytes[7]);
-
-    // Add the current cell (now in EAX) to the cell at the offset.
-    // add    BYTE PTR [r10+r13],al
-    bytes.push(0x43);
-    bytes.push(0x00);
-    bytes.push(0x04);
-    bytes.push(0x2a);
+    let location_bytes = location.to_ne_bytes();
 
-    // Set the current memory cell to 0.
-    // mov    BYTE PTR [r10],0
-    bytes.push(0x41);
-    bytes.push(0xc6);
-    bytes.push(0x02);
+    // Move the location ID into the second argument register
+    // movabs rsi,location
+    bytes.push(0x48);
+    bytes.push(0xbe);
+    bytes.push(location_bytes[0]);
+    bytes.push(location_bytes[1]);
+    bytes.push(location_bytes[2]);
+    bytes.push(location_bytes[3]);
+    bytes.push(location_bytes[4]);
+    bytes.push(location_bytes[5]);
+    bytes.push(location_bytes[6]);
+    bytes.push(location_bytes[7]);
+
+    // Move the data pointer into the third argument register
+    // mov    rdx,r10
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xd2);
+
+    call_vtable_entry(bytes, VTableEntry::Instrument);
+
+    fn_call_post(bytes);
+}
+
+/// Emit the code the assembler's traps jump to, which reports the cell that
+/// overflowed and returns from the wrapper. Only valid after the last code
+/// that falls through, since it is out of the way of everything else.
+pub fn traps(asm: &mut Assembler) {
+    for (trap, offset) in asm.take_traps() {
+        asm.bind(trap);
+        fn_call_pre(asm);
+
+        // Move the JITTarget pointer into the first argument register
+        // mov    rdi,r11
+        asm.push(0x4c);
+        asm.push(0x89);
+        asm.push(0xdf);
+
+        // Move the data pointer into the second argument register
+        // mov    rsi,r10
+        asm.push(0x4c);
+        asm.push(0x89);
+        asm.push(0xd6);
+
+        // Move the offset of the cell into the third argument register
+        // movabs rdx,offset
+        asm.push(0x48);
+        asm.push(0xba);
+        push_imm64(asm, offset as u64);
+
+        call_vtable_entry(asm, VTableEntry::Overflow);
+
+        fn_call_post(asm);
+        epilogue(asm);
+    }
+}
+
+/// Call back into Rust to grow memory if the cell `offset` cells right of the
+/// data pointer is at or past `tape_end`, taking the moved data pointer that
+/// comes back. Returns from the wrapper if the callback set `flag`.
+///
+/// Only valid between AstNodes, like `return_if_set`.
+pub fn grow_if_past_end(
+    bytes: &mut Vec<u8>,
+    tape_end: *const usize,
+    offset: usize,
+    flag: *const bool,
+) {
+    let tape_end_bytes = (tape_end as usize).to_ne_bytes();
+
+    // movabs rax,tape_end
+    bytes.push(0x48);
+    bytes.push(0xb8);
+    bytes.push(tape_end_bytes[0]);
+    bytes.push(tape_end_bytes[1]);
+    bytes.push(tape_end_bytes[2]);
+    bytes.push(tape_end_bytes[3]);
+    bytes.push(tape_end_bytes[4]);
+    bytes.push(tape_end_bytes[5]);
+    bytes.push(tape_end_bytes[6]);
+    bytes.push(tape_end_bytes[7]);
+
+    // Find the cell that has to be in memory.
+    // mov    rcx,r10
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xd1);
+
+    match i32::try_from(offset) {
+        Ok(0) => {}
+        Ok(offset) => {
+            // add    rcx,offset
+            bytes.push(0x48);
+            bytes.push(0x81);
+            bytes.push(0xc1);
+            push_imm32(bytes, offset as u32);
+        }
+        Err(_) => {
+            // movabs rdx,offset
+            bytes.push(0x48);
+            bytes.push(0xba);
+            push_imm64(bytes, offset as u64);
+
+            // add    rcx,rdx
+            bytes.push(0x48);
+            bytes.push(0x01);
+            bytes.push(0xd1);
+        }
+    }
+
+    // cmp    rcx,QWORD PTR [rax]
+    bytes.push(0x48);
+    bytes.push(0x3b);
+    bytes.push(0x08);
+
+    let mut slow_path = Vec::new();
+    fn_call_pre(&mut slow_path);
+
+    // Move the JITTarget pointer into the first argument register
+    // mov    rdi,r11
+    slow_path.push(0x4c);
+    slow_path.push(0x89);
+    slow_path.push(0xdf);
+
+    // Move the data pointer into the second argument register
+    // mov    rsi,r10
+    slow_path.push(0x4c);
+    slow_path.push(0x89);
+    slow_path.push(0xd6);
+
+    // Move the cell that has to be in memory into the third argument register
+    // mov    rdx,rcx
+    slow_path.push(0x48);
+    slow_path.push(0x89);
+    slow_path.push(0xca);
+
+    call_vtable_entry(&mut slow_path, VTableEntry::Grow);
+
+    fn_call_post(&mut slow_path);
+
+    // Take return value and store as the new data pointer
+    // mov    r10,rax
+    slow_path.push(0x49);
+    slow_path.push(0x89);
+    slow_path.push(0xc2);
+
+    return_if_set(&mut slow_path, flag);
+
+    // Skip growing memory if the cell is already in it.
+    // jb    slow_path.len()
+    bytes.push(0x72);
+    bytes.push(slow_path.len() as u8);
+
+    bytes.extend(slow_path);
+}
+
+/// Call back into Rust to report `location` and return from the wrapper if
+/// the cell `offset` cells from the data pointer is left of `tape_start`.
+/// Other cells than the current one are only the targets of transfers, which
+/// don't touch them when the current cell is zero, so are only checked when it
+/// isn't.
+///
+/// Only valid between AstNodes, like `return_if_set`.
+pub fn underflow_if_before_start(
+    bytes: &mut Vec<u8>,
+    tape_start: *const usize,
+    offset: isize,
+    location: usize,
+) {
+    let mut check = Vec::new();
+
+    // movabs rax,tape_start
+    check.push(0x48);
+    check.push(0xb8);
+    push_imm64(&mut check, tape_start as u64);
+
+    // Find the cell that has to be in memory.
+    // mov    rcx,r10
+    check.push(0x4c);
+    check.push(0x89);
+    check.push(0xd1);
+
+    match i32::try_from(offset) {
+        Ok(0) => {}
+        Ok(offset) => {
+            // add    rcx,offset
+            check.push(0x48);
+            check.push(0x81);
+            check.push(0xc1);
+            push_imm32(&mut check, offset as u32);
+        }
+        Err(_) => {
+            // movabs rdx,offset
+            check.push(0x48);
+            check.push(0xba);
+            push_imm64(&mut check, offset as u64);
+
+            // add    rcx,rdx
+            check.push(0x48);
+            check.push(0x01);
+            check.push(0xd1);
+        }
+    }
+
+    // cmp    rcx,QWORD PTR [rax]
+    check.push(0x48);
+    check.push(0x3b);
+    check.push(0x08);
+
+    let mut slow_path = Vec::new();
+    fn_call_pre(&mut slow_path);
+
+    // Move the JITTarget pointer into the first argument register
+    // mov    rdi,r11
+    slow_path.push(0x4c);
+    slow_path.push(0x89);
+    slow_path.push(0xdf);
+
+    // Move the location ID into the second argument register
+    // movabs rsi,location
+    slow_path.push(0x48);
+    slow_path.push(0xbe);
+    push_imm64(&mut slow_path, location as u64);
+
+    call_vtable_entry(&mut slow_path, VTableEntry::FarUnderflow);
+
+    fn_call_post(&mut slow_path);
+    epilogue(&mut slow_path);
+
+    // Carry on if the cell is in memory.
+    // jae    slow_path.len()
+    check.push(0x73);
+    check.push(slow_path.len() as u8);
+
+    check.extend(slow_path);
+
+    if offset != 0 {
+        // cmp    BYTE PTR [r10],0x0
+        bytes.push(0x41);
+        bytes.push(0x80);
+        bytes.push(0x3a);
+        bytes.push(0x00);
+
+        // Skip the check if the current cell is zero.
+        // je     check.len()
+        bytes.push(0x74);
+        bytes.push(check.len() as u8);
+    }
+
+    bytes.extend(check);
+}
+
+pub fn count(bytes: &mut Vec<u8>, counter: *mut u64) {
+    let counter_bytes = (counter as usize).to_ne_bytes();
+
+    // Load the address of the counter.
+    // movabs rax,counter
+    bytes.push(0x48);
+    bytes.push(0xb8);
+    bytes.push(counter_bytes[0]);
+    bytes.push(counter_bytes[1]);
+    bytes.push(counter_bytes[2]);
+    bytes.push(counter_bytes[3]);
+    bytes.push(counter_bytes[4]);
+    bytes.push(counter_bytes[5]);
+    bytes.push(counter_bytes[6]);
+    bytes.push(counter_bytes[7]);
+
+    // inc    QWORD PTR [rax]
+    bytes.push(0x48);
+    bytes.push(0xff);
     bytes.push(0x00);
 }
 
-pub fn sub(bytes: &mut Vec<u8>, offset: isize) {
-    // Copy the current cell into EAX.
-    // movzx  eax,BYTE PTR [r10]
-    bytes.push(0x41);
-    bytes.push(0x0f);
-    bytes.push(0xb6);
-    bytes.push(0x02);
+/// Load the address of a TapeStats into rcx and the index of the cell `offset`
+/// cells from the data pointer into rax, then emit `count` to run unless the
+/// cell is left of the tape, and so outside of TapeStats.
+fn tape_stats_index(
+    bytes: &mut Vec<u8>,
+    tape_stats: usize,
+    offset: isize,
+    emit_count: impl FnOnce(&mut Vec<u8>),
+) {
+    let tape_stats_bytes = tape_stats.to_ne_bytes();
+
+    // movabs rcx,tape_stats
+    bytes.push(0x48);
+    bytes.push(0xb9);
+    bytes.push(tape_stats_bytes[0]);
+    bytes.push(tape_stats_bytes[1]);
+    bytes.push(tape_stats_bytes[2]);
+    bytes.push(tape_stats_bytes[3]);
+    bytes.push(tape_stats_bytes[4]);
+    bytes.push(tape_stats_bytes[5]);
+    bytes.push(tape_stats_bytes[6]);
+    bytes.push(tape_stats_bytes[7]);
 
-    let offset_bytes = offset.to_ne_bytes();
+    // mov    rax,r10
+    bytes.push(0x4c);
+    bytes.push(0x89);
+    bytes.push(0xd0);
 
-    // Set r13 to the offset.
-    // movabs r13,offset
-    bytes.push(0x49);
-    bytes.push(0xbd);
-    bytes.push(offset_bytes[0]);
-    bytes.push(offset_bytes[1]);
-    bytes.push(offset_bytes[2]);
-    bytes.push(offset_bytes[3]);
-    bytes.push(offset_bytes[4]);
-    bytes.push(offset_bytes[5]);
-    bytes.push(offset_bytes[6]);
-    bytes.push(offset_bytes[7]);
-
-    // Add the current cell (now in EAX) to the cell at the offset.
-    // sub    BYTE PTR [r10+r13],al
-    bytes.push(0x43);
-    bytes.push(0x28);
-    bytes.push(0x04);
-    bytes.push(0x2a);
+    // Subtract TapeStats::base
+    // sub    rax,QWORD PTR [rcx]
+    bytes.push(0x48);
+    bytes.push(0x2b);
+    bytes.push(0x01);
+
+    if offset != 0 {
+        let offset: i32 = offset.try_into().expect("offset was more than 32 bits");
+
+        // add    rax,offset
+        bytes.push(0x48);
+        bytes.push(0x05);
+        push_imm32(bytes, offset as u32);
+    }
+
+    // test   rax,rax
+    bytes.push(0x48);
+    bytes.push(0x85);
+    bytes.push(0xc0);
+
+    let mut count = Vec::new();
+    emit_count(&mut count);
+
+    // js     count.len()
+    bytes.push(0x78);
+    bytes.push(count.len() as u8);
+
+    bytes.extend(count);
+}
+
+pub fn record_write(bytes: &mut Vec<u8>, tape_stats: usize, offset: isize) {
+    tape_stats_index(bytes, tape_stats, offset, |bytes| {
+        // Load TapeStats::write_counts_ptr
+        // mov    rcx,QWORD PTR [rcx+0x8]
+        bytes.push(0x48);
+        bytes.push(0x8b);
+        bytes.push(0x49);
+        bytes.push(0x08);
+
+        // Count a write to the cell.
+        // inc    QWORD PTR [rcx+rax*8]
+        bytes.push(0x48);
+        bytes.push(0xff);
+        bytes.push(0x04);
+        bytes.push(0xc1);
+    });
+}
+
+pub fn record_dp(bytes: &mut Vec<u8>, tape_stats: usize) {
+    tape_stats_index(bytes, tape_stats, 0, |bytes| {
+        // Compare with TapeStats::max_dp
+        // cmp    rax,QWORD PTR [rcx+0x10]
+        bytes.push(0x48);
+        bytes.push(0x3b);
+        bytes.push(0x41);
+        bytes.push(0x10);
+
+        // Skip the store if the data pointer has been further before.
+        // jbe    4
+        bytes.push(0x76);
+        bytes.push(0x04);
+
+        // mov    QWORD PTR [rcx+0x10],rax
+        bytes.push(0x48);
+        bytes.push(0x89);
+        bytes.push(0x41);
+        bytes.push(0x10);
+    });
+}
+
+pub fn set(asm: &mut Assembler, value: u8) {
+    set_at(asm, 0, value);
+}
+
+fn set_at(asm: &mut Assembler, offset: isize, value: u8) {
+    // Set the memory cell to the value
+    // mov    BYTE PTR [r10+offset],value
+    cell_instruction(asm, &[0xc6], 0, offset);
+    asm.push(value);
+
+    if offset == 0 {
+        asm.note(CellState::Known(value));
+    }
+}
+
+/// The value a cell saturates at when adding or subtracting (depending on
+/// `opcode`) carries.
+fn saturated(opcode: u8) -> u8 {
+    match opcode {
+        0x00 => u8::MAX,
+        _ => 0,
+    }
+}
+
+/// Add or subtract (depending on `opcode`) the cell `from` cells away to or
+/// from the cell `to` cells further, then zero the cell `from` cells away.
+fn transfer_at(asm: &mut Assembler, from: isize, to: isize, opcode: u8) {
+    // Copy the cell into EAX.
+    // movzx  eax,BYTE PTR [r10+from]
+    cell_instruction(asm, &[0x0f, 0xb6], 0, from);
+
+    // A cell left of the data pointer may be left of the tape, in the guard
+    // pages, so is only touched if the loop would have run.
+    let skip = if from + to < 0 {
+        let skip = asm.label();
+
+        // test   eax,eax
+        asm.push(0x85);
+        asm.push(0xc0);
+        // jz     skip
+        asm.push(0x0f);
+        asm.push(0x84);
+        asm.rel32(skip);
+
+        Some(skip)
+    } else {
+        None
+    };
+
+    // Add or subtract the cell (now in EAX) to the cell at the offset.
+    // add/sub BYTE PTR [r10+from+to],al
+    cell_instruction(asm, &[opcode], 0, from + to);
+
+    on_carry(asm, from + to, |asm| {
+        set_at(asm, from + to, saturated(opcode))
+    });
+
+    set_at(asm, from, 0);
+
+    if let Some(skip) = skip {
+        asm.bind(skip);
+
+        // Either way the cell moved from is zero.
+        if from == 0 {
+            asm.note(CellState::Known(0));
+        }
+    }
+}
+
+pub fn add(asm: &mut Assembler, offset: isize) {
+    transfer_at(asm, 0, offset, 0x00);
+}
+
+pub fn sub(asm: &mut Assembler, offset: isize) {
+    transfer_at(asm, 0, offset, 0x28);
+}
+
+/// Apply updates to cells at offsets from the data pointer, in order, without
+/// moving it.
+pub fn update_cells(asm: &mut Assembler, ops: &[(isize, CellOp)]) {
+    for &(offset, op) in ops {
+        match op {
+            CellOp::Incr(n) => incr_at(asm, offset, n),
+            CellOp::Decr(n) => decr_at(asm, offset, n),
+            CellOp::Set(value) => set_at(asm, offset, value),
+            CellOp::AddTo(to) => transfer_at(asm, offset, to, 0x00),
+            CellOp::SubFrom(to) => transfer_at(asm, offset, to, 0x28),
+        }
+    }
+}
+
+/// Add or subtract (depending on `opcode`) the current cell to or from the
+/// cell `offset` away around a tape of `size` cells, with the same
+/// requirements as `next_wrapping`.
+fn transfer_wrapping(asm: &mut Assembler, offset: isize, size: usize, opcode: u8) {
+    let mask = (size - 1) as u32;
+
+    // Copy the current cell into EAX.
+    // movzx  eax,BYTE PTR [r10]
+    asm.push(0x41);
+    asm.push(0x0f);
+    asm.push(0xb6);
+    asm.push(0x02);
+
+    // Find the start of the tape.
+    // mov    rcx,r10
+    asm.push(0x4c);
+    asm.push(0x89);
+    asm.push(0xd1);
+
+    // and    rcx,-size
+    asm.push(0x48);
+    asm.push(0x81);
+    asm.push(0xe1);
+    push_imm32(asm, !mask);
+
+    // Find the target cell.
+    // mov    r13,r10
+    asm.push(0x4d);
+    asm.push(0x89);
+    asm.push(0xd5);
+
+    // add    r13,offset
+    asm.push(0x49);
+    asm.push(0x81);
+    asm.push(0xc5);
+    push_imm32(asm, offset.rem_euclid(size as isize) as u32);
+
+    // and    r13,size-1
+    asm.push(0x49);
+    asm.push(0x81);
+    asm.push(0xe5);
+    push_imm32(asm, mask);
+
+    // or     r13,rcx
+    asm.push(0x49);
+    asm.push(0x09);
+    asm.push(0xcd);
+
+    // add/sub BYTE PTR [r13+0x0],al
+    asm.push(0x41);
+    asm.push(opcode);
+    asm.push(0x45);
+    asm.push(0x00);
+
+    on_carry(asm, offset, |asm| {
+        // mov    BYTE PTR [r13+0x0],saturated
+        asm.push(0x41);
+        asm.push(0xc6);
+        asm.push(0x45);
+        asm.push(0x00);
+        asm.push(saturated(opcode));
+    });
 
     // Set the current memory cell to 0.
     // mov    BYTE PTR [r10],0
-    bytes.push(0x41);
-    bytes.push(0xc6);
-    bytes.push(0x02);
-    bytes.push(0x00);
+    asm.push(0x41);
+    asm.push(0xc6);
+    asm.push(0x02);
+    asm.push(0x00);
+    asm.note(CellState::Known(0));
+}
+
+/// `add` around a tape of `size` cells.
+pub fn add_wrapping(asm: &mut Assembler, offset: isize, size: usize) {
+    transfer_wrapping(asm, offset, size, 0x00);
 }
 
-pub fn aot_loop(bytes: &mut Vec<u8>, inner_loop_bytes: Vec<u8>) {
-    let inner_loop_size = inner_loop_bytes.len() as i32;
+/// `sub` around a tape of `size` cells.
+pub fn sub_wrapping(asm: &mut Assembler, offset: isize, size: usize) {
+    transfer_wrapping(asm, offset, size, 0x28);
+}
+
+/// Multi-byte NOPs of each length from 1 to 9 bytes, as recommended by the
+/// Intel optimization manual.
+const NOPS: [&[u8]; 9] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0f, 0x1f, 0x00],
+    &[0x0f, 0x1f, 0x40, 0x00],
+    &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+/// Pad with as few NOPs as possible up to a multiple of `boundary` bytes from
+/// the start of the code. Executable memory starts on a page, so this aligns
+/// the address as well.
+pub fn align(bytes: &mut Vec<u8>, boundary: usize) {
+    let mut padding = bytes.len().next_multiple_of(boundary) - bytes.len();
+
+    while padding > 0 {
+        let nop = NOPS[padding.min(NOPS.len()) - 1];
+        bytes.extend(nop);
+        padding -= nop.len();
+    }
+}
 
-    let end_loop_size: i32 = 10; // Bytes
-    let byte_offset = inner_loop_size + end_loop_size;
+/// Compare the current cell with zero, unless the flags already have.
+fn test_cell(asm: &mut Assembler) {
+    if asm.cell() == CellState::Flagged {
+        return;
+    }
 
     // Check if the current memory cell equals zero.
     // cmp    BYTE PTR [r10],0x0
-    bytes.push(0x41);
-    bytes.push(0x80);
-    bytes.push(0x3a);
-    bytes.push(0x00);
+    asm.push(0x41);
+    asm.push(0x80);
+    asm.push(0x3a);
+    asm.push(0x00);
+}
 
-    let offset_bytes = byte_offset.to_ne_bytes();
+/// Start a loop that runs while the current cell is not zero, jumping to `end`
+/// if it is zero to begin with. Returns the label of the loop body for
+/// `loop_end` to jump back to.
+pub fn loop_start(asm: &mut Assembler, end: Label) -> Label {
+    match asm.cell() {
+        // The loop is always entered.
+        CellState::Known(value) if value != 0 => {}
+        // The loop is never entered.
+        CellState::Known(_) => jump(asm, end),
+        _ => {
+            test_cell(asm);
+
+            // Jump to the end of the loop if equal.
+            // je    end
+            asm.push(0x0f);
+            asm.push(0x84);
+            asm.rel32(end);
+        }
+    }
+
+    align(asm, LOOP_ALIGNMENT);
+
+    let body = asm.label();
+    asm.bind(body);
+
+    body
+}
 
-    // Jump to the end of the loop if equal.
-    // je    offset
-    bytes.push(0x0f);
-    bytes.push(0x84);
-    bytes.push(offset_bytes[0]);
-    bytes.push(offset_bytes[1]);
-    bytes.push(offset_bytes[2]);
-    bytes.push(offset_bytes[3]);
+/// Finish a loop started by `loop_start`.
+pub fn loop_end(asm: &mut Assembler, body: Label, end: Label) {
+    match asm.cell() {
+        // The loop never repeats.
+        CellState::Known(0) => {}
+        // The loop never ends.
+        CellState::Known(_) => jump(asm, body),
+        _ => {
+            test_cell(asm);
+
+            // Jump back to the beginning of the body if not equal.
+            // jne    body
+            asm.push(0x0f);
+            asm.push(0x85);
+            asm.rel32(body);
+        }
+    }
+
+    asm.bind(end);
+    // Every way out of a loop leaves the current cell at zero.
+    asm.note(CellState::Known(0));
+}
 
-    bytes.extend(inner_loop_bytes);
+/// Continue only if the current cell is zero, jumping to `exit` otherwise.
+pub fn guard_zero(asm: &mut Assembler, exit: Label) {
+    match asm.cell() {
+        CellState::Known(0) => {}
+        CellState::Known(_) => jump(asm, exit),
+        _ => {
+            test_cell(asm);
+
+            // Leave if not equal.
+            // jne    exit
+            asm.push(0x0f);
+            asm.push(0x85);
+            asm.rel32(exit);
+        }
+    }
+
+    asm.note(CellState::Known(0));
+}
 
-    // Check if the current memory cell equals zero.
-    // cmp    BYTE PTR [r10],0x0
-    bytes.push(0x41);
-    bytes.push(0x80);
-    bytes.push(0x3a);
-    bytes.push(0x00);
+/// Jump to `label` unconditionally.
+pub fn jump(asm: &mut Assembler, label: Label) {
+    // jmp    label
+    asm.push(0xe9);
+    asm.rel32(label);
+}
 
-    let offset_bytes = (-byte_offset).to_ne_bytes();
+/// Call a deferred loop's compiled code directly if the address at `entry` is
+/// set and `interrupted` is clear, and through `jit_loop` otherwise.
+pub fn call_loop(
+    asm: &mut Assembler,
+    loop_index: JITPromiseID,
+    entry: *const usize,
+    interrupted: *const bool,
+) {
+    let callback = asm.label();
+    let done = asm.label();
+    let entry_bytes = (entry as usize).to_ne_bytes();
+    let interrupted_bytes = (interrupted as usize).to_ne_bytes();
+
+    // movabs rax,entry
+    asm.push(0x48);
+    asm.push(0xb8);
+    asm.extend(entry_bytes);
+
+    // mov    rax,QWORD PTR [rax]
+    asm.push(0x48);
+    asm.push(0x8b);
+    asm.push(0x00);
+
+    // Go through the callback if the loop isn't compiled yet.
+    // test   rax,rax
+    asm.push(0x48);
+    asm.push(0x85);
+    asm.push(0xc0);
+
+    // je     callback
+    asm.push(0x0f);
+    asm.push(0x84);
+    asm.rel32(callback);
+
+    // movabs rcx,interrupted
+    asm.push(0x48);
+    asm.push(0xb9);
+    asm.extend(interrupted_bytes);
+
+    // Go through the callback if Ctrl-C was pressed, so it can stop the run.
+    // cmp    BYTE PTR [rcx],0x0
+    asm.push(0x80);
+    asm.push(0x39);
+    asm.push(0x00);
+
+    // jne    callback
+    asm.push(0x0f);
+    asm.push(0x85);
+    asm.rel32(callback);
+
+    // Save the registers the callback would, keeping the stack aligned.
+    // push   r11
+    asm.push(0x41);
+    asm.push(0x53);
 
-    // Jump back to the beginning of the loop if not equal.
-    // jne    offset
-    bytes.push(0x0f);
-    bytes.push(0x85);
-    bytes.push(offset_bytes[0]);
-    bytes.push(offset_bytes[1]);
-    bytes.push(offset_bytes[2]);
-    bytes.push(offset_bytes[3]);
+    // push   r12
+    asm.push(0x41);
+    asm.push(0x54);
+
+    // Call the loop with the same arguments it gets from Rust.
+    // mov    rdi,r10
+    asm.push(0x4c);
+    asm.push(0x89);
+    asm.push(0xd7);
+
+    // mov    rsi,r11
+    asm.push(0x4c);
+    asm.push(0x89);
+    asm.push(0xde);
+
+    // mov    rdx,r12
+    asm.push(0x4c);
+    asm.push(0x89);
+    asm.push(0xe2);
+
+    // call   rax
+    asm.push(0xff);
+    asm.push(0xd0);
+
+    // mov    r10,rax
+    asm.push(0x49);
+    asm.push(0x89);
+    asm.push(0xc2);
+
+    // pop    r12
+    asm.push(0x41);
+    asm.push(0x5c);
+
+    // pop    r11
+    asm.push(0x41);
+    asm.push(0x5b);
+
+    jump(asm, done);
+
+    asm.bind(callback);
+    jit_loop(asm, loop_index);
+    asm.bind(done);
 }
 
 pub fn jit_loop(bytes: &mut Vec<u8>, loop_index: JITPromiseID) {
@@ -423,3 +1325,460 @@ pub fn jit_loop(bytes: &mut Vec<u8>, loop_index: JITPromiseID) {
     bytes.push(0x41);
     bytes.push(0x5b);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::disasm;
+    use super::*;
+
+    /// Disassemble the bytes produced by `emit` into one line of Intel syntax
+    /// per instruction, with addresses starting at 0.
+    fn emitted<F: FnOnce(&mut Vec<u8>)>(emit: F) -> Vec<String> {
+        let mut bytes = Vec::new();
+        emit(&mut bytes);
+
+        disassemble(&bytes)
+    }
+
+    /// Like `emitted`, for code that jumps to labels.
+    fn assembled<F: FnOnce(&mut Assembler)>(emit: F) -> Vec<String> {
+        let mut asm = Assembler::new();
+        emit(&mut asm);
+
+        disassemble(&asm.finish())
+    }
+
+    fn disassemble(bytes: &[u8]) -> Vec<String> {
+        let lines = disasm::disassemble(bytes);
+        assert!(
+            !lines.iter().any(|line| line == "(bad)"),
+            "Invalid encoding in {:02x?}",
+            bytes
+        );
+
+        lines
+    }
+
+    #[test]
+    fn wrapper_saves_registers() {
+        assert_eq!(
+            emitted(|bytes| {
+                prologue(bytes);
+                bytes.push(0x90);
+                epilogue(bytes);
+            }),
+            vec![
+                "push rbx",
+                "push rbp",
+                "push rdi",
+                "push rsi",
+                "push rsp",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                "mov r10, rdi",
+                "mov r11, rsi",
+                "mov r12, rdx",
+                "mov rbp, rsp",
+                "nop",
+                "mov rax, r10",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop rsp",
+                "pop rsi",
+                "pop rdi",
+                "pop rbp",
+                "pop rbx",
+                "ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn cell_arithmetic() {
+        assert_eq!(
+            assembled(|asm| incr(asm, 0xff)),
+            vec!["add byte ptr [r10], 0xff"]
+        );
+        assert_eq!(
+            assembled(|asm| decr(asm, 0x80)),
+            vec!["sub byte ptr [r10], 0x80"]
+        );
+        assert_eq!(
+            assembled(|asm| set(asm, 0x7f)),
+            vec!["mov byte ptr [r10], 0x7f"]
+        );
+    }
+
+    #[test]
+    fn pointer_moves() {
+        assert_eq!(
+            emitted(|bytes| next(bytes, 0x12345678)),
+            vec!["add r10, 0x12345678"]
+        );
+        assert_eq!(emitted(|bytes| prev(bytes, 0x80)), vec!["sub r10, 0x80"]);
+
+        // Moves that don't fit in a sign extended immediate.
+        assert_eq!(
+            emitted(|bytes| next(bytes, 0x8000_0000)),
+            vec!["mov rax, 0x80000000", "add r10, rax"]
+        );
+        assert_eq!(
+            emitted(|bytes| prev(bytes, 0x1_0000_0000)),
+            vec!["mov rax, 0x100000000", "sub r10, rax"]
+        );
+    }
+
+    #[test]
+    fn growing_memory() {
+        let lines = emitted(|bytes| {
+            grow_if_past_end(bytes, 0x1000 as *const usize, 3, 0x2000 as *const bool)
+        });
+
+        assert_eq!(
+            lines[..5],
+            [
+                "mov rax, 0x1000",
+                "mov rcx, r10",
+                "add rcx, 3",
+                "cmp rcx, qword ptr [rax]",
+                "jb short 0x5e",
+            ]
+        );
+        assert!(lines.contains(&"call qword ptr [r12+0x20]".to_string()));
+        assert!(lines.contains(&"mov r10, rax".to_string()));
+        let lines = emitted(|bytes| {
+            grow_if_past_end(
+                bytes,
+                0x1000 as *const usize,
+                0x1_0000_0000,
+                0x2000 as *const bool,
+            )
+        });
+        assert_eq!(
+            lines[1..5],
+            [
+                "mov rcx, r10",
+                "mov rdx, 0x100000000",
+                "add rcx, rdx",
+                "cmp rcx, qword ptr [rax]",
+            ]
+        );
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(
+            emitted(|bytes| prev_wrapping(bytes, 1, 0x8000)),
+            vec![
+                "mov rax, r10",
+                "and rax, 0xffffffffffff8000",
+                "add r10, 0x7fff",
+                "and r10, 0x7fff",
+                "or r10, rax",
+            ]
+        );
+        assert_eq!(
+            assembled(|asm| sub_wrapping(asm, -3, 0x8000)),
+            vec![
+                "movzx eax, byte ptr [r10]",
+                "mov rcx, r10",
+                "and rcx, 0xffffffffffff8000",
+                "mov r13, r10",
+                "add r13, 0x7ffd",
+                "and r13, 0x7fff",
+                "or r13, rcx",
+                "sub byte ptr [r13], al",
+                "mov byte ptr [r10], 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn transfers() {
+        assert_eq!(
+            assembled(|asm| add(asm, -3)),
+            vec![
+                "movzx eax, byte ptr [r10]",
+                "test eax, eax",
+                "je 0x17",
+                "add byte ptr [r10-3], al",
+                "mov byte ptr [r10], 0",
+            ]
+        );
+        assert_eq!(
+            assembled(|asm| sub(asm, 0x100)),
+            vec![
+                "movzx eax, byte ptr [r10]",
+                "sub byte ptr [r10+0x100], al",
+                "mov byte ptr [r10], 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn updates_cells_at_offsets() {
+        assert_eq!(
+            assembled(|asm| update_cells(
+                asm,
+                &[
+                    (2, CellOp::Incr(1)),
+                    (-1, CellOp::Decr(2)),
+                    (0, CellOp::Set(3)),
+                    (1, CellOp::AddTo(-1)),
+                ]
+            )),
+            vec![
+                "add byte ptr [r10+2], 1",
+                "sub byte ptr [r10-1], 2",
+                "mov byte ptr [r10], 3",
+                "movzx eax, byte ptr [r10+1]",
+                "add byte ptr [r10], al",
+                "mov byte ptr [r10+1], 0",
+            ]
+        );
+        assert_eq!(
+            assembled(|asm| update_cells(
+                asm,
+                &[
+                    (0x1_0000_0000, CellOp::Incr(1)),
+                    (0, CellOp::SubFrom(-0x8000_0001)),
+                ]
+            )),
+            vec![
+                "mov rcx, 0x100000000",
+                "add byte ptr [r10+rcx], 1",
+                "movzx eax, byte ptr [r10]",
+                "test eax, eax",
+                "je 0x2d",
+                "mov rcx, 0xffffffff7fffffff",
+                "sub byte ptr [r10+rcx], al",
+                "mov byte ptr [r10], 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn io_calls() {
+        assert_eq!(
+            emitted(print),
+            vec![
+                "push r10",
+                "push r11",
+                "push r12",
+                "sub rsp, 8",
+                "mov rdi, r11",
+                "movzx rsi, byte ptr [r10]",
+                "call qword ptr [r12+0x10]",
+                "add rsp, 8",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+            ]
+        );
+        assert_eq!(
+            emitted(read),
+            vec![
+                "push r10",
+                "push r11",
+                "push r12",
+                "sub rsp, 8",
+                "mov rdi, r11",
+                "call qword ptr [r12+8]",
+                "add rsp, 8",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "mov byte ptr [r10], al",
+            ]
+        );
+    }
+
+    #[test]
+    fn instrumentation() {
+        assert_eq!(
+            emitted(|bytes| instrument(bytes, 0x1234)),
+            vec![
+                "push r10",
+                "push r11",
+                "push r12",
+                "sub rsp, 8",
+                "mov rdi, r11",
+                "mov rsi, 0x1234",
+                "mov rdx, r10",
+                "call qword ptr [r12+0x18]",
+                "add rsp, 8",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+            ]
+        );
+        assert_eq!(
+            emitted(|bytes| count(bytes, 0x1122334455667788 as *mut u64)),
+            vec!["mov rax, 0x1122334455667788", "inc qword ptr [rax]"]
+        );
+    }
+
+    #[test]
+    fn tape_stats() {
+        assert_eq!(
+            emitted(|bytes| record_write(bytes, 0x1000, -2)),
+            vec![
+                "mov rcx, 0x1000",
+                "mov rax, r10",
+                "sub rax, qword ptr [rcx]",
+                "add rax, 0xfffffffffffffffe",
+                "test rax, rax",
+                "js short 0x23",
+                "mov rcx, qword ptr [rcx+8]",
+                "inc qword ptr [rcx+rax*8]",
+            ]
+        );
+        assert_eq!(
+            emitted(|bytes| record_dp(bytes, 0x1000)),
+            vec![
+                "mov rcx, 0x1000",
+                "mov rax, r10",
+                "sub rax, qword ptr [rcx]",
+                "test rax, rax",
+                "js short 0x1f",
+                "cmp rax, qword ptr [rcx+0x10]",
+                "jbe short 0x1f",
+                "mov qword ptr [rcx+0x10], rax",
+            ]
+        );
+    }
+
+    #[test]
+    fn early_return() {
+        assert_eq!(
+            emitted(|bytes| return_if_set(bytes, 0x1000 as *const bool)),
+            vec![
+                "mov rax, 0x1000",
+                "cmp byte ptr [rax], 0",
+                "je short 0x20",
+                "mov rax, r10",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop rsp",
+                "pop rsi",
+                "pop rdi",
+                "pop rbp",
+                "pop rbx",
+                "ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn loops() {
+        // The forward jump lands just past the loop and the backward jump
+        // lands on the first instruction of the body, which is aligned.
+        assert_eq!(
+            assembled(|asm| {
+                asm.push(0x90);
+                let end = asm.label();
+                let body = loop_start(asm, end);
+                asm.push(0x90);
+                loop_end(asm, body, end);
+            }),
+            vec![
+                "nop",
+                "cmp byte ptr [r10], 0",
+                "je 0x1b",
+                "nop dword ptr [rax+rax]",
+                "nop",
+                "cmp byte ptr [r10], 0",
+                "jne 0x10",
+            ]
+        );
+        assert_eq!(
+            assembled(|asm| {
+                let end = asm.label();
+                let body = loop_start(asm, end);
+                decr(asm, 1);
+                loop_end(asm, body, end);
+
+                // The first loop leaves the cell at zero.
+                let end = asm.label();
+                let body = loop_start(asm, end);
+                incr(asm, 1);
+                loop_end(asm, body, end);
+
+                set(asm, 1);
+                let end = asm.label();
+                let body = loop_start(asm, end);
+                set(asm, 0);
+                loop_end(asm, body, end);
+            }),
+            vec![
+                "cmp byte ptr [r10], 0",
+                "je 0x1a",
+                "nop word ptr [rax+rax]",
+                "sub byte ptr [r10], 1",
+                "jne 0x10",
+                "jmp 0x2a",
+                "nop",
+                "add byte ptr [r10], 1",
+                "jne 0x20",
+                "mov byte ptr [r10], 1",
+                "xchg ax, ax",
+                "mov byte ptr [r10], 0",
+            ]
+        );
+        assert_eq!(
+            emitted(|bytes| jit_loop(bytes, 5)),
+            vec![
+                "push r11",
+                "push r12",
+                "mov rdi, r11",
+                "mov rsi, 5",
+                "mov rdx, r10",
+                "call qword ptr [r12]",
+                "mov r10, rax",
+                "pop r12",
+                "pop r11",
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_compiled_loops_directly() {
+        assert_eq!(
+            assembled(|asm| call_loop(asm, 5, 0x1000 as *const usize, 0x2000 as *const bool)),
+            vec![
+                "mov rax, 0x1000",
+                "mov rax, qword ptr [rax]",
+                "test rax, rax",
+                "je 0x44",
+                "mov rcx, 0x2000",
+                "cmp byte ptr [rcx], 0",
+                "jne 0x44",
+                "push r11",
+                "push r12",
+                "mov rdi, r10",
+                "mov rsi, r11",
+                "mov rdx, r12",
+                "call rax",
+                "mov r10, rax",
+                "pop r12",
+                "pop r11",
+                "jmp 0x64",
+                "push r11",
+                "push r12",
+                "mov rdi, r11",
+                "mov rsi, 5",
+                "mov rdx, r10",
+                "call qword ptr [r12]",
+                "mov r10, rax",
+                "pop r12",
+                "pop r11",
+            ]
+        );
+    }
+}
@@ -0,0 +1,352 @@
+//! Checks that every kind of AstNode does the same thing when JIT compiled as
+//! it does under the interpreter, across a range of operands that includes
+//! negative offsets and the edges of each immediate's encoding, and whatever
+//! cells do when they overflow. The same goes for nodes the JIT runs without
+//! compiling once they don't fit under its code limit.
+
+use std::collections::VecDeque;
+use std::io::{self, Cursor};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::jit_target::{CodeLimit, CompileOptions, JITTarget};
+use crate::parser::stream::StreamParser;
+use crate::parser::{Ast, AstNode};
+use crate::runnable::extension::Extensions;
+use crate::runnable::interpreter::Fucker;
+use crate::runnable::shared_buffer::SharedBuffer;
+use crate::runnable::{CellOverflow, Instrumentation, Runnable, CANCELLED};
+
+/// Cells in the scratch tape.
+const TAPE_LEN: usize = 4096;
+/// Starting cells: one holding a non-zero value and one holding zero.
+const STARTS: [usize; 2] = [2048, 2051];
+/// Input available to `,`.
+const INPUT: &[u8] = b"xy";
+
+/// Final memory, data pointer and output, or the error the program stopped
+/// with.
+type Outcome = Result<(Vec<u8>, usize, Vec<u8>), String>;
+
+/// A tape where every seventh cell is zero and the rest hold varied values.
+fn scratch_tape() -> Vec<u8> {
+    (0..TAPE_LEN)
+        .map(|i| if i % 7 == 0 { 0 } else { (i * 37 + 11) as u8 })
+        .collect()
+}
+
+/// Commands both backends run `Extension` nodes with: `|` rotates the cell
+/// left and `$` stops the program if it is zero.
+fn extensions() -> Extensions {
+    let mut extensions = Extensions::new();
+    extensions
+        .add('|', |cell: &mut u8| {
+            *cell = cell.rotate_left(1);
+            Ok(())
+        })
+        .unwrap();
+    extensions
+        .add('$', |cell: &mut u8| match *cell {
+            0 => Err("Cell is zero".to_string()),
+            _ => Ok(()),
+        })
+        .unwrap();
+
+    extensions
+}
+
+fn interpret(nodes: &VecDeque<AstNode>, start: usize, cell_overflow: CellOverflow) -> Outcome {
+    let output = SharedBuffer::new();
+    let mut fucker = Fucker::new(
+        nodes.clone(),
+        Box::new(Cursor::new(INPUT.to_vec())),
+        Box::new(output.clone()),
+        Instrumentation::default(),
+    );
+    fucker.set_tape(scratch_tape(), start);
+    fucker.set_cell_overflow(cell_overflow);
+    fucker.set_extensions(extensions());
+
+    while fucker.step() {}
+
+    match fucker.take_error() {
+        Some(error) => Err(error),
+        None => Ok((fucker.memory().to_vec(), fucker.dp(), output.contents())),
+    }
+}
+
+/// Run `nodes` under the JIT, compiling nothing but the call to them if
+/// `uncompiled` is set.
+fn compile_and_run(
+    nodes: &VecDeque<AstNode>,
+    start: usize,
+    cell_overflow: CellOverflow,
+    uncompiled: bool,
+) -> Outcome {
+    let output = SharedBuffer::new();
+    let code_limit = CodeLimit {
+        bytes: 0,
+        interpret: true,
+    };
+    let mut jit_target = JITTarget::with_options(
+        nodes.clone(),
+        Box::new(Cursor::new(INPUT.to_vec())),
+        Box::new(output.clone()),
+        Instrumentation::default(),
+        CompileOptions {
+            cell_overflow,
+            code_limit: Some(code_limit).filter(|_| uncompiled),
+            ..CompileOptions::default()
+        },
+    );
+    jit_target.set_extensions(extensions());
+    let mut tape = scratch_tape();
+    let dp = jit_target.run_on_tape(&mut tape, start);
+
+    match jit_target.take_error() {
+        Some(error) => Err(error),
+        None => Ok((tape, dp, output.contents())),
+    }
+}
+
+/// Run `nodes` from each starting cell under both backends.
+fn check(nodes: Vec<AstNode>) {
+    check_with(nodes, CellOverflow::Wrap);
+}
+
+/// Run `nodes` from each starting cell under both backends, with cells that
+/// overflow as `cell_overflow` says.
+fn check_with(nodes: Vec<AstNode>, cell_overflow: CellOverflow) {
+    let nodes: VecDeque<AstNode> = nodes.into();
+
+    for &start in &STARTS {
+        let interpreted = interpret(&nodes, start, cell_overflow);
+
+        for uncompiled in [false, true] {
+            let compiled = compile_and_run(&nodes, start, cell_overflow, uncompiled);
+
+            match (&interpreted, &compiled) {
+                (Ok(interpreted), Ok(compiled)) => assert!(
+                    interpreted == compiled,
+                    "{:?} starting at cell {} (uncompiled: {}): interpreter left dp={} and printed {:?}, JIT left dp={} and printed {:?}",
+                    nodes,
+                    start,
+                    uncompiled,
+                    interpreted.1,
+                    interpreted.2,
+                    compiled.1,
+                    compiled.2
+                ),
+                // Memory is left however far each backend got.
+                _ => assert_eq!(
+                    interpreted.as_ref().err(),
+                    compiled.err().as_ref(),
+                    "{:?} starting at cell {} (uncompiled: {})",
+                    nodes,
+                    start,
+                    uncompiled
+                ),
+            }
+        }
+    }
+}
+
+/// Check that `nodes` never finish under either backend, by cancelling each
+/// run after a moment.
+fn check_endless(nodes: Vec<AstNode>, cell_overflow: CellOverflow) {
+    let nodes: VecDeque<AstNode> = nodes.into();
+
+    // The interpreter, then the JIT with and without compiling.
+    for uncompiled in [None, Some(false), Some(true)] {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let timer = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::Relaxed);
+            })
+        };
+        let instrumentation = Instrumentation {
+            cancel: Some(cancel),
+            ..Instrumentation::default()
+        };
+
+        let result = match uncompiled {
+            None => {
+                let mut fucker = Fucker::new(
+                    nodes.clone(),
+                    Box::new(io::empty()),
+                    Box::new(io::sink()),
+                    instrumentation,
+                );
+                fucker.set_cell_overflow(cell_overflow);
+                fucker.run()
+            }
+            Some(uncompiled) => JITTarget::with_options(
+                nodes.clone(),
+                Box::new(io::empty()),
+                Box::new(io::sink()),
+                instrumentation,
+                CompileOptions {
+                    cell_overflow,
+                    code_limit: Some(CodeLimit {
+                        bytes: 0,
+                        interpret: true,
+                    })
+                    .filter(|_| uncompiled),
+                    ..CompileOptions::default()
+                },
+            )
+            .run(),
+        };
+        timer.join().unwrap();
+
+        assert_eq!(
+            result.err().as_deref(),
+            Some(CANCELLED),
+            "{:?} (JIT uncompiled: {:?})",
+            nodes,
+            uncompiled
+        );
+    }
+}
+
+/// Values covering both ends of a byte and the sign boundary.
+const BYTES: [u8; 6] = [0, 1, 2, 127, 128, 255];
+/// Moves and offsets around the edges of 8 and 16 bit encodings.
+const DISTANCES: [usize; 8] = [1, 2, 127, 128, 129, 255, 256, 1000];
+
+#[test]
+fn incr_and_decr() {
+    for &n in &BYTES {
+        check(vec![AstNode::Incr(n)]);
+        check(vec![AstNode::Decr(n)]);
+    }
+}
+
+#[test]
+fn next_and_prev() {
+    for &n in &DISTANCES {
+        check(vec![AstNode::Next(n)]);
+        check(vec![AstNode::Prev(n)]);
+    }
+}
+
+#[test]
+fn set() {
+    for &n in &BYTES {
+        check(vec![AstNode::Set(n)]);
+    }
+}
+
+#[test]
+fn add_to_and_sub_from() {
+    for &distance in &DISTANCES {
+        for &offset in &[distance as isize, -(distance as isize)] {
+            check(vec![AstNode::AddTo(offset)]);
+            check(vec![AstNode::SubFrom(offset)]);
+        }
+    }
+}
+
+#[test]
+fn cell_overflow() {
+    for &cell_overflow in &[CellOverflow::Error, CellOverflow::Saturate] {
+        for &n in &BYTES {
+            check_with(vec![AstNode::Incr(n)], cell_overflow);
+            check_with(vec![AstNode::Decr(n)], cell_overflow);
+        }
+
+        for &distance in &DISTANCES {
+            for &offset in &[distance as isize, -(distance as isize)] {
+                check_with(vec![AstNode::AddTo(offset)], cell_overflow);
+                check_with(vec![AstNode::SubFrom(offset)], cell_overflow);
+            }
+        }
+
+        // Updates to cells away from the data pointer, made together.
+        check_with(
+            vec![
+                AstNode::Incr(200),
+                AstNode::Next(3),
+                AstNode::Decr(100),
+                AstNode::AddTo(-3),
+                AstNode::Prev(3),
+            ],
+            cell_overflow,
+        );
+    }
+}
+
+#[test]
+fn counting_up_to_zero() {
+    // `[+]` only ends by wrapping past 255, so it isn't a clear otherwise.
+    let parse = |cell_overflow| {
+        let mut parser = StreamParser::new();
+        parser.set_cell_overflow(cell_overflow);
+        Vec::from(Ast::parse_with("+[+]", parser).unwrap().data)
+    };
+
+    check(parse(CellOverflow::Wrap));
+    check_with(parse(CellOverflow::Error), CellOverflow::Error);
+    assert!(interpret(
+        &parse(CellOverflow::Error).into(),
+        STARTS[0],
+        CellOverflow::Error
+    )
+    .is_err());
+    check_endless(parse(CellOverflow::Saturate), CellOverflow::Saturate);
+}
+
+#[test]
+fn print_and_read() {
+    check(vec![AstNode::Print]);
+    check(vec![AstNode::Read, AstNode::Print]);
+    // Reading past the end of input gives a newline.
+    check(vec![
+        AstNode::Read,
+        AstNode::Read,
+        AstNode::Read,
+        AstNode::Print,
+    ]);
+}
+
+#[test]
+fn extension_commands() {
+    check(vec![AstNode::Extension('|'), AstNode::Print]);
+    check(vec![
+        AstNode::Next(1),
+        AstNode::Extension('$'),
+        AstNode::Incr(1),
+    ]);
+    check(vec![AstNode::Extension('`')]);
+}
+
+#[test]
+fn loops() {
+    check(vec![AstNode::Loop(vec![AstNode::Decr(1)].into())]);
+    check(vec![AstNode::Loop(vec![AstNode::Next(1)].into())]);
+    check(vec![AstNode::Loop(vec![AstNode::Prev(1)].into())]);
+    check(vec![AstNode::Loop(
+        vec![
+            AstNode::Decr(1),
+            AstNode::Next(1),
+            AstNode::Loop(vec![AstNode::Decr(1), AstNode::Print].into()),
+            AstNode::Prev(1),
+        ]
+        .into(),
+    )]);
+}
+
+#[test]
+fn deferred_loops() {
+    // Long enough to be compiled into its own fragment rather than inlined.
+    let mut body = vec![AstNode::Decr(1)];
+    for _ in 0..10 {
+        body.extend(vec![AstNode::Next(1), AstNode::Incr(3), AstNode::Prev(1)]);
+    }
+
+    check(vec![AstNode::Loop(body.into())]);
+}
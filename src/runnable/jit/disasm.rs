@@ -0,0 +1,35 @@
+//! Machine code shown as Intel syntax assembly, for `--emit=asm` and for
+//! checking what the code generator emits.
+
+use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter, MemorySizeOptions};
+
+/// Disassemble `bytes` into one line per instruction, with addresses starting
+/// at 0. Bytes that don't decode give a line of `(bad)`.
+#[cfg(test)]
+pub fn disassemble(bytes: &[u8]) -> Vec<String> {
+    disassemble_with_offsets(bytes)
+        .into_iter()
+        .map(|(_, line)| line)
+        .collect()
+}
+
+/// Like `disassemble`, with the offset each instruction starts at.
+pub fn disassemble_with_offsets(bytes: &[u8]) -> Vec<(usize, String)> {
+    let mut formatter = IntelFormatter::new();
+    let options = formatter.options_mut();
+    options.set_hex_prefix("0x");
+    options.set_hex_suffix("");
+    options.set_uppercase_hex(false);
+    options.set_branch_leading_zeros(false);
+    options.set_space_after_operand_separator(true);
+    options.set_memory_size_options(MemorySizeOptions::Always);
+
+    Decoder::with_ip(64, bytes, 0, DecoderOptions::NONE)
+        .into_iter()
+        .map(|instruction| {
+            let mut line = String::new();
+            formatter.format(&instruction, &mut line);
+            (instruction.ip() as usize, line)
+        })
+        .collect()
+}
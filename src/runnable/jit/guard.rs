@@ -0,0 +1,117 @@
+//! Turns compiled code touching a cell left of the tape into an error, rather
+//! than letting it read and write whatever memory is there.
+//!
+//! JIT tapes have guard pages in front of them, and compiled code keeps the
+//! stack pointer its fragment started with in RBP. When a cell in the guard
+//! pages faults, the SIGSEGV handler sends the fragment to exit code that
+//! reports the error and returns, the same as a failed callback. Faults
+//! anywhere else are left to whatever handled SIGSEGV before.
+
+#[cfg(not(target_os = "linux"))]
+use std::ops::Range;
+
+#[cfg(target_os = "linux")]
+mod handler {
+    use std::cell::Cell;
+    use std::ops::Range;
+    use std::ptr;
+    use std::sync::Once;
+
+    /// Guard pages compiled code in a thread is running on, and the code it
+    /// is sent to when it touches them.
+    #[derive(Clone, Copy)]
+    struct Watch {
+        start: usize,
+        end: usize,
+        exit: usize,
+    }
+
+    thread_local! {
+        static WATCH: Cell<Option<Watch>> = const { Cell::new(None) };
+    }
+
+    static INSTALL: Once = Once::new();
+    /// The handler replaced by `handle_sigsegv`
+    static mut PREVIOUS: Option<libc::sigaction> = None;
+
+    extern "C" fn handle_sigsegv(
+        _signal: libc::c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut libc::c_void,
+    ) {
+        let address = unsafe { (*info).si_addr() } as usize;
+        let watch = WATCH.try_with(Cell::get).ok().flatten();
+
+        match watch {
+            Some(watch) if (watch.start..watch.end).contains(&address) => unsafe {
+                let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+                gregs[libc::REG_RSP as usize] = gregs[libc::REG_RBP as usize];
+                gregs[libc::REG_RIP as usize] = watch.exit as i64;
+            },
+            // Put the previous handler back for the access to fault again
+            // under it.
+            _ => unsafe {
+                match *ptr::addr_of!(PREVIOUS) {
+                    Some(ref previous) => libc::sigaction(libc::SIGSEGV, previous, ptr::null_mut()),
+                    None => libc::signal(libc::SIGSEGV, libc::SIG_DFL) as libc::c_int,
+                };
+            },
+        }
+    }
+
+    /// Start catching SIGSEGV, once per process. Needs to happen before
+    /// entering the sandbox, which forbids it. If the handler can't be
+    /// installed, touching the guard pages kills the process as any other
+    /// bad access would.
+    pub fn install() {
+        INSTALL.call_once(|| unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_sigsegv as *const () as libc::sighandler_t;
+            // Run on the alternate stack, if there is one, so that a stack
+            // overflow still reaches the handler that reports it.
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            // Keep the previous handler before a fault could need it.
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(libc::SIGSEGV, ptr::null(), &mut previous) != 0 {
+                return;
+            }
+            *ptr::addr_of_mut!(PREVIOUS) = Some(previous);
+
+            libc::sigaction(libc::SIGSEGV, &action, ptr::null_mut());
+        });
+    }
+
+    /// Send compiled code running in this thread that touches `guard` to
+    /// `exit`, until `unwatch` is called. `exit` is entered with the stack
+    /// pointer the fragment started with, and the fragment's registers
+    /// otherwise as they were.
+    pub fn watch(guard: Range<usize>, exit: *const u8) {
+        WATCH.with(|watch| {
+            watch.set(Some(Watch {
+                start: guard.start,
+                end: guard.end,
+                exit: exit as usize,
+            }))
+        });
+    }
+
+    /// Stop watching for compiled code touching guard pages in this thread.
+    pub fn unwatch() {
+        WATCH.with(|watch| watch.set(None));
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use self::handler::{install, unwatch, watch};
+
+/// Tapes only have guard pages on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn install() {}
+
+#[cfg(not(target_os = "linux"))]
+pub fn watch(_guard: Range<usize>, _exit: *const u8) {}
+
+#[cfg(not(target_os = "linux"))]
+pub fn unwatch() {}
@@ -1,49 +1,228 @@
 use super::code_gen;
 
-use super::immutable::Immutable;
-use std::mem;
+use std::fmt;
+use std::ops::Deref;
+use std::rc::Rc;
 use std::sync::OnceLock;
+use std::{mem, slice};
 
 use libc::{sysconf, _SC_PAGESIZE};
 
 static PAGE_SIZE: OnceLock<usize> = OnceLock::new();
 
-/// Round up an integer division.
-///
-/// * `numerator` - The upper component of a division
-/// * `denominator` - The lower component of a division
-fn int_div_ceil(numerator: usize, denominator: usize) -> usize {
-    (numerator / denominator + 1) * denominator
+/// Alignment of each piece of code packed into shared pages.
+const CODE_ALIGN: usize = 16;
+
+/// Size of a memory page, which executable memory is allocated in multiples
+/// of.
+pub fn page_size() -> usize {
+    *PAGE_SIZE.get_or_init(|| unsafe { sysconf(_SC_PAGESIZE) as usize })
+}
+
+/// Bytes of executable memory allocated to hold `length` bytes of code on
+/// pages of its own.
+fn allocation_size(length: usize) -> usize {
+    length.div_ceil(page_size()) * page_size()
 }
 
-/// Allocate a buffer of executable memory pages.
-fn allocate_buffer(length: usize) -> Vec<u8> {
-    let mut buffer = mem::MaybeUninit::<*mut libc::c_void>::uninit();
-    let buffer_ptr = buffer.as_mut_ptr();
+/// A run of executable memory pages, handed back once every piece of code
+/// placed in it is dropped.
+struct Pages {
+    ptr: *mut u8,
+    size: usize,
+}
 
-    let page_size = *PAGE_SIZE.get_or_init(|| unsafe { sysconf(_SC_PAGESIZE) as usize });
-    let buffer_size = int_div_ceil(length, page_size);
+impl Pages {
+    /// Allocate enough executable memory pages for `length` bytes of code.
+    fn allocate(length: usize) -> Self {
+        let mut buffer = mem::MaybeUninit::<*mut libc::c_void>::uninit();
+        let buffer_ptr = buffer.as_mut_ptr();
+        let size = allocation_size(length);
 
-    unsafe {
-        libc::posix_memalign(buffer_ptr, page_size, buffer_size);
-        libc::mprotect(
-            *buffer_ptr,
-            buffer_size,
-            libc::PROT_EXEC | libc::PROT_WRITE | libc::PROT_READ,
-        );
-        // for now, prepopulate with 'RET'
-        libc::memset(*buffer_ptr, code_gen::RET as i32, buffer_size);
+        unsafe {
+            libc::posix_memalign(buffer_ptr, page_size(), size);
+            libc::mprotect(
+                *buffer_ptr,
+                size,
+                libc::PROT_EXEC | libc::PROT_WRITE | libc::PROT_READ,
+            );
+            // for now, prepopulate with 'RET'
+            libc::memset(*buffer_ptr, code_gen::RET as i32, size);
+
+            Pages {
+                ptr: buffer.assume_init() as *mut u8,
+                size,
+            }
+        }
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        unsafe {
+            libc::mprotect(
+                self.ptr as *mut libc::c_void,
+                self.size,
+                libc::PROT_WRITE | libc::PROT_READ,
+            );
+            libc::free(self.ptr as *mut libc::c_void);
+        }
+    }
+}
+
+/// Code placed in executable memory. It can't be changed, since it may share
+/// its pages with other code.
+pub struct ExecutableCode {
+    pages: Rc<Pages>,
+    offset: usize,
+    length: usize,
+}
+
+impl Deref for ExecutableCode {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.pages.ptr.add(self.offset), self.length) }
+    }
+}
+
+impl fmt::Debug for ExecutableCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Executable memory that pieces of code are packed into one after another,
+/// so that small fragments share pages rather than taking one each.
+#[derive(Default)]
+pub struct ExecutableMemory {
+    /// Pages with room left for more code
+    pages: Option<Rc<Pages>>,
+    /// Bytes of `pages` already holding code
+    used: usize,
+}
+
+impl ExecutableMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        Vec::from_raw_parts(buffer.assume_init() as *mut u8, length, buffer_size)
+    /// Where `length` bytes of code would start in the pages with room left,
+    /// if they fit.
+    fn offset_for(&self, length: usize) -> Option<usize> {
+        let pages = self.pages.as_ref()?;
+        let offset = self.used.next_multiple_of(CODE_ALIGN);
+
+        Some(offset).filter(|offset| offset + length <= pages.size)
+    }
+
+    /// Bytes of executable memory that have to be allocated to place `length`
+    /// bytes of code, which is none if it fits in pages already allocated.
+    pub fn needed(&self, length: usize) -> usize {
+        match self.offset_for(length) {
+            Some(_) => 0,
+            None => allocation_size(length),
+        }
+    }
+
+    /// Copy `code` into executable memory, after the code placed before it if
+    /// there is room.
+    pub fn place(&mut self, code: &[u8]) -> ExecutableCode {
+        let (pages, offset) = match self.offset_for(code.len()) {
+            Some(offset) => (self.pages.clone().unwrap(), offset),
+            None => {
+                let pages = Rc::new(Pages::allocate(code.len()));
+                let room = self
+                    .pages
+                    .as_ref()
+                    .map_or(0, |current| current.size - self.used);
+
+                // Carry on packing into whichever pages have more room left.
+                if pages.size - code.len() > room {
+                    self.pages = Some(pages.clone());
+                    self.used = 0;
+                }
+
+                (pages, 0)
+            }
+        };
+
+        unsafe {
+            let ptr = pages.ptr.add(offset);
+            ptr.copy_from_nonoverlapping(code.as_ptr(), code.len());
+        }
+
+        if self
+            .pages
+            .as_ref()
+            .is_some_and(|current| Rc::ptr_eq(current, &pages))
+        {
+            self.used = offset + code.len();
+        }
+
+        ExecutableCode {
+            pages,
+            offset,
+            length: code.len(),
+        }
     }
 }
 
-/// Clone a slice of bytes into new executable memory pages.
-///
-/// The returned vector is immutable because re-allocation could result in lost
-/// memory protection settings.
-pub fn make_executable(source: &[u8]) -> Immutable<Vec<u8>> {
-    let mut executable = allocate_buffer(source.len());
-    executable.copy_from_slice(source);
-    Immutable::new(executable)
+/// Clone a slice of bytes into new executable memory pages of its own.
+pub fn make_executable(source: &[u8]) -> ExecutableCode {
+    ExecutableMemory::new().place(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_whole_pages() {
+        let page_size = page_size();
+        let code = vec![code_gen::RET; page_size];
+
+        let mut memory = ExecutableMemory::new();
+        assert_eq!(memory.needed(page_size), page_size);
+        assert_eq!(memory.needed(page_size + 1), page_size * 2);
+
+        let executable = memory.place(&code);
+        assert_eq!(&*executable, &code[..]);
+        assert_eq!(executable.as_ptr() as usize % page_size, 0);
+        // The page is full, so the next piece needs one of its own.
+        assert_eq!(memory.needed(1), page_size);
+    }
+
+    #[test]
+    fn packs_small_fragments() {
+        let page_size = page_size();
+        let mut memory = ExecutableMemory::new();
+
+        let first = memory.place(&[0x90, code_gen::RET]);
+        assert_eq!(memory.needed(3), 0);
+        let second = memory.place(&[0x90, 0x90, code_gen::RET]);
+
+        assert_eq!(&*first, &[0x90, code_gen::RET]);
+        assert_eq!(&*second, &[0x90, 0x90, code_gen::RET]);
+        assert_eq!(
+            second.as_ptr() as usize,
+            first.as_ptr() as usize + CODE_ALIGN
+        );
+
+        // The pages outlive the memory that placed code in them.
+        drop(memory);
+        assert_eq!(&*second, &[0x90, 0x90, code_gen::RET]);
+
+        // Code too large for the room left starts pages of its own, and
+        // smaller code carries on in the pages with more room.
+        let mut memory = ExecutableMemory::new();
+        memory.place(&[code_gen::RET]);
+        let large = memory.place(&vec![code_gen::RET; page_size]);
+        let small = memory.place(&[code_gen::RET]);
+        assert_eq!(large.as_ptr() as usize % page_size, 0);
+        assert_ne!(
+            small.as_ptr() as usize / page_size,
+            large.as_ptr() as usize / page_size
+        );
+    }
 }
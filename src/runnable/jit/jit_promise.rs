@@ -1,25 +1,45 @@
 use std::{
+    cmp,
     collections::VecDeque,
     ops::{Deref, DerefMut},
+    rc::Rc,
 };
 
 use crate::parser::AstNode;
+use crate::runnable::hot_loops::HotLoops;
+use crate::runnable::loop_profile::LoopCounters;
 
 use super::JITTarget;
 
 pub type JITPromiseID = usize;
 
+/// Characters of a loop's source that its name is made from
+const NAME_WIDTH: usize = 24;
+
 /// Holds AstNodes for later compilation.
 #[derive(Debug)]
 pub enum JITPromise {
     Deferred(VecDeque<AstNode>),
+    /// Compiled with counters on each of its child loops, to be traced once
+    /// it has run often enough.
+    Recording {
+        target: JITTarget,
+        /// Counters for each child loop, in order
+        children: Box<[LoopCounters]>,
+        /// Times the loop has been reached
+        runs: usize,
+    },
     Compiled(JITTarget),
+    /// A stretch of the program outside of any loop, compiled each time it
+    /// is reached and freed once it has run, since it runs once per run.
+    Segment(VecDeque<AstNode>),
 }
 
 impl JITPromise {
     pub fn source(&self) -> &VecDeque<AstNode> {
         match self {
-            JITPromise::Deferred(source) => source,
+            JITPromise::Deferred(source) | JITPromise::Segment(source) => source,
+            JITPromise::Recording { target, .. } => &target.source,
             JITPromise::Compiled(JITTarget { source, .. }) => source,
         }
     }
@@ -27,7 +47,11 @@ impl JITPromise {
 
 /// The global set of JITPromises for a program.
 #[derive(Debug, Default)]
-pub struct PromiseSet(Vec<Option<JITPromise>>);
+pub struct PromiseSet {
+    promises: Vec<Option<JITPromise>>,
+    /// Name of each promise, which stays the same however it is compiled
+    names: Vec<Rc<str>>,
+}
 
 impl PromiseSet {
     /// By either searching for an equivalent promise, or creating a new one,
@@ -35,7 +59,7 @@ impl PromiseSet {
     pub fn add(&mut self, nodes: VecDeque<AstNode>) -> JITPromiseID {
         for (index, promise) in self.iter().enumerate() {
             if let Some(promise) = promise {
-                if promise.source() == &nodes {
+                if !matches!(promise, JITPromise::Segment(_)) && promise.source() == &nodes {
                     return index;
                 }
             }
@@ -56,22 +80,82 @@ impl PromiseSet {
         }
 
         // If this is a new promise, add it to the pool.
-        self.push(Some(JITPromise::Deferred(nodes)));
+        let name = self.loop_name(&nodes);
+        self.promises.push(Some(JITPromise::Deferred(nodes)));
+        self.names.push(name.into());
 
         self.len() - 1
     }
+
+    /// Add a segment of the program, which starts at node `start` of it,
+    /// returning its promise ID. Segments are never shared.
+    pub fn add_segment(&mut self, nodes: VecDeque<AstNode>, start: usize) -> JITPromiseID {
+        self.promises.push(Some(JITPromise::Segment(nodes)));
+        self.names.push(format!("segment@{}", start).into());
+
+        self.len() - 1
+    }
+
+    /// Name of a promise, for showing wherever it is referred to.
+    pub fn name(&self, promise_id: JITPromiseID) -> Rc<str> {
+        self.names[promise_id].clone()
+    }
+
+    /// Take a promise out of the set to compile or run it. It has to be put
+    /// back before it can be taken again.
+    pub fn take(&mut self, promise_id: JITPromiseID) -> JITPromise {
+        match self.promises[promise_id].take() {
+            Some(promise) => promise,
+            None => panic!("Someone forgot to put {} back", self.names[promise_id]),
+        }
+    }
+
+    /// The loop not yet compiled that ran the most iterations according to
+    /// `hot_loops`, leaving out those that never ran.
+    pub fn hottest_deferred(&self, hot_loops: &HotLoops) -> Option<JITPromiseID> {
+        self.iter()
+            .enumerate()
+            .filter_map(|(index, promise)| match promise {
+                Some(JITPromise::Deferred(nodes)) => Some((index, hot_loops.get(nodes)?)),
+                _ => None,
+            })
+            .filter(|(_, counters)| counters.entries > 0)
+            .max_by_key(|&(index, counters)| (counters.iterations, cmp::Reverse(index)))
+            .map(|(index, _)| index)
+    }
+
+    /// Name for a loop with the body `nodes`, made from its source. Loops
+    /// whose sources start the same way are told apart by a number.
+    fn loop_name(&self, nodes: &VecDeque<AstNode>) -> String {
+        let body: String = nodes.iter().map(AstNode::to_string).collect();
+        let source = format!("[{}]", body);
+        let name = match source.char_indices().nth(NAME_WIDTH) {
+            Some((end, _)) => format!("loop{}...", &source[..end]),
+            None => format!("loop{}", source),
+        };
+
+        let taken = |name: &str| self.names.iter().any(|taken| &**taken == name);
+        if !taken(&name) {
+            return name;
+        }
+
+        (2..)
+            .map(|number| format!("{}#{}", name, number))
+            .find(|numbered| !taken(numbered))
+            .unwrap()
+    }
 }
 
 impl Deref for PromiseSet {
     type Target = Vec<Option<JITPromise>>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.promises
     }
 }
 
 impl DerefMut for PromiseSet {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.promises
     }
 }
@@ -1,25 +1,93 @@
-use super::super::Runnable;
-use super::code_gen;
-use super::immutable::Immutable;
-use super::jit_helpers::make_executable;
+use super::super::{interrupt, Runnable};
+use super::code_gen::{self, Assembler, CellOp, Label};
+use super::disasm::disassemble_with_offsets;
+use super::guard;
+use super::jit_helpers::{make_executable, ExecutableCode, ExecutableMemory};
 use super::jit_promise::{JITPromise, JITPromiseID, PromiseSet};
 use crate::parser::AstNode;
-use crate::runnable::BF_MEMORY_SIZE;
-use std::cell::RefCell;
+use crate::runnable::events::{self, Event};
+use crate::runnable::extension::Extensions;
+use crate::runnable::hot_loops::HotLoops;
+use crate::runnable::loop_profile::{LoopCounters, LoopKind};
+use crate::runnable::profiler::CodeSize;
+use crate::runnable::tape::{Tape, GUARD_SIZE};
+use crate::runnable::{
+    CellOverflow, FinalState, Instrumentation, RunReport, TapeMode, BF_MEMORY_SIZE, CANCELLED,
+    LEFT_TAPE_SIZE, WRAPPED_TAPE_SIZE,
+};
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::collections::VecDeque;
 use std::fmt;
 use std::io::{self, Read, Write};
 use std::mem;
+use std::ptr;
 use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 /// Set arbitrarily
 const INLINE_THRESHOLD: usize = 0x16;
+/// Times a deferred loop is reached before it is traced, when tracing
+const HOT_RUNS: usize = 16;
+/// Nodes outside of loops compiled together, for programs with more than
+/// this many
+const SEGMENT_SIZE: usize = 0x1000;
+/// Bytes of output held back before they are written out
+const OUTPUT_BUFFER_SIZE: usize = 0x2000;
+/// Characters of BrainFuck source shown in an annotation before it is cut off
+const ANNOTATION_WIDTH: usize = 48;
+/// Name of the code compiled for the program outside of any loop
+const PROGRAM_NAME: &str = "program";
+/// Error for a program that moves left of the first cell, the same as the
+/// interpreter's
+const UNDERFLOW: &str = "Attempted to point below memory location 0.";
 
 /// Indexes into the vtable passed into JIT compiled code
 pub enum VTableEntry {
     JITCallback = 0,
     Read = 1,
     Print = 2,
+    Instrument = 3,
+    Grow = 4,
+    Overflow = 5,
+    Underflow = 6,
+    Extension = 7,
+    FarUnderflow = 8,
+}
+
+/// A bound on the executable memory the JIT allocates for a program's code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CodeLimit {
+    /// Bytes of executable memory, in whole pages, over everything compiled
+    /// for the program. Code compiled again, like a segment on each run,
+    /// counts again. The code entering a program that doesn't fit, and the
+    /// code guard page faults exit through, are placed whatever the limit.
+    pub bytes: usize,
+    /// Whether code that doesn't fit is run without being compiled rather
+    /// than stopping the program with an error. Code run that way isn't seen
+    /// by instrumentation watching instructions, loops or the tape.
+    pub interpret: bool,
+}
+
+/// How a program is compiled and run, for `JITTarget::with_options`.
+#[derive(Clone, Debug, Default)]
+pub struct CompileOptions {
+    /// How the ends of the tape behave. Tape stats aren't gathered from a
+    /// wrapped tape.
+    pub tape_mode: TapeMode,
+    /// What arithmetic that carries a cell past 255 or below 0 does
+    pub cell_overflow: CellOverflow,
+    /// Loop counts from an earlier run. Loops that were hot are inlined
+    /// however large they are, and the rest of those that ran are compiled
+    /// up front, hottest first so that the code run most often sits together
+    /// in memory. Loops that never ran are still left until they are
+    /// reached.
+    pub hot_loops: Option<HotLoops>,
+    /// Most executable memory to allocate. Once something doesn't fit
+    /// nothing more is compiled, apart from the few bytes that start the
+    /// program.
+    pub code_limit: Option<CodeLimit>,
 }
 
 /// A type to unify all function pointers behind. Because the vtable is not used in the
@@ -28,13 +96,402 @@ type VoidPtr = *const ();
 /// VTable for JIT compiled code
 type VTable<const SIZE: usize> = [VoidPtr; SIZE];
 
+/// Offsets in compiled code where each node starts, with a description of the
+/// node
+type Annotations = Vec<(usize, String)>;
+
+/// A run of cell updates and data pointer moves, compiled as updates at
+/// offsets from where the data pointer started followed by a single move.
+#[derive(Default)]
+struct CellBlock {
+    ops: Vec<(isize, CellOp)>,
+    /// How far the data pointer has moved since the start of the block
+    shift: isize,
+    /// Nodes the block was made from, kept only when annotating
+    nodes: Vec<AstNode>,
+}
+
+impl CellBlock {
+    /// Add a node to the block, returning false if it can't be part of one.
+    /// Nodes that move or reach too far left for the guard pages in front of
+    /// memory to catch are left out, to be checked on their own.
+    fn push(&mut self, node: &AstNode) -> bool {
+        if self.shift - Self::reach_left(node) < -(GUARD_SIZE as isize) {
+            return false;
+        }
+
+        let op = match *node {
+            AstNode::Next(n) => {
+                self.shift += n as isize;
+                return true;
+            }
+            AstNode::Prev(n) => {
+                self.shift -= n as isize;
+                return true;
+            }
+            AstNode::Incr(n) => CellOp::Incr(n),
+            AstNode::Decr(n) => CellOp::Decr(n),
+            AstNode::Set(n) => CellOp::Set(n),
+            AstNode::AddTo(n) => CellOp::AddTo(n),
+            AstNode::SubFrom(n) => CellOp::SubFrom(n),
+            AstNode::Print | AstNode::Read | AstNode::Extension(_) | AstNode::Loop(_) => {
+                return false
+            }
+        };
+
+        self.ops.push((self.shift, op));
+        true
+    }
+
+    /// Cells left of the data pointer that `node` touches or moves to.
+    fn reach_left(node: &AstNode) -> isize {
+        match *node {
+            AstNode::Prev(n) => n as isize,
+            AstNode::AddTo(n) | AstNode::SubFrom(n) => -n.min(0),
+            _ => 0,
+        }
+    }
+
+    /// Furthest cell right of where the data pointer started that the block
+    /// touches or moves to.
+    fn reach(&self) -> isize {
+        self.ops
+            .iter()
+            .map(|&(offset, op)| match op {
+                CellOp::AddTo(n) | CellOp::SubFrom(n) => offset.max(offset + n),
+                _ => offset,
+            })
+            .fold(self.shift, cmp::max)
+    }
+}
+
 pub struct JITContext {
     /// All non-root JITTargets in the program
     promises: PromiseSet,
-    /// Reader that can be overridden to allow for input from a source other than stdin
-    pub io_read: Box<dyn Read>,
-    /// Writer that can be overriden to allow for output to a location other than stdout
-    pub io_write: Box<dyn Write>,
+    /// Reader used by the `,` command
+    io_read: Box<dyn Read>,
+    /// Writer used by the `.` command
+    io_write: Box<dyn Write>,
+    /// Output from `.` not yet written to `io_write`. It is written out when
+    /// full, before `,` reads, at the end of a run and at every newline
+    /// unless `line_buffered` is unset.
+    output: Vec<u8>,
+    /// Whether output is written out at every newline
+    line_buffered: bool,
+    /// Observers of each executed AstNode
+    instrumentation: Instrumentation,
+    /// Descriptions of the AstNodes that compiled code reports to the instrumentation
+    locations: Vec<String>,
+    /// Loops registered with the profiler
+    loops_added: usize,
+    /// Memory of the running program, kept here so that it can grow
+    memory: Tape,
+    /// Whether memory moves into huge pages once large enough
+    huge_pages: bool,
+    /// Address of the first memory cell, used to report the data pointer as an
+    /// index. Compiled code checking for moves left of the tape reads it
+    /// through its address, so it is boxed.
+    memory_base: Box<Cell<usize>>,
+    /// Code that compiled code touching the guard pages in front of memory
+    /// is sent to, to report it
+    underflow_exit: ExecutableCode,
+    /// Address just past the last memory cell. Compiled code reads it through
+    /// its address, so it is boxed.
+    tape_end: Box<Cell<usize>>,
+    /// Index of the cell the data pointer starts at
+    origin: usize,
+    /// Number of cells right of the starting cell memory may grow to
+    tape_limit: usize,
+    /// Values copied into the start of memory before running
+    preloaded: Vec<u8>,
+    /// Whether memory and the data pointer are left as they are after a run
+    keep_state: bool,
+    /// Where the data pointer was left by the last run, when `memory` has
+    /// been kept for the next one
+    kept_dp: Option<usize>,
+    /// How the ends of the tape behave
+    tape_mode: TapeMode,
+    /// What arithmetic past the range of a cell does
+    cell_overflow: CellOverflow,
+    /// Commands added to the language that the program may run
+    extensions: Extensions,
+    /// Whether hot loops are recompiled along the paths they take
+    tracing: bool,
+    /// Loop counts from an earlier run. When given, loops are inlined if they
+    /// were hot rather than if they are small.
+    hot_loops: Option<HotLoops>,
+    /// Most executable memory code may be placed in
+    code_limit: Option<CodeLimit>,
+    /// Pages that compiled code is packed into
+    executable: ExecutableMemory,
+    /// Code placed in executable memory so far
+    code_size: CodeSize,
+    /// Whether something didn't fit under `code_limit`, after which nothing
+    /// more is compiled
+    over_code_limit: bool,
+    /// Whether compiled code is annotated with the nodes it came from, for
+    /// `write_asm`
+    annotate: bool,
+    /// Set by callbacks to make compiled code return as soon as they do.
+    /// Compiled code reads it through its address, so it is boxed.
+    stopped: Box<Cell<bool>>,
+    /// Whether the program stopped because nothing was reading its output
+    output_closed: bool,
+    /// Whether the program was stopped with Ctrl-C
+    interrupted: bool,
+    /// Why the program stopped, if it was because of an error in a callback
+    error: Option<String>,
+    /// The node the program stopped at with `error`, if compiled code knows
+    /// it
+    error_location: Option<String>,
+    /// Descriptions of the nodes compiled code checks for moving or reaching
+    /// too far left for the guard pages to catch, reported if they do
+    far_moves: Vec<String>,
+    /// Bytes read by `,` in this run
+    bytes_read: u64,
+    /// Bytes written by `.` in this run
+    bytes_written: u64,
+    /// Separately compiled loops entered through `jit_callback` in this run
+    entries: u64,
+    /// Address of each compiled promise's code, or 0, for compiled code to
+    /// call it directly rather than through `jit_callback`. Boxed so that
+    /// compiled code can read them through their addresses.
+    #[allow(clippy::vec_box)]
+    entry_points: Vec<Box<Cell<usize>>>,
+    /// Whether compiled code calls compiled promises directly. Every entry
+    /// goes through `jit_callback` when it reports progress or can be
+    /// cancelled.
+    direct_calls: bool,
+    /// When the current run started
+    started: Instant,
+}
+
+impl JITContext {
+    /// Globals for a program that reads with `,` from `io_read` and writes
+    /// with `.` to `io_write`.
+    fn new(
+        io_read: Box<dyn Read>,
+        io_write: Box<dyn Write>,
+        instrumentation: Instrumentation,
+        tape_mode: TapeMode,
+        cell_overflow: CellOverflow,
+    ) -> Self {
+        let direct_calls = instrumentation.progress.is_none() && instrumentation.cancel.is_none();
+        let mut underflow_exit = Vec::new();
+        code_gen::underflow(&mut underflow_exit);
+        guard::install();
+
+        JITContext {
+            promises: PromiseSet::default(),
+            io_read,
+            io_write,
+            output: Vec::with_capacity(OUTPUT_BUFFER_SIZE),
+            line_buffered: true,
+            instrumentation,
+            locations: Vec::new(),
+            loops_added: 0,
+            memory: Tape::default(),
+            huge_pages: false,
+            memory_base: Box::new(Cell::new(0)),
+            underflow_exit: make_executable(&underflow_exit),
+            tape_end: Box::new(Cell::new(0)),
+            origin: 0,
+            tape_limit: usize::MAX,
+            preloaded: Vec::new(),
+            keep_state: false,
+            kept_dp: None,
+            tape_mode,
+            cell_overflow,
+            extensions: Extensions::new(),
+            tracing: false,
+            hot_loops: None,
+            code_limit: None,
+            executable: ExecutableMemory::new(),
+            code_size: CodeSize::default(),
+            over_code_limit: false,
+            annotate: false,
+            stopped: Box::new(Cell::new(false)),
+            output_closed: false,
+            interrupted: false,
+            error: None,
+            error_location: None,
+            far_moves: Vec::new(),
+            bytes_read: 0,
+            bytes_written: 0,
+            entries: 0,
+            entry_points: Vec::new(),
+            direct_calls,
+            started: Instant::now(),
+        }
+    }
+
+    /// Allocate memory for a run and point `memory_base`, `tape_end` and
+    /// `origin` at it.
+    fn new_tape(&mut self) -> Tape {
+        let preloaded = self.preloaded.len();
+        // Cells right of the starting cell that memory starts out with
+        let initial = BF_MEMORY_SIZE.min(self.tape_limit).max(preloaded);
+        // Memory space used by BrainFuck, and where in it the data pointer
+        // starts. A wrapped tape has to be aligned to its size for compiled
+        // code to find its start, so it is taken from the middle of a buffer
+        // twice as large.
+        let (size, len, origin) = match self.tape_mode {
+            TapeMode::Standard => (initial, initial, 0),
+            TapeMode::Wrapped => (2 * WRAPPED_TAPE_SIZE, WRAPPED_TAPE_SIZE, 0),
+            TapeMode::Bidirectional => {
+                let len = LEFT_TAPE_SIZE + initial;
+                (len, len, LEFT_TAPE_SIZE)
+            }
+        };
+        let mut bf_mem = if self.huge_pages {
+            Tape::with_huge_pages(size)
+        } else {
+            Tape::new(size)
+        };
+        let start = match self.tape_mode {
+            TapeMode::Wrapped => bf_mem.as_ptr().align_offset(WRAPPED_TAPE_SIZE),
+            _ => {
+                bf_mem.add_guard();
+                0
+            }
+        };
+        let preloaded = preloaded.min(len - origin);
+        bf_mem[start + origin..start + origin + preloaded]
+            .copy_from_slice(&self.preloaded[..preloaded]);
+        self.memory_base.set(bf_mem[start..].as_ptr() as usize);
+        self.tape_end.set(self.memory_base.get() + len);
+        self.origin = origin;
+
+        bf_mem
+    }
+
+    /// Make compiled code return if Ctrl-C has been pressed or the run has
+    /// been cancelled. Called whenever compiled code calls back into Rust,
+    /// returning whether it is stopping.
+    fn check_interrupt(&mut self) -> bool {
+        if interrupt::requested() {
+            self.interrupted = true;
+            self.stopped.set(true);
+        }
+
+        self.interrupted || self.check_cancelled()
+    }
+
+    /// Stop the program with `CANCELLED` if its cancellation token has been
+    /// set, returning whether it has.
+    fn check_cancelled(&mut self) -> bool {
+        let cancelled = self
+            .instrumentation
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed));
+
+        if cancelled && self.error.is_none() {
+            self.fail(CANCELLED.to_string());
+        }
+
+        cancelled
+    }
+
+    /// Record an error from a callback and make compiled code return as soon
+    /// as the callback does, since callbacks can't unwind through it.
+    fn fail(&mut self, error: String) {
+        self.error = Some(error);
+        self.stopped.set(true);
+    }
+
+    /// Write out the output buffered so far, stopping the program if that
+    /// fails.
+    fn flush_output(&mut self) {
+        if self.output.is_empty() {
+            return;
+        }
+
+        let write_result = self.io_write.write_all(&self.output);
+        self.output.clear();
+
+        if let Err(error) = write_result {
+            if error.kind() == io::ErrorKind::BrokenPipe {
+                // Whatever was reading the output has gone away, so there is
+                // no point in running any further.
+                self.output_closed = true;
+                self.stopped.set(true);
+                return;
+            }
+
+            self.fail(format!("Failed to write output: {}", error));
+        }
+    }
+
+    /// Log an event, stopping the program if the log can't be written.
+    fn emit(&mut self, event: Event) {
+        if let Err(error) = events::emit(&mut self.instrumentation.events, event) {
+            self.fail(format!("Failed to write event log: {}", error));
+        }
+    }
+
+    /// Send compiled code that touches the guard pages in front of memory,
+    /// if it has them, to `underflow_exit`.
+    fn watch_guard(&self) {
+        if let Some(guard) = self.memory.guard() {
+            guard::watch(guard, self.underflow_exit.as_ptr());
+        }
+    }
+
+    /// Counters for the current run.
+    fn report(&self) -> RunReport {
+        RunReport {
+            instructions: None,
+            bytes_read: self.bytes_read,
+            bytes_written: self.bytes_written,
+            peak_dp: None,
+            elapsed: self.started.elapsed(),
+        }
+    }
+
+    /// Address that compiled code finds the code of a promise at, once it
+    /// has been compiled.
+    fn entry_point(&mut self, promise_id: JITPromiseID) -> *const usize {
+        while self.entry_points.len() <= promise_id {
+            self.entry_points.push(Box::new(Cell::new(0)));
+        }
+
+        self.entry_points[promise_id].as_ptr()
+    }
+
+    /// Put a promise back once it has run, letting compiled code call it
+    /// directly from now on if it is compiled.
+    fn set_promise(&mut self, promise_id: JITPromiseID, promise: Option<JITPromise>) {
+        let entry = match promise {
+            Some(JITPromise::Compiled(ref target)) => target.bytes.as_ptr() as usize,
+            _ => 0,
+        };
+
+        if let Some(entry_point) = self.entry_points.get(promise_id) {
+            entry_point.set(entry);
+        }
+
+        self.promises[promise_id] = promise;
+    }
+
+    /// Count an entry into a separately compiled loop, passing the counters so
+    /// far to the progress callback if one is due.
+    fn report_progress(&mut self) {
+        self.entries += 1;
+        let due = self
+            .instrumentation
+            .progress
+            .as_ref()
+            .is_some_and(|progress| self.entries.is_multiple_of(progress.every.max(1)));
+
+        if due {
+            let report = self.report();
+
+            if let Some(ref mut progress) = self.instrumentation.progress {
+                (progress.callback)(&report);
+            }
+        }
+    }
 }
 
 /// Container for executable bytes.
@@ -42,7 +499,10 @@ pub struct JITTarget {
     /// Original AST
     pub source: VecDeque<AstNode>,
     /// Executable bytes buffer
-    bytes: Immutable<Vec<u8>>,
+    bytes: ExecutableCode,
+    /// Offsets in `bytes` where the code for each node starts, along with a
+    /// description of the node, when annotating
+    annotations: Vec<(usize, String)>,
     /// Globals for the whole program
     pub context: Rc<RefCell<JITContext>>,
 }
@@ -58,209 +518,2316 @@ impl fmt::Debug for JITTarget {
 }
 
 impl JITTarget {
-    /// Initialize a JIT compiled version of a program.
-    pub fn new(nodes: VecDeque<AstNode>) -> Self {
-        let mut bytes = Vec::new();
-        let context = Rc::new(RefCell::new(JITContext {
-            promises: PromiseSet::default(),
-            io_read: Box::new(io::stdin()),
-            io_write: Box::new(io::stdout()),
-        }));
+    /// Initialize a JIT compiled version of a program that reads with `,` from
+    /// `io_read` and writes with `.` to `io_write`.
+    pub fn new(
+        nodes: VecDeque<AstNode>,
+        io_read: Box<dyn Read>,
+        io_write: Box<dyn Write>,
+        instrumentation: Instrumentation,
+    ) -> Self {
+        Self::with_options(
+            nodes,
+            io_read,
+            io_write,
+            instrumentation,
+            CompileOptions::default(),
+        )
+    }
+
+    /// Like `new`, but compiled and run as `options` says.
+    pub fn with_options(
+        nodes: VecDeque<AstNode>,
+        io_read: Box<dyn Read>,
+        io_write: Box<dyn Write>,
+        instrumentation: Instrumentation,
+        options: CompileOptions,
+    ) -> Self {
+        let mut context = JITContext::new(
+            io_read,
+            io_write,
+            instrumentation,
+            options.tape_mode,
+            options.cell_overflow,
+        );
+        context.hot_loops = options.hot_loops;
+        context.code_limit = options.code_limit;
+
+        let target = Self::compile_program(nodes, context);
+        target.precompile();
+
+        target
+    }
 
-        code_gen::wrapper(
-            &mut bytes,
-            Self::shallow_compile(nodes.clone(), context.clone()),
+    /// Compile a program only to write it out with `write_asm`, with the code
+    /// for each node headed by the node and the BrainFuck it stands for.
+    pub fn for_asm(nodes: VecDeque<AstNode>, options: CompileOptions) -> Self {
+        let mut context = JITContext::new(
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+            options.tape_mode,
+            options.cell_overflow,
         );
+        context.hot_loops = options.hot_loops;
+        context.code_limit = options.code_limit;
+        context.annotate = true;
+
+        Self::compile_program(nodes, context)
+    }
+
+    /// Compile the part of a program outside of any loop.
+    fn compile_program(nodes: VecDeque<AstNode>, context: JITContext) -> Self {
+        let context = Rc::new(RefCell::new(context));
+
+        span!("codegen", nodes = nodes.len(), root = true);
+        let compile_start = Instant::now();
+
+        let mut asm = Self::start_code(&context, PROGRAM_NAME);
+
+        // Compiling a huge program in one go would hold machine code for all
+        // of it at once. Instrumentation that registers instructions or loops
+        // as they are compiled can't have them compiled again, though.
+        let registers = {
+            let instrumentation = &context.borrow().instrumentation;
+            instrumentation.observes_instructions() || instrumentation.observes_loops()
+        };
+
+        if nodes.len() > SEGMENT_SIZE && !registers {
+            Self::defer_segments(&mut asm, &nodes, &context);
+        } else {
+            Self::shallow_compile(&mut asm, nodes.clone(), context.clone());
+        }
+
+        let (bytes, annotations) = match Self::finish_code(asm, &context, PROGRAM_NAME) {
+            Some(code) => code,
+            None => Self::defer_program(&nodes, &context),
+        };
+
+        if let Some(ref mut profiler) = context.borrow_mut().instrumentation.profiler {
+            profiler.program_compiled(compile_start.elapsed());
+        }
 
         Self {
             source: nodes,
-            bytes: make_executable(&bytes),
+            bytes,
+            annotations,
             context,
         }
     }
 
-    fn new_fragment(context: Rc<RefCell<JITContext>>, nodes: VecDeque<AstNode>) -> Self {
-        let mut bytes = Vec::new();
+    /// Stop the program with an error if the data pointer moves past `cells`
+    /// cells rather than growing memory beyond that.
+    pub fn limit_tape(&mut self, cells: usize) {
+        self.context.borrow_mut().tape_limit = cells;
+    }
 
-        code_gen::wrapper(
-            &mut bytes,
-            Self::compile_loop(nodes.clone(), context.clone()),
-        );
+    /// Bytes of code placed in executable memory so far, and of executable
+    /// memory allocated for it, over every piece compiled.
+    pub fn code_size(&self) -> CodeSize {
+        self.context.borrow().code_size
+    }
 
-        Self {
-            source: nodes,
-            bytes: make_executable(&bytes),
-            context,
+    /// Move memory into huge pages once it grows large enough for them to
+    /// help.
+    pub fn use_huge_pages(&mut self) {
+        self.context.borrow_mut().huge_pages = true;
+    }
+
+    /// Run `Extension` nodes with the commands in `extensions`. Without it
+    /// they stop the program with an error.
+    pub fn set_extensions(&mut self, extensions: Extensions) {
+        self.context.borrow_mut().extensions = extensions;
+    }
+
+    /// Leave memory and the data pointer as they are after each run, so the
+    /// next run carries on from them, until `reset` is called. Values passed
+    /// to `preload` are only copied into fresh memory.
+    ///
+    /// Runs after the first start on whatever the last one left, so the
+    /// program has to be parsed with `Ast::parse_preloaded` or optimized with
+    /// `Ast::optimize_preloaded`. `Ast::parse` drops loops at the start of a
+    /// program, since they never run on zeroed memory.
+    pub fn keep_state(&mut self) {
+        self.context.borrow_mut().keep_state = true;
+    }
+
+    /// Only write output out when the buffer fills, the program reads or the
+    /// run ends, rather than at every newline as well. Output arrives in
+    /// fewer, larger writes, but later.
+    pub fn block_buffer_output(&mut self) {
+        self.context.borrow_mut().line_buffered = false;
+    }
+
+    /// Experimental: record which child loops each deferred loop runs, and
+    /// once it is hot, recompile it as a trace with those children inlined
+    /// and the rest replaced by guards. Doesn't work with instrumentation
+    /// that observes individual instructions or loops.
+    pub fn trace_hot_loops(&mut self) {
+        self.context.borrow_mut().tracing = true;
+    }
+
+    /// Compile each loop that ran according to the hot loop counts, hottest
+    /// first, including loops inside those compiled along the way.
+    fn precompile(&self) {
+        loop {
+            let promise_id = {
+                let context = self.context.borrow();
+                let hottest = context
+                    .hot_loops
+                    .as_ref()
+                    .and_then(|hot_loops| context.promises.hottest_deferred(hot_loops));
+
+                match hottest {
+                    Some(promise_id) => promise_id,
+                    None => return,
+                }
+            };
+
+            let nodes = match self.context.borrow_mut().promises.take(promise_id) {
+                JITPromise::Deferred(nodes) => nodes,
+                _ => unreachable!(),
+            };
+            let name = self.context.borrow().promises.name(promise_id);
+            let target = {
+                span!(
+                    "compile_promise",
+                    id = promise_id,
+                    name = &*name,
+                    kind = "fragment"
+                );
+                Self::new_fragment(self.context.clone(), &name, nodes.clone())
+            };
+
+            let mut context = self.context.borrow_mut();
+            let target = match target {
+                Some(target) => target,
+                // The rest are left to be dealt with when they are reached.
+                None => {
+                    context.set_promise(promise_id, Some(JITPromise::Deferred(nodes)));
+                    return;
+                }
+            };
+            context.emit(Event::Compiled {
+                fragment: promise_id,
+                name: &name,
+                kind: "fragment",
+                nodes: target.source.len(),
+            });
+            context.set_promise(promise_id, Some(JITPromise::Compiled(target)));
         }
     }
 
-    /// Compile a vector of AstNodes into executable bytes.
-    fn shallow_compile(nodes: VecDeque<AstNode>, context: Rc<RefCell<JITContext>>) -> Vec<u8> {
-        let mut bytes = Vec::new();
+    /// Write the program's machine code as assembly, compiling every loop and
+    /// segment that would otherwise only be compiled once it is reached. Each
+    /// of those follows the code that calls it, headed by its name.
+    /// Programs compiled with `for_asm` also show the node each stretch of code
+    /// came from.
+    pub fn write_asm(&self, out: &mut dyn Write) -> io::Result<()> {
+        Self::write_annotated(out, &self.bytes, &self.annotations)?;
 
-        for node in nodes {
-            match node {
-                AstNode::Incr(n) => code_gen::incr(&mut bytes, n),
-                AstNode::Decr(n) => code_gen::decr(&mut bytes, n),
-                AstNode::Next(n) => code_gen::next(&mut bytes, n),
-                AstNode::Prev(n) => code_gen::prev(&mut bytes, n),
-                AstNode::Print => code_gen::print(&mut bytes),
-                AstNode::Read => code_gen::read(&mut bytes),
-                AstNode::Set(n) => code_gen::set(&mut bytes, n),
-                AstNode::AddTo(n) => code_gen::add(&mut bytes, n),
-                AstNode::SubFrom(n) => code_gen::sub(&mut bytes, n),
-                AstNode::Loop(nodes) if nodes.len() < INLINE_THRESHOLD => {
-                    bytes.extend(Self::compile_loop(nodes, context.clone()))
+        let mut promise_id = 0;
+        while promise_id < self.context.borrow().promises.len() {
+            let promise = self.context.borrow_mut().promises.take(promise_id);
+            let name = self.context.borrow().promises.name(promise_id);
+            let mut listing = Vec::new();
+            let promise = match promise {
+                JITPromise::Deferred(nodes) => {
+                    match Self::new_fragment(self.context.clone(), &name, nodes.clone()) {
+                        Some(target) => {
+                            Self::write_annotated(
+                                &mut listing,
+                                &target.bytes,
+                                &target.annotations,
+                            )?;
+                            JITPromise::Compiled(target)
+                        }
+                        None => {
+                            writeln!(listing, "  ; over the code limit")?;
+                            JITPromise::Deferred(nodes)
+                        }
+                    }
+                }
+                JITPromise::Segment(nodes) => {
+                    match Self::new_segment(self.context.clone(), &name, nodes.clone()) {
+                        Some(target) => {
+                            Self::write_annotated(&mut listing, &target.bytes, &target.annotations)?
+                        }
+                        None => writeln!(listing, "  ; over the code limit")?,
+                    }
+                    JITPromise::Segment(nodes)
+                }
+                JITPromise::Recording { ref target, .. } | JITPromise::Compiled(ref target) => {
+                    Self::write_annotated(&mut listing, &target.bytes, &target.annotations)?;
+                    promise
                 }
-                AstNode::Loop(nodes) => bytes.extend(Self::defer_loop(nodes, context.clone())),
             };
+            self.context
+                .borrow_mut()
+                .set_promise(promise_id, Some(promise));
+
+            writeln!(out, "\n; {}", name)?;
+            out.write_all(&listing)?;
+
+            promise_id += 1;
         }
 
-        bytes
+        Ok(())
     }
 
-    /// Perform AOT compilation on a loop.
-    fn compile_loop(nodes: VecDeque<AstNode>, context: Rc<RefCell<JITContext>>) -> Vec<u8> {
-        let mut bytes = Vec::new();
+    /// Write the disassembly of `bytes`, with each annotation above the
+    /// instruction it starts at.
+    fn write_annotated(
+        out: &mut dyn Write,
+        bytes: &[u8],
+        annotations: &[(usize, String)],
+    ) -> io::Result<()> {
+        let mut annotations = annotations.iter().peekable();
 
-        code_gen::aot_loop(&mut bytes, Self::shallow_compile(nodes, context));
+        for (offset, line) in disassemble_with_offsets(bytes) {
+            while let Some((_, annotation)) = annotations.next_if(|&&(at, _)| at <= offset) {
+                writeln!(out, "  ; {}", annotation)?;
+            }
 
-        bytes
+            writeln!(out, "    {}", line)?;
+        }
+
+        Ok(())
     }
 
-    /// Perform JIT compilation on a loop.
-    fn defer_loop(nodes: VecDeque<AstNode>, context: Rc<RefCell<JITContext>>) -> Vec<u8> {
-        let mut bytes = Vec::new();
+    /// Compile a fragment named `name` around the code `compile` emits, or
+    /// return None if it doesn't fit under the code limit.
+    fn assemble_fragment(
+        context: Rc<RefCell<JITContext>>,
+        name: &str,
+        nodes: VecDeque<AstNode>,
+        compile: impl FnOnce(&mut Assembler),
+    ) -> Option<Self> {
+        span!("codegen", name, nodes = nodes.len());
+        let compile_start = Instant::now();
 
-        code_gen::jit_loop(&mut bytes, context.borrow_mut().promises.add(nodes));
+        let mut asm = Self::start_code(&context, name);
+        compile(&mut asm);
+        let (bytes, annotations) = Self::finish_code(asm, &context, name)?;
 
-        bytes
-    }
+        if let Some(ref mut profiler) = context.borrow_mut().instrumentation.profiler {
+            profiler.fragment_compiled(name, compile_start.elapsed());
+        }
 
-    /// Callback passed into compiled code. Allows for deferred compilation
-    /// targets to be compiled, ran, and later re-ran.
-    extern "C" fn jit_callback(&mut self, promise_id: JITPromiseID, mem_ptr: *mut u8) -> *mut u8 {
-        let mut promise = self.context.borrow_mut().promises[promise_id]
-            .take()
-            .expect("Someone forgot to put a promise back");
-        let return_ptr;
-        let new_promise;
+        Some(Self {
+            source: nodes,
+            bytes,
+            annotations,
+            context,
+        })
+    }
 
-        match promise {
-            JITPromise::Deferred(nodes) => {
-                let mut new_target = Self::new_fragment(self.context.clone(), nodes);
-                return_ptr = new_target.exec(mem_ptr);
-                new_promise = Some(JITPromise::Compiled(new_target));
-            }
-            JITPromise::Compiled(ref mut jit_target) => {
-                return_ptr = jit_target.exec(mem_ptr);
-                new_promise = Some(promise);
-            }
+    /// Start compiling the code named `name`, annotating it if the program
+    /// is being annotated and counting calls to it if the profiler asks to.
+    fn start_code(context: &Rc<RefCell<JITContext>>, name: &str) -> Assembler {
+        let mut context = context.borrow_mut();
+        let mut asm = if context.annotate {
+            Assembler::annotated()
+        } else {
+            Assembler::new()
         };
+        asm.set_cell_overflow(context.cell_overflow);
+        code_gen::prologue(&mut asm);
 
-        self.context.borrow_mut().promises[promise_id] = new_promise;
+        let counter = context
+            .instrumentation
+            .profiler
+            .as_mut()
+            .and_then(|profiler| profiler.call_counter(name));
 
-        return_ptr
+        if let Some(counter) = counter {
+            code_gen::count(&mut asm, counter);
+        }
+
+        asm
     }
 
-    /// Print a single byte (called by JIT compiled code)
-    extern "C" fn print(&mut self, byte: u8) {
-        let buffer = [byte];
-        let write_result = self.context.borrow_mut().io_write.write_all(&buffer);
+    /// Finish compiling the code named `name` and place it in executable
+    /// memory, returning it along with its annotations, or None if it doesn't
+    /// fit under the code limit.
+    fn finish_code(
+        asm: Assembler,
+        context: &Rc<RefCell<JITContext>>,
+        name: &str,
+    ) -> Option<(ExecutableCode, Annotations)> {
+        let (code, annotations) = Self::end_code(asm);
 
-        if let Err(error) = write_result {
-            panic!("Failed to write to stdout: {}", error);
+        {
+            let mut context = context.borrow_mut();
+            let fits = context.code_limit.is_none_or(|limit| {
+                context.code_size.allocated + context.executable.needed(code.len()) <= limit.bytes
+            });
+
+            if !fits {
+                context.over_code_limit = true;
+                return None;
+            }
         }
+
+        Some((Self::place_code(&code, context, name), annotations))
     }
 
-    /// Read a single byte (called by JIT compiled code)
-    extern "C" fn read(&mut self) -> u8 {
-        let mut buffer = [0];
-        let read_result = self.context.borrow_mut().io_read.read_exact(&mut buffer);
+    /// Finish compiling code, returning its bytes along with its
+    /// annotations.
+    fn end_code(mut asm: Assembler) -> (Vec<u8>, Annotations) {
+        code_gen::epilogue(&mut asm);
+        asm.annotate(|| "cell overflow".to_string());
+        code_gen::traps(&mut asm);
+        let annotations = asm.take_annotations();
 
-        if let Err(error) = read_result {
-            if error.kind() == io::ErrorKind::UnexpectedEof {
-                // Just send out newlines forever if the read stream has ended.
-                return b'\n';
-            }
+        (asm.finish(), annotations)
+    }
+
+    /// Place the code named `name` in executable memory, whatever the code
+    /// limit.
+    fn place_code(code: &[u8], context: &Rc<RefCell<JITContext>>, name: &str) -> ExecutableCode {
+        let mut context = context.borrow_mut();
+        let allocated = context.executable.needed(code.len());
+        let bytes = context.executable.place(code);
+        context.code_size.code += bytes.len();
+        context.code_size.allocated += allocated;
 
-            panic!("Failed to read from stdin: {}", error);
+        if let Some(ref mut profiler) = context.instrumentation.profiler {
+            profiler.code_emitted(
+                name,
+                CodeSize {
+                    code: bytes.len(),
+                    allocated,
+                },
+            );
         }
 
-        buffer[0]
+        bytes
     }
 
-    /// Execute the bytes buffer as a function.
-    fn exec(&mut self, mem_ptr: *mut u8) -> *mut u8 {
-        let vtable: VTable<3> = [
-            Self::jit_callback as VoidPtr,
-            Self::read as VoidPtr,
-            Self::print as VoidPtr,
-        ];
+    /// Compile only a call to the whole of a program that doesn't fit under
+    /// the code limit, as a segment, leaving it to be run however the limit
+    /// says once it is reached.
+    fn defer_program(
+        nodes: &VecDeque<AstNode>,
+        context: &Rc<RefCell<JITContext>>,
+    ) -> (ExecutableCode, Annotations) {
+        let mut asm = Self::start_code(context, PROGRAM_NAME);
+        let promise_id = context.borrow_mut().promises.add_segment(nodes.clone(), 0);
+        code_gen::jit_loop(&mut asm, promise_id);
+        let (code, annotations) = Self::end_code(asm);
 
-        type JitFunc = extern "C" fn(*mut u8, &mut JITTarget, &VTable<3>) -> *mut u8;
-        let func: JitFunc = unsafe { mem::transmute(self.bytes.as_ptr()) };
+        (Self::place_code(&code, context, PROGRAM_NAME), annotations)
+    }
 
-        func(mem_ptr, self, &vtable)
+    fn new_fragment(
+        context: Rc<RefCell<JITContext>>,
+        name: &str,
+        nodes: VecDeque<AstNode>,
+    ) -> Option<Self> {
+        Self::assemble_fragment(context.clone(), name, nodes.clone(), |asm| {
+            Self::compile_loop(asm, nodes, context, LoopKind::Deferred)
+        })
     }
-}
 
-impl Runnable for JITTarget {
-    fn run(&mut self) {
-        let mut bf_mem = vec![0u8; BF_MEMORY_SIZE]; // Memory space used by BrainFuck
-        self.exec(bf_mem.as_mut_ptr());
+    /// Compile a loop with every child loop inlined and counting how often it
+    /// is reached and run. Returns the fragment along with the counters.
+    fn new_recording(
+        context: Rc<RefCell<JITContext>>,
+        name: &str,
+        nodes: VecDeque<AstNode>,
+    ) -> Option<(Self, Box<[LoopCounters]>)> {
+        let mut children: Box<[LoopCounters]> = nodes
+            .iter()
+            .filter(|node| matches!(node, AstNode::Loop(_)))
+            .map(|_| LoopCounters::default())
+            .collect();
+        let counters: Vec<(*mut u64, *mut u64)> = children
+            .iter_mut()
+            .map(|child| {
+                (
+                    &mut child.entries as *mut u64,
+                    &mut child.iterations as *mut u64,
+                )
+            })
+            .collect();
+
+        let target = Self::assemble_fragment(context.clone(), name, nodes.clone(), |asm| {
+            let end = asm.label();
+            let body = code_gen::loop_start(asm, end);
+
+            Self::compile_children(asm, nodes, &context, |asm, index, inner| {
+                Self::emit_loop(asm, inner, context.clone(), Some(counters[index]))
+            });
+            Self::check_cancel(asm, &context);
+            code_gen::loop_end(asm, body, end);
+        })?;
+
+        Some((target, children))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::super::super::test_buffer::SharedBuffer;
-    use super::JITTarget;
-    use crate::parser::Ast;
-    use crate::runnable::Runnable;
-    use std::io::Cursor;
+    /// Compile a loop along the path its recording took. Child loops that
+    /// ran are inlined however large they are, and those that never did are
+    /// replaced by guards. A failed guard leaves the trace for a copy of the
+    /// loop compiled as usual, at the same point in its body.
+    fn new_trace(
+        context: Rc<RefCell<JITContext>>,
+        name: &str,
+        nodes: VecDeque<AstNode>,
+        children: &[LoopCounters],
+    ) -> Option<Self> {
+        Self::assemble_fragment(context.clone(), name, nodes.clone(), |asm| {
+            let exits: Vec<Option<Label>> = children
+                .iter()
+                .map(|child| Some(asm.label()).filter(|_| child.iterations == 0))
+                .collect();
+            let done = asm.label();
 
-    #[test]
-    fn run_hello_world() {
-        let ast = Ast::parse(include_str!("../../../test/programs/hello_world.bf")).unwrap();
-        let mut jit_target = JITTarget::new(ast.data);
-        let shared_buffer = SharedBuffer::new();
-        jit_target.context.borrow_mut().io_write = Box::new(shared_buffer.clone());
+            let end = asm.label();
+            let body = code_gen::loop_start(asm, end);
+            Self::compile_children(
+                asm,
+                nodes.clone(),
+                &context,
+                |asm, index, inner| match exits[index] {
+                    Some(exit) => code_gen::guard_zero(asm, exit),
+                    None => Self::compile_loop(asm, inner, context.clone(), LoopKind::Inlined),
+                },
+            );
+            Self::check_cancel(asm, &context);
+            code_gen::loop_end(asm, body, end);
 
-        jit_target.run();
+            if exits.iter().all(Option::is_none) {
+                return;
+            }
 
-        let output_string = shared_buffer.get_string_content();
-        assert_eq!(output_string, "Hello World!\n");
+            code_gen::jump(asm, done);
+
+            // Only reached through the guards.
+            let end = asm.label();
+            let body = asm.label();
+            asm.bind(body);
+            Self::compile_children(asm, nodes, &context, |asm, index, inner| {
+                if let Some(exit) = exits[index] {
+                    asm.bind(exit);
+                }
+                Self::shallow_compile(asm, VecDeque::from([AstNode::Loop(inner)]), context.clone());
+            });
+            Self::check_cancel(asm, &context);
+            code_gen::loop_end(asm, body, end);
+            asm.bind(done);
+        })
     }
 
-    #[test]
-    fn run_mandelbrot() {
-        let ast = Ast::parse(include_str!("../../../test/programs/mandelbrot.bf")).unwrap();
-        let mut jit_target = JITTarget::new(ast.data);
-        let shared_buffer = SharedBuffer::new();
-        jit_target.context.borrow_mut().io_write = Box::new(shared_buffer.clone());
+    /// Compile the body of a loop, leaving each child loop to `child` along
+    /// with its index among them.
+    fn compile_children(
+        asm: &mut Assembler,
+        nodes: VecDeque<AstNode>,
+        context: &Rc<RefCell<JITContext>>,
+        mut child: impl FnMut(&mut Assembler, usize, VecDeque<AstNode>),
+    ) {
+        let mut run = VecDeque::new();
+        let mut index = 0;
 
-        jit_target.run();
+        for node in nodes {
+            match node {
+                AstNode::Loop(inner) => {
+                    Self::shallow_compile(asm, mem::take(&mut run), context.clone());
+                    child(asm, index, inner);
+                    index += 1;
+                }
+                node => run.push_back(node),
+            }
+        }
 
-        let output_string = shared_buffer.get_string_content();
-        let expected_output = include_str!("../../../test/programs/mandelbrot.out");
-        assert_eq!(output_string, expected_output);
+        Self::shallow_compile(asm, run, context.clone());
     }
 
-    #[test]
-    fn run_rot13() {
-        // This rot13 program terminates after 16 characters so we can test it. Otherwise it would
-        // wait on input forever.
-        let ast = Ast::parse(include_str!("../../../test/programs/rot13-16char.bf")).unwrap();
-        let mut jit_target = JITTarget::new(ast.data);
-        let shared_buffer = SharedBuffer::new();
-        jit_target.context.borrow_mut().io_write = Box::new(shared_buffer.clone());
-        let in_cursor = Box::new(Cursor::new("Hello World! 123".as_bytes().to_vec()));
-        jit_target.context.borrow_mut().io_read = in_cursor;
+    /// Compile a vector of AstNodes into executable bytes.
+    fn shallow_compile(
+        asm: &mut Assembler,
+        nodes: VecDeque<AstNode>,
+        context: Rc<RefCell<JITContext>>,
+    ) {
+        let wrap = context.borrow().tape_mode == TapeMode::Wrapped;
+        // Compiled tape stats assume cells are never reached by wrapping.
+        let tape_stats = Self::tape_stats(&context).filter(|_| !wrap);
+        // Offsets can't wrap around the tape, and instrumentation has to see
+        // each node where it happens.
+        let batched = {
+            let instrumentation = &context.borrow().instrumentation;
+            !wrap
+                && !instrumentation.observes_instructions()
+                && !instrumentation.observes_loops()
+                && tape_stats.is_none()
+        };
+        let mut block = CellBlock::default();
 
-        jit_target.run();
+        for node in nodes {
+            if batched {
+                if block.push(&node) {
+                    if asm.annotating() {
+                        block.nodes.push(node);
+                    }
 
-        let output_string = shared_buffer.get_string_content();
-        assert_eq!(output_string, "Uryyb Jbeyq! 123");
+                    continue;
+                }
+
+                Self::compile_block(asm, mem::take(&mut block), &context);
+            }
+
+            asm.annotate(|| Self::describe(std::slice::from_ref(&node)));
+
+            // Memory has to grow before anything is written past its end,
+            // including by the instrumentation.
+            match node {
+                AstNode::AddTo(offset) | AstNode::SubFrom(offset) if !wrap && offset > 0 => {
+                    code_gen::grow_if_past_end(
+                        asm,
+                        Self::tape_end(&context),
+                        offset as usize,
+                        Self::stop_flag(&context),
+                    );
+                }
+                AstNode::AddTo(offset) | AstNode::SubFrom(offset)
+                    if !wrap && offset < -(GUARD_SIZE as isize) =>
+                {
+                    code_gen::underflow_if_before_start(
+                        asm,
+                        Self::tape_start(&context),
+                        offset,
+                        Self::add_far_move(&node, &context),
+                    );
+                }
+                _ => {}
+            }
+
+            Self::instrument_node(asm, &node, &context, tape_stats);
+
+            match node {
+                AstNode::Incr(n) => code_gen::incr(asm, n),
+                AstNode::Decr(n) => code_gen::decr(asm, n),
+                AstNode::Next(n) if wrap => code_gen::next_wrapping(asm, n, WRAPPED_TAPE_SIZE),
+                AstNode::Prev(n) if wrap => code_gen::prev_wrapping(asm, n, WRAPPED_TAPE_SIZE),
+                AstNode::AddTo(n) if wrap => code_gen::add_wrapping(asm, n, WRAPPED_TAPE_SIZE),
+                AstNode::SubFrom(n) if wrap => code_gen::sub_wrapping(asm, n, WRAPPED_TAPE_SIZE),
+                AstNode::Next(n) => {
+                    code_gen::next(asm, n);
+                    code_gen::grow_if_past_end(
+                        asm,
+                        Self::tape_end(&context),
+                        0,
+                        Self::stop_flag(&context),
+                    );
+
+                    if let Some(tape_stats) = tape_stats {
+                        code_gen::record_dp(asm, tape_stats);
+                    }
+                }
+                AstNode::Prev(n) => {
+                    code_gen::prev(asm, n);
+
+                    if n > GUARD_SIZE {
+                        code_gen::underflow_if_before_start(
+                            asm,
+                            Self::tape_start(&context),
+                            0,
+                            Self::add_far_move(&node, &context),
+                        );
+                    }
+                }
+                AstNode::Print => {
+                    code_gen::print(asm);
+                    code_gen::return_if_set(asm, Self::stop_flag(&context));
+                }
+                AstNode::Read => {
+                    code_gen::read(asm);
+                    code_gen::return_if_set(asm, Self::stop_flag(&context));
+                }
+                AstNode::Extension(command) => {
+                    code_gen::extension(asm, command as u32);
+                    code_gen::return_if_set(asm, Self::stop_flag(&context));
+                }
+                AstNode::Set(n) => code_gen::set(asm, n),
+                AstNode::AddTo(n) => code_gen::add(asm, n),
+                AstNode::SubFrom(n) => code_gen::sub(asm, n),
+                AstNode::Loop(nodes) if Self::inlines(&nodes, &context) => {
+                    Self::compile_loop(asm, nodes, context.clone(), LoopKind::Inlined)
+                }
+                AstNode::Loop(nodes) => {
+                    Self::defer_loop(asm, nodes, context.clone());
+                    // The fragment may have returned early, in which case this
+                    // function has to as well.
+                    code_gen::return_if_set(asm, Self::stop_flag(&context));
+                }
+            };
+        }
+
+        Self::compile_block(asm, block, &context);
+    }
+
+    /// Whether the loop with the body `nodes` is compiled into its parent
+    /// rather than into a fragment of its own.
+    fn inlines(nodes: &VecDeque<AstNode>, context: &Rc<RefCell<JITContext>>) -> bool {
+        match context.borrow().hot_loops {
+            Some(ref hot_loops) => hot_loops.is_hot(nodes),
+            None => nodes.len() < INLINE_THRESHOLD,
+        }
+    }
+
+    /// Emit a block of cell updates, growing memory first to cover every cell
+    /// it touches.
+    fn compile_block(asm: &mut Assembler, block: CellBlock, context: &Rc<RefCell<JITContext>>) {
+        if !block.nodes.is_empty() {
+            asm.annotate(|| Self::describe(&block.nodes));
+        }
+
+        let reach = block.reach();
+        if reach > 0 {
+            code_gen::grow_if_past_end(
+                asm,
+                Self::tape_end(context),
+                reach as usize,
+                Self::stop_flag(context),
+            );
+        }
+
+        code_gen::update_cells(asm, &block.ops);
+
+        match block.shift {
+            shift if shift > 0 => code_gen::next(asm, shift as usize),
+            shift if shift < 0 => code_gen::prev(asm, shift.unsigned_abs()),
+            _ => {}
+        }
+    }
+
+    /// Emit the instrumentation that runs before an AstNode.
+    fn instrument_node(
+        bytes: &mut Vec<u8>,
+        node: &AstNode,
+        context: &Rc<RefCell<JITContext>>,
+        tape_stats: Option<usize>,
+    ) {
+        if context.borrow().instrumentation.observes_instructions() {
+            code_gen::instrument(bytes, Self::add_location(node, context));
+            code_gen::return_if_set(bytes, Self::stop_flag(context));
+        }
+
+        if let AstNode::Set(_) | AstNode::AddTo(_) | AstNode::SubFrom(_) = node {
+            let counters =
+                Self::add_loop_counters(LoopKind::Simplified, || node.to_string(), context);
+
+            if let Some((entries, _)) = counters {
+                code_gen::count(bytes, entries);
+            }
+        }
+
+        if let Some(tape_stats) = tape_stats {
+            match node {
+                AstNode::Incr(_)
+                | AstNode::Decr(_)
+                | AstNode::Read
+                | AstNode::Set(_)
+                | AstNode::Extension(_) => {
+                    code_gen::record_write(bytes, tape_stats, 0);
+                }
+                AstNode::AddTo(offset) | AstNode::SubFrom(offset) => {
+                    code_gen::record_write(bytes, tape_stats, 0);
+                    code_gen::record_write(bytes, tape_stats, *offset);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Address of the flag that makes compiled code return early.
+    fn stop_flag(context: &Rc<RefCell<JITContext>>) -> *const bool {
+        context.borrow().stopped.as_ptr()
+    }
+
+    /// Return early once the run has been cancelled, if it can be. Emitted at
+    /// the end of every loop iteration, so that no loop can run on past
+    /// cancellation.
+    fn check_cancel(asm: &mut Assembler, context: &Rc<RefCell<JITContext>>) {
+        if let Some(ref cancel) = context.borrow().instrumentation.cancel {
+            code_gen::return_if_set(asm, cancel.as_ptr());
+        }
+    }
+
+    /// Address of the end of memory, which compiled code checks before moving
+    /// past it.
+    fn tape_end(context: &Rc<RefCell<JITContext>>) -> *const usize {
+        context.borrow().tape_end.as_ptr()
+    }
+
+    /// Address of the address of the first memory cell, for compiled code to
+    /// check moves left of the tape against.
+    fn tape_start(context: &Rc<RefCell<JITContext>>) -> *const usize {
+        context.borrow().memory_base.as_ptr()
+    }
+
+    /// Address of the TapeStats that compiled code should update, if any.
+    fn tape_stats(context: &Rc<RefCell<JITContext>>) -> Option<usize> {
+        let context = context.borrow();
+        let tape_stats = context.instrumentation.tape.as_ref()?;
+
+        Some(tape_stats.as_ptr() as usize)
+    }
+
+    /// An AstNode as shown to the instrumentation, with the nodes of loops
+    /// counted rather than listed.
+    fn description(node: &AstNode) -> String {
+        match node {
+            AstNode::Loop(nodes) => format!("Loop({} nodes)", nodes.len()),
+            node => format!("{:?}", node),
+        }
+    }
+
+    /// Annotation for the code compiled from `nodes`: the BrainFuck they
+    /// stand for, then the nodes themselves.
+    fn describe(nodes: &[AstNode]) -> String {
+        let abbreviate = |text: String| match text.char_indices().nth(ANNOTATION_WIDTH) {
+            Some((end, _)) => format!("{}...", &text[..end]),
+            None => text,
+        };
+        let source: String = nodes.iter().map(AstNode::to_string).collect();
+        let descriptions: Vec<String> = nodes.iter().map(Self::description).collect();
+
+        format!(
+            "{}  {}",
+            abbreviate(source),
+            abbreviate(descriptions.join(", "))
+        )
+    }
+
+    /// Register an AstNode with the instrumentation, returning the ID compiled
+    /// code should report when executing it.
+    fn add_location(node: &AstNode, context: &Rc<RefCell<JITContext>>) -> usize {
+        let description = Self::description(node);
+        let mut context = context.borrow_mut();
+
+        if let Some(ref mut profiler) = context.instrumentation.profiler {
+            profiler.add_location(node.name(), description.clone());
+        }
+
+        context.locations.push(description);
+
+        context.locations.len() - 1
+    }
+
+    /// Register a node compiled code checks for moving too far left, returning
+    /// the ID it should report if it does.
+    fn add_far_move(node: &AstNode, context: &Rc<RefCell<JITContext>>) -> usize {
+        let mut context = context.borrow_mut();
+        context.far_moves.push(Self::description(node));
+
+        context.far_moves.len() - 1
+    }
+
+    /// Register a loop with the profiler, if it observes loops, returning
+    /// pointers to its entry and iteration counters if it has them.
+    fn add_loop_counters(
+        kind: LoopKind,
+        source: impl FnOnce() -> String,
+        context: &Rc<RefCell<JITContext>>,
+    ) -> Option<(*mut u64, *mut u64)> {
+        let mut context = context.borrow_mut();
+        let context = &mut *context;
+        let profiler = context
+            .instrumentation
+            .profiler
+            .as_mut()
+            .filter(|profiler| profiler.observes_loops())?;
+        profiler.add_loop(kind, source());
+        let id = context.loops_added;
+        context.loops_added += 1;
+        let counters = profiler.loop_counters(id)?;
+
+        // Profilers keep their counters in place for as long as the program
+        // can run.
+        unsafe {
+            Some((
+                ptr::addr_of_mut!((*counters).entries),
+                ptr::addr_of_mut!((*counters).iterations),
+            ))
+        }
+    }
+
+    /// Perform AOT compilation on a loop.
+    fn compile_loop(
+        asm: &mut Assembler,
+        nodes: VecDeque<AstNode>,
+        context: Rc<RefCell<JITContext>>,
+        kind: LoopKind,
+    ) {
+        let source = || {
+            let body: String = nodes.iter().map(|node| node.to_string()).collect();
+            format!("[{}]", body)
+        };
+        let counters = Self::add_loop_counters(kind, source, &context);
+
+        Self::emit_loop(asm, nodes, context, counters);
+    }
+
+    /// Emit a loop, counting its entries and iterations if given counters.
+    fn emit_loop(
+        asm: &mut Assembler,
+        nodes: VecDeque<AstNode>,
+        context: Rc<RefCell<JITContext>>,
+        counters: Option<(*mut u64, *mut u64)>,
+    ) {
+        let end = asm.label();
+
+        if let Some((entries, _)) = counters {
+            code_gen::count(asm, entries);
+        }
+
+        let body = code_gen::loop_start(asm, end);
+
+        if let Some((_, iterations)) = counters {
+            code_gen::count(asm, iterations);
+        }
+
+        Self::shallow_compile(asm, nodes, context.clone());
+        asm.annotate(|| "]".to_string());
+        Self::check_cancel(asm, &context);
+        code_gen::loop_end(asm, body, end);
+    }
+
+    /// Compile calls to each segment of `nodes` in turn, leaving the segments
+    /// to be compiled when they are reached.
+    fn defer_segments(
+        asm: &mut Assembler,
+        nodes: &VecDeque<AstNode>,
+        context: &Rc<RefCell<JITContext>>,
+    ) {
+        let nodes: Vec<&AstNode> = nodes.iter().collect();
+
+        for (index, segment) in nodes.chunks(SEGMENT_SIZE).enumerate() {
+            let segment = segment.iter().map(|&node| node.clone()).collect();
+            let promise_id = context
+                .borrow_mut()
+                .promises
+                .add_segment(segment, index * SEGMENT_SIZE);
+            code_gen::jit_loop(asm, promise_id);
+            code_gen::return_if_set(asm, Self::stop_flag(context));
+        }
+    }
+
+    /// Compile a segment of the program outside of any loop.
+    fn new_segment(
+        context: Rc<RefCell<JITContext>>,
+        name: &str,
+        nodes: VecDeque<AstNode>,
+    ) -> Option<Self> {
+        Self::assemble_fragment(context.clone(), name, nodes.clone(), |asm| {
+            Self::shallow_compile(asm, nodes, context)
+        })
+    }
+
+    /// Perform JIT compilation on a loop.
+    /// Once the loop is compiled, later entries call it directly rather than
+    /// going through `jit_callback`, unless every entry has to be counted.
+    fn defer_loop(asm: &mut Assembler, nodes: VecDeque<AstNode>, context: Rc<RefCell<JITContext>>) {
+        let mut context = context.borrow_mut();
+        let promise_id = context.promises.add(nodes);
+        asm.annotate(|| format!("call {}", context.promises.name(promise_id)));
+
+        if context.direct_calls {
+            let entry = context.entry_point(promise_id);
+            code_gen::call_loop(asm, promise_id, entry, interrupt::flag());
+        } else {
+            code_gen::jit_loop(asm, promise_id);
+        }
+    }
+
+    /// Callback passed into compiled code. Allows for deferred compilation
+    /// targets to be compiled, ran, and later re-ran.
+    extern "C" fn jit_callback(&mut self, promise_id: JITPromiseID, mem_ptr: *mut u8) -> *mut u8 {
+        if self.context.borrow_mut().check_interrupt() {
+            return mem_ptr;
+        }
+
+        self.context.borrow_mut().report_progress();
+
+        let mut promise = self.context.borrow_mut().promises.take(promise_id);
+        let over_code_limit = self.context.borrow().over_code_limit;
+        let return_ptr;
+        let new_promise;
+
+        match promise {
+            JITPromise::Deferred(nodes) if over_code_limit => {
+                return_ptr = self.run_uncompiled(&nodes, true, mem_ptr);
+                new_promise = Some(JITPromise::Deferred(nodes));
+            }
+            JITPromise::Segment(nodes) if over_code_limit => {
+                return_ptr = self.run_uncompiled(&nodes, false, mem_ptr);
+                new_promise = Some(JITPromise::Segment(nodes));
+            }
+            JITPromise::Deferred(nodes) if self.context.borrow().tracing => {
+                let name = self.context.borrow().promises.name(promise_id);
+                let recording = {
+                    span!(
+                        "compile_promise",
+                        id = promise_id,
+                        name = &*name,
+                        kind = "recording"
+                    );
+                    Self::new_recording(self.context.clone(), &name, nodes.clone())
+                };
+
+                match recording {
+                    Some((mut target, children)) => {
+                        self.context.borrow_mut().emit(Event::Compiled {
+                            fragment: promise_id,
+                            name: &name,
+                            kind: "recording",
+                            nodes: target.source.len(),
+                        });
+                        return_ptr = target.exec(mem_ptr);
+                        new_promise = Some(JITPromise::Recording {
+                            target,
+                            children,
+                            runs: 1,
+                        });
+                    }
+                    None => {
+                        return_ptr = self.run_uncompiled(&nodes, true, mem_ptr);
+                        new_promise = Some(JITPromise::Deferred(nodes));
+                    }
+                }
+            }
+            JITPromise::Recording {
+                ref mut target,
+                ref children,
+                ref mut runs,
+            } => {
+                return_ptr = target.exec(mem_ptr);
+                *runs += 1;
+
+                // A recording that can't be traced under the code limit
+                // carries on as it is.
+                new_promise = if *runs < HOT_RUNS || over_code_limit {
+                    Some(promise)
+                } else {
+                    let name = self.context.borrow().promises.name(promise_id);
+                    self.context.borrow_mut().emit(Event::Promoted {
+                        fragment: promise_id,
+                        name: &name,
+                        runs: *runs,
+                    });
+                    span!(
+                        "compile_promise",
+                        id = promise_id,
+                        name = &*name,
+                        kind = "trace"
+                    );
+                    let trace = Self::new_trace(
+                        self.context.clone(),
+                        &name,
+                        target.source.clone(),
+                        children,
+                    );
+
+                    match trace {
+                        Some(trace) => {
+                            self.context.borrow_mut().emit(Event::Compiled {
+                                fragment: promise_id,
+                                name: &name,
+                                kind: "trace",
+                                nodes: trace.source.len(),
+                            });
+                            Some(JITPromise::Compiled(trace))
+                        }
+                        None => Some(promise),
+                    }
+                };
+            }
+            JITPromise::Deferred(nodes) => {
+                let name = self.context.borrow().promises.name(promise_id);
+                let new_target = {
+                    span!(
+                        "compile_promise",
+                        id = promise_id,
+                        name = &*name,
+                        kind = "fragment"
+                    );
+                    Self::new_fragment(self.context.clone(), &name, nodes.clone())
+                };
+
+                match new_target {
+                    Some(mut new_target) => {
+                        self.context.borrow_mut().emit(Event::Compiled {
+                            fragment: promise_id,
+                            name: &name,
+                            kind: "fragment",
+                            nodes: new_target.source.len(),
+                        });
+                        return_ptr = new_target.exec(mem_ptr);
+                        new_promise = Some(JITPromise::Compiled(new_target));
+                    }
+                    None => {
+                        return_ptr = self.run_uncompiled(&nodes, true, mem_ptr);
+                        new_promise = Some(JITPromise::Deferred(nodes));
+                    }
+                }
+            }
+            JITPromise::Compiled(ref mut jit_target) => {
+                return_ptr = jit_target.exec(mem_ptr);
+                new_promise = Some(promise);
+            }
+            JITPromise::Segment(nodes) => {
+                let name = self.context.borrow().promises.name(promise_id);
+                let target = {
+                    span!(
+                        "compile_promise",
+                        id = promise_id,
+                        name = &*name,
+                        kind = "segment"
+                    );
+                    Self::new_segment(self.context.clone(), &name, nodes.clone())
+                };
+
+                return_ptr = match target {
+                    Some(mut target) => {
+                        self.context.borrow_mut().emit(Event::Compiled {
+                            fragment: promise_id,
+                            name: &name,
+                            kind: "segment",
+                            nodes: target.source.len(),
+                        });
+                        target.exec(mem_ptr)
+                    }
+                    None => self.run_uncompiled(&nodes, false, mem_ptr),
+                };
+                // Only the nodes are kept, to compile again on the next run.
+                new_promise = Some(JITPromise::Segment(nodes));
+            }
+        };
+
+        let mut context = self.context.borrow_mut();
+        context.set_promise(promise_id, new_promise);
+        // The fragment may have returned because the run was cancelled, in
+        // which case its callers have to as well.
+        context.check_cancelled();
+
+        return_ptr
+    }
+
+    /// Run a loop, or a segment if `is_loop` isn't set, that doesn't fit
+    /// under the code limit without compiling it if the limit allows,
+    /// otherwise stop the program.
+    fn run_uncompiled(
+        &mut self,
+        nodes: &VecDeque<AstNode>,
+        is_loop: bool,
+        mem_ptr: *mut u8,
+    ) -> *mut u8 {
+        let limit = match self.context.borrow().code_limit {
+            Some(limit) => limit,
+            None => unreachable!("Only code over the limit is left uncompiled"),
+        };
+
+        if !limit.interpret {
+            self.context.borrow_mut().fail(format!(
+                "Exceeded the JIT code limit of {} bytes.",
+                limit.bytes
+            ));
+            return mem_ptr;
+        }
+
+        if is_loop {
+            self.interpret_loop(nodes, mem_ptr)
+        } else {
+            self.interpret(nodes, mem_ptr)
+        }
+    }
+
+    /// Run a loop with the body `nodes` without compiling it.
+    fn interpret_loop(&mut self, nodes: &VecDeque<AstNode>, mut mem_ptr: *mut u8) -> *mut u8 {
+        loop {
+            match self.cell(mem_ptr, 0) {
+                Some(cell) if unsafe { *cell } != 0 => {}
+                _ => return mem_ptr,
+            }
+
+            mem_ptr = self.interpret(nodes, mem_ptr);
+
+            if self.context.borrow_mut().check_interrupt() || self.stopped() {
+                return mem_ptr;
+            }
+        }
+    }
+
+    /// Run `nodes` without compiling them, doing what compiled code would
+    /// but without being seen by the instrumentation.
+    fn interpret(&mut self, nodes: &VecDeque<AstNode>, mut mem_ptr: *mut u8) -> *mut u8 {
+        let (wrap, cell_overflow) = {
+            let context = self.context.borrow();
+            (
+                context.tape_mode == TapeMode::Wrapped,
+                context.cell_overflow,
+            )
+        };
+
+        for node in nodes {
+            if self.stopped() {
+                break;
+            }
+
+            match *node {
+                AstNode::Next(n) if wrap => mem_ptr = self.wrapped(mem_ptr, n as isize),
+                AstNode::Prev(n) if wrap => mem_ptr = self.wrapped(mem_ptr, -(n as isize)),
+                AstNode::Next(n) => mem_ptr = self.reach(mem_ptr.wrapping_add(n), 0),
+                AstNode::Prev(n) => mem_ptr = mem_ptr.wrapping_sub(n),
+                AstNode::Extension(command) => self.extension(command as u32, mem_ptr),
+                AstNode::Loop(ref inner) => mem_ptr = self.interpret_loop(inner, mem_ptr),
+                AstNode::AddTo(offset) | AstNode::SubFrom(offset) => {
+                    if !wrap && offset > 0 {
+                        mem_ptr = self.reach(mem_ptr, offset as usize);
+                    }
+
+                    let cell = match self.cell(mem_ptr, 0) {
+                        Some(cell) => cell,
+                        None => break,
+                    };
+                    let value = unsafe { *cell };
+                    if value == 0 {
+                        continue;
+                    }
+
+                    let target = match self.cell(mem_ptr, offset) {
+                        Some(target) => target,
+                        None => break,
+                    };
+                    let updated = match *node {
+                        AstNode::AddTo(_) => cell_overflow.add(unsafe { *target }, value),
+                        _ => cell_overflow.sub(unsafe { *target }, value),
+                    };
+
+                    match updated {
+                        Some(updated) => unsafe {
+                            *target = updated;
+                            *cell = 0;
+                        },
+                        None => self.overflow(mem_ptr, offset),
+                    }
+                }
+                AstNode::Incr(_)
+                | AstNode::Decr(_)
+                | AstNode::Set(_)
+                | AstNode::Print
+                | AstNode::Read => {
+                    let cell = match self.cell(mem_ptr, 0) {
+                        Some(cell) => cell,
+                        None => break,
+                    };
+                    let value = unsafe { *cell };
+                    let updated = match *node {
+                        AstNode::Incr(n) => cell_overflow.add(value, n),
+                        AstNode::Decr(n) => cell_overflow.sub(value, n),
+                        AstNode::Set(n) => Some(n),
+                        AstNode::Read => Some(self.read()),
+                        _ => {
+                            self.print(value);
+                            Some(value)
+                        }
+                    };
+
+                    match updated {
+                        Some(updated) => unsafe { *cell = updated },
+                        None => self.overflow(mem_ptr, 0),
+                    }
+                }
+            }
+        }
+
+        mem_ptr
+    }
+
+    /// Whether the program has been told to stop.
+    fn stopped(&self) -> bool {
+        self.context.borrow().stopped.get()
+    }
+
+    /// The cell `offset` cells from `mem_ptr` on a wrapped tape.
+    fn wrapped(&self, mem_ptr: *mut u8, offset: isize) -> *mut u8 {
+        let memory_base = self.context.borrow().memory_base.get();
+        let dp = mem_ptr as usize - memory_base;
+        let cell = (dp as isize + offset).rem_euclid(WRAPPED_TAPE_SIZE as isize);
+
+        (memory_base + cell as usize) as *mut u8
+    }
+
+    /// Grow memory if the cell `offset` cells right of `mem_ptr` is past its
+    /// end, returning where the data pointer has moved to.
+    fn reach(&mut self, mem_ptr: *mut u8, offset: usize) -> *mut u8 {
+        let cell_ptr = mem_ptr.wrapping_add(offset);
+
+        if (cell_ptr as usize) < self.context.borrow().tape_end.get() {
+            return mem_ptr;
+        }
+
+        self.grow(mem_ptr, cell_ptr)
+    }
+
+    /// The cell `offset` cells from `mem_ptr`, which has to be inside memory,
+    /// or None after stopping the program if it's left of the tape.
+    fn cell(&mut self, mem_ptr: *mut u8, offset: isize) -> Option<*mut u8> {
+        if self.context.borrow().tape_mode == TapeMode::Wrapped {
+            return Some(self.wrapped(mem_ptr, offset));
+        }
+
+        let cell = mem_ptr.wrapping_offset(offset);
+        let mut context = self.context.borrow_mut();
+
+        if (cell as usize) < context.memory_base.get() {
+            context.fail(UNDERFLOW.to_string());
+            return None;
+        }
+
+        Some(cell)
+    }
+
+    /// The context, without the cost of borrowing it, for the callbacks made
+    /// on every `.` and `,`.
+    fn io_context(&mut self) -> &mut JITContext {
+        debug_assert!(self.context.try_borrow_mut().is_ok());
+        // Compiled code is only ever run while nothing has the context
+        // borrowed, and printing and reading don't call anything that could
+        // borrow it.
+        unsafe { &mut *self.context.as_ptr() }
+    }
+
+    /// Print a single byte (called by JIT compiled code)
+    extern "C" fn print(&mut self, byte: u8) {
+        let context = self.io_context();
+
+        if context.check_interrupt() {
+            return;
+        }
+
+        context.output.push(byte);
+        context.bytes_written += 1;
+
+        if context.output.len() >= OUTPUT_BUFFER_SIZE || (byte == b'\n' && context.line_buffered) {
+            context.flush_output();
+        }
+    }
+
+    /// Read a single byte (called by JIT compiled code)
+    extern "C" fn read(&mut self) -> u8 {
+        let mut buffer = [0];
+        let context = self.io_context();
+
+        if context.check_interrupt() {
+            return 0;
+        }
+
+        // Whatever the program printed may be what it's waiting on an answer
+        // to.
+        context.flush_output();
+        if context.stopped.get() {
+            return 0;
+        }
+
+        if let Err(error) = context.io_write.flush() {
+            context.fail(format!("Failed to write output: {}", error));
+            return 0;
+        }
+
+        let read_result = context.io_read.read_exact(&mut buffer);
+
+        if let Err(error) = read_result {
+            if error.kind() == io::ErrorKind::UnexpectedEof {
+                // Just send out newlines forever if the read stream has ended.
+                return b'\n';
+            }
+
+            context.fail(format!("Failed to read input: {}", error));
+        } else {
+            context.bytes_read += 1;
+        }
+
+        buffer[0]
+    }
+
+    /// Report an executed AstNode to the instrumentation (called by JIT
+    /// compiled code)
+    extern "C" fn instrument(&mut self, location: usize, mem_ptr: *mut u8) {
+        let mut context = self.context.borrow_mut();
+        let context = &mut *context;
+
+        if context.check_interrupt() {
+            return;
+        }
+
+        // Compiled code would fault on the cell in the guard pages, but this
+        // reads it first.
+        if (mem_ptr as usize) < context.memory_base.get() {
+            context.fail(UNDERFLOW.to_string());
+            return;
+        }
+
+        if let Some(ref mut tracer) = context.instrumentation.tracer {
+            let dp = mem_ptr as usize - context.memory_base.get();
+            let cell = unsafe { *mem_ptr };
+            let description = &context.locations[location];
+            let trace_result = tracer.trace(location, dp, cell, &format_args!("{}", description));
+
+            if let Err(error) = trace_result {
+                context.fail(format!("Failed to write trace: {}", error));
+                return;
+            }
+        }
+
+        if let Some(ref mut profiler) = context.instrumentation.profiler {
+            profiler.instruction_retired(location);
+        }
+    }
+
+    /// Grow memory to hold the cell at `cell_ptr`, returning where the data
+    /// pointer has moved to (called by JIT compiled code)
+    extern "C" fn grow(&mut self, mem_ptr: *mut u8, cell_ptr: *mut u8) -> *mut u8 {
+        let mut context = self.context.borrow_mut();
+        let context = &mut *context;
+        // The data pointer may be left of the tape when a cell to its right is
+        // past the end.
+        let dp = (mem_ptr as usize).wrapping_sub(context.memory_base.get());
+        let cell = cell_ptr as usize - context.memory_base.get();
+        let max_len = context.origin.saturating_add(context.tape_limit);
+
+        if cell >= max_len {
+            context.fail(format!(
+                "Exceeded the tape limit of {} cells.",
+                context.tape_limit
+            ));
+            return mem_ptr;
+        }
+
+        // Grow the same way as the interpreter, to a double of the current
+        // size or just past the cell, but no further than the tape limit.
+        let new_len = cmp::max(context.memory.len() * 2, cell + 1).min(max_len);
+        context.memory.resize(new_len);
+        context.memory_base.set(context.memory.as_ptr() as usize);
+        context.tape_end.set(context.memory_base.get() + new_len);
+        context.watch_guard();
+
+        if let Some(ref tape_stats) = context.instrumentation.tape {
+            tape_stats.borrow_mut().attach(&context.memory);
+        }
+
+        context.memory.as_mut_ptr().wrapping_add(dp)
+    }
+
+    /// Stop the program because the cell `offset` cells from the data
+    /// pointer overflowed (called by JIT compiled code)
+    extern "C" fn overflow(&mut self, mem_ptr: *mut u8, offset: isize) {
+        let mut context = self.context.borrow_mut();
+        let dp = mem_ptr as usize - context.memory_base.get();
+        let mut cell = dp as isize + offset;
+
+        if context.tape_mode == TapeMode::Wrapped {
+            cell = cell.rem_euclid(WRAPPED_TAPE_SIZE as isize);
+        }
+
+        context.fail(format!("Cell {} overflowed.", cell));
+    }
+
+    /// Run the extension added for `command` on the current cell (called
+    /// by JIT compiled code)
+    extern "C" fn extension(&mut self, command: u32, mem_ptr: *mut u8) {
+        let mut context = self.context.borrow_mut();
+
+        if context.check_interrupt() {
+            return;
+        }
+
+        // Compiled code would fault on the cell in the guard pages, but the
+        // extension would touch it first.
+        if (mem_ptr as usize) < context.memory_base.get() {
+            context.fail(UNDERFLOW.to_string());
+            return;
+        }
+
+        // Commands are always characters, since they were compiled from one.
+        let command = char::from_u32(command).unwrap_or_default();
+        if let Err(error) = context.extensions.run(command, unsafe { &mut *mem_ptr }) {
+            context.fail(error);
+        }
+    }
+
+    /// Stop the program because it touched a cell left of the tape (called
+    /// by JIT compiled code)
+    extern "C" fn underflow(&mut self) {
+        self.context.borrow_mut().fail(UNDERFLOW.to_string());
+    }
+
+    /// Stop the program because the node registered as `location` moved or
+    /// reached too far left of the tape for the guard pages (called by JIT
+    /// compiled code)
+    extern "C" fn far_underflow(&mut self, location: usize) {
+        let mut context = self.context.borrow_mut();
+        let description = context.far_moves[location].clone();
+
+        context.fail(format!(
+            "{} at {}.",
+            UNDERFLOW.trim_end_matches('.'),
+            description
+        ));
+        context.error_location = Some(description);
+    }
+
+    /// Run on a prepared tape rather than an empty one, returning the final
+    /// data pointer. The program must stay inside the tape.
+    #[cfg(test)]
+    pub(super) fn run_on_tape(&mut self, tape: &mut [u8], dp: usize) -> usize {
+        {
+            let context = self.context.borrow_mut();
+            context.memory_base.set(tape.as_ptr() as usize);
+            context.tape_end.set(context.memory_base.get() + tape.len());
+        }
+
+        let end = self.exec(tape[dp..].as_mut_ptr());
+        self.context.borrow_mut().flush_output();
+
+        end as usize - tape.as_ptr() as usize
+    }
+
+    /// Take the error that stopped the last run, for tests that run on a
+    /// prepared tape.
+    #[cfg(test)]
+    pub(super) fn take_error(&mut self) -> Option<String> {
+        self.context.borrow_mut().error.take()
+    }
+
+    /// Execute the bytes buffer as a function.
+    fn exec(&mut self, mem_ptr: *mut u8) -> *mut u8 {
+        let vtable: VTable<9> = [
+            Self::jit_callback as VoidPtr,
+            Self::read as VoidPtr,
+            Self::print as VoidPtr,
+            Self::instrument as VoidPtr,
+            Self::grow as VoidPtr,
+            Self::overflow as VoidPtr,
+            Self::underflow as VoidPtr,
+            Self::extension as VoidPtr,
+            Self::far_underflow as VoidPtr,
+        ];
+
+        type JitFunc = extern "C" fn(*mut u8, &mut JITTarget, &VTable<9>) -> *mut u8;
+        let func: JitFunc = unsafe { mem::transmute(self.bytes.as_ptr()) };
+
+        func(mem_ptr, self, &vtable)
+    }
+}
+
+impl Runnable for JITTarget {
+    fn run_report(&mut self) -> Result<(FinalState, RunReport), String> {
+        span!("run", backend = "jit");
+        let mut context = self.context.borrow_mut();
+        // Where the data pointer starts, as an index from the first cell
+        let (mut bf_mem, dp) = match context.kept_dp.take() {
+            Some(dp) => (mem::take(&mut context.memory), dp),
+            None => {
+                let bf_mem = context.new_tape();
+                (bf_mem, context.origin)
+            }
+        };
+        // A wrapped tape starts part way into its buffer.
+        let start = context.memory_base.get() - bf_mem.as_ptr() as usize;
+        let len = context.tape_end.get() - context.memory_base.get();
+        let entry = bf_mem[start + dp..].as_mut_ptr();
+        context.stopped.set(false);
+        context.output_closed = false;
+        context.interrupted = false;
+        context.error = None;
+        context.error_location = None;
+        context.bytes_read = 0;
+        context.bytes_written = 0;
+        context.entries = 0;
+        context.started = Instant::now();
+
+        if let Some(ref tape_stats) = context.instrumentation.tape {
+            tape_stats.borrow_mut().attach(&bf_mem[start..start + len]);
+        }
+
+        // Moving the tape leaves its cells where they are.
+        context.memory = bf_mem;
+        context.watch_guard();
+        drop(context);
+        let end = self.exec(entry);
+        guard::unwatch();
+        let mut context = self.context.borrow_mut();
+        context.flush_output();
+
+        // The data pointer can be left of the tape without a cell there
+        // having been touched.
+        if (end as usize) < context.memory_base.get() && context.error.is_none() {
+            context.fail(UNDERFLOW.to_string());
+        }
+        let bf_mem = mem::take(&mut context.memory);
+        context.check_cancelled();
+
+        if let Some(error) = context.error.take() {
+            let location = context.error_location.take();
+            let event = Event::Error {
+                message: &error,
+                location: location.as_deref(),
+            };
+            // The error is more important than the log.
+            events::emit(&mut context.instrumentation.events, event).ok();
+
+            return Err(error);
+        }
+
+        let dp = end as usize - context.memory_base.get();
+        let len = context.tape_end.get() - context.memory_base.get();
+
+        let state = FinalState {
+            dp,
+            memory: bf_mem[start..start + len].to_vec(),
+            output_closed: context.output_closed,
+            interrupted: context.interrupted,
+            location: None,
+        };
+
+        if context.keep_state {
+            context.memory = bf_mem;
+            context.kept_dp = Some(dp);
+        }
+
+        Ok((state, context.report()))
+    }
+
+    fn run_with_io(
+        &mut self,
+        io_read: Box<dyn Read>,
+        io_write: Box<dyn Write>,
+    ) -> Result<FinalState, String> {
+        {
+            let mut context = self.context.borrow_mut();
+            context.io_read = io_read;
+            context.io_write = io_write;
+        }
+
+        self.run()
+    }
+
+    fn preload(&mut self, cells: &[u8]) {
+        self.context.borrow_mut().preloaded = cells.to_vec();
+    }
+
+    fn reset(&mut self) {
+        let mut context = self.context.borrow_mut();
+        context.memory = Tape::default();
+        context.kept_dp = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::limits::LimitedWriter;
+    use super::super::super::shared_buffer::{ClosedPipe, SharedBuffer};
+    use super::super::jit_helpers::page_size;
+    use super::super::jit_promise::JITPromise;
+    use super::{
+        CodeLimit, CompileOptions, JITTarget, OUTPUT_BUFFER_SIZE, SEGMENT_SIZE, UNDERFLOW,
+    };
+    use crate::parser::Ast;
+    use crate::runnable::events::EventLog;
+    use crate::runnable::hot_loops::HotLoops;
+    use crate::runnable::loop_profile::LoopProfile;
+    use crate::runnable::{
+        CellOverflow, Instrumentation, Progress, Runnable, TapeMode, CANCELLED, LEFT_TAPE_SIZE,
+        WRAPPED_TAPE_SIZE,
+    };
+    use std::cell::{Cell, RefCell};
+    use std::io::{self, Cursor, Write};
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn run_hello_world() {
+        let ast = Ast::parse(include_str!("../../../test/programs/hello_world.bf")).unwrap();
+        let shared_buffer = SharedBuffer::new();
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
+
+        jit_target.run().unwrap();
+
+        let output_string = shared_buffer.get_string_content();
+        assert_eq!(output_string, "Hello World!\n");
+    }
+
+    #[test]
+    fn reports_runs() {
+        let ast = Ast::parse(",>>,[.>]<<<").unwrap();
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            Box::new(Cursor::new(b"ab".to_vec())),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+
+        let (state, report) = jit_target.run_report().unwrap();
+
+        assert_eq!(state.dp, 0);
+        assert_eq!(report.instructions, None);
+        assert_eq!(report.bytes_read, 2);
+        assert_eq!(report.bytes_written, 1);
+    }
+
+    #[test]
+    fn run_mandelbrot() {
+        let ast = Ast::parse(include_str!("../../../test/programs/mandelbrot.bf")).unwrap();
+        let shared_buffer = SharedBuffer::new();
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
+
+        jit_target.run().unwrap();
+
+        let output_string = shared_buffer.get_string_content();
+        let expected_output = include_str!("../../../test/programs/mandelbrot.out");
+        assert_eq!(output_string, expected_output);
+    }
+
+    #[test]
+    fn run_rot13() {
+        // This rot13 program terminates after 16 characters so we can test it. Otherwise it would
+        // wait on input forever.
+        let ast = Ast::parse(include_str!("../../../test/programs/rot13-16char.bf")).unwrap();
+        let shared_buffer = SharedBuffer::new();
+        let in_cursor = Box::new(Cursor::new("Hello World! 123".as_bytes().to_vec()));
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            in_cursor,
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
+
+        jit_target.run().unwrap();
+
+        let output_string = shared_buffer.get_string_content();
+        assert_eq!(output_string, "Uryyb Jbeyq! 123");
+    }
+
+    #[test]
+    fn keeps_state_between_runs() {
+        let mut jit_target = JITTarget::new(
+            Ast::parse("+>").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        jit_target.keep_state();
+
+        jit_target.run().unwrap();
+        let state = jit_target.run().unwrap();
+        assert_eq!(state.dp, 2);
+        assert_eq!(state.memory[..2], [1, 1]);
+
+        jit_target.reset();
+        let state = jit_target.run().unwrap();
+        assert_eq!(state.dp, 1);
+        assert_eq!(state.memory[..2], [1, 0]);
+
+        // The clear at the start runs on what the last run left.
+        let mut jit_target = JITTarget::new(
+            Ast::parse_preloaded("[-]+").unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        jit_target.keep_state();
+
+        jit_target.run().unwrap();
+        assert_eq!(jit_target.run().unwrap().memory[0], 1);
+    }
+
+    #[test]
+    fn reports_progress() {
+        // The inner loop is long enough to be compiled separately, and is
+        // entered three times.
+        let source = format!("+++[>+[{}-]<-]", ">+<".repeat(8));
+        let entries = Rc::new(Cell::new(0));
+        let seen = entries.clone();
+        let mut jit_target = JITTarget::new(
+            Ast::parse(&source).unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation {
+                progress: Some(Progress {
+                    every: 1,
+                    callback: Box::new(move |_| seen.set(seen.get() + 1)),
+                }),
+                ..Instrumentation::default()
+            },
+        );
+
+        jit_target.run().unwrap();
+
+        assert_eq!(entries.get(), 3);
+    }
+
+    #[test]
+    fn logs_events() {
+        // The inner loop is entered often enough to be traced.
+        let source = format!("{}[>+[{}-]<-]", "+".repeat(20), ">+<".repeat(8));
+        let log = SharedBuffer::new();
+        let mut jit_target = JITTarget::new(
+            Ast::parse(&source).unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation {
+                events: Some(EventLog::new(Box::new(log.clone()))),
+                ..Instrumentation::default()
+            },
+        );
+        jit_target.trace_hot_loops();
+
+        jit_target.run().unwrap();
+
+        let lines: Vec<String> = log.get_string_content().lines().map(String::from).collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"event":"compiled","fragment":0,"name":"loop[>+<>+<>+<>+<>+<>+<>+<>+...","kind":"recording","nodes":25}"#,
+                r#"{"event":"promoted","fragment":0,"name":"loop[>+<>+<>+<>+<>+<>+<>+<>+...","runs":16}"#,
+                r#"{"event":"compiled","fragment":0,"name":"loop[>+<>+<>+<>+<>+<>+<>+<>+...","kind":"trace","nodes":25}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        // As in `stops_when_output_closes`, the outer loop is compiled
+        // separately, and the inner one never ends without cancellation.
+        let source = format!("+[+[{}[]]]", ">+<".repeat(8));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut jit_target = JITTarget::new(
+            Ast::parse(&source).unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation {
+                cancel: Some(cancel.clone()),
+                ..Instrumentation::default()
+            },
+        );
+
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            cancel.store(true, Ordering::Relaxed);
+        });
+
+        assert_eq!(jit_target.run().err().as_deref(), Some(CANCELLED));
+        stopper.join().unwrap();
+    }
+
+    #[test]
+    fn stops_when_output_closes() {
+        // The inner loop is long enough to be compiled separately, so this
+        // also checks that returning early passes through deferred loops.
+        let source = format!("+[+[{}.]]", ">+<".repeat(8));
+        let ast = Ast::parse(&source).unwrap();
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(ClosedPipe),
+            Instrumentation::default(),
+        );
+
+        let state = jit_target.run().unwrap();
+
+        // The closed pipe is found once the output buffer fills.
+        assert!(state.output_closed);
+        assert_eq!(state.memory[..2], [2, (8 * OUTPUT_BUFFER_SIZE) as u8]);
+    }
+
+    #[test]
+    fn wraps_around_the_tape() {
+        let ast = Ast::parse("<+>>[-]+[-<<+>>]").unwrap();
+        let mut jit_target = JITTarget::with_options(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+            CompileOptions {
+                tape_mode: TapeMode::Wrapped,
+                ..CompileOptions::default()
+            },
+        );
+
+        let state = jit_target.run().unwrap();
+
+        assert_eq!(state.dp, 1);
+        assert_eq!(state.memory.len(), WRAPPED_TAPE_SIZE);
+        assert_eq!(state.memory[WRAPPED_TAPE_SIZE - 1], 2);
+    }
+
+    #[test]
+    fn applies_cell_overflow_policy() {
+        // Adds 108 to cell 1 on each of three runs of a loop that is
+        // compiled separately, carrying it past 255 on the last.
+        let source = format!("+++[->{}<{}]", "+".repeat(100), ">+<".repeat(8));
+        let run = |cell_overflow| {
+            JITTarget::with_options(
+                Ast::parse(&source).unwrap().data,
+                Box::new(io::empty()),
+                Box::new(io::sink()),
+                Instrumentation::default(),
+                CompileOptions {
+                    cell_overflow,
+                    ..CompileOptions::default()
+                },
+            )
+            .run()
+        };
+
+        assert_eq!(run(CellOverflow::Wrap).unwrap().memory[1], 68);
+        assert_eq!(run(CellOverflow::Saturate).unwrap().memory[1], 255);
+        assert_eq!(
+            run(CellOverflow::Error).err(),
+            Some("Cell 1 overflowed.".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_pointer_underflow() {
+        let run = |source: &str| {
+            JITTarget::new(
+                Ast::parse(source).unwrap().data,
+                Box::new(io::empty()),
+                Box::new(io::sink()),
+                Instrumentation::default(),
+            )
+            .run()
+            .err()
+        };
+        let underflow = Some(UNDERFLOW.to_string());
+
+        assert_eq!(run("<+"), underflow);
+        assert_eq!(run("+[<]"), underflow);
+        assert_eq!(run(">><<<"), underflow);
+        // Touched from a loop that is compiled separately.
+        assert_eq!(run(&format!("+[{}<<+]", ">+<".repeat(8))), underflow);
+        // Transfers from a zero cell never touch the cell they would move it
+        // to, the same as the loop they stand for.
+        assert_eq!(run(">[-<<+>>]"), None);
+
+        // Moves too far left for the guard pages are checked, and report the
+        // node that made them.
+        let left = "<".repeat(2_000_000);
+        let right = ">".repeat(2_000_000);
+        assert_eq!(
+            run(&format!("+{}+", left)),
+            Some("Attempted to point below memory location 0 at Prev(2000000).".to_string())
+        );
+        assert_eq!(
+            run(&format!("+[{}+{}-]", left, right)),
+            Some("Attempted to point below memory location 0 at AddTo(-2000000).".to_string())
+        );
+        assert_eq!(run(&format!(">[{}+{}-]", left, right)), None);
+    }
+
+    #[test]
+    fn traces_hot_loops() {
+        // The inner loop is reached 30 times, and the loop inside it only
+        // runs once the input stops being zeros, after it has been traced.
+        // Clearing cells that are already zero pads it out to be compiled
+        // separately.
+        let padding: String = (2..22)
+            .map(|cell| format!("{}[-]{}", ">".repeat(cell), "<".repeat(cell)))
+            .collect();
+        let source = format!("{}[>+[->,[.[-]]<{}]<-]", "+".repeat(30), padding);
+        let ast = Ast::parse(&source).unwrap();
+        let mut input = vec![0; 20];
+        input.extend(b"abcdefghij");
+        let shared_buffer = SharedBuffer::new();
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            Box::new(Cursor::new(input)),
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
+        jit_target.trace_hot_loops();
+
+        jit_target.run().unwrap();
+
+        assert_eq!(shared_buffer.get_string_content(), "abcdefghij");
+        assert!(matches!(
+            jit_target.context.borrow().promises[..],
+            [Some(JITPromise::Compiled(_))]
+        ));
+    }
+
+    #[test]
+    fn compiles_around_hot_loops() {
+        // Both loops are too large to inline, but only the first is hot.
+        let source = format!(
+            "{}[{}-]+[{}-]",
+            "+".repeat(20),
+            ">+<".repeat(8),
+            ">-<".repeat(8)
+        );
+        let nodes = Ast::parse(&source).unwrap().data;
+        let loops = Rc::new(RefCell::new(LoopProfile::default()));
+        let mut jit_target = JITTarget::new(
+            nodes.clone(),
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation {
+                profiler: Some(Box::new(loops.clone())),
+                ..Instrumentation::default()
+            },
+        );
+        jit_target.run().unwrap();
+        let hot_loops = HotLoops::from_profile(&loops.borrow());
+
+        let log = SharedBuffer::new();
+        let mut jit_target = JITTarget::with_options(
+            nodes,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation {
+                events: Some(EventLog::new(Box::new(log.clone()))),
+                ..Instrumentation::default()
+            },
+            CompileOptions {
+                hot_loops: Some(hot_loops),
+                ..CompileOptions::default()
+            },
+        );
+
+        // The loop that ran but wasn't hot is compiled before the run.
+        let compiled = [
+            r#"{"event":"compiled","fragment":0,"name":"loop[>-<>-<>-<>-<>-<>-<>-<>-...","kind":"fragment","nodes":25}"#,
+        ];
+        assert_eq!(
+            log.get_string_content().lines().collect::<Vec<_>>(),
+            compiled
+        );
+
+        let state = jit_target.run().unwrap();
+        assert_eq!(state.memory[1], 20 * 8 - 8);
+        assert_eq!(
+            log.get_string_content().lines().collect::<Vec<_>>(),
+            compiled
+        );
+    }
+
+    #[test]
+    fn limits_code_size() {
+        let source = include_str!("../../../test/programs/hello_world.bf");
+        let run = |code_limit| {
+            let shared_buffer = SharedBuffer::new();
+            let mut jit_target = JITTarget::with_options(
+                Ast::parse(source).unwrap().data,
+                Box::new(io::empty()),
+                Box::new(shared_buffer.clone()),
+                Instrumentation::default(),
+                CompileOptions {
+                    code_limit,
+                    ..CompileOptions::default()
+                },
+            );
+            let result = jit_target.run().map(|_| shared_buffer.get_string_content());
+
+            (result, jit_target.code_size())
+        };
+
+        let (result, unlimited) = run(None);
+        assert_eq!(result.unwrap(), "Hello World!\n");
+        assert!(unlimited.code > 0);
+        assert_eq!(unlimited.allocated % page_size(), 0);
+
+        // Only the call into the program is compiled.
+        let (result, size) = run(Some(CodeLimit {
+            bytes: 0,
+            interpret: false,
+        }));
+        assert_eq!(
+            result,
+            Err("Exceeded the JIT code limit of 0 bytes.".to_string())
+        );
+        assert_eq!(size.allocated, page_size());
+
+        let (result, size) = run(Some(CodeLimit {
+            bytes: 0,
+            interpret: true,
+        }));
+        assert_eq!(result.unwrap(), "Hello World!\n");
+        assert_eq!(size.allocated, page_size());
+    }
+
+    #[test]
+    fn moves_left_of_the_start() {
+        let ast = Ast::parse("+++<<[-]+[->>-<<]").unwrap();
+        let mut jit_target = JITTarget::with_options(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+            CompileOptions {
+                tape_mode: TapeMode::Bidirectional,
+                ..CompileOptions::default()
+            },
+        );
+        jit_target.preload(&[7]);
+
+        let state = jit_target.run().unwrap();
+
+        assert_eq!(state.dp, LEFT_TAPE_SIZE - 2);
+        assert_eq!(state.memory[LEFT_TAPE_SIZE], 9);
+    }
+
+    #[test]
+    fn grows_memory() {
+        // The transfer is long enough to land past the end of memory before
+        // the data pointer gets there.
+        let source = format!(
+            "{}+[-{}+{}]{}.",
+            ">".repeat(29_990),
+            ">".repeat(20),
+            "<".repeat(20),
+            ">".repeat(20_000)
+        );
+        let ast = Ast::parse(&source).unwrap();
+        let shared_buffer = SharedBuffer::new();
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
+
+        let state = jit_target.run().unwrap();
+
+        assert_eq!(state.dp, 49_990);
+        assert_eq!(state.memory.len(), 60_000);
+        assert_eq!(state.memory[30_010], 1);
+        assert_eq!(shared_buffer.contents(), [0]);
+    }
+
+    #[test]
+    fn stops_at_the_tape_limit() {
+        let ast = Ast::parse("+[>+]").unwrap();
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+        jit_target.limit_tape(40_000);
+
+        let result = jit_target.run();
+
+        assert_eq!(
+            result.err().as_deref(),
+            Some("Exceeded the tape limit of 40000 cells.")
+        );
+    }
+
+    #[test]
+    fn returns_output_errors() {
+        let ast = Ast::parse(include_str!("../../../test/programs/hello_world.bf")).unwrap();
+        let shared_buffer = SharedBuffer::new();
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(LimitedWriter::new(Box::new(shared_buffer.clone()), 5)),
+            Instrumentation::default(),
+        );
+
+        assert!(jit_target.run().is_err());
+        assert_eq!(shared_buffer.get_string_content(), "Hello");
+    }
+
+    #[test]
+    fn writes_asm_for_every_fragment() {
+        let ast = Ast::parse(&format!("+[{}-].", ">+<".repeat(8))).unwrap();
+        let shared_buffer = SharedBuffer::new();
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
+        let mut asm = Vec::new();
+
+        jit_target.write_asm(&mut asm).unwrap();
+        let asm = String::from_utf8(asm).unwrap();
+
+        assert!(asm.starts_with("    push "));
+        assert!(asm.contains("\n; loop[>+<>+<>+<>+<>+<>+<>+<>+...\n"));
+        assert_eq!(asm.matches("\n; ").count(), 1);
+        assert!(!asm.contains("(bad)"));
+        assert!(matches!(
+            jit_target.context.borrow().promises[0],
+            Some(JITPromise::Compiled(_))
+        ));
+
+        // Fragments compiled ahead of time run like any others.
+        jit_target.run().unwrap();
+        assert_eq!(shared_buffer.contents(), [0]);
+    }
+
+    #[test]
+    fn annotates_asm_with_nodes() {
+        let ast = Ast::parse(&format!("+[->>+<<]>>[{}.-]", ">+<".repeat(8))).unwrap();
+        let mut asm = Vec::new();
+
+        JITTarget::for_asm(ast.data, CompileOptions::default())
+            .write_asm(&mut asm)
+            .unwrap();
+        let asm = String::from_utf8(asm).unwrap();
+        let annotations: Vec<&str> = asm.lines().filter(|line| line.starts_with("  ;")).collect();
+
+        assert_eq!(
+            annotations,
+            [
+                "  ; +[->>+<<]>>  Incr(1), AddTo(2), Next(2)",
+                "  ; [>+<>+<>+<>+<>+<>+<>+<>+<.-]  Loop(26 nodes)",
+                "  ; call loop[>+<>+<>+<>+<>+<>+<>+<>+...",
+                "  ; >+<>+<>+<>+<>+<>+<>+<>+<  Next(1), Incr(1), Prev(1), Next(1), Incr(1), Pre...",
+                "  ; .  Print",
+                "  ; -  Decr(1)",
+                "  ; ]",
+            ]
+        );
+        // Each annotation heads the code it describes.
+        assert!(asm.contains("  ; .  Print\n    push r10\n"));
+    }
+
+    #[test]
+    fn names_fragments_after_their_source() {
+        let body = ">+<".repeat(8);
+        let ast = Ast::parse(&format!("+[{0}-]+[{0}--]+[{0}-]", body)).unwrap();
+        let jit_target = JITTarget::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        );
+
+        let context = jit_target.context.borrow();
+        assert_eq!(context.promises.len(), 2);
+        assert_eq!(
+            &*context.promises.name(0),
+            "loop[>+<>+<>+<>+<>+<>+<>+<>+..."
+        );
+        assert_eq!(
+            &*context.promises.name(1),
+            "loop[>+<>+<>+<>+<>+<>+<>+<>+...#2"
+        );
+    }
+
+    #[test]
+    fn compiles_huge_programs_in_segments() {
+        // Every other node is a loop, so none of them combine.
+        let source = format!("+{}", "[>+<-]>.".repeat(SEGMENT_SIZE));
+        let ast = Ast::parse(&source).unwrap();
+        let shared_buffer = SharedBuffer::new();
+        let mut jit_target = JITTarget::new(
+            ast.data.clone(),
+            Box::new(io::empty()),
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
+
+        jit_target.run().unwrap();
+        jit_target.run().unwrap();
+
+        let segments = jit_target
+            .context
+            .borrow()
+            .promises
+            .iter()
+            .filter(|promise| matches!(promise, Some(JITPromise::Segment(_))))
+            .count();
+        assert_eq!(segments, ast.data.len().div_ceil(SEGMENT_SIZE));
+        assert_eq!(
+            &*jit_target.context.borrow().promises.name(1),
+            format!("segment@{}", SEGMENT_SIZE)
+        );
+        assert_eq!(shared_buffer.contents(), [1; SEGMENT_SIZE * 2]);
+    }
+
+    #[test]
+    fn calls_compiled_loops_directly() {
+        // The inner loop is too long to inline, and is entered three times.
+        let source = format!("+++[>++[{}-]<-]", ">+<.".repeat(8));
+        let ast = Ast::parse(&source).unwrap();
+        let shared_buffer = SharedBuffer::new();
+        let mut jit_target = JITTarget::new(
+            ast.data,
+            Box::new(io::empty()),
+            Box::new(shared_buffer.clone()),
+            Instrumentation::default(),
+        );
+
+        jit_target.run().unwrap();
+
+        // Only the first entry goes through the callback.
+        assert_eq!(jit_target.context.borrow().entries, 1);
+        assert_eq!(
+            shared_buffer.contents(),
+            [[2; 8], [1; 8]].concat().repeat(3)
+        );
+    }
+
+    /// Writer that keeps each write it is given apart.
+    #[derive(Clone, Default)]
+    struct Writes(Rc<RefCell<Vec<Vec<u8>>>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn buffers_output() {
+        // Reads "ab\nc" and prints it, then reads and prints "d".
+        let ast = Ast::parse(",>,>,>,<<<.>.>.>.,.").unwrap();
+
+        for (line_buffered, expected) in [
+            (true, vec![&b"ab\n"[..], b"c", b"d"]),
+            (false, vec![&b"ab\nc"[..], b"d"]),
+        ] {
+            let writes = Writes::default();
+            let mut jit_target = JITTarget::new(
+                ast.data.clone(),
+                Box::new(Cursor::new(b"ab\ncd".to_vec())),
+                Box::new(writes.clone()),
+                Instrumentation::default(),
+            );
+
+            if !line_buffered {
+                jit_target.block_buffer_output();
+            }
+
+            let report = jit_target.run_report().unwrap().1;
+
+            assert_eq!(*writes.0.borrow(), expected);
+            assert_eq!(report.bytes_written, 5);
+        }
     }
 }
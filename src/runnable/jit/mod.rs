@@ -1,7 +1,11 @@
 mod code_gen;
-mod immutable;
+#[cfg(test)]
+mod conformance;
+mod disasm;
+mod guard;
 mod jit_helpers;
 mod jit_promise;
 mod jit_target;
 
-pub use self::jit_target::JITTarget;
+pub use self::jit_helpers::page_size;
+pub use self::jit_target::{CodeLimit, CompileOptions, JITTarget};
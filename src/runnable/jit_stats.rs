@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use super::profiler::{CodeSize, Profiler};
+
+/// Name the JIT gives the code for the program outside of any loop.
+const PROGRAM: &str = "program";
+
+struct FragmentRecord {
+    name: String,
+    /// Size of the last compilation of the fragment
+    size: CodeSize,
+    /// Time spent compiling the fragment, however many times it was compiled
+    compile: Duration,
+    /// Boxed so that the counter never moves once compiled code refers to it
+    calls: Box<u64>,
+}
+
+/// What each piece of JIT compiled code cost in executable memory and
+/// compilation time, and how often it was called.
+#[derive(Default)]
+pub struct JitStats {
+    fragments: Vec<FragmentRecord>,
+    /// Index into `fragments` of each name
+    indexes: HashMap<String, usize>,
+}
+
+impl JitStats {
+    /// The record for the code named `name`, created the first time it is
+    /// mentioned.
+    fn record(&mut self, name: &str) -> &mut FragmentRecord {
+        let fragments = &mut self.fragments;
+        let index = *self.indexes.entry(name.to_string()).or_insert_with(|| {
+            fragments.push(FragmentRecord {
+                name: name.to_string(),
+                size: CodeSize::default(),
+                compile: Duration::default(),
+                calls: Box::default(),
+            });
+
+            fragments.len() - 1
+        });
+
+        &mut self.fragments[index]
+    }
+
+    /// Write a table of every fragment in the order they were compiled,
+    /// followed by totals.
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{:>10}  {:>10}  {:>12}  {:>12}  fragment",
+            "code", "allocated", "compile", "calls"
+        )?;
+
+        for record in &self.fragments {
+            writeln!(
+                out,
+                "{:>10}  {:>10}  {:>12.3?}  {:>12}  {}",
+                record.size.code, record.size.allocated, record.compile, record.calls, record.name
+            )?;
+        }
+
+        writeln!(
+            out,
+            "{:>10}  {:>10}  {:>12.3?}  {:>12}  total",
+            self.fragments
+                .iter()
+                .map(|record| record.size.code)
+                .sum::<usize>(),
+            self.fragments
+                .iter()
+                .map(|record| record.size.allocated)
+                .sum::<usize>(),
+            self.fragments
+                .iter()
+                .map(|record| record.compile)
+                .sum::<Duration>(),
+            self.fragments
+                .iter()
+                .map(|record| *record.calls)
+                .sum::<u64>()
+        )
+    }
+}
+
+impl Profiler for JitStats {
+    fn program_compiled(&mut self, elapsed: Duration) {
+        self.record(PROGRAM).compile += elapsed;
+    }
+
+    fn fragment_compiled(&mut self, name: &str, elapsed: Duration) {
+        self.record(name).compile += elapsed;
+    }
+
+    fn code_emitted(&mut self, name: &str, size: CodeSize) {
+        self.record(name).size = size;
+    }
+
+    fn call_counter(&mut self, name: &str) -> Option<*mut u64> {
+        Some(&mut *self.record(name).calls)
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::parser::Ast;
+    use crate::runnable::jit::{page_size, JITTarget};
+    use crate::runnable::{Instrumentation, Runnable};
+
+    #[test]
+    fn counts_calls_and_memory() {
+        // The inner loop is too long to inline, and is reached three times.
+        let source = format!("+++[>+[{}-]<-]", ">+<".repeat(8));
+        let stats = Rc::new(RefCell::new(JitStats::default()));
+        let mut jit_target = JITTarget::new(
+            Ast::parse(&source).unwrap().data,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation {
+                profiler: Some(Box::new(stats.clone())),
+                ..Instrumentation::default()
+            },
+        );
+
+        jit_target.run().unwrap();
+
+        let stats = stats.borrow();
+        let calls: Vec<(&str, u64)> = stats
+            .fragments
+            .iter()
+            .map(|record| (&*record.name, *record.calls))
+            .collect();
+        assert_eq!(
+            calls,
+            [("program", 1), ("loop[>+<>+<>+<>+<>+<>+<>+<>+...", 3)]
+        );
+
+        // The loop is packed into the program's page, so it needs none of
+        // its own.
+        let allocated: Vec<usize> = stats
+            .fragments
+            .iter()
+            .map(|record| record.size.allocated)
+            .collect();
+        assert_eq!(allocated, [page_size(), 0]);
+        assert!(stats.fragments.iter().all(|record| record.size.code > 0));
+
+        let mut report = Vec::new();
+        stats.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert_eq!(report.lines().count(), 4);
+        assert!(report.lines().last().unwrap().ends_with("4  total"));
+    }
+}
@@ -0,0 +1,60 @@
+use std::io::{self, Write};
+
+/// Writer that fails once a program has written more than a set number of
+/// bytes, so that a runaway program can't fill a disk or a browser tab.
+pub struct LimitedWriter {
+    inner: Box<dyn Write>,
+    /// Bytes that may still be written
+    remaining: u64,
+    limit: u64,
+}
+
+impl LimitedWriter {
+    pub fn new(inner: Box<dyn Write>, limit: u64) -> Self {
+        LimitedWriter {
+            inner,
+            remaining: limit,
+            limit,
+        }
+    }
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if !buf.is_empty() && self.remaining == 0 {
+            return Err(io::Error::other(format!(
+                "Output limit of {} bytes exceeded",
+                self.limit
+            )));
+        }
+
+        // Write as much as fits, so that buffered output is cut off at the
+        // limit rather than before it.
+        let fits = self.remaining.min(buf.len() as u64) as usize;
+        let written = self.inner.write(&buf[..fits])?;
+        self.remaining -= written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runnable::shared_buffer::SharedBuffer;
+
+    #[test]
+    fn stops_at_limit() {
+        let output = SharedBuffer::new();
+        let mut writer = LimitedWriter::new(Box::new(output.clone()), 3);
+
+        assert!(writer.write_all(b"ab").is_ok());
+        assert!(writer.write_all(b"cd").is_err());
+        assert!(writer.write_all(b"e").is_err());
+        assert_eq!(output.contents(), b"abc");
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use super::profiler::Profiler;
+
+/// Number of loops listed in a loop profile report.
+const REPORT_LOOPS: usize = 20;
+/// Loop source longer than this is cut short in reports.
+const REPORT_SOURCE_CHARS: usize = 48;
+
+/// How a loop ended up being executed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopKind {
+    /// Executed by the interpreter
+    Interpreted,
+    /// Compiled into the body of its parent
+    Inlined,
+    /// Compiled into its own fragment the first time it was reached
+    Deferred,
+    /// Replaced by a single AstNode (Set, AddTo or SubFrom) during parsing
+    Simplified,
+}
+
+impl fmt::Display for LoopKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LoopKind::Interpreted => "interpreted",
+            LoopKind::Inlined => "inlined",
+            LoopKind::Deferred => "deferred",
+            LoopKind::Simplified => "simplified",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Execution counts for a single loop.
+///
+/// JIT compiled code increments these fields in place, so the layout is fixed.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoopCounters {
+    /// Number of times the loop was reached
+    pub entries: u64,
+    /// Number of times the loop body ran
+    pub iterations: u64,
+}
+
+struct LoopRecord {
+    kind: LoopKind,
+    /// BrainFuck source for the loop
+    source: String,
+    /// Boxed so that the counters never move once compiled code refers to them
+    counters: Box<LoopCounters>,
+}
+
+/// Execution counts for every loop in a program.
+#[derive(Default)]
+pub struct LoopProfile {
+    loops: Vec<LoopRecord>,
+}
+
+impl LoopProfile {
+    /// The source, kind and counts of each loop, in the order they were
+    /// added.
+    pub fn loops(&self) -> impl Iterator<Item = (&str, LoopKind, &LoopCounters)> + '_ {
+        self.loops
+            .iter()
+            .map(|record| (&*record.source, record.kind, &*record.counters))
+    }
+
+    /// Write a table of the hottest loops.
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut hottest: Vec<&LoopRecord> = self.loops.iter().collect();
+        hottest.sort_by(|a, b| {
+            (b.counters.iterations, b.counters.entries)
+                .cmp(&(a.counters.iterations, a.counters.entries))
+        });
+
+        writeln!(
+            out,
+            "{:>15}  {:>15}  {:<11}  loop",
+            "entries", "iterations", "kind"
+        )?;
+
+        for record in hottest.into_iter().take(REPORT_LOOPS) {
+            // The number of iterations a simplified loop would have taken is
+            // never computed.
+            let iterations = match record.kind {
+                LoopKind::Simplified => "-".to_string(),
+                _ => record.counters.iterations.to_string(),
+            };
+
+            writeln!(
+                out,
+                "{:>15}  {:>15}  {:<11}  {}",
+                record.counters.entries,
+                iterations,
+                record.kind.to_string(),
+                truncate(&record.source)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Profiler for LoopProfile {
+    fn observes_loops(&self) -> bool {
+        true
+    }
+
+    fn add_loop(&mut self, kind: LoopKind, source: String) {
+        self.loops.push(LoopRecord {
+            kind,
+            source,
+            counters: Box::default(),
+        });
+    }
+
+    fn loop_counters(&mut self, id: usize) -> Option<*mut LoopCounters> {
+        Some(&mut *self.loops[id].counters)
+    }
+
+    fn loop_entered(&mut self, id: usize) {
+        self.loops[id].counters.entries += 1;
+    }
+
+    fn loop_iterated(&mut self, id: usize) {
+        self.loops[id].counters.iterations += 1;
+    }
+}
+
+/// Shorten long loop source for display.
+fn truncate(source: &str) -> String {
+    if source.chars().count() <= REPORT_SOURCE_CHARS {
+        return source.to_string();
+    }
+
+    let mut short: String = source.chars().take(REPORT_SOURCE_CHARS).collect();
+    short.push_str("...");
+
+    short
+}
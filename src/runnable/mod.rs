@@ -1,16 +1,219 @@
+pub mod channel;
+pub mod crlf;
+pub mod environment;
+pub mod events;
+pub mod extension;
+#[cfg(all(test, target_arch = "x86_64"))]
+mod fuzz;
+#[cfg(test)]
+mod golden;
+pub mod heatmap;
+pub mod hooks;
+pub mod hot_loops;
 pub mod interpreter;
+pub mod interrupt;
+pub mod io_log;
 #[cfg(target_arch = "x86_64")]
 pub mod jit;
-#[cfg(test)]
-mod test_buffer;
+pub mod jit_stats;
+pub mod limits;
+pub mod loop_profile;
+pub mod profile;
+pub mod profiler;
+pub mod prompt;
+pub mod shared_buffer;
+pub mod stats;
+pub mod tape;
+pub mod tape_stats;
+pub mod trace;
+
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use self::events::EventLog;
+use self::profiler::Profiler;
+use self::tape_stats::TapeStats;
+use self::trace::Tracer;
 
 const BF_MEMORY_SIZE: usize = 30_000;
+/// Cells in a tape that the data pointer wraps around. This is a power of two
+/// so that the JIT compiler can wrap the pointer with a mask.
+pub const WRAPPED_TAPE_SIZE: usize = 32_768;
+/// Cells left of the starting cell in a bidirectional tape.
+pub const LEFT_TAPE_SIZE: usize = BF_MEMORY_SIZE;
+
+/// Error returned by a run stopped through its cancellation token.
+pub const CANCELLED: &str = "Cancelled";
+
+/// How the ends of the tape behave.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TapeMode {
+    /// Moving left of the starting cell is an error
+    #[default]
+    Standard,
+    /// The data pointer wraps around a tape of `WRAPPED_TAPE_SIZE` cells
+    Wrapped,
+    /// The data pointer starts `LEFT_TAPE_SIZE` cells into memory, so that it
+    /// can move left of where it starts
+    Bidirectional,
+}
+
+/// What happens when arithmetic carries a cell past 255 or below 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CellOverflow {
+    /// The cell wraps around, so that 255 + 1 is 0
+    #[default]
+    Wrap,
+    /// The program stops with an error
+    Error,
+    /// The cell stays at 255 or 0
+    Saturate,
+}
+
+impl CellOverflow {
+    /// Add `n` to `cell`, or return None if the program should stop.
+    pub fn add(self, cell: u8, n: u8) -> Option<u8> {
+        match self {
+            CellOverflow::Wrap => Some(cell.wrapping_add(n)),
+            CellOverflow::Error => cell.checked_add(n),
+            CellOverflow::Saturate => Some(cell.saturating_add(n)),
+        }
+    }
+
+    /// Subtract `n` from `cell`, or return None if the program should stop.
+    pub fn sub(self, cell: u8, n: u8) -> Option<u8> {
+        match self {
+            CellOverflow::Wrap => Some(cell.wrapping_sub(n)),
+            CellOverflow::Error => cell.checked_sub(n),
+            CellOverflow::Saturate => Some(cell.saturating_sub(n)),
+        }
+    }
+}
+
+/// Optional observers and controls of a program's execution. Everything is
+/// off by default.
+#[derive(Default)]
+pub struct Instrumentation {
+    /// Receives every executed instruction
+    pub tracer: Option<Tracer>,
+    /// Told about executed instructions, loops and compilation. Install
+    /// several as a `Vec`, and share one through `Rc<RefCell<_>>` to read it
+    /// once the program has finished.
+    pub profiler: Option<Box<dyn Profiler>>,
+    /// Records how memory is used
+    pub tape: Option<Rc<RefCell<TapeStats>>>,
+    /// Stops the program with `CANCELLED` as its error once set, so that
+    /// another thread can stop a program that runs too long. The interpreter
+    /// checks it between instructions, and compiled code at the end of every
+    /// loop iteration and whenever it calls back into Rust. Runs stay
+    /// cancelled until it is cleared.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Told how far the run has got every so often
+    pub progress: Option<Progress>,
+    /// Receives compilations and errors as they happen
+    pub events: Option<EventLog>,
+}
+
+impl Instrumentation {
+    /// Whether anything needs to observe individual instructions.
+    pub fn observes_instructions(&self) -> bool {
+        self.tracer.is_some()
+            || self
+                .profiler
+                .as_ref()
+                .is_some_and(|profiler| profiler.observes_instructions())
+    }
 
-/// Simple interface for an type that can be invoked without any arguments and
-/// with no return value.
+    /// Whether anything needs loops counted.
+    pub fn observes_loops(&self) -> bool {
+        self.profiler
+            .as_ref()
+            .is_some_and(|profiler| profiler.observes_loops())
+    }
+}
+
+/// What a program left behind when it finished.
+pub struct FinalState {
+    pub memory: Vec<u8>,
+    /// Data pointer
+    pub dp: usize,
+    /// Whether the program stopped early because nothing was reading its
+    /// output any more
+    pub output_closed: bool,
+    /// Whether the program was stopped with Ctrl-C, or by the interpreter's
+    /// step hook
+    pub interrupted: bool,
+    /// The instruction the program was about to execute when it stopped, if
+    /// the backend knows it
+    pub location: Option<String>,
+}
+
+impl FinalState {
+    /// Value of the cell under the data pointer.
+    pub fn current_cell(&self) -> u8 {
+        self.memory.get(self.dp).copied().unwrap_or(0)
+    }
+}
+
+/// What a program did while running, for embedders that want the numbers
+/// without setting up instrumentation.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RunReport {
+    /// Instructions executed, after optimization. Only the interpreter counts
+    /// them.
+    pub instructions: Option<u64>,
+    /// Bytes read by `,`, not counting the newlines read past the end of input
+    pub bytes_read: u64,
+    /// Bytes written by `.`
+    pub bytes_written: u64,
+    /// Highest cell the data pointer reached, counted the same way as
+    /// `FinalState::dp`. Only the interpreter tracks it.
+    pub peak_dp: Option<usize>,
+    pub elapsed: Duration,
+}
+
+/// A callback that reports how far a run has got, for progress bars and for
+/// checking that long runs are still alive.
+pub struct Progress {
+    /// Instructions the interpreter executes between calls. Compiled code
+    /// counts entries into separately compiled loops instead, which happen
+    /// far less often.
+    pub every: u64,
+    /// Receives the counters so far, with `elapsed` counting from the start
+    /// of the run
+    pub callback: Box<dyn FnMut(&RunReport)>,
+}
+
+/// Simple interface for an type that can be invoked without any arguments.
 ///
 /// Rather than this trait FnMut would have been used were it a stable feature.
 pub trait Runnable {
-    /// Invoke this type.
-    fn run(&mut self);
+    /// Invoke this type, returning the state of memory once the program has
+    /// finished, or why it couldn't.
+    fn run(&mut self) -> Result<FinalState, String> {
+        self.run_report().map(|(state, _)| state)
+    }
+
+    /// Like `run`, but also reporting what the program did.
+    fn run_report(&mut self) -> Result<(FinalState, RunReport), String>;
+
+    /// Like `run`, but with `,` reading from `io_read` and `.` writing to
+    /// `io_write` instead of the streams the runnable was created with. They
+    /// stay in place for later runs.
+    fn run_with_io(
+        &mut self,
+        io_read: Box<dyn Read>,
+        io_write: Box<dyn Write>,
+    ) -> Result<FinalState, String>;
+
+    /// Copy values into the start of memory before the program runs.
+    fn preload(&mut self, cells: &[u8]);
+
+    /// Clear memory and move the data pointer back to where it starts. Only
+    /// needed when the runnable was set to keep its state between runs.
+    fn reset(&mut self);
 }
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use super::profiler::Profiler;
+
+/// Number of locations listed in a profile report.
+const REPORT_LOCATIONS: usize = 20;
+
+/// A place in a program that can be executed.
+struct Location {
+    /// Name of the instruction at this location
+    kind: &'static str,
+    /// Human readable form of the instruction at this location
+    description: String,
+    /// Number of times this location was executed
+    count: u64,
+}
+
+/// Execution counts for each location in a program.
+#[derive(Default)]
+pub struct Profile {
+    locations: Vec<Location>,
+}
+
+impl Profile {
+    /// Total executions of each kind of instruction, most executed first.
+    fn kind_counts(&self) -> Vec<(&'static str, u64)> {
+        let mut counts = HashMap::new();
+
+        for location in &self.locations {
+            *counts.entry(location.kind).or_insert(0) += location.count;
+        }
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        counts
+    }
+
+    /// Write a table of instruction kind counts followed by the most executed
+    /// locations.
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{:>15}  instruction", "count")?;
+        for (kind, count) in self.kind_counts() {
+            writeln!(out, "{:>15}  {}", count, kind)?;
+        }
+
+        let mut hottest: Vec<(usize, &Location)> = self.locations.iter().enumerate().collect();
+        hottest.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(&b.0)));
+
+        writeln!(out)?;
+        writeln!(out, "{:>15}  {:>8}  instruction", "count", "pc")?;
+        for (pc, location) in hottest.into_iter().take(REPORT_LOCATIONS) {
+            writeln!(
+                out,
+                "{:>15}  {:>8}  {}",
+                location.count, pc, location.description
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Profiler for Profile {
+    fn observes_instructions(&self) -> bool {
+        true
+    }
+
+    fn add_location(&mut self, kind: &'static str, description: String) {
+        self.locations.push(Location {
+            kind,
+            description,
+            count: 0,
+        });
+    }
+
+    fn instruction_retired(&mut self, location: usize) {
+        self.locations[location].count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregates_kinds() {
+        let mut profile = Profile::default();
+        profile.add_location("Incr", "Incr(1)".to_string());
+        profile.add_location("Print", "Print".to_string());
+        profile.add_location("Incr", "Incr(2)".to_string());
+
+        for location in [0, 1, 2, 2] {
+            profile.instruction_retired(location);
+        }
+
+        assert_eq!(profile.kind_counts(), vec![("Incr", 3), ("Print", 1)]);
+    }
+}
@@ -0,0 +1,323 @@
+//! Hooks for measuring a run, which both backends call on whatever profiler
+//! is installed in `Instrumentation::profiler`. The CLI's `--profile`,
+//! `--profile-loops` and `--stats` are built on them. Without a profiler the
+//! interpreter skips the calls, and the JIT compiles code that never makes
+//! them.
+//!
+//! Instructions and loops are registered before they can be reported, and
+//! are numbered from 0 in the order they were registered.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::loop_profile::{LoopCounters, LoopKind};
+
+/// How much memory a piece of compiled code takes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CodeSize {
+    /// Bytes of machine code
+    pub code: usize,
+    /// Bytes of executable memory allocated for it, in whole pages
+    pub allocated: usize,
+}
+
+pub trait Profiler {
+    /// Whether the profiler wants `instruction_retired` called. Reporting
+    /// instructions slows compiled code down a great deal.
+    fn observes_instructions(&self) -> bool {
+        false
+    }
+
+    /// Whether the profiler wants loops reported.
+    fn observes_loops(&self) -> bool {
+        false
+    }
+
+    /// Register an instruction that may be executed, named by `kind` and
+    /// described in full by `description`.
+    fn add_location(&mut self, _kind: &'static str, _description: String) {}
+
+    /// An instruction was executed.
+    fn instruction_retired(&mut self, _location: usize) {}
+
+    /// Register a loop, given as BrainFuck source, which the backend will run
+    /// as `kind`.
+    fn add_loop(&mut self, _kind: LoopKind, _source: String) {}
+
+    /// Counters for a loop that compiled code can increment in place, which
+    /// must stay where they are for as long as the program can run. The JIT
+    /// only counts loops this way, since calling out on every iteration would
+    /// swamp what is being measured.
+    fn loop_counters(&mut self, _id: usize) -> Option<*mut LoopCounters> {
+        None
+    }
+
+    /// A loop was reached. Simplified loops are reported as entered each time
+    /// they run, but never as iterated.
+    fn loop_entered(&mut self, _id: usize) {}
+
+    /// The body of a loop is about to run.
+    fn loop_iterated(&mut self, _id: usize) {}
+
+    /// The program was compiled before being run.
+    fn program_compiled(&mut self, _elapsed: Duration) {}
+
+    /// Part of the program, named `name`, was compiled once it was reached
+    /// while running.
+    fn fragment_compiled(&mut self, _name: &str, _elapsed: Duration) {}
+
+    /// Machine code was placed in executable memory, for the program as a
+    /// whole, named `program`, or for a fragment each time it is compiled.
+    fn code_emitted(&mut self, _name: &str, _size: CodeSize) {}
+
+    /// A counter that the code named `name` increments each time it is
+    /// called, which must stay where it is for as long as the program can
+    /// run. Asked for each time the code is compiled, so the same name has to
+    /// get the same counter.
+    fn call_counter(&mut self, _name: &str) -> Option<*mut u64> {
+        None
+    }
+}
+
+/// A profiler shared with whoever reads its results once the run is over.
+impl<P: Profiler> Profiler for Rc<RefCell<P>> {
+    fn observes_instructions(&self) -> bool {
+        self.borrow().observes_instructions()
+    }
+
+    fn observes_loops(&self) -> bool {
+        self.borrow().observes_loops()
+    }
+
+    fn add_location(&mut self, kind: &'static str, description: String) {
+        self.borrow_mut().add_location(kind, description)
+    }
+
+    fn instruction_retired(&mut self, location: usize) {
+        self.borrow_mut().instruction_retired(location)
+    }
+
+    fn add_loop(&mut self, kind: LoopKind, source: String) {
+        self.borrow_mut().add_loop(kind, source)
+    }
+
+    fn loop_counters(&mut self, id: usize) -> Option<*mut LoopCounters> {
+        self.borrow_mut().loop_counters(id)
+    }
+
+    fn loop_entered(&mut self, id: usize) {
+        self.borrow_mut().loop_entered(id)
+    }
+
+    fn loop_iterated(&mut self, id: usize) {
+        self.borrow_mut().loop_iterated(id)
+    }
+
+    fn program_compiled(&mut self, elapsed: Duration) {
+        self.borrow_mut().program_compiled(elapsed)
+    }
+
+    fn fragment_compiled(&mut self, name: &str, elapsed: Duration) {
+        self.borrow_mut().fragment_compiled(name, elapsed)
+    }
+
+    fn code_emitted(&mut self, name: &str, size: CodeSize) {
+        self.borrow_mut().code_emitted(name, size)
+    }
+
+    fn call_counter(&mut self, name: &str) -> Option<*mut u64> {
+        self.borrow_mut().call_counter(name)
+    }
+}
+
+/// Several profilers installed at once, each told about everything.
+impl Profiler for Vec<Box<dyn Profiler>> {
+    fn observes_instructions(&self) -> bool {
+        self.iter().any(|profiler| profiler.observes_instructions())
+    }
+
+    fn observes_loops(&self) -> bool {
+        self.iter().any(|profiler| profiler.observes_loops())
+    }
+
+    fn add_location(&mut self, kind: &'static str, description: String) {
+        for profiler in self {
+            profiler.add_location(kind, description.clone());
+        }
+    }
+
+    fn instruction_retired(&mut self, location: usize) {
+        for profiler in self {
+            profiler.instruction_retired(location);
+        }
+    }
+
+    fn add_loop(&mut self, kind: LoopKind, source: String) {
+        for profiler in self {
+            profiler.add_loop(kind, source.clone());
+        }
+    }
+
+    /// The counters of the first profiler that has any.
+    fn loop_counters(&mut self, id: usize) -> Option<*mut LoopCounters> {
+        self.iter_mut()
+            .find_map(|profiler| profiler.loop_counters(id))
+    }
+
+    fn loop_entered(&mut self, id: usize) {
+        for profiler in self {
+            profiler.loop_entered(id);
+        }
+    }
+
+    fn loop_iterated(&mut self, id: usize) {
+        for profiler in self {
+            profiler.loop_iterated(id);
+        }
+    }
+
+    fn program_compiled(&mut self, elapsed: Duration) {
+        for profiler in self {
+            profiler.program_compiled(elapsed);
+        }
+    }
+
+    fn fragment_compiled(&mut self, name: &str, elapsed: Duration) {
+        for profiler in self {
+            profiler.fragment_compiled(name, elapsed);
+        }
+    }
+
+    fn code_emitted(&mut self, name: &str, size: CodeSize) {
+        for profiler in self {
+            profiler.code_emitted(name, size);
+        }
+    }
+
+    /// The counter of the first profiler that has one.
+    fn call_counter(&mut self, name: &str) -> Option<*mut u64> {
+        self.iter_mut()
+            .find_map(|profiler| profiler.call_counter(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::parser::Ast;
+    use crate::runnable::interpreter::Fucker;
+    #[cfg(target_arch = "x86_64")]
+    use crate::runnable::jit::JITTarget;
+    use crate::runnable::{Instrumentation, Runnable};
+
+    /// Counts every call made to it.
+    #[derive(Debug, Default, PartialEq)]
+    struct Calls {
+        locations: usize,
+        retired: u64,
+        loops: usize,
+        entered: u64,
+        iterated: u64,
+        programs: usize,
+    }
+
+    impl Profiler for Calls {
+        fn observes_instructions(&self) -> bool {
+            true
+        }
+
+        fn observes_loops(&self) -> bool {
+            true
+        }
+
+        fn add_location(&mut self, _kind: &'static str, _description: String) {
+            self.locations += 1;
+        }
+
+        fn instruction_retired(&mut self, location: usize) {
+            assert!(location < self.locations);
+            self.retired += 1;
+        }
+
+        fn add_loop(&mut self, _kind: LoopKind, _source: String) {
+            self.loops += 1;
+        }
+
+        fn loop_entered(&mut self, id: usize) {
+            assert!(id < self.loops);
+            self.entered += 1;
+        }
+
+        fn loop_iterated(&mut self, id: usize) {
+            assert!(id < self.loops);
+            self.iterated += 1;
+        }
+
+        fn program_compiled(&mut self, _elapsed: Duration) {
+            self.programs += 1;
+        }
+    }
+
+    fn instrumentation(calls: &Rc<RefCell<Calls>>) -> Instrumentation {
+        Instrumentation {
+            profiler: Some(Box::new(calls.clone())),
+            ..Instrumentation::default()
+        }
+    }
+
+    #[test]
+    fn calls_profilers() {
+        let nodes = Ast::parse("++[>+.<-]").unwrap().data;
+        let calls = Rc::new(RefCell::new(Calls::default()));
+        let mut fucker = Fucker::new(
+            nodes.clone(),
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            instrumentation(&calls),
+        );
+
+        fucker.run().unwrap();
+
+        assert_eq!(
+            *calls.borrow(),
+            Calls {
+                locations: 8,
+                retired: 14,
+                loops: 1,
+                entered: 1,
+                iterated: 2,
+                programs: 1,
+            }
+        );
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let calls = Rc::new(RefCell::new(Calls::default()));
+            let mut jit_target = JITTarget::new(
+                nodes,
+                Box::new(io::empty()),
+                Box::new(io::sink()),
+                instrumentation(&calls),
+            );
+
+            jit_target.run().unwrap();
+
+            // The JIT has no loop counters to increment, and compiles the
+            // loop as a single node.
+            assert_eq!(
+                *calls.borrow(),
+                Calls {
+                    locations: 7,
+                    retired: 12,
+                    loops: 1,
+                    entered: 0,
+                    iterated: 0,
+                    programs: 1,
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Prompting for input at a terminal, so that it's clear when a program is
+//! waiting on `,` rather than busy or stuck.
+
+use std::io::{self, Read, Write};
+
+/// Printed before each line of input is read.
+pub const PROMPT: &str = "> ";
+
+/// Reader that writes a prompt whenever a program starts reading a new line
+/// of input. While it exists, a terminal on stdin is kept in cooked mode, so
+/// that typed characters are echoed and can be edited before Enter sends
+/// them.
+pub struct PromptReader {
+    inner: Box<dyn Read>,
+    prompt: Box<dyn Write>,
+    /// Whether the next byte read starts a line
+    at_line_start: bool,
+    #[cfg(unix)]
+    _cooked: Option<CookedMode>,
+}
+
+impl PromptReader {
+    /// Read from `inner`, writing prompts to `prompt`.
+    pub fn new(inner: Box<dyn Read>, prompt: Box<dyn Write>) -> Self {
+        PromptReader {
+            inner,
+            prompt,
+            at_line_start: true,
+            #[cfg(unix)]
+            _cooked: CookedMode::enable(),
+        }
+    }
+}
+
+impl Read for PromptReader {
+    /// Read a single byte at a time, so that a prompt is only written once
+    /// the program has used up the line before.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.at_line_start {
+            self.prompt.write_all(PROMPT.as_bytes())?;
+            self.prompt.flush()?;
+        }
+
+        let read = self.inner.read(&mut buf[..1])?;
+        // Prompt again for whatever is read after the end of input.
+        self.at_line_start = read == 0 || buf[0] == b'\n';
+
+        Ok(read)
+    }
+}
+
+/// Line editing and echo turned on for a terminal on stdin that had them
+/// off, put back how it was when dropped.
+#[cfg(unix)]
+struct CookedMode {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl CookedMode {
+    /// Turn on line editing and echo if stdin is a terminal without them.
+    fn enable() -> Option<Self> {
+        let cooked = libc::ICANON | libc::ECHO;
+
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return None;
+            }
+
+            if termios.c_lflag & cooked == cooked {
+                return None;
+            }
+
+            let original = termios;
+            termios.c_lflag |= cooked;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return None;
+            }
+
+            Some(CookedMode { original })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for CookedMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runnable::shared_buffer::SharedBuffer;
+    use std::io::Cursor;
+
+    #[test]
+    fn prompts_for_each_line() {
+        let prompts = SharedBuffer::new();
+        let mut reader = PromptReader::new(
+            Box::new(Cursor::new(b"ab\n\nc".to_vec())),
+            Box::new(prompts.clone()),
+        );
+        let mut byte = [0];
+
+        for expected in b"ab\n\nc" {
+            assert_eq!(reader.read(&mut byte).unwrap(), 1);
+            assert_eq!(byte[0], *expected);
+        }
+        assert_eq!(prompts.contents(), PROMPT.repeat(3).as_bytes());
+
+        assert_eq!(reader.read(&mut byte).unwrap(), 0);
+        assert_eq!(reader.read(&mut byte).unwrap(), 0);
+        assert_eq!(prompts.contents(), PROMPT.repeat(4).as_bytes());
+    }
+}
@@ -0,0 +1,87 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+
+/// Writeable buffer that tracks what was written to it, for capturing a
+/// program's output.
+#[derive(Clone, Default)]
+pub struct SharedBuffer {
+    inner: Rc<RefCell<Vec<u8>>>,
+}
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.inner.borrow().clone()
+    }
+
+    /// Everything written since the last call, leaving the buffer empty.
+    pub fn take(&self) -> Vec<u8> {
+        mem::take(&mut *self.inner.borrow_mut())
+    }
+
+    #[cfg(test)]
+    pub fn get_string_content(&self) -> String {
+        let data = self.inner.borrow().clone();
+        String::from_utf8(data).expect("Data was invalid utf-8")
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.inner.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.borrow_mut().flush()
+    }
+}
+
+/// Writer that passes everything through to another writer while keeping a
+/// copy in a SharedBuffer.
+pub struct TeeWriter {
+    inner: Box<dyn Write>,
+    copy: SharedBuffer,
+}
+
+impl TeeWriter {
+    pub fn new(inner: Box<dyn Write>, copy: SharedBuffer) -> Self {
+        TeeWriter { inner, copy }
+    }
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let written = self.inner.write(buf)?;
+        self.copy.write_all(&buf[..written])?;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Writer whose reader has gone away, like stdout piped into `head` after it
+/// has exited.
+#[cfg(test)]
+pub struct ClosedPipe;
+
+#[cfg(test)]
+impl Write for ClosedPipe {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, io::Error> {
+        Err(io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
+use super::profiler::Profiler;
+
+/// Where time went while loading and running a program.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Time spent reading the source and parsing it into an optimized AST
+    pub parse: Duration,
+    /// Loops the optimizer removed because they could never run
+    pub removed_loops: usize,
+    /// Time spent compiling before execution began
+    pub codegen: Duration,
+    /// Name of each fragment that was compiled during execution, along with
+    /// the time spent compiling it
+    pub lazy_codegen: Vec<(String, Duration)>,
+    /// Wall clock time of the run, including any lazy compilation
+    pub run: Duration,
+}
+
+impl Stats {
+    /// Total time spent compiling fragments during execution.
+    fn lazy_codegen_total(&self) -> Duration {
+        self.lazy_codegen.iter().map(|&(_, elapsed)| elapsed).sum()
+    }
+
+    /// Write a summary of where time went.
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let lazy_codegen = self.lazy_codegen_total();
+        let slowest = match self
+            .lazy_codegen
+            .iter()
+            .max_by_key(|&&(_, elapsed)| elapsed)
+        {
+            Some((name, elapsed)) => format!(", slowest {:.3?} for {}", elapsed, name),
+            None => String::new(),
+        };
+
+        writeln!(
+            out,
+            "{:<14}{:>12.3?}  ({} dead loops removed)",
+            "parse", self.parse, self.removed_loops
+        )?;
+        writeln!(out, "{:<14}{:>12.3?}", "codegen", self.codegen)?;
+        writeln!(
+            out,
+            "{:<14}{:>12.3?}  ({} fragments{})",
+            "lazy codegen",
+            lazy_codegen,
+            self.lazy_codegen.len(),
+            slowest
+        )?;
+        writeln!(
+            out,
+            "{:<14}{:>12.3?}  (excluding lazy codegen)",
+            "execution",
+            self.run.saturating_sub(lazy_codegen)
+        )
+    }
+}
+
+impl Profiler for Stats {
+    fn program_compiled(&mut self, elapsed: Duration) {
+        self.codegen += elapsed;
+    }
+
+    fn fragment_compiled(&mut self, name: &str, elapsed: Duration) {
+        self.lazy_codegen.push((name.to_string(), elapsed));
+    }
+}
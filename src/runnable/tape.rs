@@ -0,0 +1,375 @@
+//! Memory cells of a running program, shared by both backends.
+//!
+//! Tapes can be asked to use huge pages, which cuts down on TLB misses once a
+//! program uses megabytes of memory. Small tapes, and systems where huge pages
+//! aren't available, silently use ordinary memory instead.
+//!
+//! Tapes can also be given inaccessible guard pages in front of them, so that
+//! JIT compiled code touching a cell left of the tape faults rather than
+//! reading or writing whatever is there.
+
+use std::ops::{Deref, DerefMut, Range};
+use std::slice;
+
+/// Size of a huge page on the architectures this runs on. Tapes smaller than
+/// this don't gain anything from huge pages.
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+/// Bytes of guard pages in front of a guarded tape. Cells further left than
+/// this aren't guarded.
+pub const GUARD_SIZE: usize = 1024 * 1024;
+
+/// Where the cells of a tape live.
+enum Cells {
+    Heap(Vec<u8>),
+    /// An anonymous mapping of `capacity` bytes, after `guard` bytes of guard
+    /// pages. Bytes past the end of the tape are always zero.
+    #[cfg(target_os = "linux")]
+    Mapped {
+        ptr: *mut u8,
+        capacity: usize,
+        guard: usize,
+    },
+}
+
+/// A zero initialized, resizable run of cells.
+pub struct Tape {
+    cells: Cells,
+    len: usize,
+    /// Whether to move into huge pages once large enough
+    huge_pages: bool,
+    /// Whether to keep guard pages in front of the cells
+    guarded: bool,
+}
+
+impl Tape {
+    /// A tape of `len` zeroed cells.
+    pub fn new(len: usize) -> Self {
+        Tape {
+            cells: Cells::Heap(vec![0; len]),
+            len,
+            huge_pages: false,
+            guarded: false,
+        }
+    }
+
+    /// A tape of `len` zeroed cells that uses huge pages whenever it is large
+    /// enough to.
+    pub fn with_huge_pages(len: usize) -> Self {
+        let mut tape = Tape {
+            cells: Cells::Heap(Vec::new()),
+            len: 0,
+            huge_pages: true,
+            guarded: false,
+        };
+        tape.resize(len);
+
+        tape
+    }
+
+    /// Whether the cells are currently in huge pages.
+    pub fn is_mapped(&self) -> bool {
+        !matches!(self.cells, Cells::Heap(_))
+    }
+
+    /// Keep guard pages in front of the cells from now on, wherever they
+    /// move. Does nothing where the tape can't be mapped.
+    pub fn add_guard(&mut self) {
+        self.guarded = true;
+        self.resize(self.len);
+    }
+
+    /// Addresses of the guard pages in front of the cells, if they have any.
+    pub fn guard(&self) -> Option<Range<usize>> {
+        match self.cells {
+            #[cfg(target_os = "linux")]
+            Cells::Mapped { ptr, guard, .. } if guard > 0 => {
+                Some(ptr as usize - guard..ptr as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// Grow or shrink the tape, zeroing any new cells.
+    pub fn resize(&mut self, len: usize) {
+        let huge = self.huge_pages && len >= HUGE_PAGE_SIZE;
+
+        if (huge || self.guarded) && self.resize_mapped(len) {
+            return;
+        }
+
+        match self.cells {
+            Cells::Heap(ref mut cells) => cells.resize(len, 0),
+            #[cfg(target_os = "linux")]
+            Cells::Mapped { .. } => {
+                // Shrinking below the huge page size, or remapping failed.
+                let mut cells = self[..len.min(self.len)].to_vec();
+                cells.resize(len, 0);
+                self.cells = Cells::Heap(cells);
+            }
+        }
+
+        self.len = len;
+    }
+
+    /// Cut the tape down to `len` cells if it is longer.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            self.resize(len);
+        }
+    }
+
+    /// Resize into a mapping, in huge pages if the tape is large enough for
+    /// them, returning false if it couldn't be mapped.
+    #[cfg(target_os = "linux")]
+    fn resize_mapped(&mut self, len: usize) -> bool {
+        let huge = self.huge_pages && len >= HUGE_PAGE_SIZE;
+        let unit = if huge {
+            HUGE_PAGE_SIZE
+        } else {
+            unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+        };
+        let capacity = len.max(1).div_ceil(unit) * unit;
+        let guard = if self.guarded { GUARD_SIZE } else { 0 };
+
+        match self.cells {
+            Cells::Mapped {
+                ptr,
+                capacity: old_capacity,
+                ..
+            } if len <= old_capacity => {
+                // Keep the bytes past the end zeroed.
+                if len < self.len {
+                    unsafe { ptr.add(len).write_bytes(0, self.len - len) };
+                }
+            }
+            // The guard pages are a mapping of their own, which can't be
+            // remapped along with the cells.
+            Cells::Mapped {
+                ptr,
+                capacity: old_capacity,
+                guard: 0,
+            } if guard == 0 => {
+                let moved = unsafe {
+                    libc::mremap(
+                        ptr as *mut libc::c_void,
+                        old_capacity,
+                        capacity,
+                        libc::MREMAP_MAYMOVE,
+                    )
+                };
+                if moved == libc::MAP_FAILED {
+                    return false;
+                }
+
+                advise_huge_pages(moved, capacity);
+                self.cells = Cells::Mapped {
+                    ptr: moved as *mut u8,
+                    capacity,
+                    guard: 0,
+                };
+            }
+            _ => {
+                let ptr = match map(capacity, guard) {
+                    Some(ptr) => ptr,
+                    None => return false,
+                };
+
+                if huge {
+                    advise_huge_pages(ptr as *mut libc::c_void, capacity);
+                }
+
+                let kept = self.len.min(len);
+                unsafe { std::ptr::copy_nonoverlapping(self.as_ptr(), ptr, kept) };
+
+                if let Cells::Mapped {
+                    ptr,
+                    capacity,
+                    guard,
+                } = self.cells
+                {
+                    unmap(ptr, capacity, guard);
+                }
+
+                self.cells = Cells::Mapped {
+                    ptr,
+                    capacity,
+                    guard,
+                };
+            }
+        }
+
+        self.len = len;
+        true
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn resize_mapped(&mut self, _len: usize) -> bool {
+        false
+    }
+}
+
+/// Map `capacity` zeroed bytes after `guard` bytes of guard pages, returning
+/// where the bytes start.
+#[cfg(target_os = "linux")]
+fn map(capacity: usize, guard: usize) -> Option<*mut u8> {
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            guard + capacity,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return None;
+    }
+
+    if guard > 0 && unsafe { libc::mprotect(ptr, guard, libc::PROT_NONE) } != 0 {
+        unsafe { libc::munmap(ptr, guard + capacity) };
+        return None;
+    }
+
+    Some(unsafe { (ptr as *mut u8).add(guard) })
+}
+
+/// Unmap what `map` mapped.
+#[cfg(target_os = "linux")]
+fn unmap(ptr: *mut u8, capacity: usize, guard: usize) {
+    unsafe { libc::munmap(ptr.sub(guard) as *mut libc::c_void, guard + capacity) };
+}
+
+/// Ask for a mapping to be backed by huge pages. The kernel is free to say
+/// no, in which case ordinary pages work just as well, only slower.
+#[cfg(target_os = "linux")]
+fn advise_huge_pages(ptr: *mut libc::c_void, len: usize) {
+    unsafe { libc::madvise(ptr, len, libc::MADV_HUGEPAGE) };
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Tape::new(0)
+    }
+}
+
+impl Deref for Tape {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.cells {
+            Cells::Heap(ref cells) => cells,
+            #[cfg(target_os = "linux")]
+            Cells::Mapped { ptr, .. } => unsafe { slice::from_raw_parts(ptr, self.len) },
+        }
+    }
+}
+
+impl DerefMut for Tape {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self.cells {
+            Cells::Heap(ref mut cells) => cells,
+            #[cfg(target_os = "linux")]
+            Cells::Mapped { ptr, .. } => unsafe { slice::from_raw_parts_mut(ptr, self.len) },
+        }
+    }
+}
+
+impl Clone for Tape {
+    fn clone(&self) -> Self {
+        let mut tape = if self.huge_pages {
+            Tape::with_huge_pages(self.len)
+        } else {
+            Tape::new(self.len)
+        };
+        if self.guarded {
+            tape.add_guard();
+        }
+        tape.copy_from_slice(self);
+
+        tape
+    }
+}
+
+impl Drop for Tape {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let Cells::Mapped {
+            ptr,
+            capacity,
+            guard,
+        } = self.cells
+        {
+            unmap(ptr, capacity, guard);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resize a tape up and down past the huge page size, checking that cells
+    /// are kept and new ones start out zeroed.
+    fn check_resizing(mut tape: Tape) {
+        tape[10] = 1;
+        tape.resize(3 * HUGE_PAGE_SIZE);
+        tape[3 * HUGE_PAGE_SIZE - 1] = 2;
+        assert_eq!(tape[10], 1);
+        assert_eq!(
+            tape.is_mapped(),
+            tape.huge_pages && cfg!(target_os = "linux")
+        );
+
+        tape.truncate(HUGE_PAGE_SIZE + 1);
+        tape.resize(3 * HUGE_PAGE_SIZE);
+        assert_eq!(tape[3 * HUGE_PAGE_SIZE - 1], 0);
+
+        tape.resize(5 * HUGE_PAGE_SIZE);
+        assert_eq!(tape[10], 1);
+        assert!(tape[HUGE_PAGE_SIZE + 1..].iter().all(|&cell| cell == 0));
+
+        tape.truncate(100);
+        assert_eq!(tape.len(), 100);
+        assert_eq!(tape[10], 1);
+        assert!(!tape.is_mapped());
+    }
+
+    #[test]
+    fn resizes_on_the_heap() {
+        check_resizing(Tape::new(30_000));
+    }
+
+    #[test]
+    fn resizes_in_huge_pages() {
+        let tape = Tape::with_huge_pages(30_000);
+        assert!(!tape.is_mapped());
+
+        check_resizing(tape);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn keeps_guard_pages_in_front() {
+        let check_guard = |tape: &Tape| {
+            let guard = tape.guard().unwrap();
+            assert_eq!(guard.end, tape.as_ptr() as usize);
+            assert_eq!(guard.len(), GUARD_SIZE);
+            assert_eq!(tape[10], 1);
+        };
+
+        let mut tape = Tape::new(100);
+        assert_eq!(tape.guard(), None);
+        tape[10] = 1;
+        tape.add_guard();
+        check_guard(&tape);
+
+        tape.resize(3 * HUGE_PAGE_SIZE);
+        check_guard(&tape);
+        assert_eq!(tape[3 * HUGE_PAGE_SIZE - 1], 0);
+
+        tape.truncate(100);
+        check_guard(&tape);
+        check_guard(&tape.clone());
+    }
+}
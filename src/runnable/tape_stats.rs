@@ -0,0 +1,107 @@
+use std::io::{self, Write};
+use std::ptr;
+
+/// How a program used its memory.
+///
+/// JIT compiled code reads `base` and `write_counts_ptr` and updates `max_dp`
+/// in place, so the layout of those fields is fixed.
+#[repr(C)]
+pub struct TapeStats {
+    /// Address of the first memory cell
+    base: usize,
+    /// Start of `write_counts`
+    write_counts_ptr: *mut u64,
+    /// Highest cell the data pointer has reached
+    max_dp: usize,
+    /// Number of writes to each cell
+    write_counts: Vec<u64>,
+}
+
+impl Default for TapeStats {
+    fn default() -> Self {
+        TapeStats {
+            base: 0,
+            write_counts_ptr: ptr::null_mut(),
+            max_dp: 0,
+            write_counts: Vec::new(),
+        }
+    }
+}
+
+impl TapeStats {
+    /// Prepare for compiled code that updates these stats while operating on
+    /// `memory`.
+    pub fn attach(&mut self, memory: &[u8]) {
+        self.base = memory.as_ptr() as usize;
+        self.write_counts.resize(memory.len(), 0);
+        self.write_counts_ptr = self.write_counts.as_mut_ptr();
+    }
+
+    /// Count a write to a cell.
+    pub fn record_write(&mut self, cell: usize) {
+        if cell >= self.write_counts.len() {
+            self.write_counts.resize(cell + 1, 0);
+        }
+
+        self.write_counts[cell] += 1;
+    }
+
+    /// Note a location the data pointer has moved to.
+    pub fn record_dp(&mut self, dp: usize) {
+        self.max_dp = self.max_dp.max(dp);
+    }
+
+    /// Number of writes to each cell, up to the highest cell written.
+    pub fn write_counts(&self) -> &[u64] {
+        let used = self
+            .write_counts
+            .iter()
+            .rposition(|&count| count != 0)
+            .map_or(0, |last| last + 1);
+
+        &self.write_counts[..used]
+    }
+
+    /// Number of distinct cells that were written.
+    fn cells_written(&self) -> usize {
+        self.write_counts
+            .iter()
+            .filter(|&&count| count != 0)
+            .count()
+    }
+
+    /// Write a summary of memory usage.
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{:<14}{:>12}", "max pointer", self.max_dp)?;
+        writeln!(out, "{:<14}{:>12}", "cells written", self.cells_written())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_distinct_cells() {
+        let mut stats = TapeStats::default();
+        stats.attach(&[0u8; 16]);
+        stats.record_write(1);
+        stats.record_write(3);
+        stats.record_write(3);
+        // Past the end of the attached memory
+        stats.record_write(20);
+
+        assert_eq!(stats.cells_written(), 3);
+    }
+
+    #[test]
+    fn trims_unwritten_cells() {
+        let mut stats = TapeStats::default();
+        stats.attach(&[0u8; 16]);
+        stats.record_write(1);
+        stats.record_write(3);
+        stats.record_write(3);
+
+        assert_eq!(stats.write_counts(), &[0, 1, 0, 2]);
+    }
+}
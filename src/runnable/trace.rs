@@ -0,0 +1,75 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// Writes a line to a stream for each instruction a program executes.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    /// Only steps within this range are written.
+    range: Range<u64>,
+    /// Only every n-th step within the range is written.
+    every: u64,
+    /// Number of instructions executed so far.
+    step: u64,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, range: Range<u64>, every: u64) -> Self {
+        Tracer {
+            out,
+            range,
+            every: every.max(1),
+            step: 0,
+        }
+    }
+
+    /// Record the execution of an instruction.
+    ///
+    /// * `pc` - Location of the instruction in the program
+    /// * `dp` - Data pointer at the time of execution
+    /// * `cell` - Value of the current memory cell
+    /// * `instr` - The instruction being executed
+    pub fn trace(
+        &mut self,
+        pc: usize,
+        dp: usize,
+        cell: u8,
+        instr: &dyn fmt::Debug,
+    ) -> io::Result<()> {
+        let step = self.step;
+        self.step += 1;
+
+        if !self.range.contains(&step) || !(step - self.range.start).is_multiple_of(self.every) {
+            return Ok(());
+        }
+
+        writeln!(
+            self.out,
+            "{:>10} pc={} dp={} cell={} {:?}",
+            step, pc, dp, cell, instr
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::shared_buffer::SharedBuffer;
+    use super::*;
+
+    #[test]
+    fn filters_by_range_and_rate() {
+        let shared_buffer = SharedBuffer::new();
+        let mut tracer = Tracer::new(Box::new(shared_buffer.clone()), 2..7, 2);
+
+        for step in 0..10 {
+            tracer.trace(step, 0, 0, &"Print").unwrap();
+        }
+
+        let output = shared_buffer.get_string_content();
+        let pcs: Vec<&str> = output
+            .lines()
+            .map(|line| line.split_whitespace().nth(1).unwrap())
+            .collect();
+        assert_eq!(pcs, vec!["pc=2", "pc=4", "pc=6"]);
+    }
+}
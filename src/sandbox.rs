@@ -0,0 +1,200 @@
+//! Restricts what the process can do before it runs a program, so that
+//! neither a runaway program nor a bug in the JIT compiler can be turned into
+//! anything worse than reading input and writing output.
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp {
+    use libc::{c_long, c_ulong, sock_filter, sock_fprog};
+
+    /// Value seccomp reports for the architecture the filter was written for.
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Offsets of the fields of `seccomp_data` the filter looks at.
+    const SYSCALL_NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    /// System calls a running program may make.
+    ///
+    /// Besides input and output this allows what the JIT compiler needs to
+    /// compile loops the first time they run, and what the allocator and huge
+    /// page backed tapes need to grow memory.
+    const ALLOWED: &[c_long] = &[
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_mremap,
+        libc::SYS_munmap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_futex,
+        libc::SYS_clock_gettime,
+        libc::SYS_sigaltstack,
+        libc::SYS_rt_sigreturn,
+    ];
+
+    fn statement(code: u32, k: u32) -> sock_filter {
+        sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// Build a BPF program that allows the system calls in `ALLOWED` and fails
+    /// every other one with EPERM. Code built for another architecture is
+    /// killed, since its system call numbers mean something else.
+    fn filter() -> Vec<sock_filter> {
+        use libc::{
+            BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W, EPERM, SECCOMP_RET_ALLOW,
+            SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
+        };
+
+        let mut program = vec![
+            statement(BPF_LD | BPF_W | BPF_ABS, ARCH_OFFSET),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD | BPF_W | BPF_ABS, SYSCALL_NR_OFFSET),
+        ];
+
+        for &syscall in ALLOWED {
+            program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, syscall as u32, 0, 1));
+            program.push(statement(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+        }
+
+        program.push(statement(BPF_RET | BPF_K, SECCOMP_RET_ERRNO | EPERM as u32));
+
+        program
+    }
+
+    /// Install the filter on the calling thread. There is no way to remove it
+    /// again, so this should happen once everything but running the program is
+    /// done.
+    pub fn enter() -> Result<(), String> {
+        let filter = filter();
+        let program = sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr() as *mut sock_filter,
+        };
+
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(format!(
+                    "Could not enter sandbox: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+
+            let mode = libc::SECCOMP_MODE_FILTER as c_ulong;
+            if libc::prctl(libc::PR_SET_SECCOMP, mode, &program as *const sock_fprog) != 0 {
+                return Err(format!(
+                    "Could not enter sandbox: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub use self::seccomp::enter;
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub fn enter() -> Result<(), String> {
+    Err("Sandboxing is only supported on Linux for x86_64 and aarch64".to_string())
+}
+
+/// Apply operating system limits on the CPU time and address space of the
+/// process. Going over the CPU limit kills the process, while going over the
+/// memory limit makes allocations fail.
+#[cfg(unix)]
+pub fn limit_resources(cpu_seconds: Option<u64>, memory_bytes: Option<u64>) -> Result<(), String> {
+    let set_limit = |resource, value: u64| {
+        let limit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            return Err(format!(
+                "Could not set resource limit: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        Ok(())
+    };
+
+    if let Some(seconds) = cpu_seconds {
+        set_limit(libc::RLIMIT_CPU, seconds)?;
+    }
+
+    if let Some(bytes) = memory_bytes {
+        set_limit(libc::RLIMIT_AS, bytes)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn limit_resources(cpu_seconds: Option<u64>, memory_bytes: Option<u64>) -> Result<(), String> {
+    if cpu_seconds.is_some() || memory_bytes.is_some() {
+        return Err("CPU and memory limits are only supported on Unix".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(all(
+    test,
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod tests {
+    use std::fs::File;
+    use std::io::ErrorKind;
+    use std::thread;
+
+    use super::enter;
+
+    #[test]
+    fn blocks_opening_files() {
+        // Filters only apply to the thread that installs them, so this leaves
+        // the rest of the tests alone.
+        thread::spawn(|| {
+            enter().unwrap();
+
+            let error = File::open("Cargo.toml").unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        })
+        .join()
+        .unwrap();
+    }
+}
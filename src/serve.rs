@@ -0,0 +1,510 @@
+//! A small HTTP service that runs programs sent to it, to back a BrainFuck
+//! playground.
+//!
+//! `POST /run` takes a program as the request body, optionally followed by a
+//! `!` and the input to give it, the usual way of passing both in one stream.
+//! The response body is the program's output, with an `X-Error` header saying
+//! why it stopped if it failed part way through.
+//!
+//! Every connection is handled by a process of its own, and every program runs
+//! in a further child process with the limits from the command line applied
+//! and, with `--sandbox`, inside the sandbox. Whatever a program does, the
+//! worst outcome is its own process being killed. Clients get a few seconds
+//! to send each part of their request, headers are capped in size, and at
+//! most `MAX_HANDLERS` connections are handled at once, with the rest left
+//! waiting to be accepted.
+
+use std::io::{self, BufRead, Read, Write};
+use std::time::Duration;
+
+use crate::link::split_input;
+use crate::parser::stream::StreamParser;
+use crate::parser::Ast;
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY: usize = 1024 * 1024;
+/// Largest request line and headers accepted together, in bytes.
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+/// Connections handled at once.
+const MAX_HANDLERS: usize = 64;
+/// Longest a client may leave a read or write of its connection waiting.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// CPU time a program gets unless `--max-cpu` says otherwise.
+const DEFAULT_MAX_CPU: u64 = 5;
+/// Bytes a program may print unless `--max-output` says otherwise.
+const DEFAULT_MAX_OUTPUT: u64 = 1024 * 1024;
+/// Bytes a program's process may map unless `--max-memory` says otherwise.
+const DEFAULT_MAX_MEMORY: u64 = 512 * 1024 * 1024;
+/// Cells a program may move past unless `--max-tape` says otherwise. Wrapped
+/// tapes are already a fixed size.
+const DEFAULT_MAX_TAPE: usize = 16 * 1024 * 1024;
+
+/// The parts of an HTTP request the service looks at.
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    /// Path without the query string
+    path: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    /// Why the program stopped early, if it did
+    error: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn output(output: Vec<u8>, error: Option<String>) -> Self {
+        Response {
+            status: "200 OK",
+            content_type: "application/octet-stream",
+            error,
+            body: output,
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            error: None,
+            body: format!("{}\n", message).into_bytes(),
+        }
+    }
+
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(
+            out,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        )?;
+
+        if let Some(ref error) = self.error {
+            write!(out, "X-Error: {}\r\n", error.replace(['\r', '\n'], " "))?;
+        }
+
+        out.write_all(b"\r\n")?;
+        out.write_all(&self.body)?;
+        out.flush()
+    }
+}
+
+/// The response to a request that couldn't be read.
+fn read_error(error: io::Error) -> Response {
+    match error.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            Response::error("408 Request Timeout", "Timed out reading the request")
+        }
+        _ => Response::error("400 Bad Request", "Malformed request"),
+    }
+}
+
+/// Read a line of the request's head, counting it against the `budget` of
+/// bytes left for the head.
+fn read_head_line(reader: &mut dyn BufRead, budget: &mut usize) -> Result<String, Response> {
+    let mut line = String::new();
+    let read = reader
+        .take(*budget as u64 + 1)
+        .read_line(&mut line)
+        .map_err(read_error)?;
+
+    if read > *budget {
+        return Err(Response::error(
+            "431 Request Header Fields Too Large",
+            &format!("Request headers may be at most {} bytes", MAX_HEADER_BYTES),
+        ));
+    }
+
+    *budget -= read;
+    Ok(line)
+}
+
+/// Read a request, or the response to send if it can't be handled.
+fn read_request(reader: &mut dyn BufRead) -> Result<Request, Response> {
+    let mut budget = MAX_HEADER_BYTES;
+    let line = read_head_line(reader, &mut budget)?;
+
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Err(Response::error("400 Bad Request", "Malformed request")),
+    };
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        let header = read_head_line(reader, &mut budget)?;
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| Response::error("400 Bad Request", "Invalid Content-Length"))?;
+            }
+        }
+    }
+
+    if content_length > MAX_BODY {
+        return Err(Response::error(
+            "413 Payload Too Large",
+            &format!("Requests may be at most {} bytes", MAX_BODY),
+        ));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(read_error)?;
+
+    Ok(Request { method, path, body })
+}
+
+#[cfg(unix)]
+pub use self::unix::run;
+
+#[cfg(not(unix))]
+pub fn run(_args: &crate::Args) -> Result<(), String> {
+    Err("Serving programs is only supported on Unix".to_string())
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::collections::VecDeque;
+    use std::fs::File;
+    use std::io::{BufReader, Cursor, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::FromRawFd;
+    use std::process::exit;
+    use std::ptr;
+
+    use super::*;
+    use crate::parser::AstNode;
+    use crate::runnable::limits::LimitedWriter;
+    use crate::runnable::{Instrumentation, TapeMode};
+    use crate::{
+        build_runnable, cell_overflow, jit_options, sandbox, tape_mode, Args, JitOptions,
+        TapeOptions,
+    };
+
+    /// How every program is run.
+    struct Settings {
+        interpret: bool,
+        jit: JitOptions,
+        tape: TapeOptions,
+        max_output: u64,
+        max_cpu: u64,
+        max_memory: u64,
+        sandbox: bool,
+    }
+
+    /// Serve requests on the address given on the command line until the
+    /// process is killed.
+    pub fn run(args: &Args) -> Result<(), String> {
+        let mode = tape_mode(args)?;
+        let default_limit = Some(DEFAULT_MAX_TAPE).filter(|_| mode != TapeMode::Wrapped);
+        let settings = Settings {
+            interpret: args.flag_int,
+            jit: jit_options(args)?,
+            tape: TapeOptions {
+                limit: args.flag_max_tape.or(default_limit),
+                mode,
+                cell_overflow: cell_overflow(args)?,
+                huge_pages: args.flag_huge_pages,
+            },
+            max_output: args.flag_max_output.unwrap_or(DEFAULT_MAX_OUTPUT),
+            max_cpu: args.flag_max_cpu.unwrap_or(DEFAULT_MAX_CPU),
+            max_memory: args.flag_max_memory.unwrap_or(DEFAULT_MAX_MEMORY),
+            sandbox: args.flag_sandbox,
+        };
+        let listener = TcpListener::bind(&args.arg_addr)
+            .map_err(|e| format!("Could not listen on {}: {}", args.arg_addr, e))?;
+
+        // Connection handlers still running
+        let mut handlers = 0;
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Could not accept a connection: {}", e);
+                    continue;
+                }
+            };
+
+            reap_handlers(&mut handlers);
+
+            match unsafe { libc::fork() } {
+                -1 => eprintln!("Could not fork: {}", io::Error::last_os_error()),
+                0 => {
+                    handle(stream, &settings);
+                    exit(0);
+                }
+                _ => handlers += 1,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reap the connection handlers that have finished, waiting for one to
+    /// if `MAX_HANDLERS` are running.
+    fn reap_handlers(handlers: &mut usize) {
+        while *handlers > 0 {
+            let flags = if *handlers >= MAX_HANDLERS {
+                0
+            } else {
+                libc::WNOHANG
+            };
+
+            if unsafe { libc::waitpid(-1, ptr::null_mut(), flags) } <= 0 {
+                return;
+            }
+
+            *handlers -= 1;
+        }
+    }
+
+    fn handle(mut stream: TcpStream, settings: &Settings) {
+        let timeouts = stream
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)));
+
+        if let Err(e) = timeouts {
+            eprintln!("Could not set a timeout: {}", e);
+            return;
+        }
+
+        let request = match stream.try_clone() {
+            Ok(reader) => read_request(&mut BufReader::new(reader)),
+            Err(e) => {
+                eprintln!("Could not read request: {}", e);
+                return;
+            }
+        };
+        let response = match request {
+            Ok(request) => respond(request, settings),
+            Err(response) => response,
+        };
+
+        if let Err(e) = response.write_to(&mut stream) {
+            eprintln!("Could not send response: {}", e);
+        }
+    }
+
+    fn respond(request: Request, settings: &Settings) -> Response {
+        if request.path != "/run" {
+            return Response::error("404 Not Found", "Programs are run with POST /run");
+        }
+
+        if request.method != "POST" {
+            return Response::error("405 Method Not Allowed", "Programs are run with POST /run");
+        }
+
+        let (program, input) = split_input(&request.body);
+        let mut parser = StreamParser::new();
+        parser.set_cell_overflow(settings.tape.cell_overflow);
+        let ast = match Ast::parse_with(&String::from_utf8_lossy(program), parser) {
+            Ok(ast) => ast,
+            Err(e) => return Response::error("400 Bad Request", &e),
+        };
+
+        match run_isolated(ast.data, input.unwrap_or_default().to_vec(), settings) {
+            Ok((output, error)) => Response::output(output, error),
+            Err(e) => Response::error("500 Internal Server Error", &e),
+        }
+    }
+
+    /// A pipe as its read and write ends.
+    fn pipe() -> Result<(File, File), String> {
+        let mut fds = [0; 2];
+
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(format!(
+                "Could not create a pipe: {}",
+                io::Error::last_os_error()
+            ));
+        }
+
+        Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+    }
+
+    /// Run a program in a child process, returning its output and why it
+    /// stopped early, if it did.
+    fn run_isolated(
+        nodes: VecDeque<AstNode>,
+        input: Vec<u8>,
+        settings: &Settings,
+    ) -> Result<(Vec<u8>, Option<String>), String> {
+        let (mut output_read, output_write) = pipe()?;
+        let (mut error_read, mut error_write) = pipe()?;
+
+        match unsafe { libc::fork() } {
+            -1 => Err(format!(
+                "Could not start the program: {}",
+                io::Error::last_os_error()
+            )),
+            0 => {
+                drop(output_read);
+                drop(error_read);
+
+                if let Err(e) = run_child(nodes, input, output_write, settings) {
+                    error_write.write_all(e.as_bytes()).ok();
+                }
+                exit(0);
+            }
+            pid => {
+                drop(output_write);
+                drop(error_write);
+
+                // Read while the program runs, so that it never blocks on a
+                // full pipe.
+                let mut output = Vec::new();
+                output_read.read_to_end(&mut output).ok();
+                let mut error = String::new();
+                error_read.read_to_string(&mut error).ok();
+
+                let mut status = 0;
+                unsafe { libc::waitpid(pid, &mut status, 0) };
+
+                let error = if libc::WIFSIGNALED(status) {
+                    Some(match libc::WTERMSIG(status) {
+                        libc::SIGXCPU | libc::SIGKILL => format!(
+                            "Exceeded the CPU time limit of {} seconds",
+                            settings.max_cpu
+                        ),
+                        signal => format!("Killed by signal {}", signal),
+                    })
+                } else {
+                    Some(error).filter(|error| !error.is_empty())
+                };
+
+                Ok((output, error))
+            }
+        }
+    }
+
+    fn run_child(
+        nodes: VecDeque<AstNode>,
+        input: Vec<u8>,
+        output: File,
+        settings: &Settings,
+    ) -> Result<(), String> {
+        let io_write: Box<dyn Write> =
+            Box::new(LimitedWriter::new(Box::new(output), settings.max_output));
+        let mut runnable = build_runnable(
+            settings.interpret,
+            settings.jit.clone(),
+            settings.tape,
+            nodes,
+            Box::new(Cursor::new(input)),
+            io_write,
+            Instrumentation::default(),
+        )?;
+
+        sandbox::limit_resources(Some(settings.max_cpu), Some(settings.max_memory))?;
+
+        if settings.sandbox {
+            sandbox::enter()?;
+        }
+
+        runnable.run()?;
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn stops_unbounded_tapes() {
+            let settings = Settings {
+                interpret: true,
+                jit: JitOptions::default(),
+                tape: TapeOptions {
+                    limit: Some(DEFAULT_MAX_TAPE),
+                    ..TapeOptions::default()
+                },
+                max_output: DEFAULT_MAX_OUTPUT,
+                max_cpu: DEFAULT_MAX_CPU,
+                max_memory: DEFAULT_MAX_MEMORY,
+                sandbox: false,
+            };
+            let request = Request {
+                method: "POST".to_string(),
+                path: "/run".to_string(),
+                body: b"+[>+]".to_vec(),
+            };
+            let response = respond(request, &settings);
+
+            assert_eq!(response.status, "200 OK");
+            assert_eq!(
+                response.error,
+                Some(format!(
+                    "Exceeded the tape limit of {} cells.",
+                    DEFAULT_MAX_TAPE
+                ))
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use super::*;
+
+    fn parse(request: &[u8]) -> Result<Request, String> {
+        read_request(&mut BufReader::new(request))
+            .map_err(|response| String::from_utf8(response.body).unwrap())
+    }
+
+    #[test]
+    fn reads_requests() {
+        assert_eq!(
+            parse(b"POST /run?x=1 HTTP/1.1\r\nHost: a\r\ncontent-length: 5\r\n\r\n,.!hi"),
+            Ok(Request {
+                method: "POST".to_string(),
+                path: "/run".to_string(),
+                body: b",.!hi".to_vec(),
+            })
+        );
+        assert_eq!(
+            parse(b"GARBAGE\r\n\r\n"),
+            Err("Malformed request\n".to_string())
+        );
+        assert!(parse(b"POST /run HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn limits_headers() {
+        let too_large = Err(format!(
+            "Request headers may be at most {} bytes\n",
+            MAX_HEADER_BYTES
+        ));
+        let long_line = format!(
+            "POST /run HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_HEADER_BYTES)
+        );
+        assert_eq!(parse(long_line.as_bytes()), too_large);
+
+        // An endless line is cut off rather than read to its end.
+        let mut endless = BufReader::new(b"POST /run ".chain(io::repeat(b'a')));
+        assert!(read_request(&mut endless).is_err());
+
+        let many_headers = format!(
+            "POST /run HTTP/1.1\r\n{}\r\n",
+            "X: a\r\n".repeat(MAX_HEADER_BYTES / 6)
+        );
+        assert_eq!(parse(many_headers.as_bytes()), too_large);
+    }
+}
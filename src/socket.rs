@@ -0,0 +1,181 @@
+//! Programs that read and write through TCP connections rather than stdin and
+//! stdout, so that BrainFuck clients and servers can be run directly.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use crate::parser::AstNode;
+use crate::runnable::limits::LimitedWriter;
+use crate::runnable::Instrumentation;
+use crate::{
+    build_runnable, cell_overflow, config, jit_options, link, parse, preload, tape_mode, Args,
+    JitOptions, ProgramIO, TapeOptions,
+};
+
+/// Connect to `addr`, returning the connection as a program's input and
+/// output.
+pub fn connect(addr: &str) -> Result<ProgramIO, String> {
+    let stream =
+        TcpStream::connect(addr).map_err(|e| format!("Could not connect to {}: {}", addr, e))?;
+    let reader = stream
+        .try_clone()
+        .map_err(|e| format!("Could not read from {}: {}", addr, e))?;
+
+    Ok((Box::new(reader), Box::new(stream)))
+}
+
+/// How each connection's program is run.
+#[derive(Clone)]
+struct Session {
+    interpret: bool,
+    jit: JitOptions,
+    tape: TapeOptions,
+    max_output: Option<u64>,
+    /// Values placed in memory before running
+    preload: Vec<u8>,
+}
+
+/// Accept connections on the address given with `--listen` until the process
+/// is killed, running the program afresh on its own thread for each one.
+pub fn listen(args: &Args) -> Result<(), String> {
+    let addr = args.flag_listen.as_deref().unwrap_or_default();
+
+    if args.flag_connect.is_some() {
+        return Err("Only one of --listen and --connect may be given".to_string());
+    }
+
+    if args.flag_record.is_some() || args.flag_replay.is_some() {
+        return Err("Can't record or replay input with --listen".to_string());
+    }
+
+    if args.flag_sandbox {
+        return Err("Can't accept connections from inside the sandbox".to_string());
+    }
+
+    let (linked, input) =
+        link::load(args).map_err(|e| format!("Error occurred while loading program: {}", e))?;
+
+    if input.is_some() || args.flag_input.is_some() {
+        return Err("Input comes from each connection with --listen".to_string());
+    }
+
+    let mut args = args.clone();
+    config::apply_pragmas(&linked.pragmas, &mut args)?;
+    let program =
+        parse(linked, &args).map_err(|e| format!("Error occurred while loading program: {}", e))?;
+    let session = Session {
+        interpret: args.flag_int,
+        jit: jit_options(&args)?,
+        tape: TapeOptions {
+            limit: args.flag_max_tape,
+            mode: tape_mode(&args)?,
+            cell_overflow: cell_overflow(&args)?,
+            huge_pages: args.flag_huge_pages,
+        },
+        max_output: args.flag_max_output,
+        preload: preload(&args)?,
+    };
+    let listener =
+        TcpListener::bind(addr).map_err(|e| format!("Could not listen on {}: {}", addr, e))?;
+
+    serve(listener, program.data, session)
+}
+
+/// Run the program for every connection made to `listener`.
+fn serve(listener: TcpListener, nodes: VecDeque<AstNode>, session: Session) -> Result<(), String> {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Could not accept a connection: {}", e);
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
+        let nodes = nodes.clone();
+        let session = session.clone();
+
+        thread::Builder::new()
+            .spawn(move || {
+                if let Err(e) = run_connection(stream, nodes, &session) {
+                    eprintln!("Error occurred while running for {}: {}", peer, e);
+                }
+            })
+            .map_err(|e| format!("Could not start a thread: {}", e))?;
+    }
+
+    Ok(())
+}
+
+fn run_connection(
+    stream: TcpStream,
+    nodes: VecDeque<AstNode>,
+    session: &Session,
+) -> Result<(), String> {
+    let reader = stream
+        .try_clone()
+        .map_err(|e| format!("Could not read from the connection: {}", e))?;
+    let mut io_write: Box<dyn Write> = Box::new(stream);
+
+    if let Some(limit) = session.max_output {
+        io_write = Box::new(LimitedWriter::new(io_write, limit));
+    }
+
+    let mut runnable = build_runnable(
+        session.interpret,
+        session.jit.clone(),
+        session.tape,
+        nodes,
+        Box::new(reader),
+        io_write,
+        Instrumentation::default(),
+    )?;
+
+    if !session.preload.is_empty() {
+        runnable.preload(&session.preload);
+    }
+
+    runnable.run()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::Shutdown;
+
+    use super::*;
+    use crate::parser::Ast;
+
+    #[test]
+    fn runs_a_program_per_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let nodes = Ast::parse(",+.,+.").unwrap().data;
+        let session = Session {
+            interpret: true,
+            jit: JitOptions::default(),
+            tape: TapeOptions::default(),
+            max_output: None,
+            preload: Vec::new(),
+        };
+        thread::spawn(move || serve(listener, nodes, session));
+
+        for input in [b"ab", b"HI"] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(input).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+
+            let mut output = Vec::new();
+            stream.read_to_end(&mut output).unwrap();
+
+            let expected: Vec<u8> = input.iter().map(|byte| byte + 1).collect();
+            assert_eq!(output, expected);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use fucker::parser::bounds::{self, Bound};
+use fucker::runnable::{TapeMode, LEFT_TAPE_SIZE, WRAPPED_TAPE_SIZE};
+
+use crate::{config, read_program, tape_mode, Args};
+
+/// Print how far each program can move the data pointer, and whether that
+/// fits the tape it would run on, with pragmas at its top taken into account.
+/// Returns 1 if any program has no bound or doesn't fit.
+pub fn run(args: &Args) -> Result<i32, String> {
+    let mut failed = false;
+
+    for program in &args.arg_programs {
+        let source = read_program(program)
+            .map_err(|e| format!("Error occurred while loading {}: {}", program, e))?;
+        let mut args = args.clone();
+        config::apply_pragmas(&source, &mut args)?;
+
+        let (lowest, highest) = match bounds::bound(&source)? {
+            Bound::Within(lowest, highest) => (lowest, highest),
+            Bound::Unbounded(start, stride) => {
+                println!(
+                    "{}:{}: No bound, since this loop moves the data pointer {} cell{} {} each pass",
+                    program,
+                    start,
+                    stride.abs(),
+                    if stride.abs() == 1 { "" } else { "s" },
+                    if stride > 0 { "right" } else { "left" }
+                );
+                failed = true;
+                continue;
+            }
+        };
+
+        println!(
+            "{}: The data pointer stays within cells {} to {} of where it starts",
+            program, lowest, highest
+        );
+
+        for problem in misfits(lowest, highest, tape_mode(&args)?, args.flag_max_tape) {
+            println!("{}: {}", program, problem);
+            failed = true;
+        }
+    }
+
+    Ok(failed as i32)
+}
+
+/// Ways that the cells from `lowest` to `highest` don't fit a tape.
+fn misfits(lowest: isize, highest: isize, mode: TapeMode, limit: Option<usize>) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    match mode {
+        TapeMode::Standard if lowest < 0 => {
+            problems.push("Moves left of where it starts, off the start of the tape".to_string())
+        }
+        TapeMode::Bidirectional if lowest < -(LEFT_TAPE_SIZE as isize) => problems.push(format!(
+            "Moves more than the {} cells left of where it starts that the tape has",
+            LEFT_TAPE_SIZE
+        )),
+        TapeMode::Wrapped if highest - lowest >= WRAPPED_TAPE_SIZE as isize => {
+            problems.push(format!(
+                "Spans more than the {} cells of the tape, so the data pointer wraps around",
+                WRAPPED_TAPE_SIZE
+            ))
+        }
+        _ => {}
+    }
+
+    // The tape limit doesn't apply to wrapped tapes.
+    match limit {
+        Some(limit) if mode != TapeMode::Wrapped && highest >= limit as isize => {
+            problems.push(format!(
+                "Reaches cell {}, past the tape limit of {} cells",
+                highest, limit
+            ))
+        }
+        _ => {}
+    }
+
+    problems
+}
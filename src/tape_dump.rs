@@ -0,0 +1,104 @@
+/// Split a `--tape-dump` argument of the form `FILE[:N]` into the file and
+/// the number of cells to write, if given. A suffix that isn't all digits is
+/// part of the file name.
+pub fn parse_spec(spec: &str) -> Result<(&str, Option<usize>), String> {
+    match spec.rsplit_once(':') {
+        Some((path, count)) if !count.is_empty() && count.bytes().all(|b| b.is_ascii_digit()) => {
+            let count = count
+                .parse()
+                .map_err(|e| format!("Invalid tape dump size {:?}: {}", count, e))?;
+
+            Ok((path, Some(count)))
+        }
+        _ => Ok((spec, None)),
+    }
+}
+
+/// The cells `--tape-dump` writes from `memory`, from `origin`, where the
+/// data pointer started. That's `count` cells if given, with any past the end
+/// of memory written as zeros, and otherwise as far as the last non-zero cell
+/// or the end of the `preloaded` cells, whichever is further.
+pub fn dumped_cells(
+    memory: &[u8],
+    origin: usize,
+    count: Option<usize>,
+    preloaded: usize,
+) -> Vec<u8> {
+    let count = count.unwrap_or_else(|| {
+        let used = memory
+            .iter()
+            .rposition(|&cell| cell != 0)
+            .map_or(0, |last| last + 1);
+
+        used.saturating_sub(origin).max(preloaded)
+    });
+    let mut cells: Vec<u8> = memory.iter().skip(origin).take(count).copied().collect();
+    cells.resize(count, 0);
+
+    cells
+}
+
+/// The first cell `--assert-tape` finds different, along with the value
+/// expected there and the value found. Cells past the end of either are
+/// zero.
+pub fn first_difference(cells: &[u8], expected: &[u8]) -> Option<(usize, u8, u8)> {
+    let cell = |cells: &[u8], index: usize| cells.get(index).copied().unwrap_or(0);
+
+    (0..cells.len().max(expected.len()))
+        .map(|index| (index, cell(expected, index), cell(cells, index)))
+        .find(|&(_, want, got)| want != got)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!(parse_spec("tape.bin"), Ok(("tape.bin", None)));
+        assert_eq!(parse_spec("tape.bin:16"), Ok(("tape.bin", Some(16))));
+        assert_eq!(parse_spec("a:b:0"), Ok(("a:b", Some(0))));
+        // Anything but digits after the last colon is part of the name.
+        assert_eq!(parse_spec("tape:16k"), Ok(("tape:16k", None)));
+        assert_eq!(parse_spec("tape:"), Ok(("tape:", None)));
+        assert_eq!(
+            parse_spec("tape:99999999999999999999999"),
+            Err(
+                "Invalid tape dump size \"99999999999999999999999\": number too large to fit in target type"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn dumps_touched_cells() {
+        let memory = [0, 0, 1, 2, 0, 0];
+
+        assert_eq!(dumped_cells(&memory, 0, None, 0), [0, 0, 1, 2]);
+        assert_eq!(dumped_cells(&memory, 2, None, 0), [1, 2]);
+        // Preloaded cells are written even when they end up zero.
+        assert_eq!(dumped_cells(&memory, 2, None, 3), [1, 2, 0]);
+        assert_eq!(dumped_cells(&[0; 4], 0, None, 0), [0u8; 0]);
+        // Past the end of memory, since the program never got there.
+        assert_eq!(dumped_cells(&memory, 0, None, 8), [0, 0, 1, 2, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn dumps_counted_cells() {
+        let memory = [0, 0, 1, 2, 0, 0];
+
+        assert_eq!(dumped_cells(&memory, 0, Some(3), 5), [0, 0, 1]);
+        assert_eq!(dumped_cells(&memory, 2, Some(0), 0), [0u8; 0]);
+        assert_eq!(dumped_cells(&memory, 4, Some(4), 0), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn finds_first_difference() {
+        assert_eq!(first_difference(&[1, 2, 3], &[1, 2, 3]), None);
+        assert_eq!(first_difference(&[1, 2, 3], &[1, 5, 4]), Some((1, 5, 2)));
+        // Cells past the end of either are zero.
+        assert_eq!(first_difference(&[1, 0, 0], &[1]), None);
+        assert_eq!(first_difference(&[1, 0, 7], &[1]), Some((2, 0, 7)));
+        assert_eq!(first_difference(&[1], &[1, 0, 9]), Some((2, 9, 0)));
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Cursor};
+
+use crate::parser::{Ast, AstNode};
+use crate::read_program;
+use crate::runnable::interpreter::Fucker;
+#[cfg(target_arch = "x86_64")]
+use crate::runnable::jit::JITTarget;
+use crate::runnable::shared_buffer::SharedBuffer;
+use crate::runnable::{Instrumentation, Runnable};
+
+/// Everything a run of a program produced.
+struct Outcome {
+    output: Vec<u8>,
+    memory: Vec<u8>,
+}
+
+/// Run a program under both the interpreter and the JIT compiler with the same
+/// input, failing if their output or final memory differ.
+pub fn run(program: &str, input: Option<&str>) -> Result<(), String> {
+    let ast = read_program(program)
+        .and_then(|source| Ast::parse(&source))
+        .map_err(|e| format!("Error occurred while loading program: {}", e))?;
+    let input = match input {
+        Some(path) => fs::read(path).map_err(|e| format!("Could not read input: {:?}", e))?,
+        None => Vec::new(),
+    };
+
+    let interpreted = execute(
+        &input,
+        Box::new(Fucker::new(
+            ast.data.clone(),
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        )),
+    )
+    .map_err(|e| format!("Error occurred under the interpreter: {}", e))?;
+    let compiled = jit_outcome(&ast.data, &input)?;
+
+    let mut diverged = false;
+
+    if let Some(offset) = first_difference(&interpreted.output, &compiled.output) {
+        eprintln!(
+            "Output differs at byte {} (interpreter wrote {} bytes, JIT wrote {})",
+            offset,
+            interpreted.output.len(),
+            compiled.output.len()
+        );
+        diverged = true;
+    }
+
+    // The two backends allocate different amounts of memory, so only compare
+    // cells up to the last one either of them left non-zero.
+    let interpreted_memory = used(&interpreted.memory);
+    let compiled_memory = used(&compiled.memory);
+
+    if let Some(cell) = first_difference(interpreted_memory, compiled_memory) {
+        eprintln!(
+            "Memory differs at cell {} (interpreter has {}, JIT has {})",
+            cell,
+            cell_value(&interpreted.memory, cell),
+            cell_value(&compiled.memory, cell)
+        );
+        diverged = true;
+    }
+
+    if diverged {
+        return Err("The interpreter and JIT compiler disagree".to_string());
+    }
+
+    eprintln!("The interpreter and JIT compiler agree");
+
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+fn jit_outcome(nodes: &VecDeque<AstNode>, input: &[u8]) -> Result<Outcome, String> {
+    execute(
+        input,
+        Box::new(JITTarget::new(
+            nodes.clone(),
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+            Instrumentation::default(),
+        )),
+    )
+    .map_err(|e| format!("Error occurred under the JIT compiler: {}", e))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn jit_outcome(_nodes: &VecDeque<AstNode>, _input: &[u8]) -> Result<Outcome, String> {
+    Err("JIT is not supported for this architecture".to_string())
+}
+
+/// Run a program on a copy of `input`, capturing its output.
+fn execute(input: &[u8], mut runnable: Box<dyn Runnable>) -> Result<Outcome, String> {
+    let output = SharedBuffer::new();
+    let state = runnable.run_with_io(
+        Box::new(Cursor::new(input.to_vec())),
+        Box::new(output.clone()),
+    )?;
+
+    Ok(Outcome {
+        output: output.contents(),
+        memory: state.memory,
+    })
+}
+
+/// Value of a cell, where cells past the end of memory are zero.
+fn cell_value(memory: &[u8], cell: usize) -> u8 {
+    memory.get(cell).copied().unwrap_or(0)
+}
+
+/// Memory up to the last non-zero cell.
+fn used(memory: &[u8]) -> &[u8] {
+    let len = memory
+        .iter()
+        .rposition(|&cell| cell != 0)
+        .map_or(0, |last| last + 1);
+
+    &memory[..len]
+}
+
+/// Index of the first byte where two buffers differ. A buffer that ends early
+/// differs from a longer one at its end.
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    (0..a.len().max(b.len())).find(|&i| a.get(i) != b.get(i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_first_difference() {
+        assert_eq!(first_difference(b"abc", b"abc"), None);
+        assert_eq!(first_difference(b"abc", b"abd"), Some(2));
+        assert_eq!(first_difference(b"abc", b"ab"), Some(2));
+    }
+
+    #[test]
+    fn ignores_trailing_zero_cells() {
+        assert_eq!(
+            first_difference(used(&[1, 0, 2, 0]), used(&[1, 0, 2])),
+            None
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! Version information, along with details of the build and machine that are
+//! useful in bug reports.
+
+#[cfg(target_arch = "x86_64")]
+use crate::runnable::jit;
+
+/// Cargo features the binary was built with.
+const FEATURES: &[(&str, bool)] = &[("tokio", cfg!(feature = "tokio"))];
+
+/// Text printed by `--version`.
+pub fn describe() -> String {
+    let features: Vec<&str> = FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+
+    format!(
+        "fucker {}\n\
+         target:    {}-{}\n\
+         features:  {}\n\
+         {}",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        if features.is_empty() {
+            "none".to_string()
+        } else {
+            features.join(", ")
+        },
+        jit_details()
+    )
+}
+
+#[cfg(target_arch = "x86_64")]
+fn jit_details() -> String {
+    format!(
+        "JIT:       x86_64 code generation\n\
+         page size: {} bytes",
+        jit::page_size()
+    )
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn jit_details() -> String {
+    "JIT:       not supported on this architecture, only --int works".to_string()
+}
@@ -0,0 +1,54 @@
+//! Bindings for running programs from JavaScript, for example to power a
+//! playground in the browser. Programs always run under the interpreter.
+
+use std::io::{self, Cursor, Write};
+
+use js_sys::Function;
+use wasm_bindgen::prelude::*;
+
+use crate::parser::Ast;
+use crate::runnable::interpreter::Fucker;
+use crate::runnable::{Instrumentation, Runnable};
+
+/// Writer that hands each byte of output to a JavaScript callback.
+struct Callback(Function);
+
+impl Write for Callback {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.0
+                .call1(&JsValue::NULL, &JsValue::from(byte))
+                .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parse a program, returning its intermediate language.
+#[wasm_bindgen]
+pub fn parse(source: &str) -> Result<String, JsValue> {
+    let ast = Ast::parse(source).map_err(|e| JsValue::from_str(&e))?;
+
+    Ok(format!("{:?}", ast))
+}
+
+/// Run a program on `input`, calling `on_output` with each byte it prints.
+#[wasm_bindgen]
+pub fn run_with_io(source: &str, input: &[u8], on_output: Function) -> Result<(), JsValue> {
+    let ast = Ast::parse(source).map_err(|e| JsValue::from_str(&e))?;
+    let mut fucker = Fucker::new(
+        ast.data,
+        Box::new(Cursor::new(input.to_vec())),
+        Box::new(Callback(on_output)),
+        Instrumentation::default(),
+    );
+
+    fucker.run().map_err(|e| JsValue::from_str(&e))?;
+
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use notify::{Event, RecursiveMode, Watcher};
+
+use crate::{run_program, Args};
+
+/// How long to wait for a burst of file events to finish before re-running.
+/// Editors often write a file in several steps when saving.
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Run the program, then run it again every time one of its files changes.
+pub fn run(args: &Args) -> Result<(), String> {
+    if args.flag_eval.is_some() {
+        return Err("Can't watch a program given with -e".to_string());
+    }
+
+    if args.arg_program.iter().any(|path| path == "-") {
+        return Err("Can't watch a program read from stdin".to_string());
+    }
+
+    if args.flag_sandbox {
+        return Err("Can't watch a program from inside the sandbox".to_string());
+    }
+
+    // The fallback watcher polls from a thread, and WASI has no threads.
+    if cfg!(target_os = "wasi") {
+        return Err("Watching files is not supported on WASI".to_string());
+    }
+
+    let paths = args
+        .arg_program
+        .iter()
+        .map(|path| Path::new(path).canonicalize())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Could not open file: {:?}", e))?;
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(sender).map_err(|e| format!("Could not watch file: {}", e))?;
+
+    // Watch the directories rather than the files themselves, since editors
+    // that save by replacing a file would otherwise end the watch.
+    let mut directories: Vec<&Path> = paths
+        .iter()
+        .map(|path| path.parent().unwrap_or(path))
+        .collect();
+    directories.sort();
+    directories.dedup();
+
+    for directory in directories {
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .map_err(|e| format!("Could not watch file: {}", e))?;
+    }
+
+    loop {
+        if let Err(e) = run_program(args) {
+            eprintln!("{}", e);
+        }
+
+        let changed = wait_for_change(&receiver, &paths)?;
+        eprintln!(
+            "---------- {} changed, running again ----------",
+            changed.display()
+        );
+    }
+}
+
+/// Block until one of `paths` has been written to and things have settled
+/// down, returning the first one that was.
+fn wait_for_change(
+    receiver: &Receiver<notify::Result<Event>>,
+    paths: &[PathBuf],
+) -> Result<PathBuf, String> {
+    let touched = |event: notify::Result<Event>| -> Result<Option<PathBuf>, String> {
+        let event = event.map_err(|e| format!("Error occurred while watching file: {}", e))?;
+
+        if event.kind.is_access() {
+            return Ok(None);
+        }
+
+        Ok(event.paths.into_iter().find(|path| paths.contains(path)))
+    };
+
+    let changed = loop {
+        let event = receiver
+            .recv()
+            .map_err(|_| "File watcher stopped unexpectedly".to_string())?;
+
+        if let Some(path) = touched(event)? {
+            break path;
+        }
+    };
+
+    while let Ok(event) = receiver.recv_timeout(SETTLE_TIME) {
+        touched(event)?;
+    }
+
+    Ok(changed)
+}